@@ -5,9 +5,14 @@ use uuid::Uuid;
 use crate::{
     api::AppState,
     auth::AdminGuard,
-    domain::{Channel, FederationToken, Server, User},
+    backup::{self, BackupInfo},
+    domain::{
+        Channel, DeliveryStatus, FederationToken, MigrationStatus, OutboxEntry, PeerStatus, PeeringRequest, RetentionPolicy,
+        Server, User,
+    },
     error::AppError,
-    federation::{outbox, protocol::{FederatedChannel, FederatedChannelMembership, FederatedUser}},
+    federation::{membership, peering, protocol::{FederatedChannel, FederatedUser, MembershipAction}},
+    retention::{self, PruneReport, RetentionRule},
 };
 
 pub fn router() -> Router<AppState> {
@@ -19,22 +24,42 @@ pub fn router() -> Router<AppState> {
         .route("/debug/sync-federated", get(debug_sync_federated))
         .route("/users/:user_id", delete(delete_user))
         .route("/users/:user_id", put(update_user))
+        .route("/users/:user_id/export", get(export_user))
         .route("/servers", post(register_server))
         .route("/servers", get(list_servers))
         .route("/servers/:server_id", delete(delete_server))
         .route("/servers/:server_id", put(update_server))
         .route("/servers/:server_id/visibility", get(get_server_visibility))
         .route("/servers/:server_id/visibility", put(set_server_visibility))
+        .route("/servers/:server_id/suspend", post(suspend_server))
+        .route("/servers/:server_id/resume", post(resume_server))
+        .route("/servers/:server_id/revoke", post(revoke_server))
+        .route("/peering/requests", get(list_peering_requests))
+        .route("/peering/requests", post(request_peering))
+        .route("/peering/requests/:request_id/approve", post(approve_peering_request))
+        .route("/peering/requests/:request_id/reject", post(reject_peering_request))
         .route("/channels", post(create_channel))
         .route("/channels", get(list_channels))
         .route("/channels/sync-federated", post(sync_federated_channels))
         .route("/channels/:channel_id", delete(delete_channel))
         .route("/channels/:channel_id", put(update_channel))
         .route("/channels/:channel_id/members", post(add_channel_member))
+        .route("/channels/:channel_id/members/:user_id", delete(remove_channel_member))
         .route("/server-info", get(server_info))
         .route("/federation-tokens", get(list_federation_tokens))
         .route("/federation-tokens", post(create_federation_token))
         .route("/federation-tokens/:token_id", delete(delete_federation_token))
+        .route("/outbox", get(list_outbox))
+        .route("/outbox/:entry_id/retry", post(retry_outbox_entry))
+        .route("/migrations", get(list_migrations))
+        .route("/backups", get(list_backups))
+        .route("/backups", post(create_backup))
+        .route("/retention", get(get_retention))
+        .route("/retention/prune", post(prune_retention))
+        .route("/retention/dm", put(set_dm_retention))
+        .route("/retention/dm", delete(delete_dm_retention))
+        .route("/retention/channels/:channel_id", put(set_channel_retention))
+        .route("/retention/channels/:channel_id", delete(delete_channel_retention))
 }
 
 #[derive(Deserialize)]
@@ -92,7 +117,7 @@ async fn create_user(
         true,
         None,
         password_hash.as_deref(),
-    )?;
+    ).await?;
     Ok(Json(CreateUserResponse {
         id: user.id,
         username: user.username,
@@ -103,7 +128,7 @@ async fn create_user(
 async fn list_users(
     state: axum::extract::State<AppState>,
 ) -> Result<Json<Vec<User>>, AppError> {
-    let users = state.store.list_users()?;
+    let users = state.store.list_users().await?;
     Ok(Json(users))
 }
 
@@ -123,14 +148,14 @@ async fn register_server(
         .unwrap_or_else(|| Uuid::new_v4().to_string());
     let server = state
         .store
-        .create_server(&payload.name, &payload.base_url, &token)?;
+        .create_server(&payload.name, &payload.base_url, &token).await?;
     Ok(Json(server))
 }
 
 async fn list_servers(
     state: axum::extract::State<AppState>,
 ) -> Result<Json<Vec<Server>>, AppError> {
-    let servers = state.store.list_servers()?;
+    let servers = state.store.list_servers().await?;
     Ok(Json(servers))
 }
 
@@ -146,14 +171,14 @@ async fn create_channel(
 ) -> Result<Json<Channel>, AppError> {
     let channel = state
         .store
-        .create_channel(&payload.name, &state.config.server_name)?;
+        .create_channel(&payload.name, &state.config.server_name).await?;
     Ok(Json(channel))
 }
 
 async fn list_channels(
     state: axum::extract::State<AppState>,
 ) -> Result<Json<Vec<Channel>>, AppError> {
-    let channels = state.store.list_channels()?;
+    let channels = state.store.list_channels().await?;
     Ok(Json(channels))
 }
 
@@ -174,56 +199,60 @@ async fn add_channel_member(
         (payload.username.clone(), None)
     };
 
-    let (server_id, target_server) = if let Some(server_name) = server_name.as_deref() {
+    let server_id = if let Some(server_name) = server_name.as_deref() {
         let server = state
             .store
-            .get_server_by_name(server_name)?
+            .get_server_by_name(server_name).await?
             .ok_or_else(|| AppError::BadRequest(format!("unknown server: {}", server_name)))?;
-        (Some(server.id), Some(server))
+        Some(server.id)
     } else {
-        (None, None)
+        None
     };
 
+    let channel = state
+        .store
+        .get_channel_by_id(channel_id).await?
+        .ok_or_else(|| AppError::BadRequest("unknown channel".to_string()))?;
+
     let is_local = server_id.is_none();
     let existing = state
         .store
-        .get_user_by_name_and_server(&member_name, server_id)?;
+        .get_user_by_name_and_server(&member_name, server_id).await?;
     let user = match existing {
         Some(user) => user,
         None => state
             .store
-            .create_user(&member_name, is_local, server_id)?,
+            .create_user(&member_name, is_local, server_id).await?,
     };
 
-    state.store.add_channel_member(channel_id, user.id)?;
-
-    if let Some(server) = target_server {
-        let channel = state
-            .store
-            .get_channel_by_id(channel_id)?
-            .ok_or_else(|| AppError::BadRequest("unknown channel".to_string()))?;
-        let membership = FederatedChannelMembership {
-            channel: FederatedChannel {
-                name: channel.name,
-                origin_server: channel.origin_server,
-            },
-            member: FederatedUser {
-                username: user.username.clone(),
-                server: server.name.clone(),
-                display_name: None,
-            },
-        };
-        outbox::send_channel_membership(
-            &state.http,
-            &state.config.server_token,
-            &server,
-            &membership,
-        )
-        .await?;
-    }
+    membership::join(&state, &channel, &user).await?;
     Ok(Json(user))
 }
 
+async fn remove_channel_member(
+    _admin: AdminGuard,
+    state: axum::extract::State<AppState>,
+    axum::extract::Path((channel_id, user_id)): axum::extract::Path<(Uuid, Uuid)>,
+) -> Result<Json<()>, AppError> {
+    let channel = state
+        .store
+        .get_channel_by_id(channel_id).await?
+        .ok_or_else(|| AppError::BadRequest("unknown channel".to_string()))?;
+    let user = state
+        .store
+        .get_user_by_id(user_id).await?
+        .ok_or_else(|| AppError::BadRequest("unknown user".to_string()))?;
+    // Only the origin can kick; elsewhere the admin takes a local user out
+    // as if they had left
+    let action = if channel.origin_server == state.config.server_name {
+        MembershipAction::Kick
+    } else {
+        MembershipAction::Leave
+    };
+    membership::leave(&state, &channel, &user, action).await?;
+    Ok(Json(()))
+}
+
 async fn delete_user(
     _guard: AdminGuard,
     Path(user_id): Path<String>,
@@ -231,7 +260,7 @@ async fn delete_user(
 ) -> Result<Json<()>, AppError> {
     let id = Uuid::parse_str(&user_id)
         .map_err(|_| AppError::BadRequest("Invalid user ID".to_string()))?;
-    state.store.delete_user(&id)?;
+    state.store.delete_user(&id).await?;
     Ok(Json(()))
 }
 
@@ -242,7 +271,100 @@ async fn delete_server(
 ) -> Result<Json<()>, AppError> {
     let id = Uuid::parse_str(&server_id)
         .map_err(|_| AppError::BadRequest("Invalid server ID".to_string()))?;
-    state.store.delete_server(&id)?;
+    state.store.delete_server(&id).await?;
+    Ok(Json(()))
+}
+
+async fn find_server(state: &AppState, server_id: &str) -> Result<Server, AppError> {
+    let id = Uuid::parse_str(server_id)
+        .map_err(|_| AppError::BadRequest("Invalid server ID".to_string()))?;
+    state
+        .store
+        .get_server_by_id(&id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("unknown server".to_string()))
+}
+
+/// Stop exchanging anything with a peer until it is resumed. Requests queued
+/// for it meanwhile wait in the outbox.
+async fn suspend_server(
+    _guard: AdminGuard,
+    Path(server_id): Path<String>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<Server>, AppError> {
+    let mut server = find_server(&state, &server_id).await?;
+    if server.name == state.config.server_name {
+        return Err(AppError::BadRequest("a server cannot suspend itself".to_string()));
+    }
+    state.store.set_server_status(&server.id, PeerStatus::Suspended).await?;
+    server.status = PeerStatus::Suspended;
+    tracing::info!(target: "federation", "Suspended peer {}", server.name);
+    Ok(Json(server))
+}
+
+async fn resume_server(
+    _guard: AdminGuard,
+    Path(server_id): Path<String>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<Server>, AppError> {
+    let mut server = find_server(&state, &server_id).await?;
+    state.store.set_server_status(&server.id, PeerStatus::Active).await?;
+    server.status = PeerStatus::Active;
+    tracing::info!(target: "federation", "Resumed peer {}", server.name);
+    state.deliveries.wake();
+    Ok(Json(server))
+}
+
+/// Like deleting the server, but the peer is told first.
+async fn revoke_server(
+    _guard: AdminGuard,
+    Path(server_id): Path<String>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<()>, AppError> {
+    let server = find_server(&state, &server_id).await?;
+    peering::revoke(&state, &server).await?;
+    Ok(Json(()))
+}
+
+async fn list_peering_requests(
+    _guard: AdminGuard,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<Vec<PeeringRequest>>, AppError> {
+    Ok(Json(state.store.list_peering_requests().await?))
+}
+
+#[derive(Deserialize)]
+struct PeeringRequestPayload {
+    base_url: String,
+}
+
+async fn request_peering(
+    _guard: AdminGuard,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Json(payload): Json<PeeringRequestPayload>,
+) -> Result<Json<PeeringRequest>, AppError> {
+    let request = peering::request_peering(&state, payload.base_url.trim()).await?;
+    Ok(Json(request))
+}
+
+async fn approve_peering_request(
+    _guard: AdminGuard,
+    Path(request_id): Path<String>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<Server>, AppError> {
+    let id = Uuid::parse_str(&request_id)
+        .map_err(|_| AppError::BadRequest("Invalid request ID".to_string()))?;
+    Ok(Json(peering::approve(&state, id).await?))
+}
+
+async fn reject_peering_request(
+    _guard: AdminGuard,
+    Path(request_id): Path<String>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<()>, AppError> {
+    let id = Uuid::parse_str(&request_id)
+        .map_err(|_| AppError::BadRequest("Invalid request ID".to_string()))?;
+    peering::reject(&state, id).await?;
     Ok(Json(()))
 }
 
@@ -253,10 +375,27 @@ async fn delete_channel(
 ) -> Result<Json<()>, AppError> {
     let id = Uuid::parse_str(&channel_id)
         .map_err(|_| AppError::BadRequest("Invalid channel ID".to_string()))?;
-    state.store.delete_channel(&id)?;
+    state.store.delete_channel(&id).await?;
     Ok(Json(()))
 }
 
+async fn export_user(
+    _guard: AdminGuard,
+    Path(user_id): Path<String>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<axum::response::Response, AppError> {
+    let id = Uuid::parse_str(&user_id)
+        .map_err(|_| AppError::BadRequest("Invalid user ID".to_string()))?;
+    let user = state
+        .store
+        .get_user_by_id(id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("unknown user".to_string()))?;
+    let export = crate::export::build_export(state.store.as_ref(), &state.config.server_name, &user).await?;
+    tracing::info!("Admin exported account data for user '{}'", user.username);
+    crate::export::into_download(&export)
+}
+
 #[derive(Deserialize)]
 struct UpdateUserRequest {
     username: String,
@@ -272,13 +411,13 @@ async fn update_user(
 ) -> Result<Json<User>, AppError> {
     let id = Uuid::parse_str(&user_id)
         .map_err(|_| AppError::BadRequest("Invalid user ID".to_string()))?;
-    let user = state.store.update_user(&id, &payload.username, payload.display_name.as_deref())?;
+    let user = state.store.update_user(&id, &payload.username, payload.display_name.as_deref()).await?;
 
     if let Some(pw) = payload.password.as_deref() {
         if !pw.is_empty() {
             let hash = bcrypt::hash(pw, bcrypt::DEFAULT_COST)
                 .map_err(|_| AppError::Internal("password hashing failed".to_string()))?;
-            state.store.set_user_password(&id, &hash)?;
+            state.store.set_user_password(&id, &hash).await?;
         }
     }
 
@@ -302,7 +441,7 @@ async fn update_server(
         .map_err(|_| AppError::BadRequest("Invalid server ID".to_string()))?;
     let token = payload.token.filter(|t| !t.is_empty())
         .unwrap_or_else(|| Uuid::new_v4().to_string());
-    let server = state.store.update_server(&id, &payload.name, &payload.base_url, &token)?;
+    let server = state.store.update_server(&id, &payload.name, &payload.base_url, &token).await?;
     Ok(Json(server))
 }
 
@@ -319,7 +458,7 @@ async fn update_channel(
 ) -> Result<Json<Channel>, AppError> {
     let id = Uuid::parse_str(&channel_id)
         .map_err(|_| AppError::BadRequest("Invalid channel ID".to_string()))?;
-    let channel = state.store.update_channel(&id, &payload.name)?;
+    let channel = state.store.update_channel(&id, &payload.name).await?;
     Ok(Json(channel))
 }
 
@@ -327,19 +466,13 @@ async fn sync_federated_users(
     _admin: AdminGuard,
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Result<Json<Vec<User>>, AppError> {
-    let servers = state.store.list_servers()?;
+    let servers = state.store.list_servers().await?;
     let mut synced_users = Vec::new();
 
     for server in servers {
         let url = format!("{}/federation/users", server.base_url.trim_end_matches('/'));
         
-        let response = match state
-            .http
-            .get(&url)
-            .header("x-federation-token", &state.config.server_token)
-            .send()
-            .await
-        {
+        let response = match state.signer.send(&state.http, &server, state.http.get(&url)).await {
             Ok(resp) => resp,
             Err(e) => {
                 eprintln!("Failed to fetch from {}: {}", url, e);
@@ -358,7 +491,7 @@ async fn sync_federated_users(
                     // Skip if user is already local on this server
                     if state
                         .store
-                        .get_user_by_name_and_server(&remote_user.username, None)?
+                        .get_user_by_name_and_server(&remote_user.username, None).await?
                         .is_some()
                     {
                         continue;
@@ -367,10 +500,10 @@ async fn sync_federated_users(
                     // Update display_name if already synced from this server
                     if let Some(existing) = state
                         .store
-                        .get_user_by_name_and_server(&remote_user.username, Some(server.id))?
+                        .get_user_by_name_and_server(&remote_user.username, Some(server.id)).await?
                     {
                         if existing.display_name != remote_user.display_name {
-                            let _ = state.store.update_user_display_name(&existing.id, remote_user.display_name.as_deref());
+                            let _ = state.store.update_user_display_name(&existing.id, remote_user.display_name.as_deref()).await;
                         }
                         continue;
                     }
@@ -379,7 +512,7 @@ async fn sync_federated_users(
                         &remote_user.username,
                         false,
                         Some(server.id),
-                    )?;
+                    ).await?;
                     synced_users.push(user);
                 }
             }
@@ -397,19 +530,13 @@ async fn sync_federated_channels(
     _admin: AdminGuard,
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Result<Json<Vec<Channel>>, AppError> {
-    let servers = state.store.list_servers()?;
+    let servers = state.store.list_servers().await?;
     let mut synced_channels = Vec::new();
 
     for server in servers {
         let url = format!("{}/federation/channels", server.base_url.trim_end_matches('/'));
 
-        let response = match state
-            .http
-            .get(&url)
-            .header("x-federation-token", &state.config.server_token)
-            .send()
-            .await
-        {
+        let response = match state.signer.send(&state.http, &server, state.http.get(&url)).await {
             Ok(resp) => resp,
             Err(e) => {
                 tracing::warn!("Failed to fetch channels from {}: {}", url, e);
@@ -428,7 +555,7 @@ async fn sync_federated_channels(
                     // Skip if already exists locally
                     if state
                         .store
-                        .get_channel_by_name_origin(&remote_channel.name, &remote_channel.origin_server)?
+                        .get_channel_by_name_origin(&remote_channel.name, &remote_channel.origin_server).await?
                         .is_some()
                     {
                         continue;
@@ -437,7 +564,8 @@ async fn sync_federated_channels(
                     let channel = state.store.create_channel(
                         &remote_channel.name,
                         &remote_channel.origin_server,
-                    )?;
+                    ).await?;
+                    crate::federation::backfill::spawn(&state, channel.clone());
                     synced_channels.push(channel);
                 }
             }
@@ -470,7 +598,7 @@ async fn debug_sync_federated(
     _admin: AdminGuard,
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Result<Json<SyncDiagnostic>, AppError> {
-    let servers = state.store.list_servers()?;
+    let servers = state.store.list_servers().await?;
     let server_diags = servers
         .into_iter()
         .map(|s| {
@@ -497,6 +625,8 @@ async fn debug_sync_federated(
 struct ServerInfoResponse {
     server_name: String,
     server_token: String,
+    /// Ed25519 key this server signs federation requests with (base64).
+    public_key: String,
 }
 
 async fn server_info(
@@ -506,6 +636,7 @@ async fn server_info(
     Ok(Json(ServerInfoResponse {
         server_name: state.config.server_name.clone(),
         server_token: state.config.server_token.clone(),
+        public_key: state.signer.public_key(),
     }))
 }
 
@@ -513,7 +644,7 @@ async fn list_federation_tokens(
     _admin: AdminGuard,
     axum::extract::State(state): axum::extract::State<AppState>,
 ) -> Result<Json<Vec<FederationToken>>, AppError> {
-    let tokens = state.store.list_federation_tokens()?;
+    let tokens = state.store.list_federation_tokens().await?;
     Ok(Json(tokens))
 }
 
@@ -531,7 +662,7 @@ async fn create_federation_token(
     if label.is_empty() {
         return Err(AppError::BadRequest("label is required".to_string()));
     }
-    let token = state.store.create_federation_token(&label)?;
+    let token = state.store.create_federation_token(&label).await?;
     Ok(Json(token))
 }
 
@@ -542,10 +673,53 @@ async fn delete_federation_token(
 ) -> Result<Json<()>, AppError> {
     let id = Uuid::parse_str(&token_id)
         .map_err(|_| AppError::BadRequest("Invalid token ID".to_string()))?;
-    state.store.delete_federation_token(&id)?;
+    state.store.delete_federation_token(&id).await?;
     Ok(Json(()))
 }
 
+#[derive(Deserialize)]
+struct OutboxQuery {
+    status: Option<DeliveryStatus>,
+    limit: Option<usize>,
+}
+
+/// Queued federation requests, by default the failed ones that delivery
+/// gave up on.
+async fn list_outbox(
+    _admin: AdminGuard,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    axum::extract::Query(query): axum::extract::Query<OutboxQuery>,
+) -> Result<Json<Vec<OutboxEntry>>, AppError> {
+    let status = query.status.unwrap_or(DeliveryStatus::Failed);
+    let limit = query.limit.unwrap_or(100).min(1000);
+    Ok(Json(state.store.list_outbox_entries(status, limit).await?))
+}
+
+/// Put a failed entry back in its peer's queue with a fresh set of attempts.
+/// It keeps its place, so it goes out before anything queued after it.
+async fn retry_outbox_entry(
+    _admin: AdminGuard,
+    Path(entry_id): Path<i64>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<OutboxEntry>, AppError> {
+    let mut entry = state
+        .store
+        .get_outbox_entry(entry_id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("unknown outbox entry".to_string()))?;
+    if entry.status != DeliveryStatus::Failed {
+        return Err(AppError::BadRequest("only failed entries can be retried".to_string()));
+    }
+    let now = crate::federation::delivery::outbox_time(time::OffsetDateTime::now_utc());
+    entry.status = DeliveryStatus::Pending;
+    entry.attempts = 0;
+    entry.next_attempt_at = now.clone();
+    entry.updated_at = now;
+    state.store.update_outbox_entry(&entry).await?;
+    state.deliveries.wake();
+    Ok(Json(entry))
+}
+
 #[derive(Serialize, Deserialize)]
 struct ServerVisibility {
     hidden_user_ids: Vec<String>,
@@ -559,8 +733,8 @@ async fn get_server_visibility(
 ) -> Result<Json<ServerVisibility>, AppError> {
     let id = Uuid::parse_str(&server_id)
         .map_err(|_| AppError::BadRequest("Invalid server ID".to_string()))?;
-    let hidden_user_ids = state.store.get_hidden_user_ids(id)?;
-    let hidden_channel_ids = state.store.get_hidden_channel_ids(id)?;
+    let hidden_user_ids = state.store.get_hidden_user_ids(id).await?;
+    let hidden_channel_ids = state.store.get_hidden_channel_ids(id).await?;
     Ok(Json(ServerVisibility {
         hidden_user_ids,
         hidden_channel_ids,
@@ -575,10 +749,128 @@ async fn set_server_visibility(
 ) -> Result<Json<ServerVisibility>, AppError> {
     let id = Uuid::parse_str(&server_id)
         .map_err(|_| AppError::BadRequest("Invalid server ID".to_string()))?;
-    state.store.set_hidden_users(id, &payload.hidden_user_ids)?;
-    state.store.set_hidden_channels(id, &payload.hidden_channel_ids)?;
+    state.store.set_hidden_users(id, &payload.hidden_user_ids).await?;
+    state.store.set_hidden_channels(id, &payload.hidden_channel_ids).await?;
     Ok(Json(ServerVisibility {
         hidden_user_ids: payload.hidden_user_ids,
         hidden_channel_ids: payload.hidden_channel_ids,
     }))
 }
+
+async fn list_migrations(
+    _admin: AdminGuard,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<Vec<MigrationStatus>>, AppError> {
+    let migrations = state.store.migration_status().await?;
+    Ok(Json(migrations))
+}
+
+async fn list_backups(
+    _admin: AdminGuard,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<Vec<BackupInfo>>, AppError> {
+    Ok(Json(backup::list_backups(&state.config)?))
+}
+
+async fn create_backup(
+    _admin: AdminGuard,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<BackupInfo>, AppError> {
+    let info = backup::create_backup(state.store.as_ref(), &state.config).await?;
+    Ok(Json(info))
+}
+
+#[derive(Serialize)]
+struct RetentionOverview {
+    default: RetentionRule,
+    policies: Vec<RetentionPolicy>,
+}
+
+#[derive(Deserialize)]
+struct SetRetentionRequest {
+    max_age_days: Option<u32>,
+    max_messages: Option<u32>,
+}
+
+async fn get_retention(
+    _admin: AdminGuard,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<RetentionOverview>, AppError> {
+    Ok(Json(RetentionOverview {
+        default: RetentionRule::server_default(&state.config),
+        policies: state.store.list_retention_policies().await?,
+    }))
+}
+
+async fn prune_retention(
+    _admin: AdminGuard,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<PruneReport>, AppError> {
+    let report = retention::prune(state.store.as_ref(), &state.config).await?;
+    Ok(Json(report))
+}
+
+async fn set_dm_retention(
+    _admin: AdminGuard,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Json(payload): Json<SetRetentionRequest>,
+) -> Result<Json<RetentionPolicy>, AppError> {
+    save_retention_policy(&state, None, payload).await
+}
+
+async fn delete_dm_retention(
+    _admin: AdminGuard,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<()>, AppError> {
+    state.store.delete_retention_policy(None).await?;
+    tracing::info!(target: "retention", "Retention policy for DMs reset to server default");
+    Ok(Json(()))
+}
+
+async fn set_channel_retention(
+    _admin: AdminGuard,
+    Path(channel_id): Path<String>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+    Json(payload): Json<SetRetentionRequest>,
+) -> Result<Json<RetentionPolicy>, AppError> {
+    let id = Uuid::parse_str(&channel_id)
+        .map_err(|_| AppError::BadRequest("Invalid channel ID".to_string()))?;
+    if state.store.get_channel_by_id(id).await?.is_none() {
+        return Err(AppError::BadRequest("unknown channel".to_string()));
+    }
+    save_retention_policy(&state, Some(id), payload).await
+}
+
+async fn delete_channel_retention(
+    _admin: AdminGuard,
+    Path(channel_id): Path<String>,
+    axum::extract::State(state): axum::extract::State<AppState>,
+) -> Result<Json<()>, AppError> {
+    let id = Uuid::parse_str(&channel_id)
+        .map_err(|_| AppError::BadRequest("Invalid channel ID".to_string()))?;
+    state.store.delete_retention_policy(Some(id)).await?;
+    tracing::info!(target: "retention", "Retention policy for channel {} reset to server default", id);
+    Ok(Json(()))
+}
+
+async fn save_retention_policy(
+    state: &AppState,
+    channel_id: Option<Uuid>,
+    payload: SetRetentionRequest,
+) -> Result<Json<RetentionPolicy>, AppError> {
+    if payload.max_age_days == Some(0) || payload.max_messages == Some(0) {
+        return Err(AppError::BadRequest("retention limits must be greater than zero".to_string()));
+    }
+    let policy = state
+        .store
+        .set_retention_policy(channel_id, payload.max_age_days, payload.max_messages)
+        .await?;
+    tracing::info!(
+        target: "retention",
+        "Retention policy for {} set: max_age_days={:?} max_messages={:?}",
+        channel_id.map(|id| format!("channel {}", id)).unwrap_or_else(|| "DMs".to_string()),
+        policy.max_age_days,
+        policy.max_messages
+    );
+    Ok(Json(policy))
+}
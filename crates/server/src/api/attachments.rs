@@ -0,0 +1,280 @@
+use std::{path::Path as FsPath, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    http::{header, HeaderMap, HeaderValue},
+    response::Response,
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{
+    api::AppState,
+    auth::UserGuard,
+    blobs::{self, BlobWriter},
+    domain::{Attachment, MessageKind, User},
+    error::AppError,
+    federation::protocol::FederatedAttachment,
+};
+
+/// Attachments a single message may carry.
+const MAX_PER_MESSAGE: usize = 10;
+
+/// How long a peer may take to answer a blob fetch, and to send each chunk
+/// of it after that. Large blobs take as long as they need while they keep
+/// arriving.
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/attachments", post(upload_attachment))
+        .route("/attachments/:attachment_id", get(download_attachment))
+}
+
+#[derive(Serialize)]
+pub(super) struct AttachmentInfo {
+    id: Uuid,
+    filename: String,
+    content_type: String,
+    size: u64,
+    /// Download path, authenticated like the rest of the user API.
+    url: String,
+}
+
+impl From<Attachment> for AttachmentInfo {
+    fn from(attachment: Attachment) -> Self {
+        Self {
+            url: format!("/api/attachments/{}", attachment.id),
+            id: attachment.id,
+            filename: attachment.filename,
+            content_type: attachment.content_type,
+            size: attachment.size,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct UploadQuery {
+    filename: Option<String>,
+}
+
+/// Store the raw request body as an attachment. The body is streamed to disk
+/// and hashed on the way, so large files are never held in memory.
+async fn upload_attachment(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Query(query): Query<UploadQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Json<AttachmentInfo>, AppError> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| AppError::BadRequest("Content-Type is required".to_string()))?
+        .to_string();
+    if !state.config.is_allowed_attachment_type(&content_type) {
+        return Err(AppError::BadRequest(format!("attachments of type '{}' are not allowed", content_type)));
+    }
+    let max_bytes = state.config.attachment_max_bytes;
+    let declared = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > max_bytes) {
+        return Err(AppError::BadRequest(format!("attachment is larger than {} bytes", max_bytes)));
+    }
+
+    let mut writer = BlobWriter::create(FsPath::new(&state.config.attachments_dir), max_bytes).await?;
+    let mut stream = body.into_data_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(|e| AppError::BadRequest(format!("upload interrupted: {}", e)))?;
+        writer.write(&chunk).await?;
+    }
+    let (hash, size) = writer.finish().await?;
+    if size == 0 {
+        return Err(AppError::BadRequest("attachment is empty".to_string()));
+    }
+
+    let attachment = Attachment {
+        id: Uuid::new_v4(),
+        hash,
+        size,
+        content_type,
+        filename: sanitize_filename(query.filename.as_deref()),
+        uploader_user_id: user.id,
+        origin_server: None,
+        created_at: OffsetDateTime::now_utc().format(&Rfc3339).map_err(|e| AppError::Internal(e.to_string()))?,
+    };
+    state.store.create_attachment(&attachment).await?;
+    tracing::info!(target: "attachments", "{} uploaded {} ({} bytes, {})", user.username, attachment.id, size, attachment.hash);
+    Ok(Json(attachment.into()))
+}
+
+async fn download_attachment(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path(attachment_id): Path<String>,
+) -> Result<Response, AppError> {
+    let id = Uuid::parse_str(&attachment_id)
+        .map_err(|_| AppError::BadRequest("Invalid attachment ID".to_string()))?;
+    let attachment = state
+        .store
+        .get_attachment(id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("unknown attachment".to_string()))?;
+    if !can_view(&state, &user, &attachment).await? {
+        return Err(AppError::BadRequest("unknown attachment".to_string()));
+    }
+
+    let file = open_or_fetch(&state, &attachment).await?;
+    let family = attachment.content_type.split('/').next().unwrap_or_default();
+    let disposition = if matches!(family, "image" | "video" | "audio") { "inline" } else { "attachment" };
+    let mut response = Response::new(Body::from_stream(tokio_util::io::ReaderStream::new(file)));
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(&attachment.content_type)
+            .unwrap_or_else(|_| HeaderValue::from_static("application/octet-stream")),
+    );
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(attachment.size));
+    if let Ok(value) = HeaderValue::from_str(&format!("{}; filename=\"{}\"", disposition, attachment.filename)) {
+        headers.insert(header::CONTENT_DISPOSITION, value);
+    }
+    headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    headers.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static("sandbox"));
+    Ok(response)
+}
+
+/// The uploader can always see an attachment; anyone else only through a
+/// message they can see.
+async fn can_view(state: &AppState, user: &User, attachment: &Attachment) -> Result<bool, AppError> {
+    if attachment.uploader_user_id == user.id {
+        return Ok(true);
+    }
+    for message_id in state.store.list_attachment_message_ids(attachment.id).await? {
+        let Some(message) = state.store.get_message_by_id(message_id).await? else {
+            continue;
+        };
+        if message.kind == MessageKind::Channel
+            || message.author_user_id == user.id
+            || message.recipient_user_id == Some(user.id)
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Open the attachment's blob, first downloading it from the origin server
+/// if this is a federated copy that has not been fetched yet.
+async fn open_or_fetch(state: &AppState, attachment: &Attachment) -> Result<tokio::fs::File, AppError> {
+    let dir = FsPath::new(&state.config.attachments_dir);
+    let path = blobs::blob_path(dir, &attachment.hash)
+        .ok_or_else(|| AppError::Internal("invalid blob hash".to_string()))?;
+    if let Ok(file) = tokio::fs::File::open(&path).await {
+        return Ok(file);
+    }
+    let origin = attachment
+        .origin_server
+        .as_deref()
+        .ok_or_else(|| AppError::Internal(format!("blob {} is missing", attachment.hash)))?;
+    let server = state
+        .store
+        .get_server_by_name(origin)
+        .await?
+        .ok_or_else(|| AppError::BadRequest(format!("unknown server: {}", origin)))?;
+
+    let url = format!("{}/federation/blobs/{}", server.base_url.trim_end_matches('/'), attachment.hash);
+    let mut response = tokio::time::timeout(PEER_TIMEOUT, state.signer.send(&state.http, &server, state.http.get(&url)))
+        .await
+        .map_err(|_| timed_out(&server.name))??;
+    if !response.status().is_success() {
+        tracing::warn!(target: "attachments", "Fetching blob {} from {} failed with {}", attachment.hash, server.name, response.status());
+        return Err(AppError::Internal(format!("could not fetch attachment from {}", server.name)));
+    }
+    // Never take more than the peer announced, nor more than we would
+    // accept as an upload
+    let mut writer = BlobWriter::create(dir, attachment.size.min(state.config.attachment_max_bytes)).await?;
+    while let Some(chunk) = tokio::time::timeout(PEER_TIMEOUT, response.chunk())
+        .await
+        .map_err(|_| timed_out(&server.name))??
+    {
+        writer.write(&chunk).await?;
+    }
+    let (hash, _) = writer.finish().await?;
+    if hash != attachment.hash {
+        // The mismatched blob is unreferenced and will be garbage-collected.
+        tracing::warn!(target: "attachments", "Server {} sent the wrong content for blob {}", server.name, attachment.hash);
+        return Err(AppError::Internal(format!("{} sent a corrupt attachment", server.name)));
+    }
+    tracing::debug!(target: "attachments", "Fetched blob {} from {}", attachment.hash, server.name);
+    tokio::fs::File::open(&path).await.map_err(|e| AppError::Internal(format!("failed to open blob: {}", e)))
+}
+
+fn timed_out(server: &str) -> AppError {
+    tracing::warn!(target: "attachments", "Fetching a blob from {} timed out", server);
+    AppError::Internal(format!("{} did not send the attachment in time", server))
+}
+
+/// Resolve attachment ids given with a new message. Users can only send
+/// attachments they uploaded themselves.
+pub(super) async fn claim_attachments(state: &AppState, user: &User, ids: &[String]) -> Result<Vec<Attachment>, AppError> {
+    if ids.len() > MAX_PER_MESSAGE {
+        return Err(AppError::BadRequest(format!("at most {} attachments per message", MAX_PER_MESSAGE)));
+    }
+    let mut attachments = Vec::with_capacity(ids.len());
+    for id in ids {
+        let id = Uuid::parse_str(id).map_err(|_| AppError::BadRequest("Invalid attachment ID".to_string()))?;
+        let attachment = state
+            .store
+            .get_attachment(id)
+            .await?
+            .filter(|a| a.uploader_user_id == user.id)
+            .ok_or_else(|| AppError::BadRequest("unknown attachment".to_string()))?;
+        if !attachments.iter().any(|a: &Attachment| a.id == attachment.id) {
+            attachments.push(attachment);
+        }
+    }
+    Ok(attachments)
+}
+
+/// How peers see an attachment. Blobs are fetched from the server that first
+/// stored them.
+pub fn federated_attachments(state: &AppState, attachments: &[Attachment]) -> Vec<FederatedAttachment> {
+    attachments
+        .iter()
+        .map(|a| FederatedAttachment {
+            id: a.id.to_string(),
+            hash: a.hash.clone(),
+            size: a.size,
+            content_type: a.content_type.clone(),
+            filename: a.filename.clone(),
+            origin_server: a.origin_server.clone().unwrap_or_else(|| state.config.server_name.clone()),
+        })
+        .collect()
+}
+
+/// Keep only the last path component and drop characters that cannot appear
+/// in a `Content-Disposition` header.
+fn sanitize_filename(filename: Option<&str>) -> String {
+    let name = filename.unwrap_or_default().rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .filter(|c| !c.is_control() && *c != '"')
+        .take(255)
+        .collect();
+    let name = name.trim();
+    if name.is_empty() || name == "." || name == ".." {
+        "attachment".to_string()
+    } else {
+        name.to_string()
+    }
+}
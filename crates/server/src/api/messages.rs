@@ -7,12 +7,12 @@ use uuid::Uuid;
 use std::collections::HashMap;
 
 use crate::{
-    api::AppState,
+    api::{attachments::{self, AttachmentInfo}, AppState},
     auth::UserGuard,
     channel_call::CallParticipant,
-    domain::{Channel, MessageKind, User},
+    domain::{Attachment, Channel, ChannelPin, ConversationRef, DeliveryStatus, HistoryPage, MentionKind, Message, MessageCursor, MessageEdit, MessageKind, MessageSearch, PresenceState, PresenceStatus, ReadMarker, Server, ThreadSummary, UnreadCount, User, UserPresence},
     error::AppError,
-    federation::{outbox, protocol::{FederatedChannel, FederatedChannelCallEvent, FederatedMessage, FederatedUser, FederatedWebRtcSignal}},
+    federation::{membership, outbox, protocol::{FederatedChannel, FederatedChannelCallEvent, FederatedMessage, FederatedMessageDelete, FederatedMessageEdit, FederatedPin, FederatedReaction, FederatedUser, FederatedWebRtcSignal, MembershipAction}},
 };
 
 pub fn router() -> Router<AppState> {
@@ -23,10 +23,20 @@ pub fn router() -> Router<AppState> {
         .route("/messages/inbox", get(get_inbox))
         .route("/messages/channel/:channel_id", get(get_channel_messages))
         .route("/messages/dm/:user_id", get(get_dm_messages))
+        .route("/messages/:message_id", get(get_message).patch(edit_message).delete(delete_message))
+        .route("/messages/:message_id/edits", get(list_message_edits))
+        .route("/messages/:message_id/replies", post(reply_in_thread))
+        .route("/messages/:message_id/thread", get(get_thread))
+        .route("/messages/:message_id/reactions/:emoji", put(add_reaction).delete(remove_reaction))
         .route("/users", get(list_all_users))
         .route("/channels", get(list_all_channels))
         .route("/channels", post(create_channel_user))
         .route("/channels/active-calls", get(channel_active_calls))
+        .route("/channels/:channel_id/threads", get(list_channel_threads))
+        .route("/channels/:channel_id/pins", get(list_channel_pins))
+        .route("/channels/:channel_id/pins/:message_id", put(pin_message).delete(unpin_message))
+        .route("/channels/:channel_id/join", post(join_channel))
+        .route("/channels/:channel_id/leave", post(leave_channel))
         .route("/channels/:channel_id/members", post(add_channel_member_user))
         .route("/channels/:channel_id/members/:user_id", delete(remove_channel_member))
         .route("/channels/:channel_id/call/join", post(channel_call_join))
@@ -34,7 +44,14 @@ pub fn router() -> Router<AppState> {
         .route("/channels/:channel_id/call/participants", get(channel_call_participants))
         .route("/call/signal", post(call_signal))
         .route("/profile", put(update_profile))
+        .route("/presence", get(get_presence).put(set_presence))
+        .route("/presence/activity", post(report_activity))
         .route("/profile/password", put(change_password))
+        .route("/read-marker", post(update_read_marker))
+        .route("/typing", post(update_typing))
+        .route("/search", get(search_messages))
+        .route("/mentions", get(list_mentions))
+        .route("/export", get(export_account))
         .route("/gif/search", get(gif_search))
 }
 
@@ -58,11 +75,11 @@ async fn user_login(
 ) -> Result<Json<UserLoginResponse>, AppError> {
     let user = state
         .store
-        .get_user_by_name_and_server(&payload.username, None)?
+        .get_user_by_name_and_server(&payload.username, None).await?
         .ok_or(AppError::Unauthorized)?;
 
     // Verify password if the user has one set
-    if let Some(hash) = state.store.get_user_password_hash(&user.id)? {
+    if let Some(hash) = state.store.get_user_password_hash(&user.id).await? {
         let valid = bcrypt::verify(&payload.password, &hash)
             .map_err(|_| AppError::Internal("password verification failed".to_string()))?;
         if !valid {
@@ -86,6 +103,8 @@ async fn user_login(
 struct SendDmRequest {
     recipient: String,
     body: String,
+    #[serde(default)]
+    attachment_ids: Vec<String>,
 }
 
 #[derive(Serialize)]
@@ -99,28 +118,29 @@ async fn send_dm(
     Json(payload): Json<SendDmRequest>,
 ) -> Result<Json<SendMessageResponse>, AppError> {
     let (recipient_name, recipient_server_name) = split_recipient(&payload.recipient, &state.config.server_name);
+    let attachments = attachments::claim_attachments(&state, &user, &payload.attachment_ids).await?;
     
     let (recipient_user, recipient_server) = if recipient_server_name == state.config.server_name {
         // Local recipient
-        let u = if let Some(u) = state.store.get_user_by_name_and_server(&recipient_name, None)? {
+        let u = if let Some(u) = state.store.get_user_by_name_and_server(&recipient_name, None).await? {
             u
         } else {
             // Auto-create local user if not found (matching original logic but safer)
-            state.store.create_user(&recipient_name, true, None)?
+            state.store.create_user(&recipient_name, true, None).await?
         };
         (u, None)
     } else {
         // Remote recipient
         let server = state
             .store
-            .get_server_by_name(&recipient_server_name)?
+            .get_server_by_name(&recipient_server_name).await?
             .ok_or_else(|| AppError::BadRequest(format!("unknown server: {}", recipient_server_name)))?;
         
-        let u = if let Some(u) = state.store.get_user_by_name_and_server(&recipient_name, Some(server.id))? {
+        let u = if let Some(u) = state.store.get_user_by_name_and_server(&recipient_name, Some(server.id)).await? {
             u
         } else {
             // Create remote user reference
-            state.store.create_user(&recipient_name, false, Some(server.id))?
+            state.store.create_user(&recipient_name, false, Some(server.id)).await?
         };
         (u, Some(server))
     };
@@ -132,8 +152,12 @@ async fn send_dm(
         user.id,
         Some(recipient_user.id),
         None,
+        None,
         &sent_at,
-    )?;
+    ).await?;
+    let attachment_ids: Vec<Uuid> = attachments.iter().map(|a| a.id).collect();
+    state.store.attach_to_message(message.id, &attachment_ids).await?;
+    crate::mentions::record(&state, &message, &user).await?;
 
     // Notify recipient of new message
     crate::websocket::notify_new_message(
@@ -159,14 +183,11 @@ async fn send_dm(
                 display_name: None,
             }),
             channel: None,
+            thread_root_id: None,
+            attachments: attachments::federated_attachments(&state, &attachments),
         };
-        outbox::send_to_server(
-            &state.http,
-            &state.config.server_token,
-            &server,
-            &fed_message,
-        )
-        .await?;
+        // Delivered in the background; the author sees the status on the message
+        outbox::queue_message(&state, &server, &fed_message).await?;
     }
 
     Ok(Json(SendMessageResponse {
@@ -179,6 +200,8 @@ struct SendChannelRequest {
     channel: String,
     origin_server: Option<String>,
     body: String,
+    #[serde(default)]
+    attachment_ids: Vec<String>,
 }
 
 async fn send_channel(
@@ -189,74 +212,106 @@ async fn send_channel(
     let origin_server = payload.origin_server.as_deref().unwrap_or(&state.config.server_name);
     let channel = state
         .store
-        .get_channel_by_name_origin(&payload.channel, origin_server)?
+        .get_channel_by_name_origin(&payload.channel, origin_server).await?
         .ok_or_else(|| AppError::BadRequest("unknown channel".to_string()))?;
 
+    let attachments = attachments::claim_attachments(&state, &user, &payload.attachment_ids).await?;
+    let message = post_channel_message(&state, &user, channel, payload.body, None, attachments).await?;
+    Ok(Json(SendMessageResponse {
+        message_id: message.id.to_string(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct ReplyRequest {
+    body: String,
+    #[serde(default)]
+    attachment_ids: Vec<String>,
+}
+
+/// Reply in the thread of a channel message. Replying to a reply adds to the
+/// same thread, since threads are one level deep.
+async fn reply_in_thread(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path(message_id): Path<String>,
+    Json(payload): Json<ReplyRequest>,
+) -> Result<Json<SendMessageResponse>, AppError> {
+    if payload.body.trim().is_empty() && payload.attachment_ids.is_empty() {
+        return Err(AppError::BadRequest("message body cannot be empty".to_string()));
+    }
+    let attachments = attachments::claim_attachments(&state, &user, &payload.attachment_ids).await?;
+    let root = thread_root(&state, &message_id).await?;
+    let channel_id = root.channel_id.ok_or_else(|| AppError::Internal("channel message without channel".to_string()))?;
+    let channel = state
+        .store
+        .get_channel_by_id(channel_id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("unknown channel".to_string()))?;
+    let message = post_channel_message(&state, &user, channel, payload.body, Some(root.id), attachments).await?;
+    Ok(Json(SendMessageResponse {
+        message_id: message.id.to_string(),
+    }))
+}
+
+/// Store a channel message from a local user, notify local clients and send
+/// it to peers.
+async fn post_channel_message(
+    state: &AppState,
+    user: &User,
+    channel: Channel,
+    body: String,
+    thread_root_id: Option<Uuid>,
+    attachments: Vec<Attachment>,
+) -> Result<Message, AppError> {
     let sent_at = OffsetDateTime::now_utc().format(&Rfc3339).map_err(|e| AppError::Internal(e.to_string()))?;
     let message = state.store.create_message(
         MessageKind::Channel,
-        &payload.body,
+        &body,
         user.id,
         None,
         Some(channel.id),
+        thread_root_id,
         &sent_at,
-    )?;
+    ).await?;
+    let attachment_ids: Vec<Uuid> = attachments.iter().map(|a| a.id).collect();
+    state.store.attach_to_message(message.id, &attachment_ids).await?;
+    crate::mentions::record(state, &message, user).await?;
 
     // Notify local channel members of new message
-    crate::websocket::notify_new_message(
-        &state.message_broadcaster,
-        None,
-        Some(channel.id.to_string()),
-    );
+    if thread_root_id.is_some() {
+        crate::websocket::notify_thread_reply(&state.message_broadcaster, &message);
+    } else {
+        crate::websocket::notify_new_message(
+            &state.message_broadcaster,
+            None,
+            Some(channel.id.to_string()),
+        );
+    }
 
     let fed_message = FederatedMessage {
         message_id: message.id.to_string(),
         sent_at,
         kind: MessageKind::Channel,
-        body: payload.body,
+        body,
         author: FederatedUser {
-            username: user.username,
+            username: user.username.clone(),
             server: state.config.server_name.clone(),
             display_name: None,
         },
         recipient: None,
         channel: Some(FederatedChannel {
-            name: channel.name,
+            name: channel.name.clone(),
             origin_server: channel.origin_server.clone(),
         }),
+        thread_root_id: thread_root_id.map(|id| id.to_string()),
+        attachments: attachments::federated_attachments(state, &attachments),
     };
 
-    // If we are not the origin server, we should also send it to the origin server
-    // (In this simple model, we send to ALL members' servers, which includes origin)
-    outbox::send_to_channel_members(
-        &state.http,
-        &state.store,
-        &state.config.server_name,
-        &state.config.server_token,
-        channel.id,
-        &fed_message,
-    )
-    .await?;
-    
-    // If it's a remote channel, ensure we send to origin server even if no other local users are in it
-    if channel.origin_server != state.config.server_name {
-        if let Some(server) = state.store.get_server_by_name(&channel.origin_server)? {
-            // Only send if not already sent by send_to_channel_members
-            let member_servers = state.store.list_channel_member_servers(channel.id)?;
-            if !member_servers.iter().any(|s| s.name == server.name) {
-                outbox::send_to_server(
-                    &state.http,
-                    &state.config.server_token,
-                    &server,
-                    &fed_message,
-                ).await?;
-            }
-        }
-    }
+    // Member servers and the origin, even if no other local users are in it
+    outbox::queue_to_channel_members(state, &channel, &fed_message).await?;
 
-    Ok(Json(SendMessageResponse {
-        message_id: message.id.to_string(),
-    }))
+    Ok(message)
 }
 
 fn split_recipient(recipient: &str, default_server: &str) -> (String, String) {
@@ -282,7 +337,7 @@ async fn get_inbox(
     UserGuard(user): UserGuard,
     state: axum::extract::State<AppState>,
 ) -> Result<Json<Vec<InboxMessage>>, AppError> {
-    let messages = state.store.list_messages_for_user(user.id, 50)?;
+    let messages = state.store.list_messages_for_user(user.id, 50).await?;
     let inbox = messages
         .into_iter()
         .map(|message| InboxMessage {
@@ -306,6 +361,35 @@ struct MessageRecord {
     author_username: String,
     author_display_name: Option<String>,
     sent_at: String,
+    edited_at: Option<String>,
+    /// Deleted messages stay in history as tombstones with an empty body.
+    deleted_at: Option<String>,
+    reactions: Vec<ReactionSummary>,
+    attachments: Vec<AttachmentInfo>,
+    /// Set on thread replies.
+    thread_root_id: Option<String>,
+    /// Replies in this message's thread, not counting deleted ones.
+    reply_count: u64,
+    last_reply_at: Option<String>,
+    /// How far the message got to each peer it was sent to. Only filled in
+    /// for the viewer's own messages.
+    delivery: Vec<DeliveryInfo>,
+}
+
+#[derive(Serialize)]
+struct DeliveryInfo {
+    server: String,
+    status: DeliveryStatus,
+    attempts: u32,
+    last_error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReactionSummary {
+    emoji: String,
+    count: u64,
+    /// Whether the requesting user is one of the reactors.
+    reacted: bool,
 }
 
 #[derive(Serialize)]
@@ -317,89 +401,907 @@ struct UserListItem {
     is_local: bool,
     server_name: Option<String>,
     is_online: bool,
+    /// `state` is what the caller may see, so invisible users are offline to
+    /// everyone but themselves.
+    #[serde(flatten)]
+    presence: PresenceStatus,
     display_name: Option<String>,
+    /// When the user was last connected, if known. Not updated while they
+    /// are invisible.
+    last_seen_at: Option<String>,
+    /// Unread DMs from this user to the caller.
+    unread_count: u64,
+    mention_count: u64,
+}
+
+#[derive(Serialize)]
+struct ChannelListItem {
+    id: Uuid,
+    name: String,
+    origin_server: String,
+    is_member: bool,
+    /// Always 0 for channels the caller is not a member of.
+    unread_count: u64,
+    mention_count: u64,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Cursor from `prev_cursor`; returns messages older than it.
+    before: Option<String>,
+    /// Cursor from `next_cursor`; returns messages newer than it.
+    after: Option<String>,
+    /// Message id to centre the page on.
+    around: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct MessagePage {
+    messages: Vec<MessageRecord>,
+    /// Pass as `before` to load older messages. Absent at the start of history.
+    prev_cursor: Option<String>,
+    /// Pass as `after` to load newer messages. Absent when the page ends at the latest message.
+    next_cursor: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ThreadPage {
+    root: MessageRecord,
+    #[serde(flatten)]
+    replies: MessagePage,
+}
+
+#[derive(Deserialize)]
+struct ThreadListQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Clone, Copy)]
+enum Conversation {
+    /// Top-level channel messages; thread replies live under `Thread`.
+    Channel(Uuid),
+    Dm { user_id: Uuid, other_user_id: Uuid },
+    Thread(Uuid),
+}
+
+impl Conversation {
+    fn contains(&self, message: &Message) -> bool {
+        match *self {
+            Conversation::Channel(channel_id) => {
+                message.channel_id == Some(channel_id) && message.thread_root_id.is_none()
+            }
+            Conversation::Thread(root_id) => message.thread_root_id == Some(root_id),
+            Conversation::Dm { user_id, other_user_id } => {
+                message.kind == MessageKind::Dm
+                    && ((message.author_user_id == user_id && message.recipient_user_id == Some(other_user_id))
+                        || (message.author_user_id == other_user_id && message.recipient_user_id == Some(user_id)))
+            }
+        }
+    }
 }
 
 async fn get_channel_messages(
-    UserGuard(_user): UserGuard,
+    UserGuard(user): UserGuard,
     state: axum::extract::State<AppState>,
     Path(channel_id): Path<String>,
-) -> Result<Json<Vec<MessageRecord>>, AppError> {
+    Query(params): Query<HistoryQuery>,
+) -> Result<Json<MessagePage>, AppError> {
     let id = Uuid::parse_str(&channel_id)
         .map_err(|_| AppError::BadRequest("Invalid channel ID".to_string()))?;
-    let messages = state.store.list_channel_messages(id)?;
-    let records = messages
-        .into_iter()
-        .map(|msg| {
-            let author_user = state.store.get_user_by_id(msg.author_user_id)
-                .ok()
-                .flatten();
-            MessageRecord {
-                message_id: msg.id.to_string(),
-                body: msg.body,
-                author_user_id: msg.author_user_id.to_string(),
-                author_username: author_user.as_ref().map(|u| u.username.clone()).unwrap_or_default(),
-                author_display_name: author_user.as_ref().and_then(|u| u.display_name.clone()),
-                sent_at: msg.sent_at,
-            }
-        })
-        .collect();
-    Ok(Json(records))
+    Ok(Json(load_history(&state, user.id, Conversation::Channel(id), params).await?))
 }
 
 async fn get_dm_messages(
     UserGuard(user): UserGuard,
     state: axum::extract::State<AppState>,
     Path(other_user_id): Path<String>,
-) -> Result<Json<Vec<MessageRecord>>, AppError> {
+    Query(params): Query<HistoryQuery>,
+) -> Result<Json<MessagePage>, AppError> {
     let other_id = Uuid::parse_str(&other_user_id)
         .map_err(|_| AppError::BadRequest("Invalid user ID".to_string()))?;
-    let messages = state.store.list_dm_messages(user.id, other_id)?;
-    let records = messages
+    let conversation = Conversation::Dm { user_id: user.id, other_user_id: other_id };
+    Ok(Json(load_history(&state, user.id, conversation, params).await?))
+}
+
+/// A thread's root message and one page of its replies.
+async fn get_thread(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path(message_id): Path<String>,
+    Query(params): Query<HistoryQuery>,
+) -> Result<Json<ThreadPage>, AppError> {
+    let root = thread_root(&state, &message_id).await?;
+    let replies = load_history(&state, user.id, Conversation::Thread(root.id), params).await?;
+    let mut records = message_records(&state, user.id, vec![root]).await?;
+    Ok(Json(ThreadPage {
+        root: records.remove(0),
+        replies,
+    }))
+}
+
+/// Root messages of a channel's threads, most recently replied to first.
+async fn list_channel_threads(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path(channel_id): Path<String>,
+    Query(params): Query<ThreadListQuery>,
+) -> Result<Json<Vec<MessageRecord>>, AppError> {
+    let id = Uuid::parse_str(&channel_id)
+        .map_err(|_| AppError::BadRequest("Invalid channel ID".to_string()))?;
+    let limit = params.limit.unwrap_or(20).clamp(1, 100);
+    let mut roots = Vec::new();
+    for thread in state.store.list_active_threads(id, limit).await? {
+        roots.extend(state.store.get_message_by_id(thread.root_id).await?);
+    }
+    Ok(Json(message_records(&state, user.id, roots).await?))
+}
+
+/// The root of the thread `message_id` belongs to: the message itself, or
+/// its root if it is a reply. Only channel messages have threads.
+async fn thread_root(state: &AppState, message_id: &str) -> Result<Message, AppError> {
+    let id = Uuid::parse_str(message_id)
+        .map_err(|_| AppError::BadRequest("Invalid message ID".to_string()))?;
+    let mut message = state
+        .store
+        .get_message_by_id(id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("unknown message".to_string()))?;
+    if let Some(root_id) = message.thread_root_id {
+        message = state
+            .store
+            .get_message_by_id(root_id)
+            .await?
+            .ok_or_else(|| AppError::BadRequest("unknown thread".to_string()))?;
+    }
+    if message.kind != MessageKind::Channel {
+        return Err(AppError::BadRequest("only channel messages have threads".to_string()));
+    }
+    Ok(message)
+}
+
+async fn load_history(
+    state: &AppState,
+    viewer_id: Uuid,
+    conversation: Conversation,
+    params: HistoryQuery,
+) -> Result<MessagePage, AppError> {
+    let limit = params.limit.unwrap_or(50).clamp(1, 200);
+    let parse_cursor = |value: &str| {
+        MessageCursor::parse(value).ok_or_else(|| AppError::BadRequest("Invalid cursor".to_string()))
+    };
+
+    let (messages, has_older, has_newer) = match (params.before, params.after, params.around) {
+        (None, None, None) => {
+            let (messages, has_older) = read_page(state, conversation, HistoryPage::Latest, limit).await?;
+            (messages, has_older, false)
+        }
+        (Some(before), None, None) => {
+            let (messages, has_older) = read_page(state, conversation, HistoryPage::Before(parse_cursor(&before)?), limit).await?;
+            (messages, has_older, true)
+        }
+        (None, Some(after), None) => {
+            let (messages, has_newer) = read_page(state, conversation, HistoryPage::After(parse_cursor(&after)?), limit).await?;
+            (messages, true, has_newer)
+        }
+        (None, None, Some(around)) => {
+            let id = Uuid::parse_str(&around)
+                .map_err(|_| AppError::BadRequest("Invalid message ID".to_string()))?;
+            let anchor = state
+                .store
+                .get_message_by_id(id)
+                .await?
+                .filter(|m| conversation.contains(m))
+                .ok_or_else(|| AppError::BadRequest("message not found in this conversation".to_string()))?;
+            let cursor = MessageCursor::of(&anchor);
+            let older_limit = limit / 2;
+            let newer_limit = limit - older_limit - 1;
+            let (mut messages, has_older) = read_page(state, conversation, HistoryPage::Before(cursor.clone()), older_limit).await?;
+            let (newer, has_newer) = read_page(state, conversation, HistoryPage::After(cursor), newer_limit).await?;
+            messages.push(anchor);
+            messages.extend(newer);
+            (messages, has_older, has_newer)
+        }
+        _ => {
+            return Err(AppError::BadRequest(
+                "only one of before, after or around may be given".to_string(),
+            ))
+        }
+    };
+
+    let prev_cursor = messages.first().filter(|_| has_older).map(|m| MessageCursor::of(m).encode());
+    let next_cursor = messages.last().filter(|_| has_newer).map(|m| MessageCursor::of(m).encode());
+    Ok(MessagePage {
+        messages: message_records(state, viewer_id, messages).await?,
+        prev_cursor,
+        next_cursor,
+    })
+}
+
+/// Read up to `limit` messages (oldest first) and report whether more exist
+/// beyond the far end of the page in the direction being read.
+async fn read_page(
+    state: &AppState,
+    conversation: Conversation,
+    page: HistoryPage,
+    limit: usize,
+) -> Result<(Vec<Message>, bool), AppError> {
+    let newest_first = !matches!(page, HistoryPage::After(_));
+    let mut messages = match conversation {
+        Conversation::Channel(channel_id) => state.store.list_channel_messages(channel_id, &page, limit + 1).await?,
+        Conversation::Dm { user_id, other_user_id } => {
+            state.store.list_dm_messages(user_id, other_user_id, &page, limit + 1).await?
+        }
+        Conversation::Thread(root_id) => state.store.list_thread_messages(root_id, &page, limit + 1).await?,
+    };
+    let has_more = messages.len() > limit;
+    if has_more {
+        if newest_first {
+            messages.remove(0);
+        } else {
+            messages.truncate(limit);
+        }
+    }
+    Ok((messages, has_more))
+}
+
+async fn message_records(
+    state: &AppState,
+    viewer_id: Uuid,
+    messages: Vec<Message>,
+) -> Result<Vec<MessageRecord>, AppError> {
+    let ids: Vec<Uuid> = messages.iter().map(|m| m.id).collect();
+    let mut threads: HashMap<Uuid, ThreadSummary> = state
+        .store
+        .thread_summaries(&ids)
+        .await?
         .into_iter()
-        .map(|msg| {
-            let author_user = state.store.get_user_by_id(msg.author_user_id)
-                .ok()
-                .flatten();
-            MessageRecord {
-                message_id: msg.id.to_string(),
-                body: msg.body,
-                author_user_id: msg.author_user_id.to_string(),
-                author_username: author_user.as_ref().map(|u| u.username.clone()).unwrap_or_default(),
-                author_display_name: author_user.as_ref().and_then(|u| u.display_name.clone()),
-                sent_at: msg.sent_at,
-            }
-        })
+        .map(|t| (t.root_id, t))
         .collect();
-    Ok(Json(records))
+    let mut reactions: HashMap<Uuid, Vec<ReactionSummary>> = HashMap::new();
+    for count in state.store.reaction_counts(&ids, viewer_id).await? {
+        reactions.entry(count.message_id).or_default().push(ReactionSummary {
+            emoji: count.emoji,
+            count: count.count,
+            reacted: count.reacted,
+        });
+    }
+    let mut attachments: HashMap<Uuid, Vec<AttachmentInfo>> = HashMap::new();
+    for (message_id, attachment) in state.store.list_message_attachments(&ids).await? {
+        attachments.entry(message_id).or_default().push(attachment.into());
+    }
+    let own: Vec<Uuid> = messages.iter().filter(|m| m.author_user_id == viewer_id).map(|m| m.id).collect();
+    let mut deliveries: HashMap<Uuid, Vec<DeliveryInfo>> = HashMap::new();
+    for delivery in state.store.message_deliveries(&own).await? {
+        deliveries.entry(delivery.message_id).or_default().push(DeliveryInfo {
+            server: delivery.server_name,
+            status: delivery.status,
+            attempts: delivery.attempts,
+            last_error: delivery.last_error,
+        });
+    }
+
+    let mut records = Vec::with_capacity(messages.len());
+    for msg in messages {
+        let thread = threads.remove(&msg.id);
+        let author_user = state.store.get_user_by_id(msg.author_user_id).await
+            .ok()
+            .flatten();
+        records.push(MessageRecord {
+            message_id: msg.id.to_string(),
+            body: msg.body,
+            author_user_id: msg.author_user_id.to_string(),
+            author_username: author_user.as_ref().map(|u| u.username.clone()).unwrap_or_default(),
+            author_display_name: author_user.as_ref().and_then(|u| u.display_name.clone()),
+            sent_at: msg.sent_at,
+            edited_at: msg.edited_at,
+            deleted_at: msg.deleted_at,
+            reactions: reactions.remove(&msg.id).unwrap_or_default(),
+            attachments: attachments.remove(&msg.id).unwrap_or_default(),
+            thread_root_id: msg.thread_root_id.map(|id| id.to_string()),
+            reply_count: thread.as_ref().map_or(0, |t| t.reply_count),
+            last_reply_at: thread.map(|t| t.last_reply_at),
+            delivery: deliveries.remove(&msg.id).unwrap_or_default(),
+        });
+    }
+    Ok(records)
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    channel_id: Option<String>,
+    /// Restrict to the DM conversation with this user.
+    dm_user_id: Option<String>,
+    author_id: Option<String>,
+    /// Inclusive lower bound, RFC3339 or `YYYY-MM-DD`.
+    from: Option<String>,
+    /// Upper bound, RFC3339 (exclusive) or `YYYY-MM-DD` (inclusive of that day).
+    to: Option<String>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct SearchResult {
+    message_id: String,
+    kind: MessageKind,
+    body: String,
+    snippet: String,
+    author_user_id: String,
+    author_username: String,
+    author_display_name: Option<String>,
+    recipient_user_id: Option<String>,
+    channel_id: Option<String>,
+    sent_at: String,
+}
+
+#[derive(Deserialize)]
+struct EditMessageRequest {
+    body: String,
+}
+
+async fn edit_message(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path(message_id): Path<String>,
+    Json(payload): Json<EditMessageRequest>,
+) -> Result<Json<MessageRecord>, AppError> {
+    if payload.body.trim().is_empty() {
+        return Err(AppError::BadRequest("message body cannot be empty".to_string()));
+    }
+    let message = authorize_message_change(&state, &user, &message_id).await?;
+    let edited_at = OffsetDateTime::now_utc().format(&Rfc3339).map_err(|e| AppError::Internal(e.to_string()))?;
+    let updated = state
+        .store
+        .edit_message(message.id, &payload.body, user.id, &edited_at)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("message was deleted or changed concurrently".to_string()))?;
+    crate::websocket::notify_message_changed(&state.message_broadcaster, &updated);
+
+    let (author, channel) = federated_origin(&state, &updated).await?;
+    let edit = FederatedMessageEdit {
+        message_id: updated.id.to_string(),
+        body: updated.body.clone(),
+        edited_at,
+        author,
+        editor: local_federated_user(&state, &user),
+        channel,
+    };
+    for server in message_peer_servers(&state, &updated).await? {
+        outbox::queue_message_edit(&state, &server, &edit).await?;
+    }
+
+    let mut records = message_records(&state, user.id, vec![updated]).await?;
+    Ok(Json(records.remove(0)))
+}
+
+async fn delete_message(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path(message_id): Path<String>,
+) -> Result<Json<MessageRecord>, AppError> {
+    let message = authorize_message_change(&state, &user, &message_id).await?;
+    let deleted_at = OffsetDateTime::now_utc().format(&Rfc3339).map_err(|e| AppError::Internal(e.to_string()))?;
+    let tombstone = state
+        .store
+        .delete_message(message.id, &deleted_at)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("message was already deleted".to_string()))?;
+    crate::websocket::notify_message_changed(&state.message_broadcaster, &tombstone);
+
+    let (author, channel) = federated_origin(&state, &tombstone).await?;
+    let delete = FederatedMessageDelete {
+        message_id: tombstone.id.to_string(),
+        deleted_at,
+        author,
+        editor: local_federated_user(&state, &user),
+        channel,
+    };
+    for server in message_peer_servers(&state, &tombstone).await? {
+        outbox::queue_message_delete(&state, &server, &delete).await?;
+    }
+
+    let mut records = message_records(&state, user.id, vec![tombstone]).await?;
+    Ok(Json(records.remove(0)))
+}
+
+/// A single message in the history format, for clients refreshing one they
+/// were told changed.
+async fn get_message(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path(message_id): Path<String>,
+) -> Result<Json<MessageRecord>, AppError> {
+    let message = visible_message(&state, &user, &message_id).await?;
+    let record = message_records(&state, user.id, vec![message]).await?.pop();
+    record.map(Json).ok_or_else(|| AppError::Internal("message vanished".to_string()))
+}
+
+async fn list_message_edits(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path(message_id): Path<String>,
+) -> Result<Json<Vec<MessageEdit>>, AppError> {
+    let message = visible_message(&state, &user, &message_id).await?;
+    Ok(Json(state.store.list_message_edits(message.id).await?))
+}
+
+/// A channel message, or a DM the user sent or received.
+async fn visible_message(state: &AppState, user: &User, message_id: &str) -> Result<Message, AppError> {
+    let id = Uuid::parse_str(message_id)
+        .map_err(|_| AppError::BadRequest("Invalid message ID".to_string()))?;
+    state
+        .store
+        .get_message_by_id(id)
+        .await?
+        .filter(|m| m.kind == MessageKind::Channel || m.author_user_id == user.id || m.recipient_user_id == Some(user.id))
+        .ok_or_else(|| AppError::BadRequest("unknown message".to_string()))
+}
+
+async fn add_reaction(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path((message_id, emoji)): Path<(String, String)>,
+) -> Result<Json<Vec<ReactionSummary>>, AppError> {
+    set_reaction(&state, &user, &message_id, &emoji, true).await.map(Json)
+}
+
+async fn remove_reaction(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path((message_id, emoji)): Path<(String, String)>,
+) -> Result<Json<Vec<ReactionSummary>>, AppError> {
+    set_reaction(&state, &user, &message_id, &emoji, false).await.map(Json)
+}
+
+/// Add or remove one of the user's reactions and return the message's
+/// reactions afterwards. Repeating a request is harmless; only the first one
+/// is broadcast and federated.
+async fn set_reaction(
+    state: &AppState,
+    user: &User,
+    message_id: &str,
+    emoji: &str,
+    added: bool,
+) -> Result<Vec<ReactionSummary>, AppError> {
+    if emoji.is_empty() || emoji.chars().count() > 32 || emoji.chars().any(char::is_whitespace) {
+        return Err(AppError::BadRequest("invalid emoji".to_string()));
+    }
+    let id = Uuid::parse_str(message_id)
+        .map_err(|_| AppError::BadRequest("Invalid message ID".to_string()))?;
+    let message = state
+        .store
+        .get_message_by_id(id)
+        .await?
+        .filter(|m| m.kind == MessageKind::Channel || m.author_user_id == user.id || m.recipient_user_id == Some(user.id))
+        .filter(|m| m.deleted_at.is_none())
+        .ok_or_else(|| AppError::BadRequest("unknown message".to_string()))?;
+
+    let changed = if added {
+        state.store.add_reaction(message.id, user.id, emoji).await?
+    } else {
+        state.store.remove_reaction(message.id, user.id, emoji).await?
+    };
+    if changed {
+        crate::websocket::notify_reaction_changed(&state.message_broadcaster, &message, emoji, user.id, added);
+        let (_, channel) = federated_origin(state, &message).await?;
+        let reaction = FederatedReaction {
+            message_id: message.id.to_string(),
+            emoji: emoji.to_string(),
+            reactor: local_federated_user(state, user),
+            added,
+            channel,
+        };
+        for server in message_peer_servers(state, &message).await? {
+            outbox::queue_reaction(state, &server, &reaction).await?;
+        }
+    }
+
+    let mut records = message_records(state, user.id, vec![message]).await?;
+    Ok(records.remove(0).reactions)
+}
+
+#[derive(Serialize)]
+struct PinRecord {
+    pinned_by_user_id: String,
+    pinned_at: String,
+    #[serde(flatten)]
+    message: MessageRecord,
+}
+
+async fn list_channel_pins(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path(channel_id): Path<String>,
+) -> Result<Json<Vec<PinRecord>>, AppError> {
+    let channel = find_channel(&state, &channel_id).await?;
+    pin_records(&state, user.id, channel.id).await.map(Json)
+}
+
+async fn pin_message(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path((channel_id, message_id)): Path<(String, String)>,
+) -> Result<Json<Vec<PinRecord>>, AppError> {
+    set_pin(&state, &user, &channel_id, &message_id, true).await.map(Json)
+}
+
+async fn unpin_message(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path((channel_id, message_id)): Path<(String, String)>,
+) -> Result<Json<Vec<PinRecord>>, AppError> {
+    set_pin(&state, &user, &channel_id, &message_id, false).await.map(Json)
+}
+
+async fn find_channel(state: &AppState, channel_id: &str) -> Result<Channel, AppError> {
+    let id = Uuid::parse_str(channel_id)
+        .map_err(|_| AppError::BadRequest("Invalid channel ID".to_string()))?;
+    state
+        .store
+        .get_channel_by_id(id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("unknown channel".to_string()))
+}
+
+/// Pin or unpin a message for everyone in the channel and return the pins
+/// afterwards. Only members may change pins; repeating a request is harmless.
+async fn set_pin(
+    state: &AppState,
+    user: &User,
+    channel_id: &str,
+    message_id: &str,
+    pinned: bool,
+) -> Result<Vec<PinRecord>, AppError> {
+    let channel = find_channel(state, channel_id).await?;
+    if !state.store.is_channel_member(channel.id, user.id).await? {
+        return Err(AppError::BadRequest("only channel members can change pins".to_string()));
+    }
+    let id = Uuid::parse_str(message_id)
+        .map_err(|_| AppError::BadRequest("Invalid message ID".to_string()))?;
+    let message = state
+        .store
+        .get_message_by_id(id)
+        .await?
+        .filter(|m| m.channel_id == Some(channel.id))
+        .ok_or_else(|| AppError::BadRequest("unknown message".to_string()))?;
+
+    let pinned_at = OffsetDateTime::now_utc().format(&Rfc3339).map_err(|e| AppError::Internal(e.to_string()))?;
+    let changed = if pinned {
+        if message.deleted_at.is_some() {
+            return Err(AppError::BadRequest("message was deleted".to_string()));
+        }
+        let pin = ChannelPin {
+            channel_id: channel.id,
+            message_id: message.id,
+            pinned_by_user_id: user.id,
+            pinned_at: pinned_at.clone(),
+        };
+        state.store.pin_message(&pin).await?
+    } else {
+        state.store.unpin_message(channel.id, message.id).await?
+    };
+    if changed {
+        crate::websocket::notify_pin_changed(&state.message_broadcaster, channel.id, message.id, user.id, pinned);
+        let pin = FederatedPin {
+            message_id: message.id.to_string(),
+            channel: FederatedChannel {
+                name: channel.name.clone(),
+                origin_server: channel.origin_server.clone(),
+            },
+            pinner: local_federated_user(state, user),
+            pinned,
+            pinned_at,
+        };
+        let mut servers = state.store.list_channel_member_servers(channel.id).await?;
+        if channel.origin_server != state.config.server_name {
+            servers.extend(state.store.get_server_by_name(&channel.origin_server).await?);
+        }
+        servers.retain(|s| s.name != state.config.server_name);
+        servers.sort_by(|a, b| a.name.cmp(&b.name));
+        servers.dedup_by(|a, b| a.name == b.name);
+        for server in servers {
+            outbox::queue_pin(state, &server, &pin).await?;
+        }
+    }
+
+    pin_records(state, user.id, channel.id).await
+}
+
+async fn pin_records(state: &AppState, viewer_id: Uuid, channel_id: Uuid) -> Result<Vec<PinRecord>, AppError> {
+    let mut pins = Vec::new();
+    let mut messages = Vec::new();
+    for pin in state.store.list_channel_pins(channel_id).await? {
+        if let Some(message) = state.store.get_message_by_id(pin.message_id).await? {
+            pins.push(pin);
+            messages.push(message);
+        }
+    }
+    let records = message_records(state, viewer_id, messages).await?;
+    Ok(pins
+        .into_iter()
+        .zip(records)
+        .map(|(pin, message)| PinRecord {
+            pinned_by_user_id: pin.pinned_by_user_id.to_string(),
+            pinned_at: pin.pinned_at,
+            message,
+        })
+        .collect())
+}
+
+/// Authors may change their own messages. Moderators may also change channel
+/// messages, as long as peers will accept the change: the channel must
+/// originate here or the author must be local.
+async fn authorize_message_change(state: &AppState, user: &User, message_id: &str) -> Result<Message, AppError> {
+    let id = Uuid::parse_str(message_id)
+        .map_err(|_| AppError::BadRequest("Invalid message ID".to_string()))?;
+    let message = state
+        .store
+        .get_message_by_id(id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("unknown message".to_string()))?;
+    if message.deleted_at.is_some() {
+        return Err(AppError::BadRequest("message was deleted".to_string()));
+    }
+    if message.author_user_id == user.id {
+        return Ok(message);
+    }
+    let Some(channel_id) = message.channel_id else {
+        return Err(AppError::Unauthorized);
+    };
+    if !user.is_local || !state.config.is_moderator(&user.username) {
+        return Err(AppError::Unauthorized);
+    }
+    let channel_is_local = state
+        .store
+        .get_channel_by_id(channel_id)
+        .await?
+        .is_some_and(|c| c.origin_server == state.config.server_name);
+    let author_is_local = state
+        .store
+        .get_user_by_id(message.author_user_id)
+        .await?
+        .is_some_and(|u| u.is_local);
+    if !channel_is_local && !author_is_local {
+        return Err(AppError::BadRequest(
+            "moderators can only act on messages in local channels or by local users".to_string(),
+        ));
+    }
+    Ok(message)
+}
+
+fn local_federated_user(state: &AppState, user: &User) -> FederatedUser {
+    FederatedUser {
+        username: user.username.clone(),
+        server: state.config.server_name.clone(),
+        display_name: None,
+    }
+}
+
+/// The author and channel of a message as peers name them.
+async fn federated_origin(
+    state: &AppState,
+    message: &Message,
+) -> Result<(FederatedUser, Option<FederatedChannel>), AppError> {
+    let author = state
+        .store
+        .get_user_by_id(message.author_user_id)
+        .await?
+        .ok_or_else(|| AppError::Internal("message author is missing".to_string()))?;
+    let server = match author.server_id {
+        Some(server_id) => state.store.get_server_by_id(&server_id).await?.map(|s| s.name),
+        None => None,
+    };
+    let author = FederatedUser {
+        username: author.username,
+        server: server.unwrap_or_else(|| state.config.server_name.clone()),
+        display_name: None,
+    };
+    let channel = match message.channel_id {
+        Some(channel_id) => state.store.get_channel_by_id(channel_id).await?.map(|c| FederatedChannel {
+            name: c.name,
+            origin_server: c.origin_server,
+        }),
+        None => None,
+    };
+    Ok((author, channel))
+}
+
+/// Peers holding a copy of the message: the other DM participant's server;
+/// for channels we originate, every peer (as with new messages), otherwise
+/// the servers with members plus the channel's origin.
+async fn message_peer_servers(state: &AppState, message: &Message) -> Result<Vec<Server>, AppError> {
+    let mut servers: Vec<Server> = Vec::new();
+    match message.channel_id {
+        Some(channel_id) => {
+            let channel = state
+                .store
+                .get_channel_by_id(channel_id)
+                .await?
+                .ok_or_else(|| AppError::BadRequest("unknown channel".to_string()))?;
+            if channel.origin_server == state.config.server_name {
+                servers.extend(state.store.list_servers().await?);
+            } else {
+                servers.extend(state.store.list_channel_member_servers(channel_id).await?);
+                servers.extend(state.store.get_server_by_name(&channel.origin_server).await?);
+            }
+        }
+        None => {
+            for user_id in [Some(message.author_user_id), message.recipient_user_id].into_iter().flatten() {
+                if let Some(server_id) = state.store.get_user_by_id(user_id).await?.and_then(|u| u.server_id) {
+                    servers.extend(state.store.get_server_by_id(&server_id).await?);
+                }
+            }
+        }
+    }
+    servers.retain(|s| s.name != state.config.server_name);
+    servers.sort_by(|a, b| a.name.cmp(&b.name));
+    servers.dedup_by(|a, b| a.name == b.name);
+    Ok(servers)
+}
+
+#[derive(Deserialize)]
+struct MentionsQuery {
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct MentionRecord {
+    kind: MentionKind,
+    /// Set for channel messages; DM mentions are in the conversation with the author.
+    channel_id: Option<String>,
+    #[serde(flatten)]
+    message: MessageRecord,
+}
+
+/// The caller's most recent mentions, newest first.
+async fn list_mentions(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Query(params): Query<MentionsQuery>,
+) -> Result<Json<Vec<MentionRecord>>, AppError> {
+    let limit = params.limit.unwrap_or(50).clamp(1, 200);
+    let mut kinds = Vec::new();
+    let mut messages = Vec::new();
+    for mention in state.store.list_mentions(user.id, limit).await? {
+        if let Some(message) = state.store.get_message_by_id(mention.message_id).await? {
+            kinds.push(mention.kind);
+            messages.push(message);
+        }
+    }
+    let channel_ids: Vec<Option<String>> = messages.iter().map(|m| m.channel_id.map(|id| id.to_string())).collect();
+    let records = message_records(&state, user.id, messages).await?;
+    Ok(Json(
+        kinds
+            .into_iter()
+            .zip(channel_ids)
+            .zip(records)
+            .map(|((kind, channel_id), message)| MentionRecord { kind, channel_id, message })
+            .collect(),
+    ))
+}
+
+async fn search_messages(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Query(params): Query<SearchQuery>,
+) -> Result<Json<Vec<SearchResult>>, AppError> {
+    if params.q.trim().is_empty() {
+        return Err(AppError::BadRequest("q is required".to_string()));
+    }
+    let parse_id = |value: Option<String>, what: &str| {
+        value
+            .filter(|v| !v.is_empty())
+            .map(|v| Uuid::parse_str(&v).map_err(|_| AppError::BadRequest(format!("Invalid {}", what))))
+            .transpose()
+    };
+    let search = MessageSearch {
+        query: params.q,
+        channel_id: parse_id(params.channel_id, "channel ID")?,
+        dm_user_id: parse_id(params.dm_user_id, "user ID")?,
+        author_user_id: parse_id(params.author_id, "author ID")?,
+        since: params.from.as_deref().map(|v| parse_search_bound(v, false)).transpose()?,
+        until: params.to.as_deref().map(|v| parse_search_bound(v, true)).transpose()?,
+        limit: params.limit.unwrap_or(50).clamp(1, 200),
+    };
+
+    let hits = state.store.search_messages(user.id, &search).await?;
+    let mut results = Vec::with_capacity(hits.len());
+    for hit in hits {
+        let msg = hit.message;
+        let author_user = state.store.get_user_by_id(msg.author_user_id).await
+            .ok()
+            .flatten();
+        results.push(SearchResult {
+            message_id: msg.id.to_string(),
+            kind: msg.kind,
+            body: msg.body,
+            snippet: hit.snippet,
+            author_user_id: msg.author_user_id.to_string(),
+            author_username: author_user.as_ref().map(|u| u.username.clone()).unwrap_or_default(),
+            author_display_name: author_user.as_ref().and_then(|u| u.display_name.clone()),
+            recipient_user_id: msg.recipient_user_id.map(|id| id.to_string()),
+            channel_id: msg.channel_id.map(|id| id.to_string()),
+            sent_at: msg.sent_at,
+        });
+    }
+    Ok(Json(results))
+}
+
+async fn export_account(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+) -> Result<axum::response::Response, AppError> {
+    let export = crate::export::build_export(state.store.as_ref(), &state.config.server_name, &user).await?;
+    tracing::info!("User '{}' exported their account data", user.username);
+    crate::export::into_download(&export)
+}
+
+/// Normalise a date filter to a UTC RFC3339 string comparable with `sent_at`.
+/// A bare date used as an upper bound covers the whole day.
+fn parse_search_bound(value: &str, upper: bool) -> Result<String, AppError> {
+    let invalid = || AppError::BadRequest(format!("Invalid date '{}': expected RFC3339 or YYYY-MM-DD", value));
+    let parsed = if value.len() == 10 {
+        let start = OffsetDateTime::parse(&format!("{}T00:00:00Z", value), &Rfc3339).map_err(|_| invalid())?;
+        if upper {
+            start + time::Duration::days(1)
+        } else {
+            start
+        }
+    } else {
+        OffsetDateTime::parse(value, &Rfc3339).map_err(|_| invalid())?
+    };
+    parsed
+        .to_offset(time::UtcOffset::UTC)
+        .format(&Rfc3339)
+        .map_err(|e| AppError::Internal(e.to_string()))
+}
+
+/// The caller's unread and mention counts keyed by conversation.
+async fn unread_by_conversation(state: &AppState, user: &User) -> Result<HashMap<ConversationRef, UnreadCount>, AppError> {
+    Ok(state
+        .store
+        .unread_counts(user.id)
+        .await?
+        .into_iter()
+        .map(|count| (count.conversation, count))
+        .collect())
 }
 
 async fn list_all_users(
-    UserGuard(_user): UserGuard,
+    UserGuard(viewer): UserGuard,
     state: axum::extract::State<AppState>,
 ) -> Result<Json<Vec<UserListItem>>, AppError> {
-    let users = state.store.list_users()?;
+    let users = state.store.list_users().await?;
+    let unread = unread_by_conversation(&state, &viewer).await?;
+    let chosen: HashMap<Uuid, UserPresence> = state
+        .store
+        .list_user_presence()
+        .await?
+        .into_iter()
+        .map(|p| (p.user_id, p))
+        .collect();
+    let now = OffsetDateTime::now_utc().format(&Rfc3339).map_err(|e| AppError::Internal(e.to_string()))?;
     let mut results = Vec::with_capacity(users.len());
     for user in users {
         let server_name = match user.server_id {
-            Some(server_id) => state.store.get_server_by_id(&server_id)?.map(|s| s.name),
+            Some(server_id) => state.store.get_server_by_id(&server_id).await?.map(|s| s.name),
             None => None,
         };
         
         // Check local presence for local users, remote presence for remote users
-        let is_online = if user.is_local {
-            state.presence.is_online(user.id)
+        let presence = if user.is_local {
+            state.presence.local_status(user.id, chosen.get(&user.id), Some(viewer.id), &now)
         } else {
             // For remote users, check using username@server format
-            match &server_name {
-                Some(srv) => {
-                    let remote_key = format!("{}@{}", user.username, srv);
-                    state.presence.is_remote_user_online(&remote_key)
-                }
-                None => false,
-            }
+            server_name
+                .as_ref()
+                .and_then(|srv| state.presence.remote_presence(&format!("{}@{}", user.username, srv)))
+                .map_or_else(|| PresenceStatus::new(PresenceState::Offline), |p| p.expire(&now))
         };
-        
+        let is_online = presence.state != PresenceState::Offline;
+
+        let counts = unread.get(&ConversationRef::Dm(user.id));
         results.push(UserListItem {
             id: user.id.to_string(),
             username: user.username,
@@ -408,20 +1310,135 @@ async fn list_all_users(
             is_local: user.is_local,
             server_name,
             is_online,
+            presence,
             display_name: user.display_name,
+            last_seen_at: user.last_seen_at,
+            unread_count: counts.map_or(0, |c| c.unread),
+            mention_count: counts.map_or(0, |c| c.mentions),
         });
     }
     Ok(Json(results))
 }
 
 async fn list_all_channels(
-    UserGuard(_user): UserGuard,
+    UserGuard(user): UserGuard,
     state: axum::extract::State<AppState>,
-) -> Result<Json<Vec<Channel>>, AppError> {
-    let channels = state.store.list_channels()?;
+) -> Result<Json<Vec<ChannelListItem>>, AppError> {
+    let unread = unread_by_conversation(&state, &user).await?;
+    let joined: std::collections::HashSet<Uuid> =
+        state.store.list_channels_for_user(user.id).await?.into_iter().map(|c| c.id).collect();
+    let channels = state
+        .store
+        .list_channels()
+        .await?
+        .into_iter()
+        .map(|channel| {
+            let counts = unread.get(&ConversationRef::Channel(channel.id));
+            ChannelListItem {
+                id: channel.id,
+                name: channel.name,
+                origin_server: channel.origin_server,
+                is_member: joined.contains(&channel.id),
+                unread_count: counts.map_or(0, |c| c.unread),
+                mention_count: counts.map_or(0, |c| c.mentions),
+            }
+        })
+        .collect();
     Ok(Json(channels))
 }
 
+#[derive(Deserialize)]
+struct ReadMarkerRequest {
+    channel_id: Option<String>,
+    dm_user_id: Option<String>,
+    message_id: String,
+}
+
+#[derive(Serialize)]
+struct ReadMarkerResponse {
+    channel_id: Option<Uuid>,
+    dm_user_id: Option<Uuid>,
+    message_id: Uuid,
+    sent_at: String,
+    updated_at: String,
+}
+
+impl From<ReadMarker> for ReadMarkerResponse {
+    fn from(marker: ReadMarker) -> Self {
+        let (channel_id, dm_user_id) = match marker.conversation {
+            ConversationRef::Channel(id) => (Some(id), None),
+            ConversationRef::Dm(id) => (None, Some(id)),
+        };
+        Self {
+            channel_id,
+            dm_user_id,
+            message_id: marker.message_id,
+            sent_at: marker.sent_at,
+            updated_at: marker.updated_at,
+        }
+    }
+}
+
+/// Show or clear the caller's typing indicator in a channel or DM. Clients
+/// repeat `typing: true` while the user types; it expires otherwise.
+async fn update_typing(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Json(payload): Json<crate::typing::TypingRequest>,
+) -> Result<Json<&'static str>, AppError> {
+    crate::typing::update(&state, &user, payload).await?;
+    Ok(Json("ok"))
+}
+
+/// Mark everything up to and including `message_id` as read. Markers only
+/// move forward, so a stale device cannot un-read newer messages.
+async fn update_read_marker(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Json(payload): Json<ReadMarkerRequest>,
+) -> Result<Json<ReadMarkerResponse>, AppError> {
+    let parse = |value: &str, what: &str| {
+        Uuid::parse_str(value).map_err(|_| AppError::BadRequest(format!("Invalid {}", what)))
+    };
+    let (conversation, target) = match (payload.channel_id.as_deref(), payload.dm_user_id.as_deref()) {
+        (Some(channel_id), None) => {
+            let id = parse(channel_id, "channel ID")?;
+            (Conversation::Channel(id), ConversationRef::Channel(id))
+        }
+        (None, Some(dm_user_id)) => {
+            let id = parse(dm_user_id, "user ID")?;
+            (Conversation::Dm { user_id: user.id, other_user_id: id }, ConversationRef::Dm(id))
+        }
+        _ => {
+            return Err(AppError::BadRequest(
+                "exactly one of channel_id or dm_user_id is required".to_string(),
+            ))
+        }
+    };
+    let message = state
+        .store
+        .get_message_by_id(parse(&payload.message_id, "message ID")?)
+        .await?
+        .filter(|m| conversation.contains(m))
+        .ok_or_else(|| AppError::BadRequest("message not found in this conversation".to_string()))?;
+
+    let marker = ReadMarkerResponse::from(
+        state
+            .store
+            .advance_read_marker(user.id, target, &MessageCursor::of(&message))
+            .await?,
+    );
+    if let Ok(payload) = serde_json::to_string(&marker) {
+        crate::websocket::notify_read_marker_updated(
+            &state.message_broadcaster,
+            &user.id.to_string(),
+            marker.channel_id.map(|id| id.to_string()),
+            &payload,
+        );
+    }
+    Ok(Json(marker))
+}
+
 #[derive(Deserialize)]
 struct CreateChannelRequest {
     name: String,
@@ -434,7 +1451,7 @@ async fn create_channel_user(
 ) -> Result<Json<Channel>, AppError> {
     let channel = state
         .store
-        .create_channel(&payload.name, &state.config.server_name)?;
+        .create_channel(&payload.name, &state.config.server_name).await?;
     Ok(Json(channel))
 }
 
@@ -449,29 +1466,58 @@ async fn add_channel_member_user(
     Path(channel_id): Path<String>,
     Json(payload): Json<AddMemberRequest>,
 ) -> Result<Json<User>, AppError> {
-    let channel_uuid = Uuid::parse_str(&channel_id)
-        .map_err(|_| AppError::BadRequest("Invalid channel ID".to_string()))?;
+    let channel = find_channel(&state, &channel_id).await?;
     let user_uuid = Uuid::parse_str(&payload.user_id)
         .map_err(|_| AppError::BadRequest("Invalid user ID".to_string()))?;
     
-    let user = state.store.get_user_by_id(user_uuid)?
+    let user = state.store.get_user_by_id(user_uuid).await?
         .ok_or_else(|| AppError::BadRequest("User not found".to_string()))?;
     
-    state.store.add_channel_member(channel_uuid, user_uuid)?;
+    membership::join(&state, &channel, &user).await?;
     Ok(Json(user))
 }
 
+/// Remove a member. Anyone may remove themselves; removing someone else is
+/// a kick, which only moderators on the channel's origin can do.
 async fn remove_channel_member(
-    UserGuard(_user): UserGuard,
+    UserGuard(user): UserGuard,
     state: axum::extract::State<AppState>,
     Path((channel_id, user_id)): Path<(String, String)>,
 ) -> Result<Json<()>, AppError> {
-    let channel_uuid = Uuid::parse_str(&channel_id)
-        .map_err(|_| AppError::BadRequest("Invalid channel ID".to_string()))?;
+    let channel = find_channel(&state, &channel_id).await?;
     let user_uuid = Uuid::parse_str(&user_id)
         .map_err(|_| AppError::BadRequest("Invalid user ID".to_string()))?;
-    
-    state.store.remove_channel_member(channel_uuid, user_uuid)?;
+    if user_uuid == user.id {
+        membership::leave(&state, &channel, &user, MembershipAction::Leave).await?;
+        return Ok(Json(()));
+    }
+
+    if !user.is_local || !state.config.is_moderator(&user.username) {
+        return Err(AppError::Unauthorized);
+    }
+    let member = state.store.get_user_by_id(user_uuid).await?
+        .ok_or_else(|| AppError::BadRequest("User not found".to_string()))?;
+    membership::leave(&state, &channel, &member, MembershipAction::Kick).await?;
+    Ok(Json(()))
+}
+
+async fn join_channel(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path(channel_id): Path<String>,
+) -> Result<Json<()>, AppError> {
+    let channel = find_channel(&state, &channel_id).await?;
+    membership::join(&state, &channel, &user).await?;
+    Ok(Json(()))
+}
+
+async fn leave_channel(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Path(channel_id): Path<String>,
+) -> Result<Json<()>, AppError> {
+    let channel = find_channel(&state, &channel_id).await?;
+    membership::leave(&state, &channel, &user, MembershipAction::Leave).await?;
     Ok(Json(()))
 }
 
@@ -508,7 +1554,7 @@ async fn call_signal(
     if target_server_name == state.config.server_name {
         let target_user = state
             .store
-            .get_user_by_name_and_server(&target_name, None)?
+            .get_user_by_name_and_server(&target_name, None).await?
             .ok_or_else(|| AppError::BadRequest("unknown user".to_string()))?;
 
         let sse_payload = serde_json::to_string(&signal)
@@ -522,14 +1568,14 @@ async fn call_signal(
     } else {
         let server = state
             .store
-            .get_server_by_name(&target_server_name)?
+            .get_server_by_name(&target_server_name).await?
             .ok_or_else(|| {
                 AppError::BadRequest(format!("unknown server: {}", target_server_name))
             })?;
 
         outbox::send_webrtc_signal(
             &state.http,
-            &state.config.server_token,
+            &state.signer,
             &server,
             &signal,
         )
@@ -549,10 +1595,109 @@ async fn update_profile(
     state: axum::extract::State<AppState>,
     Json(payload): Json<UpdateProfileRequest>,
 ) -> Result<Json<User>, AppError> {
-    let updated = state.store.update_user(&user.id, &user.username, payload.display_name.as_deref())?;
+    let updated = state.store.update_user(&user.id, &user.username, payload.display_name.as_deref()).await?;
     Ok(Json(updated))
 }
 
+/// Longest custom status text, in characters.
+const MAX_STATUS_TEXT: usize = 100;
+/// Longest custom status emoji, in characters. Emoji sequences can be long.
+const MAX_STATUS_EMOJI: usize = 16;
+
+#[derive(Deserialize)]
+struct SetPresenceRequest {
+    state: PresenceState,
+    status_text: Option<String>,
+    status_emoji: Option<String>,
+    /// RFC3339; the custom status is cleared at this time.
+    status_expires_at: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PresenceRecord {
+    /// The state the user picked. `state` below is how they currently
+    /// appear to themselves, e.g. `away` while idle.
+    chosen_state: PresenceState,
+    #[serde(flatten)]
+    status: PresenceStatus,
+}
+
+async fn get_presence(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<PresenceRecord>, AppError> {
+    let chosen = state.store.get_user_presence(user.id).await?;
+    presence_record(&state, &user, chosen).map(Json)
+}
+
+/// Set the caller's presence state and custom status. The whole status is
+/// replaced, so leaving out the text clears it.
+async fn set_presence(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+    Json(payload): Json<SetPresenceRequest>,
+) -> Result<Json<PresenceRecord>, AppError> {
+    if payload.state == PresenceState::Offline {
+        return Err(AppError::BadRequest("offline cannot be chosen; use invisible".to_string()));
+    }
+    let clean = |value: Option<String>, max: usize, what: &str| -> Result<Option<String>, AppError> {
+        let value = value.map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        if value.as_ref().is_some_and(|v| v.chars().count() > max) {
+            return Err(AppError::BadRequest(format!("{} is longer than {} characters", what, max)));
+        }
+        Ok(value)
+    };
+    let status_text = clean(payload.status_text, MAX_STATUS_TEXT, "status text")?;
+    let status_emoji = clean(payload.status_emoji, MAX_STATUS_EMOJI, "status emoji")?;
+
+    let now = OffsetDateTime::now_utc();
+    let status_expires_at = match payload.status_expires_at.as_deref() {
+        Some(at) if status_text.is_some() || status_emoji.is_some() => {
+            let at = OffsetDateTime::parse(at, &Rfc3339)
+                .map_err(|_| AppError::BadRequest("status_expires_at must be an RFC3339 time".to_string()))?;
+            if at <= now {
+                return Err(AppError::BadRequest("status_expires_at is in the past".to_string()));
+            }
+            Some(at.to_offset(time::UtcOffset::UTC).format(&Rfc3339).map_err(|e| AppError::Internal(e.to_string()))?)
+        }
+        _ => None,
+    };
+
+    let chosen = UserPresence {
+        user_id: user.id,
+        state: payload.state,
+        status_text,
+        status_emoji,
+        status_expires_at,
+        updated_at: now.format(&Rfc3339).map_err(|e| AppError::Internal(e.to_string()))?,
+    };
+    state.store.set_user_presence(&chosen).await?;
+    state.presence.record_activity(user.id);
+    state.presence.notify_changed();
+    crate::websocket::notify_presence_changed(&state.message_broadcaster);
+    presence_record(&state, &user, Some(chosen)).map(Json)
+}
+
+fn presence_record(state: &AppState, user: &User, chosen: Option<UserPresence>) -> Result<PresenceRecord, AppError> {
+    let now = OffsetDateTime::now_utc().format(&Rfc3339).map_err(|e| AppError::Internal(e.to_string()))?;
+    Ok(PresenceRecord {
+        chosen_state: chosen.as_ref().map_or(PresenceState::Online, |p| p.state),
+        status: state.presence.local_status(user.id, chosen.as_ref(), Some(user.id), &now),
+    })
+}
+
+/// Clients call this on user input so an idle user shows as online again;
+/// without it they turn away after `PRESENCE_IDLE_SECS`.
+async fn report_activity(
+    UserGuard(user): UserGuard,
+    state: axum::extract::State<AppState>,
+) -> Result<Json<&'static str>, AppError> {
+    if state.presence.record_activity(user.id) {
+        crate::websocket::notify_presence_changed(&state.message_broadcaster);
+    }
+    Ok(Json("ok"))
+}
+
 #[derive(Deserialize)]
 struct ChangePasswordRequest {
     current_password: Option<String>,
@@ -565,7 +1710,7 @@ async fn change_password(
     Json(payload): Json<ChangePasswordRequest>,
 ) -> Result<Json<&'static str>, AppError> {
     // Verify current password if user has one
-    if let Some(hash) = state.store.get_user_password_hash(&user.id)? {
+    if let Some(hash) = state.store.get_user_password_hash(&user.id).await? {
         let current = payload.current_password.as_deref().unwrap_or("");
         let valid = bcrypt::verify(current, &hash)
             .map_err(|_| AppError::Internal("password verification failed".to_string()))?;
@@ -576,7 +1721,7 @@ async fn change_password(
 
     let new_hash = bcrypt::hash(&payload.new_password, bcrypt::DEFAULT_COST)
         .map_err(|_| AppError::Internal("password hashing failed".to_string()))?;
-    state.store.set_user_password(&user.id, &new_hash)?;
+    state.store.set_user_password(&user.id, &new_hash).await?;
 
     Ok(Json("ok"))
 }
@@ -625,7 +1770,7 @@ async fn channel_call_join(
     );
 
     // Broadcast to federated servers
-    if let Ok(Some(channel)) = state.store.get_channel_by_id(channel_uuid) {
+    if let Ok(Some(channel)) = state.store.get_channel_by_id(channel_uuid).await {
         let fed_event = FederatedChannelCallEvent {
             channel: FederatedChannel {
                 name: channel.name,
@@ -639,11 +1784,11 @@ async fn channel_call_join(
             },
             participant_user_id: user.id.to_string(),
         };
-        let servers = state.store.list_servers().unwrap_or_default();
+        let servers = state.store.list_servers().await.unwrap_or_default();
         for server in servers {
             let _ = outbox::send_channel_call_event(
                 &state.http,
-                &state.config.server_token,
+                &state.signer,
                 &server,
                 &fed_event,
             ).await;
@@ -688,7 +1833,7 @@ async fn channel_call_leave(
     );
 
     // Broadcast to federated servers
-    if let Ok(Some(channel)) = state.store.get_channel_by_id(channel_uuid) {
+    if let Ok(Some(channel)) = state.store.get_channel_by_id(channel_uuid).await {
         let fed_event = FederatedChannelCallEvent {
             channel: FederatedChannel {
                 name: channel.name,
@@ -702,11 +1847,11 @@ async fn channel_call_leave(
             },
             participant_user_id: user.id.to_string(),
         };
-        let servers = state.store.list_servers().unwrap_or_default();
+        let servers = state.store.list_servers().await.unwrap_or_default();
         for server in servers {
             let _ = outbox::send_channel_call_event(
                 &state.http,
-                &state.config.server_token,
+                &state.signer,
                 &server,
                 &fed_event,
             ).await;
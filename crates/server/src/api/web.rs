@@ -1,9 +1,10 @@
-use axum::response::Html;
+use axum::{extract::State, response::Html};
 
-pub async fn admin_ui() -> Html<String> {
-  // Inject the current server name from environment/config into the admin UI
-  let cfg = crate::config::Config::from_env();
-  let html = UI_HTML.replace("{{SERVER_NAME}}", &cfg.server_name);
+use crate::api::AppState;
+
+pub async fn admin_ui(State(state): State<AppState>) -> Html<String> {
+  // Inject the server name from the running config into the admin UI
+  let html = UI_HTML.replace("{{SERVER_NAME}}", &state.config.server_name);
   Html(html)
 }
 
@@ -347,6 +348,24 @@ const UI_HTML: &str = r#"<!doctype html>
         <div class="items-list" id="serversList"></div>
       </div>
 
+      <!-- Peering Section -->
+      <div class="section">
+        <h2>Peering Requests</h2>
+        <p style="color: var(--muted); font-size: 13px; margin: 0 0 12px;">
+            Ask another server to peer by its base URL. Once its admin approves, each server adds the other automatically.
+        </p>
+        <div class="form-row">
+          <div class="form-group" style="flex: 1;">
+            <label>Their base URL</label>
+            <input id="peeringUrl" placeholder="https://chat.example.org" />
+          </div>
+          <div class="form-group" style="justify-content: flex-end;">
+            <button onclick="requestPeering()" style="margin-top: auto;">Request Peering</button>
+          </div>
+        </div>
+        <div class="items-list" id="peeringList"></div>
+      </div>
+
       <!-- Sync All Users Section -->
       <div class="section">
         <h2>All Users Across Servers</h2>
@@ -597,6 +616,7 @@ const UI_HTML: &str = r#"<!doctype html>
               </div>
               <div class="item-actions">
                 <button class="secondary small" onclick="openEditUserModal('${u.id}', '${u.username}', '${u.display_name || ''}')">Edit</button>
+                <button class="secondary small" onclick="exportUser('${u.id}')">Export</button>
                 <button class="danger small" onclick="deleteUserConfirm('${u.id}', '${u.username}')">Delete</button>
               </div>
             </div>
@@ -636,6 +656,23 @@ const UI_HTML: &str = r#"<!doctype html>
       }
     }
 
+    async function exportUser(id) {
+      try {
+        const response = await fetch(`/admin/users/${id}/export`, { headers: adminHeaders() });
+        if (!response.ok) throw new Error((await response.text()) || response.statusText);
+        const disposition = response.headers.get('content-disposition') || '';
+        const match = disposition.match(/filename="([^"]+)"/);
+        const url = URL.createObjectURL(await response.blob());
+        const link = document.createElement('a');
+        link.href = url;
+        link.download = match ? match[1] : 'export.json';
+        link.click();
+        URL.revokeObjectURL(url);
+      } catch (error) {
+        alert('Error: ' + error.message);
+      }
+    }
+
     function openEditUserModal(id, name, displayName) {
       editingUserId = id;
       document.getElementById('editUserName').value = name;
@@ -775,9 +812,15 @@ const UI_HTML: &str = r#"<!doctype html>
               <div class="name">${s.name}</div>
               <div class="detail">URL: ${s.base_url}</div>
               <div class="detail">Token: ${s.token.substring(0, 8)}...</div>
+              ${s.protocol_version ? `<div class="detail">Protocol ${s.protocol_version}${s.capabilities ? ': ' + s.capabilities.join(', ') : ''}</div>` : ''}
+              ${s.status === 'suspended' ? '<div class="detail" style="color: var(--danger);">Suspended</div>' : ''}
             </div>
             <div class="item-actions">
               <button class="secondary small" onclick="openEditServerModal('${s.id}', '${s.name}', '${s.base_url}', '${s.token}')">Edit</button>
+              ${s.status === 'suspended'
+                ? `<button class="secondary small" onclick="setServerStatus('${s.id}', 'resume')">Resume</button>`
+                : `<button class="secondary small" onclick="setServerStatus('${s.id}', 'suspend')">Suspend</button>`}
+              <button class="danger small" onclick="revokeServerConfirm('${s.id}', '${s.name}')">Revoke</button>
               <button class="danger small" onclick="deleteServerConfirm('${s.id}', '${s.name}')">Delete</button>
             </div>
           </div>
@@ -788,6 +831,75 @@ const UI_HTML: &str = r#"<!doctype html>
       }
     }
 
+    async function setServerStatus(id, action) {
+      try {
+        await requestJson(`/admin/servers/${id}/${action}`, { method: 'POST', headers: adminHeaders() });
+        await loadServers();
+      } catch (error) {
+        alert('Error: ' + error.message);
+      }
+    }
+
+    async function revokeServerConfirm(id, name) {
+      if (confirm(`Revoke peering with "${name}"? It will be told and removed here.`)) {
+        try {
+          await requestJson(`/admin/servers/${id}/revoke`, { method: 'POST', headers: adminHeaders() });
+          await loadServers();
+        } catch (error) {
+          alert('Error: ' + error.message);
+        }
+      }
+    }
+
+    async function requestPeering() {
+      try {
+        const base_url = document.getElementById('peeringUrl').value.trim();
+        if (!base_url) throw new Error('enter their base URL');
+        await requestJson('/admin/peering/requests', {
+          method: 'POST',
+          headers: adminHeaders(),
+          body: JSON.stringify({ base_url })
+        });
+        document.getElementById('peeringUrl').value = '';
+        await loadPeeringRequests();
+      } catch (error) {
+        alert('Error: ' + error.message);
+      }
+    }
+
+    async function loadPeeringRequests() {
+      try {
+        const requests = await requestJson('/admin/peering/requests', { headers: adminHeaders() });
+        const html = requests.map(r => `
+          <div class="item">
+            <div class="item-info">
+              <div class="name">${r.direction === 'incoming' ? 'From' : 'To'} ${r.server_name}</div>
+              <div class="detail">URL: ${r.base_url}</div>
+              <div class="detail">${r.status} · ${new Date(r.updated_at).toLocaleString()}</div>
+            </div>
+            <div class="item-actions">
+              ${r.direction === 'incoming' && r.status === 'pending' ? `
+                <button class="small" onclick="decidePeering('${r.id}', 'approve')">Approve</button>
+                <button class="danger small" onclick="decidePeering('${r.id}', 'reject')">Reject</button>
+              ` : ''}
+            </div>
+          </div>
+        `).join('');
+        document.getElementById('peeringList').innerHTML = html || '<div class="status">No peering requests</div>';
+      } catch (error) {
+        document.getElementById('peeringList').innerHTML = `<div style="color: var(--danger);">Error: ${error.message}</div>`;
+      }
+    }
+
+    async function decidePeering(id, action) {
+      try {
+        await requestJson(`/admin/peering/requests/${id}/${action}`, { method: 'POST', headers: adminHeaders() });
+        await Promise.all([loadPeeringRequests(), loadServers()]);
+      } catch (error) {
+        alert('Error: ' + error.message);
+      }
+    }
+
     async function openEditServerModal(id, name, url, token) {
       editingServerId = id;
       document.getElementById('editServerName').value = name;
@@ -1004,6 +1116,15 @@ const UI_HTML: &str = r#"<!doctype html>
             <div class="item-actions">
               <button class="secondary small" onclick="copyToken('${info.server_token}', this)">Copy</button>
             </div>
+          </div>
+          <div class="item">
+            <div class="item-info">
+              <div class="name">Federation Signing Key (Ed25519)</div>
+              <div class="detail" style="font-family: monospace; word-break: break-all;">${info.public_key}</div>
+            </div>
+            <div class="item-actions">
+              <button class="secondary small" onclick="copyToken('${info.public_key}', this)">Copy</button>
+            </div>
           </div>`;
       } catch (error) {
         document.getElementById('serverIdentity').innerHTML = `<div style="color: var(--danger);">Error: ${error.message}</div>`;
@@ -1079,7 +1200,7 @@ const UI_HTML: &str = r#"<!doctype html>
 
     async function refreshAll() {
       if (getSessionToken()) {
-        await Promise.all([loadServerInfo(), loadFedTokens(), loadServers()]);
+        await Promise.all([loadServerInfo(), loadFedTokens(), loadServers(), loadPeeringRequests()]);
         await Promise.all([loadUsers(), loadChannels()]);
       }
     }
@@ -1173,6 +1294,21 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
             text-overflow: ellipsis;
             white-space: nowrap;
         }
+
+        .delivery-status {
+            margin-left: 6px;
+            cursor: default;
+        }
+        .delivery-status.failed {
+            color: #e74c3c;
+        }
+
+        .last-seen {
+            margin-left: 6px;
+            font-size: 11px;
+            color: var(--muted);
+            white-space: nowrap;
+        }
         .video-call-btn {
             display: none;
             background: transparent;
@@ -1325,11 +1461,40 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
             margin-bottom: 4px;
         }
         .message-text { font-size: 14px; }
+        .message-text.deleted { font-style: italic; color: var(--muted); }
         .message-time {
             font-size: 11px;
             color: var(--muted);
             margin-top: 4px;
         }
+        .message-action {
+            background: none;
+            border: none;
+            color: var(--muted);
+            font-size: 11px;
+            cursor: pointer;
+            margin-left: 8px;
+            padding: 0;
+        }
+        .message-action:hover { color: var(--accent); }
+        .message-reactions { display: flex; flex-wrap: wrap; gap: 4px; margin-top: 4px; }
+        .reaction-chip {
+            background: var(--bg);
+            border: 1px solid var(--border);
+            border-radius: 10px;
+            color: var(--text);
+            font-size: 12px;
+            cursor: pointer;
+            padding: 1px 6px;
+        }
+        .reaction-chip.reacted { border-color: var(--accent); color: var(--accent); }
+        .typing-indicator {
+            min-height: 18px;
+            padding: 0 24px;
+            font-size: 12px;
+            font-style: italic;
+            color: var(--muted);
+        }
         .input-area {
             padding: 16px 24px;
             border-top: 1px solid var(--border);
@@ -1454,12 +1619,26 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
             font-size: 11px;
             margin-top: 8px;
         }
+        .load-older-btn {
+            display: block;
+            margin: 0 auto 16px;
+            padding: 6px 14px;
+            background: transparent;
+            color: var(--muted);
+            border: 1px solid var(--border);
+            border-radius: 6px;
+            cursor: pointer;
+            font-size: 12px;
+        }
+        .load-older-btn:hover { color: var(--accent); border-color: var(--accent); }
         .gif-msg-img {
             max-width: 100%;
             max-height: 300px;
             border-radius: 8px;
             display: block;
         }
+        .message-attachments { margin-top: 6px; display: flex; flex-direction: column; gap: 6px; }
+        .attachment-link { color: var(--accent); cursor: pointer; font-size: 13px; text-decoration: underline; }
         .modal {
             display: none;
             position: fixed;
@@ -1507,6 +1686,18 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
         }
         .modal .btn-primary { background: var(--accent); color: var(--sidebar-bg); }
         .modal .btn-secondary { background: var(--border); color: var(--text); }
+        .modal-content.thread-content { max-width: 560px; }
+        .thread-messages {
+            max-height: 50vh;
+            overflow-y: auto;
+            margin-bottom: 16px;
+            display: flex;
+            flex-direction: column;
+            gap: 8px;
+        }
+        .thread-message { font-size: 13px; }
+        .thread-message.root { padding-bottom: 8px; border-bottom: 1px solid var(--border); }
+        .thread-message .message-author { display: inline; margin-right: 6px; }
         .login-screen {
             display: flex;
             align-items: center;
@@ -1880,6 +2071,17 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
             border-radius: 10px;
             margin-left: 6px;
         }
+        .unread-badge {
+            display: inline-block;
+            background: #ff9800;
+            color: #1a1a1a;
+            font-size: 11px;
+            font-weight: 700;
+            padding: 2px 7px;
+            border-radius: 10px;
+            margin-left: 6px;
+        }
+        .unread-badge.mention { background: var(--danger); color: #fff; }
     </style>
 </head>
 <body>
@@ -1931,9 +2133,12 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
                 </div>
             </div>
             <div class="messages" id="messages"></div>
+            <div class="typing-indicator" id="typing-indicator"></div>
             <div class="input-area" id="input-area">
                 <input type="text" id="msg-input" placeholder="Type a message... (/gif to search GIFs)"/>
                 <button class="gif-btn" id="gif-btn" onclick="openGifPicker()" title="Send a GIF">GIF</button>
+                <button class="gif-btn" id="attach-btn" title="Attach files">FILE</button>
+                <input type="file" id="attach-input" multiple style="display:none"/>
                 <button class="send-btn" id="send-btn">Send</button>
             </div>
         </div>
@@ -1963,6 +2168,28 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
         </div>
     </div>
 
+    <div class="modal" id="thread-modal">
+        <div class="modal-content thread-content">
+            <h2>Thread</h2>
+            <div class="thread-messages" id="thread-messages"></div>
+            <input type="text" id="thread-input" placeholder="Reply in thread..."/>
+            <div class="modal-buttons">
+                <button class="btn-secondary" id="thread-close">Close</button>
+                <button class="btn-primary" id="thread-send">Reply</button>
+            </div>
+        </div>
+    </div>
+
+    <div class="modal" id="pins-modal">
+        <div class="modal-content thread-content">
+            <h2>Pinned messages</h2>
+            <div class="thread-messages" id="pins-messages"></div>
+            <div class="modal-buttons">
+                <button class="btn-secondary" id="pins-close">Close</button>
+            </div>
+        </div>
+    </div>
+
     <!-- Video Call Overlay -->
     <div id="call-overlay" class="call-overlay">
         <div class="call-status" id="call-status">Calling...</div>
@@ -2012,8 +2239,11 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
         let currentUser = null;
         let currentTarget = null;
         let currentTargetType = null;
+        let historyKey = null; // conversation the loaded older pages belong to
+        let olderMessages = []; // pages fetched with "Load older messages"
+        let olderCursor = null;
         let allUsers = [];
-        let unreadDMs = new Set(); // Track users with unread messages
+        let lastReadSent = {}; // conversation key -> last message id sent as read marker
         let eventSource = null;
 
         // ===== WebRTC Call State =====
@@ -2975,6 +3205,31 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
             return data;
         }
 
+        const PRESENCE_DOTS = { online: '🟢', away: '🟡', dnd: '🔴', invisible: '⚪', offline: '⚪' };
+
+        // "last seen 3h ago" for users who are offline
+        function lastSeenText(u) {
+            if (u.state !== 'offline' || !u.last_seen_at) return '';
+            const seconds = Math.max(0, (Date.now() - new Date(u.last_seen_at).getTime()) / 1000);
+            if (isNaN(seconds)) return '';
+            if (seconds < 60) return 'last seen just now';
+            if (seconds < 3600) return 'last seen ' + Math.floor(seconds / 60) + 'm ago';
+            if (seconds < 86400) return 'last seen ' + Math.floor(seconds / 3600) + 'h ago';
+            return 'last seen ' + Math.floor(seconds / 86400) + 'd ago';
+        }
+
+        // Tell the server we are active so we don't show as away. Once a
+        // minute is plenty; the idle timeout is several minutes.
+        let activitySentAt = Date.now();
+        function reportActivity() {
+            if (!currentUser || Date.now() - activitySentAt < 60000) return;
+            activitySentAt = Date.now();
+            requestJson('/api/presence/activity', 'POST');
+        }
+        ['keydown', 'mousedown', 'mousemove', 'focus'].forEach(function(type) {
+            window.addEventListener(type, reportActivity);
+        });
+
         async function loadUsers(seedUsers) {
           const users = seedUsers || await requestJson('/api/users');
           if (!users) return;
@@ -3008,15 +3263,15 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
             filtered.forEach(function(u) {
                 const div = document.createElement('div');
                 div.className = 'sidebar-item';
-                if (currentTarget === u.id && currentTargetType === 'user') {
+                const viewing = currentTarget === u.id && currentTargetType === 'user';
+                if (viewing) {
                     div.classList.add('active');
                 }
-                // Add 'unread' class if this user has unread messages
-                if (unreadDMs.has(u.id)) {
+                if (!viewing && u.unread_count > 0) {
                     div.classList.add('unread');
                 }
               const serverSuffix = u.server_name ? ('@' + u.server_name) : '';
-              const onlineDot = u.is_online ? '🟢' : '⚪';
+              const onlineDot = PRESENCE_DOTS[u.state] || '⚪';
               const displayName = u.display_name || (u.username + serverSuffix);
 
               const content = document.createElement('span');
@@ -3024,8 +3279,17 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
 
               const nameSpan = document.createElement('span');
               nameSpan.className = 'sidebar-item-name';
-              nameSpan.textContent = onlineDot + ' 👤 ' + displayName;
+              nameSpan.textContent = onlineDot + ' 👤 ' + displayName + (u.status_emoji ? ' ' + u.status_emoji : '');
+              const lastSeen = lastSeenText(u);
+              nameSpan.title = [u.state, u.status_text, lastSeen].filter(Boolean).join(' — ');
               content.appendChild(nameSpan);
+              if (lastSeen) {
+                  const lastSeenSpan = document.createElement('span');
+                  lastSeenSpan.className = 'last-seen';
+                  lastSeenSpan.textContent = lastSeen.replace('last seen ', '');
+                  content.appendChild(lastSeenSpan);
+              }
+              if (!viewing) appendUnreadBadge(content, u);
 
               const camBtn = document.createElement('button');
               camBtn.className = 'video-call-btn' + (u.is_online ? ' online' : '');
@@ -3062,10 +3326,15 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
             filtered.forEach(function(c) {
                 const div = document.createElement('div');
                 div.className = 'sidebar-item';
-                if (currentTarget === c.id && currentTargetType === 'channel') {
+                const viewing = currentTarget === c.id && currentTargetType === 'channel';
+                if (viewing) {
                     div.classList.add('active');
                 }
+                if (!viewing && c.unread_count > 0) {
+                    div.classList.add('unread');
+                }
                 div.textContent = '# ' + c.name;
+                if (!viewing) appendUnreadBadge(div, c);
                 var callCount = activeChannelCalls[c.id];
                 if (callCount && callCount > 0) {
                     var badge = document.createElement('span');
@@ -3078,21 +3347,71 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
             });
         }
 
+        function appendUnreadBadge(parent, item) {
+            if (!item.unread_count) return;
+            const badge = document.createElement('span');
+            badge.className = 'unread-badge' + (item.mention_count > 0 ? ' mention' : '');
+            badge.textContent = item.mention_count > 0 ? '@' + item.mention_count : item.unread_count;
+            badge.title = item.unread_count + ' unread, ' + item.mention_count + ' mentioning you';
+            parent.appendChild(badge);
+        }
+
+        async function markRead(type, id, message) {
+            const key = type + ':' + id;
+            if (!message || lastReadSent[key] === message.message_id) return;
+            lastReadSent[key] = message.message_id;
+            const body = { message_id: message.message_id };
+            if (type === 'dm') body.dm_user_id = id; else body.channel_id = id;
+            await requestJson('/api/read-marker', 'POST', body);
+        }
+
+        // Typing indicators: who is typing in each conversation, and when we
+        // last told the server that we are. The server expires indicators
+        // after a few seconds, so we repeat ours while the user keeps typing.
+        const typists = {};
+        let typingSentAt = 0;
+
+        function typingBody(typing) {
+            const body = { typing: typing };
+            if (currentTargetType === 'user') body.dm_user_id = currentTarget; else body.channel_id = currentTarget;
+            return body;
+        }
+
+        function sendTyping() {
+            if (!currentTarget || Date.now() - typingSentAt < 3000) return;
+            typingSentAt = Date.now();
+            requestJson('/api/typing', 'POST', typingBody(true));
+        }
+
+        function stopTyping() {
+            if (!typingSentAt || !currentTarget) return;
+            typingSentAt = 0;
+            requestJson('/api/typing', 'POST', typingBody(false));
+        }
+
+        function renderTyping() {
+            const names = Object.values(typists[currentTargetType + ':' + currentTarget] || {});
+            document.getElementById('typing-indicator').textContent =
+                names.length === 0 ? '' : names.join(', ') + (names.length === 1 ? ' is typing…' : ' are typing…');
+        }
+
         function selectUser(user) {
+            stopTyping();
             currentTarget = user.id;
             currentTargetType = 'user';
+            renderTyping();
             document.getElementById('current-title').textContent = 'DM with ' + (user.display_name || user.username);
             document.getElementById('input-area').classList.add('active');
-            // Mark DM as read when viewing it
-            unreadDMs.delete(user.id);
             loadMessages('dm', user.id);
             loadUsers();
             loadChannels();
         }
 
         function selectChannel(channel) {
+            stopTyping();
             currentTarget = channel.id;
             currentTargetType = 'channel';
+            renderTyping();
             var titleEl = document.getElementById('current-title');
             titleEl.textContent = '# ' + channel.name;
 
@@ -3112,6 +3431,25 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
             };
             titleEl.appendChild(callBtn);
             updateChannelCallButton();
+            var pinsBtn = document.createElement('button');
+            pinsBtn.className = 'channel-call-btn';
+            pinsBtn.textContent = 'Pins';
+            pinsBtn.onclick = openPins;
+            titleEl.appendChild(pinsBtn);
+            var memberBtn = document.createElement('button');
+            memberBtn.className = 'channel-call-btn';
+            memberBtn.textContent = channel.is_member ? 'Leave' : 'Join';
+            memberBtn.onclick = async function() {
+                const action = channel.is_member ? 'leave' : 'join';
+                const result = await requestJson('/api/channels/' + channel.id + '/' + action, 'POST');
+                if (result && result.error) {
+                    alert('Could not ' + action + ' #' + channel.name + ': ' + result.error);
+                    return;
+                }
+                channel.is_member = !channel.is_member;
+                selectChannel(channel);
+            };
+            titleEl.appendChild(memberBtn);
 
             document.getElementById('input-area').classList.add('active');
             loadMessages('channel', channel.id);
@@ -3133,7 +3471,7 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
                 loadUsers();
             };
             
-            eventSource.onmessage = function(event) {
+            eventSource.onmessage = async function(event) {
                 try {
                     const notification = JSON.parse(event.data);
                 addDebugLog(`📨 SSE: ${notification.event} received (user_id: ${notification.user_id || 'none'}, channel_id: ${notification.channel_id || 'none'})`);
@@ -3166,6 +3504,100 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
                         return;
                     }
 
+                    // Someone mentioned us - refresh the mention badges
+                    if (notification.event === 'mention') {
+                        if (currentUser && notification.target_user_id === currentUser.id) {
+                            addDebugLog('📣 SSE -> you were mentioned');
+                            loadUsers();
+                            loadChannels();
+                        }
+                        return;
+                    }
+
+                    // Typing indicators for channels, and for DMs sent to us
+                    if (notification.event === 'typing_started' || notification.event === 'typing_stopped') {
+                        if (!currentUser || notification.user_id === currentUser.id) return;
+                        let key = null;
+                        if (notification.channel_id) key = 'channel:' + notification.channel_id;
+                        else if (notification.target_user_id === currentUser.id) key = 'user:' + notification.user_id;
+                        if (!key) return;
+                        try {
+                            const typist = JSON.parse(notification.payload);
+                            typists[key] = typists[key] || {};
+                            if (notification.event === 'typing_started') {
+                                typists[key][notification.user_id] = typist.display_name || typist.username;
+                            } else {
+                                delete typists[key][notification.user_id];
+                            }
+                        } catch (e) {}
+                        renderTyping();
+                        return;
+                    }
+
+                    // Another session of ours read something - refresh counts
+                    if (notification.event === 'read_marker_updated') {
+                        if (currentUser && notification.target_user_id === currentUser.id) {
+                            loadUsers();
+                            loadChannels();
+                        }
+                        return;
+                    }
+
+                    // Edits and deletions: patch cached older pages, then redraw
+                    if (notification.event === 'message_edited' || notification.event === 'message_deleted') {
+                        try {
+                            await applyMessageChange(JSON.parse(notification.payload));
+                        } catch (e) {}
+                        if (currentTarget) {
+                            loadMessages(currentTargetType === 'user' ? 'dm' : 'channel', currentTarget, true);
+                        }
+                        return;
+                    }
+
+                    // Thread replies: refresh reply counts and any open thread
+                    if (notification.event === 'thread_reply') {
+                        try {
+                            const reply = JSON.parse(notification.payload);
+                            if (reply.thread_root_id === currentThreadId) loadThread();
+                        } catch (e) {}
+                        if (currentTarget && currentTargetType === 'channel') {
+                            loadMessages('channel', currentTarget, true);
+                        }
+                        return;
+                    }
+
+                    // Pins: redraw the channel and any open pin list
+                    if (notification.event === 'pin_changed') {
+                        if (currentTargetType === 'channel' && notification.channel_id === currentTarget) {
+                            loadMessages('channel', currentTarget, true);
+                            if (document.getElementById('pins-modal').classList.contains('active')) loadPins();
+                        }
+                        return;
+                    }
+
+                    // Reactions: adjust cached counts, then redraw
+                    if (notification.event === 'reaction_changed') {
+                        try {
+                            applyReactionChange(JSON.parse(notification.payload));
+                        } catch (e) {}
+                        if (currentTarget) {
+                            loadMessages(currentTargetType === 'user' ? 'dm' : 'channel', currentTarget, true);
+                        }
+                        return;
+                    }
+
+                    // Our messages reached (or failed to reach) another server
+                    if (notification.event === 'delivery_changed') {
+                        if (!currentUser || notification.target_user_id !== currentUser.id) return;
+                        try {
+                            applyDeliveryChange(JSON.parse(notification.payload));
+                        } catch (e) {}
+                        if (currentTarget) {
+                            loadMessages(currentTargetType === 'user' ? 'dm' : 'channel', currentTarget, true);
+                        }
+                        return;
+                    }
+
                     // Handle presence changes - reload user list
                     if (notification.event === 'presence_changed') {
                         addDebugLog('👥 SSE -> presence changed, refreshing user list');
@@ -3189,13 +3621,10 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
                         loadMessages(currentTargetType === 'user' ? 'dm' : 'channel', currentTarget, true);
                     }
                     
-                    // Mark message as unread if it's for a user we're not currently viewing
-                    if (notification.event === 'new_message' && notification.user_id) {
-                        if (currentTargetType !== 'user' || currentTarget !== notification.user_id) {
-                            addDebugLog(`🔔 Unread DM from user: ${notification.user_id}`);
-                            unreadDMs.add(notification.user_id);
-                            loadUsers(); // Refresh to show orange background
-                        }
+                    // Refresh unread counts for conversations we're not viewing
+                    if (notification.event === 'new_message') {
+                        loadUsers();
+                        loadChannels();
                     }
                 } catch (e) {
                     addDebugLog(`⚠ SSE parse error: ${e.message}`);
@@ -3209,17 +3638,51 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
             };
         }
 
+        function historyUrl(type, id) {
+            return (type === 'dm' ? '/api/messages/dm/' : '/api/messages/channel/') + id;
+        }
+
+        async function loadOlderMessages(type, id) {
+            if (!olderCursor) return;
+            const page = await requestJson(historyUrl(type, id) + '?before=' + encodeURIComponent(olderCursor));
+            if (!page || !page.messages) return;
+            olderMessages = page.messages.concat(olderMessages);
+            olderCursor = page.prev_cursor;
+            const container = document.getElementById('messages');
+            const fromBottom = container.scrollHeight - container.scrollTop;
+            await loadMessages(type, id, true);
+            container.scrollTop = container.scrollHeight - fromBottom;
+        }
+
         async function loadMessages(type, id, silent) {
-            let messages = [];
+            const key = type + ':' + id;
+            if (key !== historyKey) {
+                historyKey = key;
+                olderMessages = [];
+                olderCursor = null;
+            }
             if (!silent) addDebugLog(`Loading ${type} messages for ID: ${id}`);
-            if (type === 'dm') {
-                messages = await requestJson('/api/messages/dm/' + id) || [];
-            } else if (type === 'channel') {
-                messages = await requestJson('/api/messages/channel/' + id) || [];
+            const page = await requestJson(historyUrl(type, id));
+            const pins = type === 'channel' ? await requestJson('/api/channels/' + id + '/pins') : null;
+            currentPins = new Set((Array.isArray(pins) ? pins : []).map(function(p) { return p.message_id; }));
+            const latest = page && page.messages ? page.messages : [];
+            if (olderMessages.length === 0) {
+                olderCursor = page ? page.prev_cursor : null;
             }
+            const seen = new Set(latest.map(function(m) { return m.message_id; }));
+            const messages = olderMessages.filter(function(m) { return !seen.has(m.message_id); }).concat(latest);
             if (!silent) addDebugLog(`Got ${messages.length} messages`);
             const container = document.getElementById('messages');
+            const atBottom = container.scrollHeight - container.scrollTop - container.clientHeight < 40;
+            const previousScrollTop = container.scrollTop;
             container.innerHTML = '';
+            if (olderCursor) {
+                const more = document.createElement('button');
+                more.className = 'load-older-btn';
+                more.textContent = 'Load older messages';
+                more.onclick = function() { loadOlderMessages(type, id); };
+                container.appendChild(more);
+            }
             messages.forEach(function(m) {
                 const div = document.createElement('div');
                 div.className = 'message';
@@ -3234,7 +3697,10 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
                 const text = document.createElement('div');
                 text.className = 'message-text';
                 var gifMatch = m.body.match(/^\[gif:(https?:\/\/[^\]]+)\]$/);
-                if (gifMatch) {
+                if (m.deleted_at) {
+                    text.classList.add('deleted');
+                    text.textContent = 'Message deleted';
+                } else if (gifMatch) {
                     var img = document.createElement('img');
                     img.src = gifMatch[1];
                     img.alt = 'GIF';
@@ -3246,20 +3712,247 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
                 const time = document.createElement('div');
                 time.className = 'message-time';
                 const d = new Date(m.sent_at);
-                time.textContent = d.toLocaleTimeString();
+                time.textContent = d.toLocaleTimeString() + (m.edited_at && !m.deleted_at ? ' (edited)' : '');
+                const delivery = deliveryIndicator(m.delivery);
+                if (delivery) time.appendChild(delivery);
+                if (!m.deleted_at) {
+                    const reactBtn = document.createElement('button');
+                    reactBtn.className = 'message-action';
+                    reactBtn.textContent = 'React';
+                    reactBtn.onclick = function() {
+                        const emoji = prompt('React with emoji', '👍');
+                        if (emoji && emoji.trim()) toggleReaction(m, emoji.trim(), true);
+                    };
+                    time.appendChild(reactBtn);
+                    if (type === 'channel') {
+                        const replyBtn = document.createElement('button');
+                        replyBtn.className = 'message-action';
+                        replyBtn.textContent = m.reply_count > 0
+                            ? m.reply_count + (m.reply_count === 1 ? ' reply' : ' replies')
+                            : 'Reply';
+                        replyBtn.onclick = function() { openThread(m.message_id); };
+                        time.appendChild(replyBtn);
+                        const pinBtn = document.createElement('button');
+                        pinBtn.className = 'message-action';
+                        pinBtn.textContent = currentPins.has(m.message_id) ? 'Unpin' : 'Pin';
+                        pinBtn.onclick = function() { togglePin(m.message_id, !currentPins.has(m.message_id)); };
+                        time.appendChild(pinBtn);
+                    }
+                } else if (m.reply_count > 0) {
+                    const threadBtn = document.createElement('button');
+                    threadBtn.className = 'message-action';
+                    threadBtn.textContent = m.reply_count + (m.reply_count === 1 ? ' reply' : ' replies');
+                    threadBtn.onclick = function() { openThread(m.message_id); };
+                    time.appendChild(threadBtn);
+                }
+                if (m.author_user_id === currentUser.id && !m.deleted_at) {
+                    if (!gifMatch) {
+                        const editBtn = document.createElement('button');
+                        editBtn.className = 'message-action';
+                        editBtn.textContent = 'Edit';
+                        editBtn.onclick = function() { editMessage(m); };
+                        time.appendChild(editBtn);
+                    }
+                    const deleteBtn = document.createElement('button');
+                    deleteBtn.className = 'message-action';
+                    deleteBtn.textContent = 'Delete';
+                    deleteBtn.onclick = function() { deleteMessage(m); };
+                    time.appendChild(deleteBtn);
+                }
                 content.appendChild(author);
                 content.appendChild(text);
+                if (m.attachments && m.attachments.length > 0) {
+                    content.appendChild(renderAttachments(m.attachments));
+                }
+                if (m.reactions && m.reactions.length > 0) {
+                    const reactions = document.createElement('div');
+                    reactions.className = 'message-reactions';
+                    m.reactions.forEach(function(r) {
+                        const chip = document.createElement('button');
+                        chip.className = 'reaction-chip' + (r.reacted ? ' reacted' : '');
+                        chip.textContent = r.emoji + ' ' + r.count;
+                        chip.onclick = function() { toggleReaction(m, r.emoji, !r.reacted); };
+                        reactions.appendChild(chip);
+                    });
+                    content.appendChild(reactions);
+                }
                 content.appendChild(time);
                 div.appendChild(content);
                 container.appendChild(div);
             });
-            container.scrollTop = container.scrollHeight;
+            if (!silent || atBottom) {
+                container.scrollTop = container.scrollHeight;
+                markRead(type, id, messages[messages.length - 1]);
+            } else {
+                container.scrollTop = previousScrollTop;
+            }
+        }
+
+        // One mark for a message sent to other servers: the worst state across
+        // them, with the details per server in the tooltip.
+        function deliveryIndicator(deliveries) {
+            if (!deliveries || deliveries.length === 0) return null;
+            const span = document.createElement('span');
+            span.className = 'delivery-status';
+            const failed = deliveries.some(function(x) { return x.status === 'failed'; });
+            const pending = deliveries.some(function(x) { return x.status === 'pending'; });
+            if (failed) {
+                span.classList.add('failed');
+                span.textContent = '⚠';
+            } else if (pending) {
+                span.textContent = '⏳';
+            } else {
+                span.textContent = '✓';
+            }
+            span.title = deliveries.map(function(x) {
+                let line = x.server + ': ' + x.status;
+                if (x.status !== 'delivered' && x.attempts > 0) {
+                    line += ' after ' + x.attempts + (x.attempts === 1 ? ' attempt' : ' attempts');
+                }
+                if (x.status !== 'delivered' && x.last_error) line += ' (' + x.last_error + ')';
+                return line;
+            }).join('\n');
+            return span;
+        }
+
+        function applyDeliveryChange(change) {
+            olderMessages.forEach(function(m) {
+                if (m.message_id !== change.message_id || !m.delivery) return;
+                m.delivery.forEach(function(x) {
+                    if (x.server !== change.server_name) return;
+                    x.status = change.status;
+                    x.attempts = change.attempts;
+                    x.last_error = change.last_error;
+                });
+            });
+        }
+
+        // Events carry no body, so a cached older message is fetched again
+        async function applyMessageChange(change) {
+            const index = olderMessages.findIndex(function(m) { return m.message_id === change.message_id; });
+            if (index < 0) return;
+            const message = await requestJson('/api/messages/' + change.message_id);
+            if (message && message.message_id) olderMessages[index] = message;
+        }
+
+        function applyReactionChange(change) {
+            olderMessages.forEach(function(m) {
+                if (m.message_id !== change.message_id) return;
+                m.reactions = m.reactions || [];
+                let r = m.reactions.find(function(x) { return x.emoji === change.emoji; });
+                if (!r) {
+                    if (!change.added) return;
+                    r = { emoji: change.emoji, count: 0, reacted: false };
+                    m.reactions.push(r);
+                }
+                r.count += change.added ? 1 : -1;
+                if (currentUser && change.user_id === currentUser.id) r.reacted = change.added;
+                m.reactions = m.reactions.filter(function(x) { return x.count > 0; });
+            });
+        }
+
+        async function toggleReaction(m, emoji, add) {
+            const url = '/api/messages/' + m.message_id + '/reactions/' + encodeURIComponent(emoji);
+            const reactions = await requestJson(url, add ? 'PUT' : 'DELETE');
+            if (reactions) {
+                olderMessages.forEach(function(x) {
+                    if (x.message_id === m.message_id) x.reactions = reactions;
+                });
+            }
+            loadMessages(currentTargetType === 'user' ? 'dm' : 'channel', currentTarget, true);
+        }
+
+        async function editMessage(m) {
+            const body = prompt('Edit message', m.body);
+            if (body === null || !body.trim() || body === m.body) return;
+            const updated = await requestJson('/api/messages/' + m.message_id, 'PATCH', { body: body });
+            if (updated) applyMessageChange(updated);
+            loadMessages(currentTargetType === 'user' ? 'dm' : 'channel', currentTarget, true);
+        }
+
+        async function deleteMessage(m) {
+            if (!confirm('Delete this message?')) return;
+            const tombstone = await requestJson('/api/messages/' + m.message_id, 'DELETE');
+            if (tombstone) applyMessageChange(tombstone);
+            loadMessages(currentTargetType === 'user' ? 'dm' : 'channel', currentTarget, true);
+        }
+
+        // Attachment downloads need the auth header, so they are fetched here
+        // and shown through object URLs rather than linked directly.
+        const attachmentUrls = {};
+
+        async function attachmentUrl(a) {
+            if (!attachmentUrls[a.id]) {
+                const resp = await fetch(a.url, { headers: { 'x-admin-token': getUserToken() } });
+                if (!resp.ok) throw new Error('attachment download failed: ' + resp.status);
+                attachmentUrls[a.id] = URL.createObjectURL(await resp.blob());
+            }
+            return attachmentUrls[a.id];
+        }
+
+        function renderAttachments(attachments) {
+            const list = document.createElement('div');
+            list.className = 'message-attachments';
+            attachments.forEach(function(a) {
+                if (a.content_type.startsWith('image/')) {
+                    const img = document.createElement('img');
+                    img.alt = a.filename;
+                    img.className = 'gif-msg-img';
+                    attachmentUrl(a).then(function(url) { img.src = url; })
+                        .catch(function(e) { addDebugLog(`⚠ ${e.message}`); });
+                    list.appendChild(img);
+                    return;
+                }
+                const link = document.createElement('a');
+                link.className = 'attachment-link';
+                link.textContent = a.filename + ' (' + Math.ceil(a.size / 1024) + ' KB)';
+                link.onclick = async function() {
+                    try {
+                        const save = document.createElement('a');
+                        save.href = await attachmentUrl(a);
+                        save.download = a.filename;
+                        save.click();
+                    } catch (e) {
+                        addDebugLog(`⚠ ${e.message}`);
+                    }
+                };
+                list.appendChild(link);
+            });
+            return list;
+        }
+
+        let pendingAttachments = [];
+
+        function updateAttachButton() {
+            const btn = document.getElementById('attach-btn');
+            btn.textContent = pendingAttachments.length > 0 ? 'FILE (' + pendingAttachments.length + ')' : 'FILE';
+        }
+
+        async function uploadAttachments(files) {
+            for (const file of files) {
+                addDebugLog(`Uploading ${file.name} (${file.size} bytes)`);
+                const resp = await fetch('/api/attachments?filename=' + encodeURIComponent(file.name), {
+                    method: 'POST',
+                    headers: {
+                        'x-admin-token': getUserToken(),
+                        'Content-Type': file.type || 'application/octet-stream'
+                    },
+                    body: file
+                });
+                if (!resp.ok) {
+                    alert('Could not attach ' + file.name + ': ' + await resp.text());
+                    continue;
+                }
+                pendingAttachments.push((await resp.json()).id);
+            }
+            updateAttachButton();
         }
 
         async function sendMessage() {
             const input = document.getElementById('msg-input');
             const body = input.value.trim();
-            if (!body || !currentTarget) return;
+            if ((!body && pendingAttachments.length === 0) || !currentTarget) return;
+            const attachmentIds = pendingAttachments;
             // Intercept /gif command
             if (body === '/gif' || body.startsWith('/gif ')) {
                 var gifQuery = body.slice(4).trim();
@@ -3268,7 +3961,10 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
                 return;
             }
             addDebugLog(`Sending message: "${body}" to target: ${currentTarget} (${currentTargetType})`);
+            stopTyping();
             input.value = '';
+            pendingAttachments = [];
+            updateAttachButton();
             if (currentTargetType === 'user') {
                 const user = allUsers.find(function(u) {
                     return u.id === currentTarget;
@@ -3285,7 +3981,8 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
                     addDebugLog(`  POSTing to /api/messages/dm with recipient: ${recipient}`);
                     const result = await requestJson('/api/messages/dm', 'POST', {
                         recipient: recipient,
-                        body: body
+                        body: body,
+                        attachment_ids: attachmentIds
                     });
                     addDebugLog(`  Send result: ${result ? 'success' : 'null/error'}`);
                     loadMessages('dm', currentTarget);
@@ -3301,7 +3998,8 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
                     const result = await requestJson('/api/messages/channel', 'POST', {
                         channel: channel.name,
                         origin_server: channel.origin_server,
-                        body: body
+                        body: body,
+                        attachment_ids: attachmentIds
                     });
                     addDebugLog(`  Send result: ${result ? 'success' : 'null/error'}`);
                     loadMessages('channel', currentTarget);
@@ -3311,6 +4009,96 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
             }
         }
 
+        let currentPins = new Set();
+
+        async function togglePin(messageId, pin) {
+            const url = '/api/channels/' + currentTarget + '/pins/' + messageId;
+            await requestJson(url, pin ? 'PUT' : 'DELETE');
+            loadMessages('channel', currentTarget, true);
+            if (document.getElementById('pins-modal').classList.contains('active')) loadPins();
+        }
+
+        function openPins() {
+            document.getElementById('pins-modal').classList.add('active');
+            loadPins();
+        }
+
+        async function loadPins() {
+            if (currentTargetType !== 'channel') return;
+            const pins = await requestJson('/api/channels/' + currentTarget + '/pins');
+            const container = document.getElementById('pins-messages');
+            container.innerHTML = '';
+            (Array.isArray(pins) ? pins : []).forEach(function(m) {
+                const div = document.createElement('div');
+                div.className = 'thread-message';
+                const author = document.createElement('span');
+                author.className = 'message-author';
+                author.textContent = m.author_display_name || m.author_username;
+                const text = document.createElement('span');
+                text.className = 'message-text' + (m.deleted_at ? ' deleted' : '');
+                text.textContent = m.deleted_at ? 'Message deleted' : m.body;
+                const unpin = document.createElement('button');
+                unpin.className = 'message-action';
+                unpin.textContent = 'Unpin';
+                unpin.onclick = function() { togglePin(m.message_id, false); };
+                div.appendChild(author);
+                div.appendChild(text);
+                div.appendChild(unpin);
+                container.appendChild(div);
+            });
+            if (container.children.length === 0) container.textContent = 'No pinned messages.';
+        }
+
+        let currentThreadId = null;
+
+        function openThread(messageId) {
+            currentThreadId = messageId;
+            document.getElementById('thread-messages').innerHTML = '';
+            document.getElementById('thread-modal').classList.add('active');
+            loadThread();
+        }
+
+        function closeThread() {
+            currentThreadId = null;
+            document.getElementById('thread-modal').classList.remove('active');
+            document.getElementById('thread-input').value = '';
+        }
+
+        async function loadThread() {
+            if (!currentThreadId) return;
+            const threadId = currentThreadId;
+            const thread = await requestJson('/api/messages/' + threadId + '/thread?limit=200');
+            if (!thread || threadId !== currentThreadId) return;
+            const container = document.getElementById('thread-messages');
+            container.innerHTML = '';
+            [thread.root].concat(thread.messages).forEach(function(m, i) {
+                const div = document.createElement('div');
+                div.className = 'thread-message' + (i === 0 ? ' root' : '');
+                const author = document.createElement('span');
+                author.className = 'message-author';
+                author.textContent = m.author_display_name || m.author_username;
+                const text = document.createElement('span');
+                text.className = 'message-text' + (m.deleted_at ? ' deleted' : '');
+                text.textContent = m.deleted_at ? 'Message deleted' : m.body;
+                div.appendChild(author);
+                div.appendChild(text);
+                if (m.attachments && m.attachments.length > 0) {
+                    div.appendChild(renderAttachments(m.attachments));
+                }
+                container.appendChild(div);
+            });
+            container.scrollTop = container.scrollHeight;
+        }
+
+        async function sendThreadReply() {
+            const input = document.getElementById('thread-input');
+            const body = input.value.trim();
+            if (!body || !currentThreadId) return;
+            const result = await requestJson('/api/messages/' + currentThreadId + '/replies', 'POST', { body: body });
+            if (result) input.value = '';
+            loadThread();
+        }
+
         function showNewChannelModal() {
             document.getElementById('new-channel-modal').classList.add('active');
         }
@@ -3338,7 +4126,7 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
           currentUser = null;
           currentTarget = null;
           currentTargetType = null;
-          unreadDMs.clear();
+          lastReadSent = {};
             document.getElementById('app').classList.remove('active');
             document.getElementById('login-screen').style.display = 'flex';
             document.getElementById('login-username').value = '';
@@ -3409,6 +4197,14 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
         document.getElementById('new-channel-btn').addEventListener('click', showNewChannelModal);
         document.getElementById('modal-cancel').addEventListener('click', closeNewChannelModal);
         document.getElementById('modal-create').addEventListener('click', createChannel);
+        document.getElementById('thread-close').addEventListener('click', closeThread);
+        document.getElementById('pins-close').addEventListener('click', function() {
+            document.getElementById('pins-modal').classList.remove('active');
+        });
+        document.getElementById('thread-send').addEventListener('click', sendThreadReply);
+        document.getElementById('thread-input').addEventListener('keypress', function(e) {
+            if (e.key === 'Enter') sendThreadReply();
+        });
         document.getElementById('logout-btn').addEventListener('click', logout);
 
         // User dropdown menu toggle
@@ -3425,9 +4221,19 @@ const CHAT_UI_HTML: &str = r#"<!DOCTYPE html>
         });
 
         document.getElementById('send-btn').addEventListener('click', sendMessage);
+        document.getElementById('attach-btn').addEventListener('click', function() {
+            document.getElementById('attach-input').click();
+        });
+        document.getElementById('attach-input').addEventListener('change', function(e) {
+            uploadAttachments(Array.from(e.target.files));
+            e.target.value = '';
+        });
         document.getElementById('msg-input').addEventListener('keypress', function(e) {
             if (e.key === 'Enter') sendMessage();
         });
+        document.getElementById('msg-input').addEventListener('input', function(e) {
+            if (e.target.value.trim()) sendTyping(); else stopTyping();
+        });
 
         // Wire call overlay buttons
         document.getElementById('call-hangup').addEventListener('click', function() { endCall(true); });
@@ -3745,6 +4551,37 @@ const SETTINGS_UI_HTML: &str = r#"<!DOCTYPE html>
             </div>
             <button id="save-display-name" style="padding:10px 24px;border-radius:8px;border:none;background:var(--accent);color:#08211c;font-weight:600;cursor:pointer;font-family:inherit;font-size:14px;">Save Display Name</button>
             <div id="profile-status" style="font-size:12px;color:var(--accent);margin-top:8px;"></div>
+
+            <div class="form-group" style="margin-top:24px;">
+                <label for="presence-state">Availability</label>
+                <select id="presence-state">
+                    <option value="online">Online</option>
+                    <option value="away">Away</option>
+                    <option value="dnd">Do not disturb</option>
+                    <option value="invisible">Invisible</option>
+                </select>
+            </div>
+            <div class="form-group">
+                <label for="status-text">Custom Status</label>
+                <div style="display:flex;gap:8px;">
+                    <input type="text" id="status-emoji" placeholder="🙂" maxlength="16"
+                           style="width:64px;padding:10px 12px;background:rgba(255,255,255,0.06);border:1px solid var(--border);border-radius:8px;color:var(--text);font-family:inherit;font-size:14px;outline:none;" />
+                    <input type="text" id="status-text" placeholder="What are you up to?" maxlength="100"
+                           style="width:100%;padding:10px 12px;background:rgba(255,255,255,0.06);border:1px solid var(--border);border-radius:8px;color:var(--text);font-family:inherit;font-size:14px;outline:none;" />
+                </div>
+            </div>
+            <div class="form-group">
+                <label for="status-expiry">Clear Status After</label>
+                <select id="status-expiry">
+                    <option value="">Don't clear</option>
+                    <option value="30">30 minutes</option>
+                    <option value="60">1 hour</option>
+                    <option value="240">4 hours</option>
+                    <option value="1440">1 day</option>
+                </select>
+            </div>
+            <button id="save-presence" style="padding:10px 24px;border-radius:8px;border:none;background:var(--accent);color:#08211c;font-weight:600;cursor:pointer;font-family:inherit;font-size:14px;">Save Status</button>
+            <div id="presence-status" style="font-size:12px;color:var(--accent);margin-top:8px;"></div>
         </div>
 
         <div id="tab-security" class="tab-panel">
@@ -4007,6 +4844,46 @@ const SETTINGS_UI_HTML: &str = r#"<!DOCTYPE html>
             }
         });
 
+        // Presence: availability and custom status
+        async function loadPresence() {
+            const token = sessionStorage.getItem('user_token') || localStorage.getItem('user_token');
+            if (!token) return;
+            try {
+                const resp = await fetch('/api/presence', { headers: { 'x-admin-token': token } });
+                if (!resp.ok) return;
+                const presence = await resp.json();
+                document.getElementById('presence-state').value = presence.chosen_state;
+                document.getElementById('status-emoji').value = presence.status_emoji || '';
+                document.getElementById('status-text').value = presence.status_text || '';
+            } catch (e) {}
+        }
+
+        document.getElementById('save-presence').addEventListener('click', async function() {
+            const token = sessionStorage.getItem('user_token') || localStorage.getItem('user_token');
+            if (!token) return;
+            const statusEl = document.getElementById('presence-status');
+            const minutes = parseInt(document.getElementById('status-expiry').value, 10);
+            const body = {
+                state: document.getElementById('presence-state').value,
+                status_text: document.getElementById('status-text').value.trim() || null,
+                status_emoji: document.getElementById('status-emoji').value.trim() || null,
+                status_expires_at: minutes ? new Date(Date.now() + minutes * 60000).toISOString() : null
+            };
+            try {
+                const resp = await fetch('/api/presence', {
+                    method: 'PUT',
+                    headers: { 'Content-Type': 'application/json', 'x-admin-token': token },
+                    body: JSON.stringify(body)
+                });
+                statusEl.style.color = resp.ok ? 'var(--accent)' : '#ff6b6b';
+                statusEl.textContent = resp.ok ? 'Status saved!' : 'Failed to save: ' + await resp.text();
+                if (resp.ok) setTimeout(function() { statusEl.textContent = ''; }, 3000);
+            } catch (e) {
+                statusEl.textContent = 'Error: ' + e.message;
+                statusEl.style.color = '#ff6b6b';
+            }
+        });
+
         document.getElementById('change-password-btn').addEventListener('click', async function() {
             const token = sessionStorage.getItem('user_token') || localStorage.getItem('user_token');
             if (!token) return;
@@ -4058,6 +4935,7 @@ const SETTINGS_UI_HTML: &str = r#"<!DOCTYPE html>
         });
 
         loadProfile();
+        loadPresence();
     </script>
 </body>
 </html>"#;
\ No newline at end of file
@@ -39,7 +39,7 @@ impl FromRequestParts<AppState> for UserGuard {
 
         // Check user session first
         if let Some(user_id) = state.sessions.validate_user_session(&token) {
-            if let Some(user) = state.store.get_user_by_id(user_id)? {
+            if let Some(user) = state.store.get_user_by_id(user_id).await? {
                 return Ok(UserGuard(user));
             }
         }
@@ -47,7 +47,7 @@ impl FromRequestParts<AppState> for UserGuard {
         // Fall back to permanent DB token for backwards compat
         let user = state
             .store
-            .get_user_by_token(&token)?
+            .get_user_by_token(&token).await?
             .ok_or(AppError::Unauthorized)?;
         Ok(UserGuard(user))
     }
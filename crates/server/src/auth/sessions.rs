@@ -16,7 +16,7 @@ pub struct UserSession {
     pub expires_at: u64,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct Sessions {
     inner: Arc<Mutex<HashMap<String, AdminSession>>>,
     user_sessions: Arc<Mutex<HashMap<String, UserSession>>>,
@@ -24,10 +24,7 @@ pub struct Sessions {
 
 impl Sessions {
     pub fn new() -> Self {
-        Self {
-            inner: Arc::new(Mutex::new(HashMap::new())),
-            user_sessions: Arc::new(Mutex::new(HashMap::new())),
-        }
+        Self::default()
     }
 
     pub fn create(&self, ttl_seconds: u64) -> AdminSession {
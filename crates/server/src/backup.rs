@@ -0,0 +1,222 @@
+use std::path::{Path, PathBuf};
+
+use rusqlite::{Connection, OpenFlags};
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::{
+    config::Config,
+    error::AppError,
+    storage::{migrations, DynStore, Store},
+};
+
+/// Scheduled and on-demand backups are named `<PREFIX><timestamp>.sqlite`;
+/// only files matching that pattern are rotated.
+const PREFIX: &str = "beringchat-";
+const EXTENSION: &str = "sqlite";
+
+#[derive(Debug, Serialize)]
+pub struct BackupInfo {
+    pub file_name: String,
+    pub path: String,
+    pub size_bytes: u64,
+    pub created_at: String,
+}
+
+/// Snapshot the database into `config.backup_dir` and rotate old snapshots.
+pub async fn create_backup(store: &dyn Store, config: &Config) -> Result<BackupInfo, AppError> {
+    let dir = PathBuf::from(&config.backup_dir);
+    std::fs::create_dir_all(&dir).map_err(|e| io_error("create backup directory", e))?;
+    let format = time::format_description::parse("[year][month][day]T[hour][minute][second][subsecond digits:3]Z")
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let stamp = OffsetDateTime::now_utc()
+        .format(&format)
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    let path = dir.join(format!("{}{}.{}", PREFIX, stamp, EXTENSION));
+
+    store.backup_to(&path).await?;
+    let info = backup_info(&path)?;
+    tracing::info!(target: "backup", "Wrote backup {} ({} bytes)", info.path, info.size_bytes);
+
+    rotate(&dir, config.backup_keep)?;
+    Ok(info)
+}
+
+/// Backups in `config.backup_dir`, newest first.
+pub fn list_backups(config: &Config) -> Result<Vec<BackupInfo>, AppError> {
+    let mut backups = backup_files(Path::new(&config.backup_dir))?
+        .iter()
+        .map(|path| backup_info(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    backups.reverse();
+    Ok(backups)
+}
+
+/// Delete all but the newest `keep` backups.
+fn rotate(dir: &Path, keep: usize) -> Result<(), AppError> {
+    let files = backup_files(dir)?;
+    let excess = files.len().saturating_sub(keep);
+    for path in &files[..excess] {
+        std::fs::remove_file(path).map_err(|e| io_error("remove old backup", e))?;
+        tracing::info!(target: "backup", "Rotated out old backup {}", path.display());
+    }
+    Ok(())
+}
+
+/// Named backups in `dir`, oldest first (the timestamp in the name sorts).
+fn backup_files(dir: &Path) -> Result<Vec<PathBuf>, AppError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(io_error("read backup directory", e)),
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(PREFIX) && n.ends_with(&format!(".{}", EXTENSION)))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn backup_info(path: &Path) -> Result<BackupInfo, AppError> {
+    let metadata = std::fs::metadata(path).map_err(|e| io_error("read backup metadata", e))?;
+    let created_at = metadata
+        .modified()
+        .ok()
+        .and_then(|t| OffsetDateTime::from(t).format(&Rfc3339).ok())
+        .unwrap_or_default();
+    Ok(BackupInfo {
+        file_name: path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string(),
+        path: path.display().to_string(),
+        size_bytes: metadata.len(),
+        created_at,
+    })
+}
+
+/// Replace the database at `database_path` with `backup`. The server must be
+/// stopped. The backup is checked for integrity and for a schema version this
+/// binary can run before the live file is touched; the previous database is kept
+/// beside it with a `.pre-restore` suffix. Returns the restored schema version.
+pub fn restore(backup: &Path, database_path: &Path) -> Result<i64, AppError> {
+    // Validate a staged copy: the FTS integrity check needs a writable
+    // database and the backup itself should stay untouched.
+    let staged = sibling(database_path, ".restoring");
+    std::fs::copy(backup, &staged).map_err(|e| io_error("stage backup", e))?;
+    let version = match validate(&staged) {
+        Ok(version) => version,
+        Err(e) => {
+            let _ = std::fs::remove_file(&staged);
+            return Err(e);
+        }
+    };
+
+    // Leftover WAL/SHM files belong to the old database and would corrupt
+    // the restored one, so they move aside with it.
+    for suffix in ["", "-wal", "-shm"] {
+        let current = sibling(database_path, suffix);
+        if current.exists() {
+            std::fs::rename(&current, sibling(database_path, &format!(".pre-restore{}", suffix)))
+                .map_err(|e| io_error("move current database aside", e))?;
+        }
+    }
+    std::fs::rename(&staged, database_path).map_err(|e| io_error("swap in restored database", e))?;
+    Ok(version)
+}
+
+fn validate(path: &Path) -> Result<i64, AppError> {
+    let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    let integrity: String = conn.query_row("PRAGMA integrity_check", [], |row| row.get(0))?;
+    if integrity != "ok" {
+        return Err(AppError::BadRequest(format!("backup failed integrity check: {}", integrity)));
+    }
+    let has_versions: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_versions {
+        return Err(AppError::BadRequest("backup has no schema_version table".to_string()));
+    }
+    let version = migrations::current_version(&conn)?;
+    let latest = migrations::latest_version();
+    if version > latest {
+        return Err(AppError::BadRequest(format!(
+            "backup schema version {} is newer than this binary supports ({})",
+            version, latest
+        )));
+    }
+    Ok(version)
+}
+
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn io_error(action: &str, e: std::io::Error) -> AppError {
+    AppError::Internal(format!("failed to {}: {}", action, e))
+}
+
+pub async fn backup_task(store: DynStore, config: Config) {
+    let Some(interval) = config.backup_interval_secs else {
+        return;
+    };
+    tracing::info!(target: "backup", "Scheduled backups every {}s into {} (keeping {})", interval, config.backup_dir, config.backup_keep);
+    loop {
+        tokio::time::sleep(tokio::time::Duration::from_secs(interval)).await;
+        if let Err(e) = create_backup(store.as_ref(), &config).await {
+            tracing::warn!(target: "backup", "Scheduled backup failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SqliteStore;
+
+    #[tokio::test]
+    async fn backup_rotates_and_restores() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let db_path = dir.path().join("live.sqlite");
+        let store = SqliteStore::new(db_path.to_str().unwrap()).expect("store");
+        store.init().await.expect("init");
+        store.create_user("alice", true, None).await.expect("alice");
+
+        let mut config = Config::from_env().expect("config");
+        config.backup_dir = dir.path().join("backups").display().to_string();
+        config.backup_keep = 2;
+        for _ in 0..3 {
+            create_backup(&store, &config).await.expect("backup");
+        }
+        let backups = list_backups(&config).expect("list");
+        assert_eq!(backups.len(), 2);
+
+        store.create_user("bob", true, None).await.expect("bob");
+        drop(store);
+
+        let version = restore(Path::new(&backups[0].path), &db_path).expect("restore");
+        assert_eq!(version, migrations::latest_version());
+        assert!(sibling(&db_path, ".pre-restore").exists());
+
+        let restored = SqliteStore::new(db_path.to_str().unwrap()).expect("store");
+        let names: Vec<String> = restored.list_users().await.expect("users").into_iter().map(|u| u.username).collect();
+        assert_eq!(names, vec!["alice".to_string()]);
+    }
+
+    #[test]
+    fn restore_rejects_non_beringchat_database() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let bogus = dir.path().join("bogus.sqlite");
+        Connection::open(&bogus).unwrap().execute_batch("CREATE TABLE t (x);").unwrap();
+        let target = dir.path().join("live.sqlite");
+        assert!(restore(&bogus, &target).is_err());
+        assert!(!target.exists());
+    }
+}
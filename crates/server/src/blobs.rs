@@ -0,0 +1,257 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    config::Config,
+    error::AppError,
+    storage::{DynStore, Store},
+};
+
+/// Attachments nobody has linked to a message yet, and blob files no
+/// attachment refers to, are kept this long before garbage collection so
+/// uploads that are about to be sent are not lost.
+const ORPHAN_GRACE: time::Duration = time::Duration::hours(24);
+
+/// Uploads are written here first and moved into place once hashed.
+const TMP_DIR: &str = "tmp";
+
+/// Whether `hash` is a lowercase hex SHA-256, the only form blob names take.
+pub fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// Where the blob with this hash lives: `<dir>/ab/cd/abcd...`. Returns
+/// `None` for invalid hashes, so callers can pass hashes from requests
+/// straight in.
+pub fn blob_path(dir: &Path, hash: &str) -> Option<PathBuf> {
+    if !is_valid_hash(hash) {
+        return None;
+    }
+    Some(dir.join(&hash[..2]).join(&hash[2..4]).join(hash))
+}
+
+/// Streams one upload into the blob store, hashing as it goes. The temporary
+/// file is removed if the writer is dropped before `finish`.
+pub struct BlobWriter {
+    dir: PathBuf,
+    tmp_path: PathBuf,
+    file: Option<tokio::fs::File>,
+    hasher: Sha256,
+    size: u64,
+    max_bytes: u64,
+}
+
+impl BlobWriter {
+    pub async fn create(dir: &Path, max_bytes: u64) -> Result<Self, AppError> {
+        let tmp_dir = dir.join(TMP_DIR);
+        tokio::fs::create_dir_all(&tmp_dir)
+            .await
+            .map_err(|e| io_error("create attachments directory", e))?;
+        let tmp_path = tmp_dir.join(uuid::Uuid::new_v4().to_string());
+        let file = tokio::fs::File::create(&tmp_path)
+            .await
+            .map_err(|e| io_error("create upload file", e))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            tmp_path,
+            file: Some(file),
+            hasher: Sha256::new(),
+            size: 0,
+            max_bytes,
+        })
+    }
+
+    pub async fn write(&mut self, chunk: &[u8]) -> Result<(), AppError> {
+        self.size += chunk.len() as u64;
+        if self.size > self.max_bytes {
+            return Err(AppError::BadRequest(format!(
+                "attachment is larger than {} bytes",
+                self.max_bytes
+            )));
+        }
+        self.hasher.update(chunk);
+        let file = self.file.as_mut().ok_or_else(|| AppError::Internal("upload already finished".to_string()))?;
+        file.write_all(chunk).await.map_err(|e| io_error("write upload", e))
+    }
+
+    /// Move the upload to its content address and return `(hash, size)`.
+    /// Identical content already in the store is kept, with its modification
+    /// time reset so garbage collection treats it like a fresh upload until
+    /// the new attachment refers to it.
+    pub async fn finish(mut self) -> Result<(String, u64), AppError> {
+        let mut file = self.file.take().ok_or_else(|| AppError::Internal("upload already finished".to_string()))?;
+        file.flush().await.map_err(|e| io_error("write upload", e))?;
+        file.sync_all().await.map_err(|e| io_error("write upload", e))?;
+        drop(file);
+
+        let hash: String = self.hasher.clone().finalize().iter().map(|b| format!("{:02x}", b)).collect();
+        let path = blob_path(&self.dir, &hash).ok_or_else(|| AppError::Internal("invalid blob hash".to_string()))?;
+        if touch(&path).await? {
+            let _ = tokio::fs::remove_file(&self.tmp_path).await;
+        } else {
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent)
+                    .await
+                    .map_err(|e| io_error("create blob directory", e))?;
+            }
+            tokio::fs::rename(&self.tmp_path, &path)
+                .await
+                .map_err(|e| io_error("store blob", e))?;
+        }
+        Ok((hash, self.size))
+    }
+}
+
+/// Set the file's modification time to now. Returns `false` if there is
+/// no such file.
+async fn touch(path: &Path) -> Result<bool, AppError> {
+    let file = match tokio::fs::OpenOptions::new().append(true).open(path).await {
+        Ok(file) => file.into_std().await,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(io_error("open blob", e)),
+    };
+    tokio::task::spawn_blocking(move || file.set_modified(std::time::SystemTime::now()))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))?
+        .map_err(|e| io_error("touch blob", e))?;
+    Ok(true)
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            let _ = std::fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct GcReport {
+    pub attachments: usize,
+    pub blobs: usize,
+}
+
+/// Delete attachments that were never sent (or whose messages are gone),
+/// then blob files no remaining attachment refers to.
+pub async fn collect_garbage(store: &dyn Store, config: &Config) -> Result<GcReport, AppError> {
+    let cutoff = OffsetDateTime::now_utc() - ORPHAN_GRACE;
+    let cutoff_str = cutoff.format(&Rfc3339).map_err(|e| AppError::Internal(e.to_string()))?;
+    let mut report = GcReport {
+        attachments: store.delete_orphaned_attachments(&cutoff_str).await?,
+        blobs: 0,
+    };
+
+    let in_use: HashSet<String> = store.list_attachment_hashes().await?.into_iter().collect();
+    let dir = PathBuf::from(&config.attachments_dir);
+    report.blobs = tokio::task::spawn_blocking(move || sweep_blobs(&dir, &in_use, cutoff.into()))
+        .await
+        .map_err(|e| AppError::Internal(e.to_string()))??;
+    Ok(report)
+}
+
+/// Remove blob files (and stale temporary uploads) older than `cutoff` that
+/// are not in `in_use`. Newer files may belong to uploads still in flight.
+fn sweep_blobs(dir: &Path, in_use: &HashSet<String>, cutoff: std::time::SystemTime) -> Result<usize, AppError> {
+    let mut removed = 0;
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let entries = match std::fs::read_dir(&current) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(io_error("read attachments directory", e)),
+        };
+        for entry in entries {
+            let entry = entry.map_err(|e| io_error("read attachments directory", e))?;
+            let metadata = entry.metadata().map_err(|e| io_error("read blob metadata", e))?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let is_tmp = current.ends_with(TMP_DIR);
+            if !is_tmp && in_use.contains(&name) {
+                continue;
+            }
+            if metadata.modified().map_or(true, |modified| modified >= cutoff) {
+                continue;
+            }
+            std::fs::remove_file(entry.path()).map_err(|e| io_error("remove blob", e))?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+fn io_error(action: &str, e: std::io::Error) -> AppError {
+    AppError::Internal(format!("failed to {}: {}", action, e))
+}
+
+pub async fn gc_task(store: DynStore, config: Config) {
+    let interval = tokio::time::Duration::from_secs(config.attachment_gc_interval_secs);
+    loop {
+        tokio::time::sleep(interval).await;
+        match collect_garbage(store.as_ref(), &config).await {
+            Ok(report) if report.attachments > 0 || report.blobs > 0 => {
+                tracing::info!(
+                    target: "attachments",
+                    "Removed {} orphaned attachments and {} unused blobs",
+                    report.attachments, report.blobs
+                );
+            }
+            Ok(_) => tracing::debug!(target: "attachments", "Attachment GC found nothing to remove"),
+            Err(e) => tracing::warn!(target: "attachments", "Attachment GC failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn uploads_are_content_addressed_and_size_limited() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let mut writer = BlobWriter::create(dir.path(), 16).await.expect("writer");
+        writer.write(b"hello ").await.expect("write");
+        writer.write(b"world").await.expect("write");
+        let (hash, size) = writer.finish().await.expect("finish");
+        assert_eq!(hash, "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9");
+        assert_eq!(size, 11);
+        let path = blob_path(dir.path(), &hash).expect("path");
+        assert_eq!(std::fs::read(path).expect("blob"), b"hello world");
+
+        let mut writer = BlobWriter::create(dir.path(), 4).await.expect("writer");
+        assert!(writer.write(b"too big").await.is_err());
+        drop(writer);
+        assert_eq!(std::fs::read_dir(dir.path().join(TMP_DIR)).expect("tmp").count(), 0);
+        assert!(blob_path(dir.path(), "../etc/passwd").is_none());
+    }
+
+    #[tokio::test]
+    async fn uploading_existing_content_keeps_the_blob_from_the_sweep() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let mut writer = BlobWriter::create(dir.path(), 16).await.expect("writer");
+        writer.write(b"hello").await.expect("write");
+        let (hash, _) = writer.finish().await.expect("finish");
+        let path = blob_path(dir.path(), &hash).expect("path");
+        let long_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(48 * 3600);
+        std::fs::File::options()
+            .append(true)
+            .open(&path)
+            .and_then(|f| f.set_modified(long_ago))
+            .expect("age blob");
+
+        let mut writer = BlobWriter::create(dir.path(), 16).await.expect("writer");
+        writer.write(b"hello").await.expect("write");
+        assert_eq!(writer.finish().await.expect("finish").0, hash);
+        let cutoff = std::time::SystemTime::now() - std::time::Duration::from_secs(24 * 3600);
+        assert_eq!(sweep_blobs(dir.path(), &HashSet::new(), cutoff).expect("sweep"), 0);
+        assert!(path.exists());
+    }
+}
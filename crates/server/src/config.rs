@@ -1,36 +1,207 @@
 use std::env;
 
+use crate::error::AppError;
+
+/// Which `Store` implementation backs the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageBackend {
+    Sqlite,
+    Memory,
+    Postgres,
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
     pub server_name: String,
     pub base_url: String,
+    pub storage_backend: StorageBackend,
     pub database_path: String,
+    pub database_url: Option<String>,
+    pub database_pool_size: u32,
     pub admin_token: String,
     pub server_token: String,
     pub admin_username: String,
     pub admin_password: String,
+    /// Local usernames allowed to edit and delete other people's messages in
+    /// channels. The admin account is always a moderator.
+    pub moderators: Vec<String>,
     pub tenor_api_key: Option<String>,
+    /// Server-wide retention default, used for channels and DMs without
+    /// their own policy. `None` keeps messages forever.
+    pub retention_days: Option<u32>,
+    pub retention_max_messages: Option<u32>,
+    pub retention_interval_secs: u64,
+    pub backup_dir: String,
+    /// Scheduled backups are off unless an interval is set.
+    pub backup_interval_secs: Option<u64>,
+    pub backup_keep: usize,
+    pub attachments_dir: String,
+    pub attachment_max_bytes: u64,
+    /// MIME types accepted for uploads. `type/*` matches a whole family.
+    pub attachment_allowed_types: Vec<String>,
+    pub attachment_gc_interval_secs: u64,
+    /// Connected users with no client activity for this long show as away.
+    pub presence_idle_secs: u64,
+    /// How often to fetch the full presence, channels and users of every
+    /// peer. Presence changes are pushed as they happen in between.
+    pub presence_reconcile_secs: u64,
+    /// Failed deliveries to a peer are retried with growing delays, and
+    /// marked failed after this many attempts.
+    pub outbox_max_attempts: u32,
+    /// Where this server's Ed25519 federation key is kept. Generated on
+    /// first start if the file does not exist.
+    pub federation_key_path: String,
+    /// Also accept unsigned federation requests that only carry a token, for
+    /// peers that have not been upgraded to signed requests yet.
+    pub federation_allow_unsigned: bool,
+    /// Accept peering requests from servers on loopback and private
+    /// addresses, for federations inside one private network.
+    pub federation_allow_private_peers: bool,
 }
 
 impl Config {
-    pub fn from_env() -> Self {
+    /// Read the configuration from the environment. Unset variables take
+    /// their defaults; values that cannot be used are an error.
+    pub fn from_env() -> Result<Self, AppError> {
         let server_name = env::var("SERVER_NAME").unwrap_or_else(|_| "local".to_string());
         let base_url = env::var("BASE_URL").unwrap_or_else(|_| "http://localhost:8080".to_string());
+        let storage_backend = match env::var("STORAGE_BACKEND").unwrap_or_default().to_lowercase().as_str() {
+            "" | "sqlite" => StorageBackend::Sqlite,
+            "memory" => StorageBackend::Memory,
+            "postgres" | "postgresql" => StorageBackend::Postgres,
+            other => {
+                return Err(AppError::Internal(format!(
+                    "unknown STORAGE_BACKEND '{}' (expected sqlite, memory or postgres)",
+                    other
+                )))
+            }
+        };
         let database_path = env::var("DATABASE_PATH").unwrap_or_else(|_| "./data.sqlite".to_string());
+        let database_url = env::var("DATABASE_URL").ok().filter(|s| !s.is_empty());
+        let database_pool_size = env::var("DATABASE_POOL_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(8);
         let admin_token = env::var("ADMIN_TOKEN").unwrap_or_else(|_| "admin-token".to_string());
         let server_token = env::var("SERVER_TOKEN").unwrap_or_else(|_| "server-token".to_string());
         let admin_username = env::var("ADMIN_USERNAME").unwrap_or_else(|_| "admin".to_string());
         let admin_password = env::var("ADMIN_PASSWORD").unwrap_or_else(|_| "admin".to_string());
+        let moderators = env::var("MODERATORS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .collect();
         let tenor_api_key = env::var("TENOR_API_KEY").ok().filter(|s| !s.is_empty());
-        Self {
+        let retention_days = env::var("RETENTION_DAYS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0);
+        let retention_max_messages = env::var("RETENTION_MAX_MESSAGES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0);
+        let retention_interval_secs = env::var("RETENTION_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0)
+            .unwrap_or(3600);
+        let backup_dir = env::var("BACKUP_DIR").unwrap_or_else(|_| "./backups".to_string());
+        let backup_interval_secs = env::var("BACKUP_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0);
+        let backup_keep = env::var("BACKUP_KEEP")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0)
+            .unwrap_or(7);
+        let attachments_dir = env::var("ATTACHMENTS_DIR").unwrap_or_else(|_| "./attachments".to_string());
+        let attachment_max_bytes = env::var("ATTACHMENT_MAX_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0)
+            .unwrap_or(25 * 1024 * 1024);
+        let attachment_allowed_types = env::var("ATTACHMENT_ALLOWED_TYPES")
+            .unwrap_or_else(|_| "image/*,video/*,audio/*,application/pdf,text/plain".to_string())
+            .split(',')
+            .map(|t| t.trim().to_ascii_lowercase())
+            .filter(|t| !t.is_empty())
+            .collect();
+        let attachment_gc_interval_secs = env::var("ATTACHMENT_GC_INTERVAL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0)
+            .unwrap_or(3600);
+        let presence_idle_secs = env::var("PRESENCE_IDLE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0)
+            .unwrap_or(300);
+        let presence_reconcile_secs = env::var("PRESENCE_RECONCILE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0)
+            .unwrap_or(60);
+        let outbox_max_attempts = env::var("OUTBOX_MAX_ATTEMPTS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&v| v > 0)
+            .unwrap_or(12);
+        let federation_key_path = env::var("FEDERATION_KEY_PATH").unwrap_or_else(|_| "./federation.key".to_string());
+        let federation_allow_unsigned = matches!(
+            env::var("FEDERATION_ALLOW_UNSIGNED").unwrap_or_default().to_lowercase().as_str(),
+            "1" | "true" | "yes"
+        );
+        let federation_allow_private_peers = matches!(
+            env::var("FEDERATION_ALLOW_PRIVATE_PEERS").unwrap_or_default().to_lowercase().as_str(),
+            "1" | "true" | "yes"
+        );
+        Ok(Self {
             server_name,
             base_url,
+            storage_backend,
             database_path,
+            database_url,
+            database_pool_size,
             admin_token,
             server_token,
             admin_username,
             admin_password,
+            moderators,
             tenor_api_key,
-        }
+            retention_days,
+            retention_max_messages,
+            retention_interval_secs,
+            backup_dir,
+            backup_interval_secs,
+            backup_keep,
+            attachments_dir,
+            attachment_max_bytes,
+            attachment_allowed_types,
+            attachment_gc_interval_secs,
+            presence_idle_secs,
+            presence_reconcile_secs,
+            outbox_max_attempts,
+            federation_key_path,
+            federation_allow_unsigned,
+            federation_allow_private_peers,
+        })
+    }
+
+    /// Whether a local user may edit or delete other people's channel messages.
+    pub fn is_moderator(&self, username: &str) -> bool {
+        username == self.admin_username || self.moderators.iter().any(|m| m == username)
+    }
+
+    /// Whether uploads with this MIME type (parameters ignored) are accepted.
+    pub fn is_allowed_attachment_type(&self, content_type: &str) -> bool {
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        let Some((family, _)) = essence.split_once('/') else {
+            return false;
+        };
+        self.attachment_allowed_types.iter().any(|allowed| {
+            allowed == &essence || allowed.strip_suffix("/*").is_some_and(|prefix| prefix == family)
+        })
     }
 }
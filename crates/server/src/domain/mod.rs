@@ -9,6 +9,10 @@ pub struct User {
     pub server_id: Option<Uuid>,
     pub is_local: bool,
     pub display_name: Option<String>,
+    /// When the user's last connection closed, RFC3339 UTC. For remote users
+    /// this is what their server reported.
+    #[serde(default)]
+    pub last_seen_at: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -17,6 +21,40 @@ pub struct Server {
     pub name: String,
     pub base_url: String,
     pub token: String,
+    /// The peer's Ed25519 public key (base64), fetched from its well-known
+    /// endpoint the first time one of its signed requests arrives.
+    pub public_key: Option<String>,
+    /// Suspended peers are neither sent to nor accepted from, until resumed.
+    pub status: PeerStatus,
+    /// The federation protocol version and capabilities the peer's discovery
+    /// document announced when last checked. `None` until then, or for
+    /// peers that predate capabilities.
+    pub protocol_version: Option<String>,
+    pub capabilities: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerStatus {
+    Active,
+    Suspended,
+}
+
+impl PeerStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PeerStatus::Active => "active",
+            PeerStatus::Suspended => "suspended",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "active" => Some(PeerStatus::Active),
+            "suspended" => Some(PeerStatus::Suspended),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,6 +73,255 @@ pub struct Message {
     pub recipient_user_id: Option<Uuid>,
     pub channel_id: Option<Uuid>,
     pub sent_at: String,
+    pub edited_at: Option<String>,
+    /// Set when the message was deleted. The row stays behind as a tombstone
+    /// with an empty body so history and cursors stay stable.
+    pub deleted_at: Option<String>,
+    /// The channel message this one replies to. Threads are one level deep,
+    /// so this is always a top-level message.
+    pub thread_root_id: Option<Uuid>,
+}
+
+/// A superseded version of a message, recorded each time it is edited.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageEdit {
+    pub message_id: Uuid,
+    pub previous_body: String,
+    pub edited_at: String,
+    pub editor_user_id: Uuid,
+}
+
+/// An uploaded file. Its bytes live in the blob store under `hash`. Copies
+/// received through federation have `origin_server` set and fetch the blob
+/// from there the first time it is downloaded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: Uuid,
+    /// Lowercase hex SHA-256 of the content.
+    pub hash: String,
+    pub size: u64,
+    pub content_type: String,
+    pub filename: String,
+    pub uploader_user_id: Uuid,
+    pub origin_server: Option<String>,
+    pub created_at: String,
+}
+
+/// Reply statistics for a thread. Deleted replies are not counted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadSummary {
+    pub root_id: Uuid,
+    pub reply_count: u64,
+    pub last_reply_at: String,
+}
+
+/// All reactions with one emoji on one message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionCount {
+    pub message_id: Uuid,
+    pub emoji: String,
+    pub count: u64,
+    /// Whether the viewing user is one of the reactors.
+    pub reacted: bool,
+}
+
+/// A message pinned in a channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelPin {
+    pub channel_id: Uuid,
+    pub message_id: Uuid,
+    pub pinned_by_user_id: Uuid,
+    pub pinned_at: String,
+}
+
+/// How a user was mentioned: by name, or through `@channel` or `@here`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MentionKind {
+    User,
+    Channel,
+    Here,
+}
+
+impl MentionKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MentionKind::User => "user",
+            MentionKind::Channel => "channel",
+            MentionKind::Here => "here",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "user" => Some(MentionKind::User),
+            "channel" => Some(MentionKind::Channel),
+            "here" => Some(MentionKind::Here),
+            _ => None,
+        }
+    }
+}
+
+/// A user mentioned in a message. `sent_at` is the message's, so mentions
+/// list in conversation order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mention {
+    pub message_id: Uuid,
+    pub user_id: Uuid,
+    pub kind: MentionKind,
+    pub sent_at: String,
+}
+
+/// A user's availability. Users choose `Online`, `Away`, `Dnd` or
+/// `Invisible`; `Away` is also shown once an online user goes idle, and
+/// `Offline` is only ever reported, for users with no open connection or
+/// who are invisible to the viewer.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceState {
+    Online,
+    Away,
+    Dnd,
+    Invisible,
+    Offline,
+}
+
+impl PresenceState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PresenceState::Online => "online",
+            PresenceState::Away => "away",
+            PresenceState::Dnd => "dnd",
+            PresenceState::Invisible => "invisible",
+            PresenceState::Offline => "offline",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "online" => Some(PresenceState::Online),
+            "away" => Some(PresenceState::Away),
+            "dnd" => Some(PresenceState::Dnd),
+            "invisible" => Some(PresenceState::Invisible),
+            "offline" => Some(PresenceState::Offline),
+            _ => None,
+        }
+    }
+}
+
+/// The presence state a local user chose and their custom status. Users
+/// without a row are `Online` with no status.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UserPresence {
+    pub user_id: Uuid,
+    pub state: PresenceState,
+    pub status_text: Option<String>,
+    pub status_emoji: Option<String>,
+    /// RFC3339 UTC; the custom status is dropped from this time on.
+    pub status_expires_at: Option<String>,
+    pub updated_at: String,
+}
+
+impl UserPresence {
+    /// The custom status alongside the state `state` the user is shown in.
+    pub fn status(&self, state: PresenceState) -> PresenceStatus {
+        PresenceStatus {
+            state,
+            status_text: self.status_text.clone(),
+            status_emoji: self.status_emoji.clone(),
+            status_expires_at: self.status_expires_at.clone(),
+        }
+    }
+}
+
+/// A user's presence as others see it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PresenceStatus {
+    pub state: PresenceState,
+    #[serde(default)]
+    pub status_text: Option<String>,
+    #[serde(default)]
+    pub status_emoji: Option<String>,
+    #[serde(default)]
+    pub status_expires_at: Option<String>,
+}
+
+impl PresenceStatus {
+    pub fn new(state: PresenceState) -> Self {
+        Self {
+            state,
+            status_text: None,
+            status_emoji: None,
+            status_expires_at: None,
+        }
+    }
+
+    /// Drop the custom status if it has expired by `now` (RFC3339 UTC).
+    pub fn expire(self, now: &str) -> Self {
+        if self.status_expires_at.as_deref().is_some_and(|at| at <= now) {
+            Self::new(self.state)
+        } else {
+            self
+        }
+    }
+}
+
+/// Position in a conversation. History is ordered by `(sent_at, id)` so
+/// messages sharing a timestamp still page deterministically.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageCursor {
+    pub sent_at: String,
+    pub id: Uuid,
+}
+
+impl MessageCursor {
+    pub fn of(message: &Message) -> Self {
+        Self {
+            sent_at: message.sent_at.clone(),
+            id: message.id,
+        }
+    }
+
+    /// Opaque form handed to clients: `<sent_at>_<id>`.
+    pub fn encode(&self) -> String {
+        format!("{}_{}", self.sent_at, self.id)
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        let (sent_at, id) = value.rsplit_once('_')?;
+        Some(Self {
+            sent_at: sent_at.to_string(),
+            id: Uuid::parse_str(id).ok()?,
+        })
+    }
+}
+
+/// Which slice of a conversation to read. Cursors are exclusive.
+#[derive(Debug, Clone)]
+pub enum HistoryPage {
+    Latest,
+    Before(MessageCursor),
+    After(MessageCursor),
+}
+
+/// Filters for `Store::search_messages`. `since` is inclusive and `until`
+/// exclusive; both are compared against `sent_at` as RFC3339 strings.
+#[derive(Debug, Clone, Default)]
+pub struct MessageSearch {
+    pub query: String,
+    pub channel_id: Option<Uuid>,
+    pub dm_user_id: Option<Uuid>,
+    pub author_user_id: Option<Uuid>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub message: Message,
+    /// HTML-escaped excerpt of the body with matches wrapped in `<mark>`.
+    pub snippet: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,6 +332,182 @@ pub struct FederationToken {
     pub created_at: String,
 }
 
+/// Retention rule for one channel, or for all DM conversations when
+/// `channel_id` is `None`. A policy with neither limit set keeps messages
+/// forever, overriding the server default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionPolicy {
+    pub channel_id: Option<Uuid>,
+    pub max_age_days: Option<u32>,
+    pub max_messages: Option<u32>,
+    pub updated_at: String,
+}
+
+/// A conversation from one user's point of view: a channel, or their DMs with
+/// one other user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConversationRef {
+    Channel(Uuid),
+    Dm(Uuid),
+}
+
+/// How far a user has read in a conversation. Everything at or before
+/// `(sent_at, message_id)` counts as read.
+#[derive(Debug, Clone)]
+pub struct ReadMarker {
+    pub conversation: ConversationRef,
+    pub message_id: Uuid,
+    pub sent_at: String,
+    pub updated_at: String,
+}
+
+/// Messages from other users past the read marker. `mentions` is the subset
+/// that mention the reader by `@username`.
+#[derive(Debug, Clone, Copy)]
+pub struct UnreadCount {
+    pub conversation: ConversationRef,
+    pub unread: u64,
+    pub mentions: u64,
+}
+
+/// Where a queued federation request stands. `Failed` is the dead-letter
+/// state, reached once the delivery worker gives up retrying.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Failed => "failed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(DeliveryStatus::Pending),
+            "delivered" => Some(DeliveryStatus::Delivered),
+            "failed" => Some(DeliveryStatus::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A federation request queued for one peer. A peer's pending entries are
+/// delivered one at a time in `id` order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub server_id: Uuid,
+    /// Federation endpoint the payload is posted to, e.g. `/federation/messages`.
+    pub path: String,
+    /// JSON request body. Emptied once delivered.
+    pub payload: String,
+    /// Set when the entry sends a new message, for its delivery status.
+    pub message_id: Option<Uuid>,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    /// RFC3339 UTC with six fractional digits (see `delivery::outbox_time`);
+    /// a pending entry is not tried again before this.
+    pub next_attempt_at: String,
+    pub last_error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// How far a message got to one peer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageDelivery {
+    pub message_id: Uuid,
+    pub server_name: String,
+    pub status: DeliveryStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub updated_at: String,
+}
+
+/// Whether a peering request was sent by us or to us.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PeeringDirection {
+    Incoming,
+    Outgoing,
+}
+
+impl PeeringDirection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PeeringDirection::Incoming => "incoming",
+            PeeringDirection::Outgoing => "outgoing",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "incoming" => Some(PeeringDirection::Incoming),
+            "outgoing" => Some(PeeringDirection::Outgoing),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PeeringStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+impl PeeringStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PeeringStatus::Pending => "pending",
+            PeeringStatus::Approved => "approved",
+            PeeringStatus::Rejected => "rejected",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(PeeringStatus::Pending),
+            "approved" => Some(PeeringStatus::Approved),
+            "rejected" => Some(PeeringStatus::Rejected),
+            _ => None,
+        }
+    }
+}
+
+/// A request to peer with another server. Once the receiving admin approves
+/// it, both servers add each other to `servers`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeeringRequest {
+    pub id: Uuid,
+    pub direction: PeeringDirection,
+    /// The other server: the requester for incoming requests, the server
+    /// asked for outgoing ones.
+    pub server_name: String,
+    pub base_url: String,
+    /// The other server's key, from its discovery document.
+    pub public_key: String,
+    pub status: PeeringStatus,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: String,
+    pub applied_at: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum MessageKind {
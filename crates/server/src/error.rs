@@ -9,6 +9,14 @@ pub enum AppError {
     BadRequest(String),
     #[error("database error")]
     Database(#[from] rusqlite::Error),
+    #[error("database pool error")]
+    Pool(#[from] r2d2::Error),
+    #[cfg(feature = "postgres")]
+    #[error("database error")]
+    Postgres(#[from] tokio_postgres::Error),
+    #[cfg(feature = "postgres")]
+    #[error("database pool error")]
+    PostgresPool(#[from] deadpool_postgres::PoolError),
     #[error("http error")]
     Http(#[from] reqwest::Error),
     #[error("internal error: {0}")]
@@ -26,6 +34,11 @@ impl IntoResponse for AppError {
             AppError::Unauthorized => StatusCode::UNAUTHORIZED,
             AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
             AppError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Pool(_) => StatusCode::SERVICE_UNAVAILABLE,
+            #[cfg(feature = "postgres")]
+            AppError::Postgres(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            #[cfg(feature = "postgres")]
+            AppError::PostgresPool(_) => StatusCode::SERVICE_UNAVAILABLE,
             AppError::Http(_) => StatusCode::BAD_GATEWAY,
             AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
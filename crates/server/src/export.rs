@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{
+    domain::{HistoryPage, Message, MessageCursor, MessageKind, User},
+    error::AppError,
+    storage::Store,
+};
+
+/// Messages are read in pages of this size so large histories never sit in a
+/// single query result.
+const PAGE_SIZE: usize = 500;
+
+/// Everything tied to one account. Tokens and password hashes are left out.
+#[derive(Debug, Serialize)]
+pub struct UserExport {
+    pub exported_at: String,
+    pub server: String,
+    pub profile: ExportProfile,
+    pub memberships: Vec<ExportChannel>,
+    pub direct_messages: Vec<ExportConversation>,
+    pub channel_messages: Vec<ExportMessage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportProfile {
+    pub id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub is_local: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportChannel {
+    pub id: Uuid,
+    pub name: String,
+    pub origin_server: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportConversation {
+    pub user_id: Uuid,
+    pub username: String,
+    pub server: Option<String>,
+    pub messages: Vec<ExportMessage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ExportMessage {
+    pub id: Uuid,
+    pub author_user_id: Uuid,
+    pub channel_id: Option<Uuid>,
+    pub channel_name: Option<String>,
+    pub body: String,
+    pub sent_at: String,
+    pub edited_at: Option<String>,
+    pub thread_root_id: Option<Uuid>,
+}
+
+impl ExportMessage {
+    fn new(message: Message, channel_name: Option<&str>) -> Self {
+        Self {
+            id: message.id,
+            author_user_id: message.author_user_id,
+            channel_id: message.channel_id,
+            channel_name: channel_name.map(str::to_string),
+            body: message.body,
+            sent_at: message.sent_at,
+            edited_at: message.edited_at,
+            thread_root_id: message.thread_root_id,
+        }
+    }
+}
+
+pub async fn build_export(store: &dyn Store, server_name: &str, user: &User) -> Result<UserExport, AppError> {
+    let memberships = store
+        .list_channels_for_user(user.id)
+        .await?
+        .into_iter()
+        .map(|c| ExportChannel {
+            id: c.id,
+            name: c.name,
+            origin_server: c.origin_server,
+        })
+        .collect();
+
+    let mut direct_messages = Vec::new();
+    for other_id in store.list_dm_partner_ids(user.id).await? {
+        let Some(other) = store.get_user_by_id(other_id).await? else {
+            continue;
+        };
+        let messages = without_tombstones(read_all(store, History::Dm(user.id, other.id)).await?);
+        if messages.is_empty() {
+            continue;
+        }
+        let server = match other.server_id {
+            Some(id) => store.get_server_by_id(&id).await?.map(|s| s.name),
+            None => None,
+        };
+        direct_messages.push(ExportConversation {
+            user_id: other.id,
+            username: other.username,
+            server,
+            messages: messages.into_iter().map(|m| ExportMessage::new(m, None)).collect(),
+        });
+    }
+    direct_messages.sort_by(|a, b| a.username.cmp(&b.username));
+
+    let channel_names: HashMap<Uuid, String> = store.list_channels().await?.into_iter().map(|c| (c.id, c.name)).collect();
+    let channel_messages = without_tombstones(read_all(store, History::Author(user.id)).await?)
+        .into_iter()
+        .filter(|m| m.kind == MessageKind::Channel)
+        .map(|m| {
+            let channel_name = m.channel_id.and_then(|id| channel_names.get(&id)).cloned();
+            ExportMessage::new(m, channel_name.as_deref())
+        })
+        .collect();
+
+    Ok(UserExport {
+        exported_at: OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+        server: server_name.to_string(),
+        profile: ExportProfile {
+            id: user.id,
+            username: user.username.clone(),
+            display_name: user.display_name.clone(),
+            is_local: user.is_local,
+        },
+        memberships,
+        direct_messages,
+        channel_messages,
+    })
+}
+
+enum History {
+    Dm(Uuid, Uuid),
+    Author(Uuid),
+}
+
+/// Walk a conversation from the beginning, one page at a time.
+async fn read_all(store: &dyn Store, history: History) -> Result<Vec<Message>, AppError> {
+    let mut all: Vec<Message> = Vec::new();
+    let mut page = HistoryPage::After(MessageCursor {
+        sent_at: String::new(),
+        id: Uuid::nil(),
+    });
+    loop {
+        let batch = match history {
+            History::Dm(user_id, other_user_id) => store.list_dm_messages(user_id, other_user_id, &page, PAGE_SIZE).await?,
+            History::Author(user_id) => store.list_messages_by_author(user_id, &page, PAGE_SIZE).await?,
+        };
+        let done = batch.len() < PAGE_SIZE;
+        if let Some(last) = batch.last() {
+            page = HistoryPage::After(MessageCursor::of(last));
+        }
+        all.extend(batch);
+        if done {
+            return Ok(all);
+        }
+    }
+}
+
+/// Deleted messages are not exported.
+fn without_tombstones(messages: Vec<Message>) -> Vec<Message> {
+    messages.into_iter().filter(|m| m.deleted_at.is_none()).collect()
+}
+
+/// Serve the export as a JSON file download.
+pub fn into_download(export: &UserExport) -> Result<Response, AppError> {
+    let body = serde_json::to_vec_pretty(export).map_err(|e| AppError::Internal(e.to_string()))?;
+    let date = export.exported_at.get(..10).unwrap_or("export");
+    let username: String = export
+        .profile
+        .username
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let filename = format!("beringchat-export-{}-{}.json", username, date);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::SqliteStore;
+
+    #[tokio::test]
+    async fn export_covers_dms_authored_channel_messages_and_memberships() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let bob = store.create_user("bob", true, None).await.expect("bob");
+        let channel = store.create_channel("general", "local").await.expect("channel");
+        store.add_channel_member(channel.id, alice.id).await.expect("member");
+        store
+            .create_message(MessageKind::Dm, "hi bob", alice.id, Some(bob.id), None, None, "2024-01-01T00:00:00Z")
+            .await
+            .expect("dm");
+        store
+            .create_message(MessageKind::Dm, "hi alice", bob.id, Some(alice.id), None, None, "2024-01-01T00:00:01Z")
+            .await
+            .expect("dm");
+        store
+            .create_message(MessageKind::Channel, "mine", alice.id, None, Some(channel.id), None, "2024-01-01T00:00:02Z")
+            .await
+            .expect("channel message");
+        let carol = store.create_user("carol", true, None).await.expect("carol");
+        store
+            .create_message(MessageKind::Dm, "unanswered", carol.id, Some(alice.id), None, None, "2024-01-01T00:00:05Z")
+            .await
+            .expect("dm");
+        store.create_user("dave", true, None).await.expect("dave");
+        let theirs = store
+            .create_message(MessageKind::Channel, "not mine", bob.id, None, Some(channel.id), None, "2024-01-01T00:00:03Z")
+            .await
+            .expect("channel message");
+        store
+            .create_message(MessageKind::Channel, "my reply", alice.id, None, Some(channel.id), Some(theirs.id), "2024-01-01T00:00:04Z")
+            .await
+            .expect("reply");
+
+        let export = build_export(&store, "local", &alice).await.expect("export");
+        assert_eq!(export.memberships.len(), 1);
+        let partners: Vec<&str> = export.direct_messages.iter().map(|c| c.username.as_str()).collect();
+        assert_eq!(partners, vec!["bob", "carol"]);
+        assert_eq!(export.direct_messages[0].messages.len(), 2);
+        assert_eq!(export.channel_messages.len(), 2);
+        assert_eq!(export.channel_messages[0].body, "mine");
+        assert_eq!(export.channel_messages[1].thread_root_id, Some(theirs.id));
+    }
+}
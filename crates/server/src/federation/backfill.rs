@@ -0,0 +1,94 @@
+//! Backfilling channels that originate on another server. Peers only push
+//! messages sent after they learned of a channel, so when one first appears
+//! here its recent history is fetched from the origin, newest page first.
+
+use std::time::Duration;
+
+use crate::{
+    api::AppState,
+    domain::{Channel, Server},
+    error::AppError,
+    federation::{handlers, protocol::FederatedHistoryPage},
+};
+
+const PAGE_SIZE: usize = 100;
+/// At most this many pages, so a new channel brings in at most the latest
+/// 500 messages.
+const MAX_PAGES: usize = 5;
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Backfill `channel` in the background, if it originates elsewhere.
+pub fn spawn(state: &AppState, channel: Channel) {
+    if channel.origin_server == state.config.server_name {
+        return;
+    }
+    let state = state.clone();
+    tokio::spawn(async move {
+        match backfill(&state, &channel).await {
+            Ok(0) => {}
+            Ok(stored) => {
+                tracing::info!(target: "federation", "Backfilled {} messages of '{}' from {}", stored, channel.name, channel.origin_server);
+                crate::websocket::notify_new_message(&state.message_broadcaster, None, Some(channel.id.to_string()));
+            }
+            Err(e) => {
+                tracing::warn!(target: "federation", "Backfilling '{}' from {} failed: {}", channel.name, channel.origin_server, e);
+            }
+        }
+    });
+}
+
+/// Fetch `channel`'s history from its origin and store what is missing.
+/// Stops at the first page that was already here entirely. Returns how many
+/// messages were stored.
+pub async fn backfill(state: &AppState, channel: &Channel) -> Result<usize, AppError> {
+    let origin = state
+        .store
+        .get_server_by_name(&channel.origin_server)
+        .await?
+        .ok_or_else(|| AppError::BadRequest(format!("unknown server: {}", channel.origin_server)))?;
+    let mut before = None;
+    let mut stored = 0;
+    for _ in 0..MAX_PAGES {
+        let page = fetch_page(state, &origin, channel, before.as_deref()).await?;
+        let mut new_on_page = 0;
+        for message in &page.messages {
+            match handlers::store_history_message(state, channel, message).await {
+                Ok(true) => new_on_page += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::debug!(target: "federation", "Skipping backfilled message {}: {}", message.message_id, e);
+                }
+            }
+        }
+        stored += new_on_page;
+        match page.prev_cursor {
+            Some(cursor) if new_on_page > 0 => before = Some(cursor),
+            _ => break,
+        }
+    }
+    Ok(stored)
+}
+
+async fn fetch_page(
+    state: &AppState,
+    origin: &Server,
+    channel: &Channel,
+    before: Option<&str>,
+) -> Result<FederatedHistoryPage, AppError> {
+    let mut url = reqwest::Url::parse(&origin.base_url)
+        .map_err(|e| AppError::Internal(format!("invalid base URL of {}: {}", origin.name, e)))?;
+    url.path_segments_mut()
+        .map_err(|_| AppError::Internal(format!("invalid base URL of {}", origin.name)))?
+        .pop_if_empty()
+        .extend(["federation", "channels", &channel.origin_server, &channel.name, "history"]);
+    url.query_pairs_mut().append_pair("limit", &PAGE_SIZE.to_string());
+    if let Some(before) = before {
+        url.query_pairs_mut().append_pair("before", before);
+    }
+
+    let response = state.signer.send(&state.http, origin, state.http.get(url).timeout(PEER_TIMEOUT)).await?;
+    if !response.status().is_success() {
+        return Err(AppError::Internal(format!("{} answered {}", origin.name, response.status())));
+    }
+    Ok(response.json().await?)
+}
@@ -0,0 +1,219 @@
+//! Delivery of the federation outbox. Requests that must reach a peer are
+//! queued in `federation_outbox` and posted by `delivery_task`, one at a
+//! time per peer and in the order they were queued, so a peer never sees an
+//! edit before the message it edits. Failed attempts are retried with
+//! growing delays until `OUTBOX_MAX_ATTEMPTS`, when the entry is marked
+//! failed and the peer's next entry goes ahead.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use time::{OffsetDateTime, UtcOffset};
+use tokio::sync::Notify;
+
+use crate::{
+    api::AppState,
+    domain::{DeliveryStatus, MessageDelivery, OutboxEntry, Server},
+    error::AppError,
+};
+
+/// How often the queue is checked for retries that came due.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_BASE: Duration = Duration::from_secs(2);
+const RETRY_MAX: Duration = Duration::from_secs(15 * 60);
+/// Longest response body kept as `last_error`.
+const MAX_ERROR_LEN: usize = 500;
+
+/// Wakes the delivery worker when something is queued.
+#[derive(Clone, Default)]
+pub struct DeliveryQueue {
+    wake: Arc<Notify>,
+}
+
+impl DeliveryQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    async fn woken(&self) {
+        self.wake.notified().await;
+    }
+}
+
+/// Delay before the next try after `attempts` failed ones: 2s, 4s, 8s and
+/// so on, up to 15 minutes.
+pub fn retry_delay(attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    RETRY_BASE.saturating_mul(factor).min(RETRY_MAX)
+}
+
+pub async fn delivery_task(state: AppState) {
+    loop {
+        match deliver_due(&state).await {
+            // Go again right away: those peers may have more queued
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => tracing::warn!(target: "federation", "Outbox delivery failed: {}", e),
+        }
+        tokio::select! {
+            _ = state.deliveries.woken() => {}
+            _ = tokio::time::sleep(POLL_INTERVAL) => {}
+        }
+    }
+}
+
+/// Try the oldest entry of every peer that is due, all peers at once.
+/// Returns true if anything was tried.
+async fn deliver_due(state: &AppState) -> Result<bool, AppError> {
+    let entries = state.store.due_outbox_entries(&outbox_time(OffsetDateTime::now_utc())).await?;
+    if entries.is_empty() {
+        return Ok(false);
+    }
+    let attempts = entries.into_iter().map(|entry| deliver(state, entry));
+    for result in futures_util::future::join_all(attempts).await {
+        result?;
+    }
+    Ok(true)
+}
+
+async fn deliver(state: &AppState, mut entry: OutboxEntry) -> Result<(), AppError> {
+    let server = state.store.get_server_by_id(&entry.server_id).await?;
+    let outcome = match &server {
+        Some(server) => post(state, server, &entry).await,
+        None => Err("server is no longer registered".to_string()),
+    };
+
+    let now = OffsetDateTime::now_utc();
+    entry.updated_at = outbox_time(now);
+    match outcome {
+        Ok(()) => {
+            entry.status = DeliveryStatus::Delivered;
+            entry.last_error = None;
+        }
+        Err(error) => {
+            entry.attempts += 1;
+            entry.last_error = Some(error);
+            if entry.attempts >= state.config.outbox_max_attempts {
+                tracing::warn!(
+                    target: "federation",
+                    "Giving up on outbox entry {} ({}) after {} attempts: {}",
+                    entry.id,
+                    entry.path,
+                    entry.attempts,
+                    entry.last_error.as_deref().unwrap_or_default()
+                );
+                entry.status = DeliveryStatus::Failed;
+            } else {
+                entry.next_attempt_at = outbox_time(now + retry_delay(entry.attempts));
+            }
+        }
+    }
+    state.store.update_outbox_entry(&entry).await?;
+
+    if entry.status != DeliveryStatus::Pending {
+        let server_name = server.map(|s| s.name).unwrap_or_default();
+        notify_author(state, &entry, server_name).await?;
+    }
+    Ok(())
+}
+
+async fn post(state: &AppState, server: &Server, entry: &OutboxEntry) -> Result<(), String> {
+    let url = format!("{}{}", server.base_url.trim_end_matches('/'), entry.path);
+    let request = state
+        .http
+        .post(&url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(entry.payload.clone())
+        .timeout(REQUEST_TIMEOUT);
+    let response = state.signer.send(&state.http, server, request).await.map_err(|e| match e {
+        AppError::Http(e) => e.to_string(),
+        other => other.to_string(),
+    })?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.text().await.unwrap_or_default();
+    let mut error = format!("{}: {}", status, body);
+    if error.len() > MAX_ERROR_LEN {
+        let mut end = MAX_ERROR_LEN;
+        while !error.is_char_boundary(end) {
+            end -= 1;
+        }
+        error.truncate(end);
+    }
+    Err(error)
+}
+
+/// Tell the local author of the message an entry sends how delivery ended.
+async fn notify_author(state: &AppState, entry: &OutboxEntry, server_name: String) -> Result<(), AppError> {
+    let Some(message_id) = entry.message_id else {
+        return Ok(());
+    };
+    let Some(message) = state.store.get_message_by_id(message_id).await? else {
+        return Ok(());
+    };
+    let author_is_local = state
+        .store
+        .get_user_by_id(message.author_user_id)
+        .await?
+        .is_some_and(|author| author.is_local);
+    if author_is_local {
+        let delivery = MessageDelivery {
+            message_id,
+            server_name,
+            status: entry.status,
+            attempts: entry.attempts,
+            last_error: entry.last_error.clone(),
+            updated_at: entry.updated_at.clone(),
+        };
+        crate::websocket::notify_delivery_changed(&state.message_broadcaster, message.author_user_id, &delivery);
+    }
+    Ok(())
+}
+
+/// Outbox times are compared as text, so they are always written in UTC
+/// with exactly six fractional digits, e.g. `2024-01-01T00:00:02.500000Z`.
+pub fn outbox_time(at: OffsetDateTime) -> String {
+    let at = at.to_offset(UtcOffset::UTC);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        at.year(),
+        u8::from(at.month()),
+        at.day(),
+        at.hour(),
+        at.minute(),
+        at.second(),
+        at.microsecond()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outbox_times_sort_as_text() {
+        let at = OffsetDateTime::from_unix_timestamp(1_704_067_202).expect("time");
+        assert_eq!(outbox_time(at), "2024-01-01T00:00:02.000000Z");
+        let later = at + Duration::from_millis(500);
+        assert_eq!(outbox_time(later), "2024-01-01T00:00:02.500000Z");
+        assert!(outbox_time(at) < outbox_time(later));
+        assert!(outbox_time(later) < outbox_time(at + Duration::from_secs(1)));
+        let offset = at.to_offset(UtcOffset::from_hms(2, 0, 0).expect("offset"));
+        assert_eq!(outbox_time(offset), outbox_time(at));
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        assert_eq!(retry_delay(1), Duration::from_secs(2));
+        assert_eq!(retry_delay(2), Duration::from_secs(4));
+        assert_eq!(retry_delay(5), Duration::from_secs(32));
+        assert_eq!(retry_delay(40), RETRY_MAX);
+    }
+}
@@ -1,67 +1,42 @@
-use axum::{Json, extract::State, http::HeaderMap};
+use std::collections::HashMap;
+
+use axum::{Json, body::Body, extract::{Path, Query, State}, http::{header, HeaderValue}, response::Response};
+use serde::Deserialize;
 
 use crate::{
     api::AppState,
     channel_call::CallParticipant,
-    domain::{MessageKind, Server, User},
+    domain::{Attachment, Channel, ChannelPin, HistoryPage, Message, MessageCursor, MessageKind, Server, User},
     error::AppError,
-    federation::{outbox, protocol::{FederatedChannel, FederatedChannelCallEvent, FederatedChannelMembership, FederatedMessage, FederatedUser, FederatedWebRtcSignal}},
+    federation::{backfill, outbox, signing::{self, FederationCaller}, protocol::{FederatedChannel, FederatedChannelCallEvent, FederatedHistoryPage, PresenceDelta, PresenceResponse, PublicKeyDocument, FederatedAttachment, FederatedChannelMembership, FederatedMessage, MembershipAction, FederatedMessageDelete, FederatedMessageEdit, FederatedPin, FederatedReaction, FederatedTyping, FederatedUser, FederatedWebRtcSignal}},
 };
 
-/// Extract the federation token from headers, then validate it against:
-/// 1. The `servers` table (known server tokens) — returns `Some(server)` if found
-/// 2. The `federation_tokens` table (additional accepted tokens) — returns `None` for server
-/// 3. The primary `SERVER_TOKEN` env var — returns `None` for server
-/// Returns `Err(Unauthorized)` if neither matches.
-fn validate_federation_token(state: &AppState, headers: &HeaderMap) -> Result<Option<Server>, AppError> {
-    let token = headers
-        .get("x-federation-token")
-        .and_then(|value| value.to_str().ok())
-        .ok_or(AppError::Unauthorized)?;
-
-    // Check servers table first
-    if let Some(server) = state.store.get_server_by_token(token)? {
-        return Ok(Some(server));
-    }
-
-    // Check federation_tokens table
-    if state.store.is_valid_federation_token(token)? {
-        return Ok(None);
-    }
-
-    // Check primary server token (this server's own token authenticates too)
-    if token == state.config.server_token {
-        return Ok(None);
-    }
-
-    tracing::debug!(target: "federation", "No server or federation token matches provided token");
-    Err(AppError::Unauthorized)
-}
-
 pub async fn receive_message(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    FederationCaller(caller): FederationCaller,
     Json(message): Json<FederatedMessage>,
 ) -> Result<Json<&'static str>, AppError> {
-    let caller_server = validate_federation_token(&state, &headers)?;
+    let caller = caller.ok_or(AppError::Unauthorized)?;
+    if !message.may_be_sent_by(&caller.name) {
+        tracing::warn!(
+            target: "federation",
+            "server '{}' sent message_id={} by {}@{}",
+            caller.name, message.message_id, message.author.username, message.author.server
+        );
+        return Err(AppError::Unauthorized);
+    }
 
     // Ensure the declared author server exists in the DB (we still need its
     // record to store proper user references). If it's missing, reject to
     // avoid creating orphaned user records.
-    let author_server = state
+    state
         .store
-        .get_server_by_name(&message.author.server)?
+        .get_server_by_name(&message.author.server).await?
         .ok_or_else(|| {
             tracing::warn!(target: "federation", "Author server {} not known", message.author.server);
             AppError::Unauthorized
         })?;
 
-    if let Some(ref caller) = caller_server {
-        if caller.name != author_server.name {
-            tracing::warn!(target: "federation", "Message forwarded: author server '{}' differs from caller '{}'", author_server.name, caller.name);
-        }
-    }
-
     let author_user = ensure_remote_user(&state, &message.author).await?;
 
     match message.kind {
@@ -72,65 +47,412 @@ pub async fn receive_message(
     Ok(Json("ok"))
 }
 
-pub async fn receive_channel_membership(
+pub async fn receive_message_edit(
     State(state): State<AppState>,
-    headers: HeaderMap,
-    Json(payload): Json<FederatedChannelMembership>,
+    FederationCaller(caller): FederationCaller,
+    Json(edit): Json<FederatedMessageEdit>,
 ) -> Result<Json<&'static str>, AppError> {
-    let token = headers
-        .get("x-federation-token")
-        .and_then(|value| value.to_str().ok())
-        .ok_or(AppError::Unauthorized)?;
+    let caller = caller.ok_or(AppError::Unauthorized)?;
+    let (message, channel) = authorize_message_change(&state, &caller, &edit.message_id, &edit.author).await?;
+    let editor = resolve_user(&state, &edit.editor).await?;
+
+    let Some(updated) = state.store.edit_message(message.id, &edit.body, editor.id, &edit.edited_at).await? else {
+        tracing::debug!(target: "federation", "stale or duplicate edit for message_id={}, skipping", edit.message_id);
+        return Ok(Json("ok"));
+    };
+    crate::websocket::notify_message_changed(&state.message_broadcaster, &updated);
+
+    for server in relay_targets(&state, &caller, &edit.author, channel.as_ref()).await? {
+        outbox::queue_message_edit(&state, &server, &edit).await?;
+    }
+    Ok(Json("ok"))
+}
+
+pub async fn receive_message_delete(
+    State(state): State<AppState>,
+    FederationCaller(caller): FederationCaller,
+    Json(delete): Json<FederatedMessageDelete>,
+) -> Result<Json<&'static str>, AppError> {
+    let caller = caller.ok_or(AppError::Unauthorized)?;
+    let (message, channel) = authorize_message_change(&state, &caller, &delete.message_id, &delete.author).await?;
 
-    let origin_server = state
+    let Some(tombstone) = state.store.delete_message(message.id, &delete.deleted_at).await? else {
+        tracing::debug!(target: "federation", "duplicate delete for message_id={}, skipping", delete.message_id);
+        return Ok(Json("ok"));
+    };
+    crate::websocket::notify_message_changed(&state.message_broadcaster, &tombstone);
+
+    for server in relay_targets(&state, &caller, &delete.author, channel.as_ref()).await? {
+        outbox::queue_message_delete(&state, &server, &delete).await?;
+    }
+    Ok(Json("ok"))
+}
+
+pub async fn receive_reaction(
+    State(state): State<AppState>,
+    FederationCaller(caller): FederationCaller,
+    Json(reaction): Json<FederatedReaction>,
+) -> Result<Json<&'static str>, AppError> {
+    let caller = caller.ok_or(AppError::Unauthorized)?;
+    let message_id = uuid::Uuid::parse_str(&reaction.message_id)
+        .map_err(|_| AppError::BadRequest("invalid message_id".to_string()))?;
+    let message = state
         .store
-        .get_server_by_name(&payload.channel.origin_server)?
-        .ok_or(AppError::Unauthorized)?;
+        .get_message_by_id(message_id)
+        .await?
+        .filter(|m| m.deleted_at.is_none())
+        .ok_or_else(|| AppError::BadRequest("unknown message".to_string()))?;
+    let channel = match message.channel_id {
+        Some(channel_id) => state.store.get_channel_by_id(channel_id).await?,
+        None => None,
+    };
 
-    // Accept if token matches origin server's token OR is a valid federation token
-    if origin_server.token != token
-        && !state.store.is_valid_federation_token(token)?
-        && token != state.config.server_token
+    let owns_reactor = caller.name == reaction.reactor.server;
+    let owns_channel = channel.as_ref().is_some_and(|c| c.origin_server == caller.name);
+    if !owns_reactor && !owns_channel {
+        tracing::warn!(
+            target: "federation",
+            "server '{}' tried to react as {}@{}",
+            caller.name, reaction.reactor.username, reaction.reactor.server
+        );
+        return Err(AppError::Unauthorized);
+    }
+
+    let reactor = resolve_user(&state, &reaction.reactor).await?;
+    if message.kind == MessageKind::Dm
+        && reactor.id != message.author_user_id
+        && Some(reactor.id) != message.recipient_user_id
     {
         return Err(AppError::Unauthorized);
     }
 
+    let changed = if reaction.added {
+        state.store.add_reaction(message.id, reactor.id, &reaction.emoji).await?
+    } else {
+        state.store.remove_reaction(message.id, reactor.id, &reaction.emoji).await?
+    };
+    if !changed {
+        tracing::debug!(target: "federation", "reaction already applied for message_id={}, skipping", reaction.message_id);
+        return Ok(Json("ok"));
+    }
+    crate::websocket::notify_reaction_changed(&state.message_broadcaster, &message, &reaction.emoji, reactor.id, reaction.added);
+
+    for server in relay_targets(&state, &caller, &reaction.reactor, channel.as_ref()).await? {
+        outbox::queue_reaction(&state, &server, &reaction).await?;
+    }
+    Ok(Json("ok"))
+}
+
+pub async fn receive_pin(
+    State(state): State<AppState>,
+    FederationCaller(caller): FederationCaller,
+    Json(pin): Json<FederatedPin>,
+) -> Result<Json<&'static str>, AppError> {
+    let caller = caller.ok_or(AppError::Unauthorized)?;
+    let channel = state
+        .store
+        .get_channel_by_name_origin(&pin.channel.name, &pin.channel.origin_server)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("unknown channel".to_string()))?;
+    if caller.name != pin.pinner.server && caller.name != channel.origin_server {
+        tracing::warn!(
+            target: "federation",
+            "server '{}' tried to pin as {}@{}",
+            caller.name, pin.pinner.username, pin.pinner.server
+        );
+        return Err(AppError::Unauthorized);
+    }
+    let message_id = uuid::Uuid::parse_str(&pin.message_id)
+        .map_err(|_| AppError::BadRequest("invalid message_id".to_string()))?;
+    let message = state
+        .store
+        .get_message_by_id(message_id)
+        .await?
+        .filter(|m| m.channel_id == Some(channel.id))
+        .ok_or_else(|| AppError::BadRequest("unknown message".to_string()))?;
+
+    let pinner = resolve_user(&state, &pin.pinner).await?;
+    let is_origin = channel.origin_server == state.config.server_name;
+    // The origin holds the authoritative member list.
+    if is_origin && !state.store.is_channel_member(channel.id, pinner.id).await? {
+        return Err(AppError::Unauthorized);
+    }
+
+    let changed = if pin.pinned {
+        let record = ChannelPin {
+            channel_id: channel.id,
+            message_id: message.id,
+            pinned_by_user_id: pinner.id,
+            pinned_at: pin.pinned_at.clone(),
+        };
+        state.store.pin_message(&record).await?
+    } else {
+        state.store.unpin_message(channel.id, message.id).await?
+    };
+    if !changed {
+        tracing::debug!(target: "federation", "pin already applied for message_id={}, skipping", pin.message_id);
+        return Ok(Json("ok"));
+    }
+    crate::websocket::notify_pin_changed(&state.message_broadcaster, channel.id, message.id, pinner.id, pin.pinned);
+
+    if is_origin {
+        for server in state.store.list_channel_member_servers(channel.id).await? {
+            if server.name == caller.name || server.name == pin.pinner.server || server.name == state.config.server_name {
+                continue;
+            }
+            outbox::queue_pin(&state, &server, &pin).await?;
+        }
+    }
+    Ok(Json("ok"))
+}
+
+/// A remote user started or stopped typing. Only the typist's own server may
+/// send this, except that a channel's origin relays it to the other servers
+/// in the channel.
+pub async fn receive_typing(
+    State(state): State<AppState>,
+    FederationCaller(caller): FederationCaller,
+    Json(event): Json<FederatedTyping>,
+) -> Result<Json<&'static str>, AppError> {
+    let caller = caller.ok_or(AppError::Unauthorized)?;
+    if event.sender.server == state.config.server_name {
+        return Err(AppError::Unauthorized);
+    }
+
+    let (target, relay) = match (&event.channel, &event.recipient) {
+        (Some(channel), None) => {
+            let channel = state
+                .store
+                .get_channel_by_name_origin(&channel.name, &channel.origin_server)
+                .await?
+                .ok_or_else(|| AppError::BadRequest("unknown channel".to_string()))?;
+            if caller.name != event.sender.server && caller.name != channel.origin_server {
+                return Err(AppError::Unauthorized);
+            }
+            let relay = if channel.origin_server == state.config.server_name {
+                state.store.list_channel_member_servers(channel.id).await?
+            } else {
+                Vec::new()
+            };
+            (crate::typing::TypingTarget::Channel(channel.id), relay)
+        }
+        (None, Some(recipient)) => {
+            if caller.name != event.sender.server {
+                return Err(AppError::Unauthorized);
+            }
+            if recipient.server != state.config.server_name {
+                return Err(AppError::BadRequest("recipient is not on this server".to_string()));
+            }
+            let recipient = state
+                .store
+                .get_user_by_name_and_server(&recipient.username, None)
+                .await?
+                .ok_or_else(|| AppError::BadRequest("unknown local recipient".to_string()))?;
+            (crate::typing::TypingTarget::Dm { recipient: recipient.id }, Vec::new())
+        }
+        _ => return Err(AppError::BadRequest("give either channel or recipient".to_string())),
+    };
+
+    let sender = ensure_remote_user(&state, &event.sender).await?;
+    crate::typing::set_typing(&state, target, &sender, event.typing);
+
+    for server in relay {
+        if server.name == caller.name || server.name == event.sender.server || server.name == state.config.server_name {
+            continue;
+        }
+        if let Err(e) = outbox::send_typing(&state.http, &state.signer, &server, &event).await {
+            tracing::debug!(target: "federation", server = %server.name, "typing relay failed: {:?}", e);
+        }
+    }
+    Ok(Json("ok"))
+}
+
+/// Load the local copy of a message a peer wants to change and check the
+/// peer may change it. Only the author's home server may do so, or the
+/// origin server of the channel the message is in (relaying, or acting for
+/// one of its moderators). Callers authenticated by a shared federation token
+/// have no server identity and are rejected before this point.
+async fn authorize_message_change(
+    state: &AppState,
+    caller: &Server,
+    message_id: &str,
+    author: &FederatedUser,
+) -> Result<(Message, Option<Channel>), AppError> {
+    let id = uuid::Uuid::parse_str(message_id)
+        .map_err(|_| AppError::BadRequest("invalid message_id".to_string()))?;
+    let message = state
+        .store
+        .get_message_by_id(id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("unknown message".to_string()))?;
+
+    let author_user = resolve_user(state, author).await?;
+    if author_user.id != message.author_user_id {
+        tracing::warn!(target: "federation", "server '{}' named the wrong author for message_id={}", caller.name, message_id);
+        return Err(AppError::BadRequest("author does not match message".to_string()));
+    }
+
+    let channel = match message.channel_id {
+        Some(channel_id) => state.store.get_channel_by_id(channel_id).await?,
+        None => None,
+    };
+    let owns_author = caller.name == author.server;
+    let owns_channel = channel.as_ref().is_some_and(|c| c.origin_server == caller.name);
+    if !owns_author && !owns_channel {
+        tracing::warn!(
+            target: "federation",
+            "server '{}' tried to change message_id={} by {}@{}",
+            caller.name, message_id, author.username, author.server
+        );
+        return Err(AppError::Unauthorized);
+    }
+    Ok((message, channel))
+}
+
+/// Look up a federated user, which may be one of ours when a remote
+/// moderator acts on a local author's message.
+async fn resolve_user(state: &AppState, user: &FederatedUser) -> Result<User, AppError> {
+    if user.server == state.config.server_name {
+        return state
+            .store
+            .get_user_by_name_and_server(&user.username, None)
+            .await?
+            .ok_or_else(|| AppError::BadRequest("unknown local user".to_string()));
+    }
+    ensure_remote_user(state, user).await
+}
+
+/// The origin of a channel forwards changes to every other server with
+/// members in it, as it does for new channel messages; DMs and foreign
+/// channels are not relayed. `actor` is the user who made the change, whose
+/// server already has it.
+async fn relay_targets(
+    state: &AppState,
+    caller: &Server,
+    actor: &FederatedUser,
+    channel: Option<&Channel>,
+) -> Result<Vec<Server>, AppError> {
+    let Some(channel) = channel.filter(|c| c.origin_server == state.config.server_name) else {
+        return Ok(Vec::new());
+    };
+    Ok(state
+        .store
+        .list_channel_member_servers(channel.id)
+        .await?
+        .into_iter()
+        .filter(|s| s.name != caller.name && s.name != actor.server)
+        .collect())
+}
+
+pub async fn receive_channel_membership(
+    State(state): State<AppState>,
+    FederationCaller(caller): FederationCaller,
+    Json(payload): Json<FederatedChannelMembership>,
+) -> Result<Json<&'static str>, AppError> {
+    if payload.channel.origin_server == state.config.server_name {
+        return receive_membership_request(&state, caller, &payload).await;
+    }
+
+    // Only the channel's origin may change its members (or a shared
+    // federation token, for unsigned requests)
+    if caller.is_some_and(|caller| caller.name != payload.channel.origin_server) {
+        return Err(AppError::Unauthorized);
+    }
+
     if payload.member.server != state.config.server_name {
         return Err(AppError::BadRequest("membership target mismatch".to_string()));
     }
 
     let member_user = state
         .store
-        .get_user_by_name_and_server(&payload.member.username, None)?
+        .get_user_by_name_and_server(&payload.member.username, None).await?
         .ok_or_else(|| AppError::BadRequest("unknown local member".to_string()))?;
 
-    let channel_record = match state
+    let existing = state
         .store
-        .get_channel_by_name_origin(&payload.channel.name, &payload.channel.origin_server)?
-    {
+        .get_channel_by_name_origin(&payload.channel.name, &payload.channel.origin_server).await?;
+    if payload.action != MembershipAction::Join {
+        if let Some(channel) = existing {
+            state.store.remove_channel_member(channel.id, member_user.id).await?;
+        }
+        return Ok(Json("ok"));
+    }
+
+    let channel_record = match existing {
         Some(channel) => channel,
-        None => state
-            .store
-            .create_channel(&payload.channel.name, &payload.channel.origin_server)?,
+        None => {
+            state
+                .store
+                .create_channel(&payload.channel.name, &payload.channel.origin_server).await?
+        }
     };
 
     state
         .store
-        .add_channel_member(channel_record.id, member_user.id)?;
+        .add_channel_member(channel_record.id, member_user.id).await?;
+    backfill::spawn(&state, channel_record);
 
     Ok(Json("ok"))
 }
 
-#[derive(serde::Serialize)]
-pub struct PresenceResponse {
-    pub online_users: Vec<String>,
+/// A member's server asks us, the channel's origin, to add or remove one of
+/// its own users. Channels hidden from the caller look like unknown ones.
+async fn receive_membership_request(
+    state: &AppState,
+    caller: Option<Server>,
+    payload: &FederatedChannelMembership,
+) -> Result<Json<&'static str>, AppError> {
+    let caller = caller.ok_or(AppError::Unauthorized)?;
+    if caller.name != payload.member.server {
+        return Err(AppError::Unauthorized);
+    }
+    if payload.action == MembershipAction::Kick {
+        return Err(AppError::BadRequest("only the channel's origin can kick members".to_string()));
+    }
+    let channel = visible_channel(state, &caller, &payload.channel.name).await?;
+    let member = ensure_remote_user(state, &payload.member).await?;
+    match payload.action {
+        MembershipAction::Join => state.store.add_channel_member(channel.id, member.id).await?,
+        _ => state.store.remove_channel_member(channel.id, member.id).await?,
+    }
+    tracing::info!(
+        target: "federation",
+        "{}@{} {} '{}'",
+        member.username,
+        caller.name,
+        if payload.action == MembershipAction::Join { "joined" } else { "left" },
+        channel.name
+    );
+    Ok(Json("ok"))
+}
+
+async fn visible_channel(state: &AppState, caller: &Server, name: &str) -> Result<Channel, AppError> {
+    let unknown = || AppError::BadRequest("unknown channel".to_string());
+    let channel = state
+        .store
+        .get_channel_by_name_origin(name, &state.config.server_name)
+        .await?
+        .ok_or_else(unknown)?;
+    if state.store.get_hidden_channel_ids(caller.id).await?.contains(&channel.id.to_string()) {
+        return Err(unknown());
+    }
+    Ok(channel)
+}
+
+/// The key this server signs its federation requests with. Served without
+/// authentication, since peers need it to check our signatures.
+pub async fn public_key(State(state): State<AppState>) -> Json<PublicKeyDocument> {
+    Json(PublicKeyDocument {
+        server: state.config.server_name.clone(),
+        algorithm: signing::ALGORITHM.to_string(),
+        public_key: state.signer.public_key(),
+    })
 }
 
 pub async fn presence(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    FederationCaller(caller): FederationCaller,
 ) -> Result<Json<PresenceResponse>, AppError> {
-    let caller = validate_federation_token(&state, &headers)?;
 
     if let Some(ref server) = caller {
         tracing::debug!(target: "presence", "Token validated for server: {}", server.name);
@@ -139,38 +461,35 @@ pub async fn presence(
     }
 
     let hidden_user_ids = if let Some(ref server) = caller {
-        state.store.get_hidden_user_ids(server.id)?
+        state.store.get_hidden_user_ids(server.id).await?
     } else {
         Vec::new()
     };
 
-    let mut online_users = Vec::new();
-    for user_id in state.presence.online_user_ids() {
-        if hidden_user_ids.contains(&user_id.to_string()) {
-            continue;
-        }
-        if let Some(user) = state.store.get_user_by_id(user_id)? {
-            if user.is_local {
-                online_users.push(user.username.clone());
-                tracing::debug!(target: "presence", "  - {} (local user, online)", user.username);
-            }
-        }
-    }
-
-    tracing::debug!(target: "presence", "Responding with {} online users: {:?}", online_users.len(), online_users);
+    let report = crate::federation::presence::report(&state, &hidden_user_ids).await?;
+    tracing::debug!(target: "presence", "Responding with {} online users: {:?}", report.online_users.len(), report.online_users);
+    Ok(Json(report))
+}
 
-    Ok(Json(PresenceResponse { online_users }))
+/// A peer pushed changes to its users' presence.
+pub async fn receive_presence_delta(
+    State(state): State<AppState>,
+    FederationCaller(caller): FederationCaller,
+    Json(delta): Json<PresenceDelta>,
+) -> Result<Json<&'static str>, AppError> {
+    let caller = caller.ok_or(AppError::Unauthorized)?;
+    crate::federation::presence::apply_delta(&state, caller, delta).await?;
+    Ok(Json("ok"))
 }
 
 pub async fn list_users(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    FederationCaller(caller): FederationCaller,
 ) -> Result<Json<Vec<FederatedUser>>, AppError> {
-    let caller = validate_federation_token(&state, &headers)?;
 
-    let users = state.store.list_users()?;
+    let users = state.store.list_users().await?;
     let hidden_user_ids = if let Some(ref server) = caller {
-        state.store.get_hidden_user_ids(server.id)?
+        state.store.get_hidden_user_ids(server.id).await?
     } else {
         Vec::new()
     };
@@ -191,13 +510,12 @@ pub async fn list_users(
 
 pub async fn list_channels(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    FederationCaller(caller): FederationCaller,
 ) -> Result<Json<Vec<FederatedChannel>>, AppError> {
-    let caller = validate_federation_token(&state, &headers)?;
 
-    let channels = state.store.list_channels()?;
+    let channels = state.store.list_channels().await?;
     let hidden_channel_ids = if let Some(ref server) = caller {
-        state.store.get_hidden_channel_ids(server.id)?
+        state.store.get_hidden_channel_ids(server.id).await?
     } else {
         Vec::new()
     };
@@ -215,26 +533,144 @@ pub async fn list_channels(
     Ok(Json(local_channels))
 }
 
+#[derive(Deserialize)]
+pub struct ChannelHistoryQuery {
+    before: Option<String>,
+    limit: Option<usize>,
+}
+
+/// A page of history of a channel that originates here, newest page first,
+/// for peers backfilling it. Deleted messages and thread replies are left
+/// out, and channels hidden from the caller look like unknown ones.
+pub async fn channel_history(
+    State(state): State<AppState>,
+    FederationCaller(caller): FederationCaller,
+    Path((origin, name)): Path<(String, String)>,
+    Query(params): Query<ChannelHistoryQuery>,
+) -> Result<Json<FederatedHistoryPage>, AppError> {
+    let unknown = || AppError::BadRequest("unknown channel".to_string());
+    if origin != state.config.server_name {
+        return Err(unknown());
+    }
+    let channel = state.store.get_channel_by_name_origin(&name, &origin).await?.ok_or_else(unknown)?;
+    let hidden_user_ids = if let Some(ref server) = caller {
+        if state.store.get_hidden_channel_ids(server.id).await?.contains(&channel.id.to_string()) {
+            return Err(unknown());
+        }
+        state.store.get_hidden_user_ids(server.id).await?
+    } else {
+        Vec::new()
+    };
+
+    let limit = params.limit.unwrap_or(100).clamp(1, 200);
+    let page = match params.before.as_deref() {
+        Some(before) => HistoryPage::Before(
+            MessageCursor::parse(before).ok_or_else(|| AppError::BadRequest("Invalid cursor".to_string()))?,
+        ),
+        None => HistoryPage::Latest,
+    };
+    let mut messages = state.store.list_channel_messages(channel.id, &page, limit + 1).await?;
+    let has_older = messages.len() > limit;
+    if has_older {
+        messages.remove(0);
+    }
+    let prev_cursor = messages.first().filter(|_| has_older).map(|m| MessageCursor::of(m).encode());
+    messages.retain(|m| m.deleted_at.is_none() && !hidden_user_ids.contains(&m.author_user_id.to_string()));
+
+    let ids: Vec<uuid::Uuid> = messages.iter().map(|m| m.id).collect();
+    let mut attachments: HashMap<uuid::Uuid, Vec<Attachment>> = HashMap::new();
+    for (message_id, attachment) in state.store.list_message_attachments(&ids).await? {
+        attachments.entry(message_id).or_default().push(attachment);
+    }
+    let channel_ref = FederatedChannel {
+        name: channel.name.clone(),
+        origin_server: channel.origin_server.clone(),
+    };
+    let mut authors: HashMap<uuid::Uuid, Option<FederatedUser>> = HashMap::new();
+    let mut page = Vec::with_capacity(messages.len());
+    for message in messages {
+        let author = match authors.get(&message.author_user_id) {
+            Some(author) => author.clone(),
+            None => {
+                let author = federated_user(&state, message.author_user_id).await?;
+                authors.insert(message.author_user_id, author.clone());
+                author
+            }
+        };
+        // Authors on servers we no longer peer with cannot be named
+        let Some(author) = author else {
+            continue;
+        };
+        page.push(FederatedMessage {
+            message_id: message.id.to_string(),
+            sent_at: message.sent_at,
+            kind: MessageKind::Channel,
+            body: message.body,
+            author,
+            recipient: None,
+            channel: Some(channel_ref.clone()),
+            thread_root_id: None,
+            attachments: crate::api::attachments::federated_attachments(
+                &state,
+                attachments.get(&message.id).map(Vec::as_slice).unwrap_or_default(),
+            ),
+        });
+    }
+    Ok(Json(FederatedHistoryPage { messages: page, prev_cursor }))
+}
+
+/// The members of a channel that originates here, which member servers
+/// compare their own view against. Users and channels hidden from the
+/// caller are left out.
+pub async fn channel_members(
+    State(state): State<AppState>,
+    FederationCaller(caller): FederationCaller,
+    Path((origin, name)): Path<(String, String)>,
+) -> Result<Json<Vec<FederatedUser>>, AppError> {
+    if origin != state.config.server_name {
+        return Err(AppError::BadRequest("unknown channel".to_string()));
+    }
+    let caller = caller.ok_or(AppError::Unauthorized)?;
+    let channel = visible_channel(&state, &caller, &name).await?;
+    let hidden_user_ids = state.store.get_hidden_user_ids(caller.id).await?;
+    let mut members = Vec::new();
+    for user in state.store.list_channel_members(channel.id).await? {
+        if hidden_user_ids.contains(&user.id.to_string()) {
+            continue;
+        }
+        members.extend(federated_user(&state, user.id).await?);
+    }
+    Ok(Json(members))
+}
+
+/// How peers name the user with `user_id`, or `None` if they are on a
+/// server we no longer peer with.
+pub async fn federated_user(state: &AppState, user_id: uuid::Uuid) -> Result<Option<FederatedUser>, AppError> {
+    let Some(user) = state.store.get_user_by_id(user_id).await? else {
+        return Ok(None);
+    };
+    let server = match user.server_id {
+        None => state.config.server_name.clone(),
+        Some(server_id) => match state.store.get_server_by_id(&server_id).await? {
+            Some(server) => server.name,
+            None => return Ok(None),
+        },
+    };
+    Ok(Some(FederatedUser {
+        username: user.username,
+        server,
+        display_name: user.display_name,
+    }))
+}
+
 pub async fn receive_webrtc_signal(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    FederationCaller(caller): FederationCaller,
     Json(signal): Json<FederatedWebRtcSignal>,
 ) -> Result<Json<&'static str>, AppError> {
-    let token = headers
-        .get("x-federation-token")
-        .and_then(|value| value.to_str().ok())
-        .ok_or(AppError::Unauthorized)?;
-
-    let from_server = state
-        .store
-        .get_server_by_name(&signal.from_user.server)?
-        .ok_or(AppError::Unauthorized)?;
-
-    // Accept if token matches from_server's token OR is a valid federation token
-    if from_server.token != token
-        && !state.store.is_valid_federation_token(token)?
-        && token != state.config.server_token
-    {
+    // Signals come from the caller's own users (or a shared federation
+    // token, for unsigned requests)
+    if caller.is_some_and(|caller| caller.name != signal.from_user.server) {
         return Err(AppError::Unauthorized);
     }
 
@@ -246,7 +682,7 @@ pub async fn receive_webrtc_signal(
 
     let target_user = state
         .store
-        .get_user_by_name_and_server(&signal.to_user.username, None)?
+        .get_user_by_name_and_server(&signal.to_user.username, None).await?
         .ok_or_else(|| AppError::BadRequest("unknown local recipient".to_string()))?;
 
     let sse_payload = serde_json::to_string(&signal)
@@ -269,9 +705,12 @@ async fn handle_dm(
     let recipient = message
         .recipient
         .ok_or_else(|| AppError::BadRequest("missing recipient".to_string()))?;
+    if recipient.server != state.config.server_name {
+        return Err(AppError::BadRequest("recipient is not on this server".to_string()));
+    }
     let recipient_user = state
         .store
-        .get_user_by_name_and_server(&recipient.username, None)?
+        .get_user_by_name_and_server(&recipient.username, None).await?
         .ok_or_else(|| AppError::BadRequest("unknown local recipient".to_string()))?;
 
     // Insert using the federated message id to avoid duplicate processing.
@@ -282,14 +721,17 @@ async fn handle_dm(
         author_user.id,
         Some(recipient_user.id),
         None,
+        None,
         &message.sent_at,
-    )?;
+    ).await?;
 
     // If this message already exists, skip notification.
-    if created_opt.is_none() {
+    let Some(created) = created_opt else {
         tracing::warn!(target: "federation", "duplicate DM received, skipping message_id={} to={}", message.message_id, recipient_user.username);
         return Ok(());
-    }
+    };
+    store_attachments(state, &created, &author_user, &message.author.server, None, &message.attachments).await?;
+    crate::mentions::record(state, &created, &author_user).await?;
 
     // Notify recipient of new message
     crate::websocket::notify_new_message(
@@ -312,12 +754,28 @@ async fn handle_channel(
         .ok_or_else(|| AppError::BadRequest("missing channel".to_string()))?;
     let channel_record = match state
         .store
-        .get_channel_by_name_origin(&channel.name, &channel.origin_server)?
+        .get_channel_by_name_origin(&channel.name, &channel.origin_server).await?
     {
         Some(channel) => channel,
         None => state
             .store
-            .create_channel(&channel.name, &channel.origin_server)?,
+            .create_channel(&channel.name, &channel.origin_server).await?,
+    };
+
+    // Replies keep the root id even if the root has not arrived yet, so they
+    // show up in the thread once it does.
+    let thread_root_id = match message.thread_root_id.as_deref() {
+        Some(root_id) => {
+            let root_id = uuid::Uuid::parse_str(root_id)
+                .map_err(|_| AppError::BadRequest("invalid thread_root_id".to_string()))?;
+            if let Some(root) = state.store.get_message_by_id(root_id).await? {
+                if root.channel_id != Some(channel_record.id) || root.thread_root_id.is_some() {
+                    return Err(AppError::BadRequest("thread root is not a message in this channel".to_string()));
+                }
+            }
+            Some(root_id)
+        }
+        None => None,
     };
 
     // Insert using the federated message id to avoid duplicate processing.
@@ -328,58 +786,188 @@ async fn handle_channel(
         author_user.id,
         None,
         Some(channel_record.id),
+        thread_root_id,
         &message.sent_at,
-    )?;
+    ).await?;
 
     // If the message already exists, skip notify and fanout.
-    if created_opt.is_none() {
+    let Some(created) = created_opt else {
         tracing::warn!(target: "federation", "duplicate channel message received, skipping message_id={}", message.message_id);
         return Ok(());
-    }
+    };
+    store_attachments(
+        state,
+        &created,
+        &author_user,
+        &message.author.server,
+        Some(&channel_record.origin_server),
+        &message.attachments,
+    )
+    .await?;
+    crate::mentions::record(state, &created, &author_user).await?;
 
     // Notify channel members of new message
-    crate::websocket::notify_new_message(
-        &state.message_broadcaster,
-        None,
-        Some(channel_record.id.to_string()),
-    );
+    if created.thread_root_id.is_some() {
+        crate::websocket::notify_thread_reply(&state.message_broadcaster, &created);
+    } else {
+        crate::websocket::notify_new_message(
+            &state.message_broadcaster,
+            None,
+            Some(channel_record.id.to_string()),
+        );
+    }
 
-    // If this server owns the channel, fan out to all federated servers so
-    // offline users still see the full history when they log in.
+    // If this server owns the channel, fan out to the other servers with
+    // members in it so offline users still see the full history when they
+    // log in.
     if channel_record.origin_server == state.config.server_name {
-        let servers = state.store.list_servers()?;
+        let servers = state.store.list_channel_member_servers(channel_record.id).await?;
         for server in servers {
             if server.name == message.author.server {
                 continue;
             }
-            if let Err(e) = outbox::send_to_server(
-                &state.http,
-                &state.config.server_token,
-                &server,
-                &message,
-            )
-            .await
-            {
-                tracing::error!(target: "federation", server = %server.name, "fanout send failed: {:?}", e);
-                // continue with the next server rather than failing the whole handler
-            }
+            outbox::queue_message(state, &server, &message).await?;
         }
     }
     
     Ok(())
 }
 
+/// Store a message from a backfilled page of `channel`'s history. Unlike
+/// live messages these are not announced, mentions are not recorded and
+/// nothing is relayed. Returns false if the message was already here.
+pub async fn store_history_message(
+    state: &AppState,
+    channel: &Channel,
+    message: &FederatedMessage,
+) -> Result<bool, AppError> {
+    let in_channel = message.channel.as_ref().is_some_and(|c| {
+        c.name == channel.name && c.origin_server == channel.origin_server
+    });
+    if message.kind != MessageKind::Channel || !in_channel || message.thread_root_id.is_some() {
+        return Err(AppError::BadRequest("history message is not in this channel".to_string()));
+    }
+    let author = resolve_user(state, &message.author).await?;
+    let Some(created) = state.store.create_message_with_id(
+        &message.message_id,
+        MessageKind::Channel,
+        &message.body,
+        author.id,
+        None,
+        Some(channel.id),
+        None,
+        &message.sent_at,
+    ).await? else {
+        return Ok(false);
+    };
+    store_attachments(
+        state,
+        &created,
+        &author,
+        &message.author.server,
+        Some(&channel.origin_server),
+        &message.attachments,
+    )
+    .await?;
+    Ok(true)
+}
+
+/// Record the metadata of attachments sent with a federated message. Blobs
+/// are not copied here; they are fetched from the origin server the first
+/// time a local user opens them. Attachments over our size limit, of types
+/// we do not accept, or said to live anywhere but on the author's server or
+/// the channel's origin are dropped.
+async fn store_attachments(
+    state: &AppState,
+    message: &Message,
+    author: &User,
+    author_server: &str,
+    channel_origin: Option<&str>,
+    attachments: &[FederatedAttachment],
+) -> Result<(), AppError> {
+    let mut ids = Vec::with_capacity(attachments.len());
+    for fed in attachments {
+        let Ok(id) = uuid::Uuid::parse_str(&fed.id) else {
+            tracing::warn!(target: "federation", "invalid attachment id '{}' on message_id={}", fed.id, message.id);
+            continue;
+        };
+        if !crate::blobs::is_valid_hash(&fed.hash) {
+            tracing::warn!(target: "federation", "invalid attachment hash on message_id={}", message.id);
+            continue;
+        }
+        if fed.size > state.config.attachment_max_bytes || !state.config.is_allowed_attachment_type(&fed.content_type) {
+            tracing::warn!(
+                target: "federation",
+                "attachment {} ({}, {} bytes) on message_id={} is not accepted here, skipping",
+                id, fed.content_type, fed.size, message.id
+            );
+            continue;
+        }
+        if fed.origin_server != author_server && Some(fed.origin_server.as_str()) != channel_origin {
+            tracing::warn!(
+                target: "federation",
+                "attachment {} on message_id={} claims to live on {}, skipping",
+                id, message.id, fed.origin_server
+            );
+            continue;
+        }
+        let attachment = Attachment {
+            id,
+            hash: fed.hash.clone(),
+            size: fed.size,
+            content_type: fed.content_type.clone(),
+            filename: fed.filename.clone(),
+            uploader_user_id: author.id,
+            origin_server: (fed.origin_server != state.config.server_name).then(|| fed.origin_server.clone()),
+            created_at: message.sent_at.clone(),
+        };
+        if !state.store.create_attachment(&attachment).await? {
+            // Already known, e.g. our own upload coming back through the
+            // channel origin. Never let a peer rebind an id to other content.
+            let existing = state.store.get_attachment(id).await?;
+            if existing.is_none_or(|a| a.hash != fed.hash) {
+                tracing::warn!(target: "federation", "attachment {} conflicts with a stored one, skipping", id);
+                continue;
+            }
+        }
+        ids.push(id);
+    }
+    state.store.attach_to_message(message.id, &ids).await
+}
+
+/// Serve a blob to a peer fetching an attachment that originated here.
+pub async fn get_blob(
+    State(state): State<AppState>,
+    _caller: FederationCaller,
+    Path(hash): Path<String>,
+) -> Result<Response, AppError> {
+    let dir = std::path::Path::new(&state.config.attachments_dir);
+    let path = crate::blobs::blob_path(dir, &hash)
+        .ok_or_else(|| AppError::BadRequest("invalid blob hash".to_string()))?;
+    if !state.store.has_attachment_with_hash(&hash).await? {
+        return Err(AppError::BadRequest("unknown blob".to_string()));
+    }
+    let file = tokio::fs::File::open(&path)
+        .await
+        .map_err(|_| AppError::BadRequest("unknown blob".to_string()))?;
+
+    let mut response = Response::new(Body::from_stream(tokio_util::io::ReaderStream::new(file)));
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    Ok(response)
+}
+
 pub async fn receive_channel_call_event(
     State(state): State<AppState>,
-    headers: HeaderMap,
+    _caller: FederationCaller,
     Json(event): Json<FederatedChannelCallEvent>,
 ) -> Result<Json<&'static str>, AppError> {
-    let _caller = validate_federation_token(&state, &headers)?;
 
     // Look up the channel by name + origin_server to get local UUID
     let channel = state
         .store
-        .get_channel_by_name_origin(&event.channel.name, &event.channel.origin_server)?
+        .get_channel_by_name_origin(&event.channel.name, &event.channel.origin_server).await?
         .ok_or_else(|| {
             tracing::debug!(target: "federation", "Channel call event for unknown channel {}@{}", event.channel.name, event.channel.origin_server);
             AppError::BadRequest("unknown channel".to_string())
@@ -423,15 +1011,15 @@ pub async fn receive_channel_call_event(
 async fn ensure_remote_user(state: &AppState, user: &FederatedUser) -> Result<User, AppError> {
     let server = state
         .store
-        .get_server_by_name(&user.server)?
+        .get_server_by_name(&user.server).await?
         .ok_or(AppError::Unauthorized)?;
     if let Some(existing) = state
         .store
-        .get_user_by_name_and_server(&user.username, Some(server.id))?
+        .get_user_by_name_and_server(&user.username, Some(server.id)).await?
     {
         return Ok(existing);
     }
     state
         .store
-        .create_user(&user.username, false, Some(server.id))
+        .create_user(&user.username, false, Some(server.id)).await
 }
@@ -0,0 +1,160 @@
+//! Channel membership across servers. A channel's origin owns its member
+//! list: joins and leaves of a remote channel go to the origin first and
+//! only take effect here once it accepted them, while the origin queues the
+//! outcome of every change for the member's own server. The periodic sync
+//! compares each shared channel's roster with the origin's and corrects
+//! whatever drifted.
+
+use std::{collections::HashSet, time::Duration};
+
+use crate::{
+    api::AppState,
+    domain::{Channel, Server, User},
+    error::AppError,
+    federation::{
+        backfill, handlers, outbox,
+        protocol::{FederatedChannel, FederatedChannelMembership, FederatedUser, MembershipAction},
+    },
+};
+
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Add `user` to `channel`.
+pub async fn join(state: &AppState, channel: &Channel, user: &User) -> Result<(), AppError> {
+    change(state, channel, user, MembershipAction::Join).await
+}
+
+/// Remove `user` from `channel`, on their own account (`Leave`) or a
+/// moderator's (`Kick`). Only the origin can kick.
+pub async fn leave(state: &AppState, channel: &Channel, user: &User, action: MembershipAction) -> Result<(), AppError> {
+    change(state, channel, user, action).await
+}
+
+async fn change(state: &AppState, channel: &Channel, user: &User, action: MembershipAction) -> Result<(), AppError> {
+    let member = handlers::federated_user(state, user.id)
+        .await?
+        .ok_or_else(|| AppError::BadRequest("unknown member".to_string()))?;
+    let membership = FederatedChannelMembership {
+        channel: FederatedChannel {
+            name: channel.name.clone(),
+            origin_server: channel.origin_server.clone(),
+        },
+        member,
+        action,
+    };
+
+    if channel.origin_server == state.config.server_name {
+        apply(state, channel.id, user.id, action).await?;
+        if let Some(server_id) = user.server_id {
+            if let Some(server) = state.store.get_server_by_id(&server_id).await? {
+                outbox::queue_channel_membership(state, &server, &membership).await?;
+            }
+        }
+        return Ok(());
+    }
+
+    // A remote channel: only our own users, and only with the origin's say
+    if !user.is_local {
+        return Err(AppError::BadRequest(format!(
+            "members from other servers are managed by {}",
+            channel.origin_server
+        )));
+    }
+    if action == MembershipAction::Kick {
+        return Err(AppError::BadRequest(format!(
+            "only {} can remove members of this channel",
+            channel.origin_server
+        )));
+    }
+    let origin = state
+        .store
+        .get_server_by_name(&channel.origin_server)
+        .await?
+        .ok_or_else(|| AppError::BadRequest(format!("unknown server: {}", channel.origin_server)))?;
+    outbox::send_channel_membership(&state.http, &state.signer, &origin, &membership).await?;
+    apply(state, channel.id, user.id, action).await?;
+    if action == MembershipAction::Join {
+        backfill::spawn(state, channel.clone());
+    }
+    Ok(())
+}
+
+async fn apply(state: &AppState, channel_id: uuid::Uuid, user_id: uuid::Uuid, action: MembershipAction) -> Result<(), AppError> {
+    match action {
+        MembershipAction::Join => state.store.add_channel_member(channel_id, user_id).await,
+        MembershipAction::Leave | MembershipAction::Kick => state.store.remove_channel_member(channel_id, user_id).await,
+    }
+}
+
+/// Bring our users' memberships of channels that originate on `server` in
+/// line with the rosters it reports. Only channels with local members are
+/// checked; joins the origin made on our users' behalf arrive through the
+/// outbox.
+pub async fn sync_rosters(state: &AppState, server: &Server) {
+    if server.name == state.config.server_name {
+        return;
+    }
+    let channels = match state.store.list_channels().await {
+        Ok(channels) => channels,
+        Err(e) => {
+            tracing::warn!(target: "federation", "Listing channels failed: {}", e);
+            return;
+        }
+    };
+    for channel in channels.into_iter().filter(|c| c.origin_server == server.name) {
+        if let Err(e) = sync_roster(state, server, &channel).await {
+            tracing::debug!(target: "federation", "Roster sync of '{}' with {} failed: {}", channel.name, server.name, e);
+        }
+    }
+}
+
+async fn sync_roster(state: &AppState, origin: &Server, channel: &Channel) -> Result<(), AppError> {
+    let local_members: Vec<User> = state
+        .store
+        .list_channel_members(channel.id)
+        .await?
+        .into_iter()
+        .filter(|u| u.is_local)
+        .collect();
+    if local_members.is_empty() {
+        return Ok(());
+    }
+
+    let roster = fetch_roster(state, origin, channel).await?;
+    let on_roster: HashSet<&str> = roster
+        .iter()
+        .filter(|u| u.server == state.config.server_name)
+        .map(|u| u.username.as_str())
+        .collect();
+    for member in &local_members {
+        if !on_roster.contains(member.username.as_str()) {
+            state.store.remove_channel_member(channel.id, member.id).await?;
+            tracing::info!(target: "federation", "{} is no longer in '{}' on {}", member.username, channel.name, origin.name);
+        }
+    }
+    for username in on_roster {
+        if local_members.iter().any(|m| m.username == username) {
+            continue;
+        }
+        if let Some(user) = state.store.get_user_by_name_and_server(username, None).await? {
+            state.store.add_channel_member(channel.id, user.id).await?;
+            tracing::info!(target: "federation", "{} joined '{}' on {}", username, channel.name, origin.name);
+        }
+    }
+    Ok(())
+}
+
+async fn fetch_roster(state: &AppState, origin: &Server, channel: &Channel) -> Result<Vec<FederatedUser>, AppError> {
+    let mut url = reqwest::Url::parse(&origin.base_url)
+        .map_err(|e| AppError::Internal(format!("invalid base URL of {}: {}", origin.name, e)))?;
+    url.path_segments_mut()
+        .map_err(|_| AppError::Internal(format!("invalid base URL of {}", origin.name)))?
+        .pop_if_empty()
+        .extend(["federation", "channels", &channel.origin_server, &channel.name, "members"]);
+
+    let response = state.signer.send(&state.http, origin, state.http.get(url).timeout(PEER_TIMEOUT)).await?;
+    if !response.status().is_success() {
+        return Err(AppError::Internal(format!("{} answered {}", origin.name, response.status())));
+    }
+    Ok(response.json().await?)
+}
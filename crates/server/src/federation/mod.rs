@@ -1,21 +1,69 @@
-use axum::Router;
+use axum::{extract::Request, middleware::Next, response::Response, Router};
 
-use crate::api::AppState;
+use crate::{api::AppState, error::AppError};
 
+pub mod backfill;
+pub mod delivery;
 pub mod handlers;
+pub mod membership;
 pub mod outbox;
+pub mod peering;
+pub mod presence;
 pub mod protocol;
+pub mod signing;
 
-pub fn router() -> Router<AppState> {
+/// Federation routes. Signed requests are verified before they reach a
+/// handler, except on the peering handshake routes: those come from servers
+/// that are not peers yet, and check signatures themselves. Requests from
+/// servers speaking an incompatible protocol version are refused on all.
+pub fn router(state: AppState) -> Router<AppState> {
     Router::new()
         .route("/messages", axum::routing::post(handlers::receive_message))
+        .route("/messages/edit", axum::routing::post(handlers::receive_message_edit))
+        .route("/messages/delete", axum::routing::post(handlers::receive_message_delete))
+        .route("/messages/reaction", axum::routing::post(handlers::receive_reaction))
         .route(
             "/channel-memberships",
             axum::routing::post(handlers::receive_channel_membership),
         )
-        .route("/presence", axum::routing::get(handlers::presence))
+        .route("/blobs/:hash", axum::routing::get(handlers::get_blob))
+        .route("/channels/pins", axum::routing::post(handlers::receive_pin))
+        .route("/typing", axum::routing::post(handlers::receive_typing))
+        .route(
+            "/presence",
+            axum::routing::get(handlers::presence).post(handlers::receive_presence_delta),
+        )
         .route("/users", axum::routing::get(handlers::list_users))
         .route("/channels", axum::routing::get(handlers::list_channels))
+        .route(
+            "/channels/:origin/:name/history",
+            axum::routing::get(handlers::channel_history),
+        )
+        .route(
+            "/channels/:origin/:name/members",
+            axum::routing::get(handlers::channel_members),
+        )
         .route("/webrtc-signal", axum::routing::post(handlers::receive_webrtc_signal))
         .route("/channel-call-event", axum::routing::post(handlers::receive_channel_call_event))
+        .route("/peering/revoke", axum::routing::post(peering::receive_revoke))
+        .route_layer(axum::middleware::from_fn_with_state(state, signing::verify_requests))
+        .route("/peering", axum::routing::post(peering::receive_request))
+        .route("/peering/decision", axum::routing::post(peering::receive_decision))
+        .route_layer(axum::middleware::from_fn(check_protocol_version))
+}
+
+/// Refuse requests whose sender speaks another major protocol version.
+/// Servers that predate versioning send no version and speak 1.0.
+async fn check_protocol_version(request: Request, next: Next) -> Result<Response, AppError> {
+    if let Some(version) = request.headers().get(protocol::VERSION_HEADER) {
+        let version = version.to_str().unwrap_or_default();
+        if !protocol::is_compatible(version) {
+            return Err(AppError::BadRequest(format!(
+                "incompatible federation protocol version {}; this server speaks {}",
+                version,
+                protocol::PROTOCOL_VERSION
+            )));
+        }
+    }
+    Ok(next.run(request).await)
 }
@@ -1,71 +1,115 @@
 use reqwest::Client;
+use time::OffsetDateTime;
+use uuid::Uuid;
 
 use crate::{
-    domain::Server,
+    api::AppState,
+    domain::{Channel, Server},
     error::AppError,
-    federation::protocol::{FederatedChannelCallEvent, FederatedChannelMembership, FederatedMessage, FederatedWebRtcSignal},
-    storage::SqliteStore,
+    federation::{
+        protocol::{
+            self, Capability, FederatedChannelCallEvent, FederatedChannelMembership, FederatedMessage, FederatedMessageDelete,
+            FederatedMessageEdit, FederatedPin, FederatedReaction, FederatedTyping, FederatedWebRtcSignal,
+            PresenceDelta,
+        },
+        delivery,
+        signing::RequestSigner,
+    },
 };
 
-pub async fn send_to_server(
-    http: &Client,
-    local_token: &str,
+/// Queue `body` for `server` at `path` in the federation outbox and wake
+/// the delivery worker. `message_id` is set when it sends a new message, so
+/// the author can see how delivery went. Nothing is queued for a peer that
+/// speaks an incompatible protocol version.
+pub async fn enqueue<T: serde::Serialize>(
+    state: &AppState,
     server: &Server,
-    message: &FederatedMessage,
+    path: &str,
+    body: &T,
+    message_id: Option<Uuid>,
 ) -> Result<(), AppError> {
-    let url = format!("{}/federation/messages", server.base_url.trim_end_matches('/'));
+    if let Some(version) = server.protocol_version.as_deref().filter(|v| !protocol::is_compatible(v)) {
+        tracing::warn!(target: "federation", "Not queueing {} for {}, which speaks incompatible protocol {}", path, server.name, version);
+        return Ok(());
+    }
+    let payload = serde_json::to_string(body).map_err(|e| AppError::Internal(e.to_string()))?;
+    let now = delivery::outbox_time(OffsetDateTime::now_utc());
+    let entry = state.store.enqueue_outbox(server.id, path, &payload, message_id, &now).await?;
     tracing::info!(
         target = "federation",
         server = %server.name,
-        url = %url,
-        "sending federated message"
+        path = %path,
+        entry = entry.id,
+        "queued federation request"
     );
-    let resp = http
-        .post(&url)
-        .header("X-Federation-Token", local_token)
-        .json(message)
-        .send()
-        .await?;
-    if !resp.status().is_success() {
-        let status = resp.status();
-        let body = resp.text().await.unwrap_or_default();
-        tracing::error!(
-            target = "federation",
-            server = %server.name,
-            url = %url,
-            status = %status,
-            body = %body,
-            "federation request failed"
-        );
-        return Err(AppError::Internal(format!(
-            "federation http error: {}",
-            status
-        )));
-    }
+    state.deliveries.wake();
     Ok(())
 }
 
-pub async fn send_to_channel_members(
-    http: &Client,
-    store: &SqliteStore,
-    local_server_name: &str,
-    local_token: &str,
-    channel_id: uuid::Uuid,
+pub async fn queue_message(state: &AppState, server: &Server, message: &FederatedMessage) -> Result<(), AppError> {
+    let message_id = Uuid::parse_str(&message.message_id).ok();
+    enqueue(state, server, "/federation/messages", &downgrade(server, message), message_id).await
+}
+
+/// `message` as `server` can take it: a thread reply becomes a plain message
+/// for a peer without threads, and attachments are named in the body for a
+/// peer without attachments.
+fn downgrade(server: &Server, message: &FederatedMessage) -> FederatedMessage {
+    let mut message = message.clone();
+    if message.thread_root_id.is_some() && !Capability::Threads.supported_by(server) {
+        message.thread_root_id = None;
+    }
+    if !message.attachments.is_empty() && !Capability::Attachments.supported_by(server) {
+        let mut lines: Vec<String> = Vec::new();
+        if !message.body.is_empty() {
+            lines.push(std::mem::take(&mut message.body));
+        }
+        lines.extend(message.attachments.drain(..).map(|a| format!("[attachment: {}]", a.filename)));
+        message.body = lines.join("\n");
+    }
+    message
+}
+
+/// Whether `server` supports `capability`. Changes it doesn't are not sent.
+fn supports(server: &Server, capability: Capability) -> bool {
+    let supported = capability.supported_by(server);
+    if !supported {
+        tracing::debug!(target: "federation", "Not sending {} to {}, which does not support them", capability.as_str(), server.name);
+    }
+    supported
+}
+
+/// Queue a channel message for every other server with members in the
+/// channel, and for the channel's origin.
+pub async fn queue_to_channel_members(
+    state: &AppState,
+    channel: &Channel,
     message: &FederatedMessage,
 ) -> Result<(), AppError> {
-    let servers = store.list_channel_member_servers(channel_id)?;
+    let mut servers = state.store.list_channel_member_servers(channel.id).await?;
+    if channel.origin_server != state.config.server_name {
+        servers.extend(state.store.get_server_by_name(&channel.origin_server).await?);
+    }
+    servers.retain(|s| s.name != state.config.server_name);
+    servers.sort_by(|a, b| a.name.cmp(&b.name));
+    servers.dedup_by(|a, b| a.name == b.name);
     for server in servers {
-        if server.name == local_server_name {
-            continue;
-        }
-        send_to_server(http, local_token, &server, message).await?;
+        queue_message(state, &server, message).await?;
     }
     Ok(())
 }
 
+pub async fn queue_channel_membership(
+    state: &AppState,
+    server: &Server,
+    membership: &FederatedChannelMembership,
+) -> Result<(), AppError> {
+    enqueue(state, server, "/federation/channel-memberships", membership, None).await
+}
+
 pub async fn send_channel_membership(
     http: &Client,
-    local_token: &str,
+    signer: &RequestSigner,
     server: &Server,
     membership: &FederatedChannelMembership,
 ) -> Result<(), AppError> {
@@ -73,18 +117,13 @@ pub async fn send_channel_membership(
         "{}/federation/channel-memberships",
         server.base_url.trim_end_matches('/')
     );
-    http.post(url)
-        .header("X-Federation-Token", local_token)
-        .json(membership)
-        .send()
-        .await?
-        .error_for_status()?;
+    signer.send(http, server, http.post(url).json(membership)).await?.error_for_status()?;
     Ok(())
 }
 
 pub async fn send_channel_call_event(
     http: &Client,
-    local_token: &str,
+    signer: &RequestSigner,
     server: &Server,
     event: &FederatedChannelCallEvent,
 ) -> Result<(), AppError> {
@@ -92,12 +131,7 @@ pub async fn send_channel_call_event(
         "{}/federation/channel-call-event",
         server.base_url.trim_end_matches('/')
     );
-    let resp = http
-        .post(&url)
-        .header("X-Federation-Token", local_token)
-        .json(event)
-        .send()
-        .await?;
+    let resp = signer.send(http, server, http.post(&url).json(event)).await?;
     if !resp.status().is_success() {
         let status = resp.status();
         tracing::warn!(target: "federation", "channel-call-event to {} failed: {}", server.name, status);
@@ -107,7 +141,7 @@ pub async fn send_channel_call_event(
 
 pub async fn send_webrtc_signal(
     http: &Client,
-    local_token: &str,
+    signer: &RequestSigner,
     server: &Server,
     signal: &FederatedWebRtcSignal,
 ) -> Result<(), AppError> {
@@ -115,12 +149,7 @@ pub async fn send_webrtc_signal(
         "{}/federation/webrtc-signal",
         server.base_url.trim_end_matches('/')
     );
-    let resp = http
-        .post(&url)
-        .header("X-Federation-Token", local_token)
-        .json(signal)
-        .send()
-        .await?;
+    let resp = signer.send(http, server, http.post(&url).json(signal)).await?;
     if !resp.status().is_success() {
         let status = resp.status();
         let _body = resp.text().await.unwrap_or_default();
@@ -131,3 +160,136 @@ pub async fn send_webrtc_signal(
     }
     Ok(())
 }
+
+pub async fn queue_message_edit(state: &AppState, server: &Server, edit: &FederatedMessageEdit) -> Result<(), AppError> {
+    if !supports(server, Capability::Edits) {
+        return Ok(());
+    }
+    enqueue(state, server, "/federation/messages/edit", edit, None).await
+}
+
+pub async fn queue_message_delete(
+    state: &AppState,
+    server: &Server,
+    delete: &FederatedMessageDelete,
+) -> Result<(), AppError> {
+    if !supports(server, Capability::Deletes) {
+        return Ok(());
+    }
+    enqueue(state, server, "/federation/messages/delete", delete, None).await
+}
+
+pub async fn queue_reaction(state: &AppState, server: &Server, reaction: &FederatedReaction) -> Result<(), AppError> {
+    if !supports(server, Capability::Reactions) {
+        return Ok(());
+    }
+    enqueue(state, server, "/federation/messages/reaction", reaction, None).await
+}
+
+pub async fn queue_pin(state: &AppState, server: &Server, pin: &FederatedPin) -> Result<(), AppError> {
+    if !supports(server, Capability::Pins) {
+        return Ok(());
+    }
+    enqueue(state, server, "/federation/channels/pins", pin, None).await
+}
+
+pub async fn send_typing(
+    http: &Client,
+    signer: &RequestSigner,
+    server: &Server,
+    typing: &FederatedTyping,
+) -> Result<(), AppError> {
+    if !supports(server, Capability::Typing) {
+        return Ok(());
+    }
+    post_json(http, signer, server, "/federation/typing", typing).await
+}
+
+pub async fn send_presence_delta(
+    http: &Client,
+    signer: &RequestSigner,
+    server: &Server,
+    delta: &PresenceDelta,
+) -> Result<(), AppError> {
+    post_json(http, signer, server, "/federation/presence", delta).await
+}
+
+async fn post_json<T: serde::Serialize>(
+    http: &Client,
+    signer: &RequestSigner,
+    server: &Server,
+    path: &str,
+    body: &T,
+) -> Result<(), AppError> {
+    let url = format!("{}{}", server.base_url.trim_end_matches('/'), path);
+    let resp = signer.send(http, server, http.post(&url).json(body)).await?;
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        tracing::warn!(target: "federation", "{} to {} failed: {} {}", path, server.name, status, body);
+        return Err(AppError::Internal(format!("federation http error: {}", status)));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        domain::{MessageKind, PeerStatus},
+        federation::protocol::{FederatedAttachment, FederatedChannel, FederatedUser},
+    };
+
+    fn peer(capabilities: Option<&[&str]>) -> Server {
+        Server {
+            id: Uuid::new_v4(),
+            name: "b".to_string(),
+            base_url: "http://b".to_string(),
+            token: "token".to_string(),
+            public_key: None,
+            status: PeerStatus::Active,
+            protocol_version: Some("1.0".to_string()),
+            capabilities: capabilities.map(|c| c.iter().map(|c| c.to_string()).collect()),
+        }
+    }
+
+    fn reply_with_attachment() -> FederatedMessage {
+        FederatedMessage {
+            message_id: Uuid::new_v4().to_string(),
+            sent_at: "2024-01-01T00:00:00Z".to_string(),
+            kind: MessageKind::Channel,
+            body: "see this".to_string(),
+            author: FederatedUser {
+                username: "alice".to_string(),
+                server: "a".to_string(),
+                display_name: None,
+            },
+            recipient: None,
+            channel: Some(FederatedChannel {
+                name: "general".to_string(),
+                origin_server: "a".to_string(),
+            }),
+            thread_root_id: Some(Uuid::new_v4().to_string()),
+            attachments: vec![FederatedAttachment {
+                id: Uuid::new_v4().to_string(),
+                hash: "abc".to_string(),
+                size: 3,
+                content_type: "text/plain".to_string(),
+                filename: "notes.txt".to_string(),
+                origin_server: "a".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn messages_are_downgraded_for_peers_without_the_features() {
+        let message = reply_with_attachment();
+        let full = downgrade(&peer(None), &message);
+        assert_eq!((full.body.as_str(), full.attachments.len()), ("see this", 1));
+        assert!(full.thread_root_id.is_some());
+
+        let plain = downgrade(&peer(Some(&["reactions"])), &message);
+        assert_eq!(plain.body, "see this\n[attachment: notes.txt]");
+        assert!(plain.attachments.is_empty() && plain.thread_root_id.is_none());
+    }
+}
@@ -0,0 +1,448 @@
+//! Peering: how two servers that don't know each other yet become peers.
+//! Each publishes a discovery document at `/.well-known/beringchat`. When
+//! an admin of A asks B to peer, A sends B a request signed with its key.
+//! B checks it against the key in A's discovery document and holds it until
+//! B's admin decides. On approval B adds A to `servers` and sends A a signed
+//! decision, and A adds B in turn. Either side can later suspend the peer or
+//! revoke the peering altogether.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{OriginalUri, State},
+    http::{HeaderMap, Method},
+    Json,
+};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use uuid::Uuid;
+
+use crate::{
+    api::AppState,
+    domain::{PeeringDirection, PeeringRequest, PeeringStatus, Server},
+    error::AppError,
+    federation::{
+        protocol::{self, Capability, DiscoveryDocument, DiscoveryEndpoints, PeeringDecision, PeeringRequestBody, PROTOCOL_VERSION},
+        signing::{self, FederationCaller},
+    },
+};
+
+pub const DISCOVERY_PATH: &str = "/.well-known/beringchat";
+const PEERING_PATH: &str = "/federation/peering";
+const DECISION_PATH: &str = "/federation/peering/decision";
+const REVOKE_PATH: &str = "/federation/peering/revoke";
+const PEER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Incoming requests waiting for an admin. Further servers are turned away
+/// until some of them are decided.
+const MAX_PENDING_REQUESTS: usize = 20;
+
+/// What another server needs to know to peer with this one. Served without
+/// authentication.
+pub async fn discovery(State(state): State<AppState>) -> Json<DiscoveryDocument> {
+    Json(DiscoveryDocument {
+        server: state.config.server_name.clone(),
+        base_url: state.config.base_url.clone(),
+        protocol_version: PROTOCOL_VERSION.to_string(),
+        capabilities: Some(Capability::ALL.iter().map(|c| c.as_str().to_string()).collect()),
+        algorithm: signing::ALGORITHM.to_string(),
+        public_key: state.signer.public_key(),
+        endpoints: DiscoveryEndpoints {
+            federation: "/federation".to_string(),
+            peering: PEERING_PATH.to_string(),
+            key: signing::KEY_PATH.to_string(),
+        },
+    })
+}
+
+/// Fetch the discovery document of the server at `base_url` with `http`.
+pub async fn fetch_discovery(http: &reqwest::Client, base_url: &str) -> Result<DiscoveryDocument, AppError> {
+    let url = format!("{}{}", base_url.trim_end_matches('/'), DISCOVERY_PATH);
+    let response = http.get(&url).timeout(PEER_TIMEOUT).send().await?;
+    if !response.status().is_success() {
+        return Err(AppError::BadRequest(format!("{} returned {}", url, response.status())));
+    }
+    let document: DiscoveryDocument = response
+        .json()
+        .await
+        .map_err(|e| AppError::BadRequest(format!("{} is not a discovery document: {}", url, e)))?;
+    if document.algorithm != signing::ALGORITHM || signing::decode_public_key(&document.public_key).is_none() {
+        return Err(AppError::BadRequest(format!("{} does not publish a usable {} key", document.server, signing::ALGORITHM)));
+    }
+    Ok(document)
+}
+
+/// Ask the server at `base_url` to peer with us, and remember that we did.
+pub async fn request_peering(state: &AppState, base_url: &str) -> Result<PeeringRequest, AppError> {
+    let document = fetch_discovery(&state.http, base_url).await?;
+    ensure_compatible(&document)?;
+    ensure_new_peer(state, &document.server).await?;
+
+    let url = format!("{}{}", document.base_url.trim_end_matches('/'), document.endpoints.peering);
+    let body = PeeringRequestBody {
+        server: state.config.server_name.clone(),
+        base_url: state.config.base_url.clone(),
+    };
+    let request = state.http.post(&url).json(&body).timeout(PEER_TIMEOUT);
+    check_response(state.signer.send_signed(&state.http, request).await?, &document.server).await?;
+
+    let now = now()?;
+    let request = PeeringRequest {
+        id: Uuid::new_v4(),
+        direction: PeeringDirection::Outgoing,
+        server_name: document.server,
+        base_url: document.base_url,
+        public_key: document.public_key,
+        status: PeeringStatus::Pending,
+        created_at: now.clone(),
+        updated_at: now,
+    };
+    state.store.save_peering_request(&request, None).await?;
+    tracing::info!(target: "federation", "Asked {} to peer", request.server_name);
+    Ok(request)
+}
+
+/// Approve a pending incoming request: add the requester as a peer and tell
+/// it so. Nothing is kept if the requester cannot be told.
+pub async fn approve(state: &AppState, id: Uuid) -> Result<Server, AppError> {
+    let request = pending_incoming(state, id).await?;
+    ensure_new_peer(state, &request.server_name).await?;
+
+    let token = Uuid::new_v4().simple().to_string();
+    let server = state.store.create_server(&request.server_name, &request.base_url, &token).await?;
+    state.store.set_server_public_key(&server.id, &request.public_key).await?;
+    if let Err(e) = send_decision(state, &request, true).await {
+        state.store.delete_server(&server.id).await?;
+        return Err(e);
+    }
+    state.store.set_peering_request_status(id, PeeringStatus::Approved, &now()?).await?;
+    tracing::info!(target: "federation", "Now peered with {}", server.name);
+    spawn_negotiate(state, server.clone());
+    state.store.get_server_by_id(&server.id).await?.ok_or_else(|| AppError::Internal("peer vanished".to_string()))
+}
+
+/// Reject a pending incoming request. The requester is told if it can be
+/// reached.
+pub async fn reject(state: &AppState, id: Uuid) -> Result<(), AppError> {
+    let request = pending_incoming(state, id).await?;
+    state.store.set_peering_request_status(id, PeeringStatus::Rejected, &now()?).await?;
+    if let Err(e) = send_decision(state, &request, false).await {
+        tracing::warn!(target: "federation", "Could not tell {} its peering request was rejected: {}", request.server_name, e);
+    }
+    Ok(())
+}
+
+/// End the peering with `server`: tell it, if it can be reached, and
+/// forget it. It has to ask again to peer with us.
+pub async fn revoke(state: &AppState, server: &Server) -> Result<(), AppError> {
+    if server.name == state.config.server_name {
+        return Err(AppError::BadRequest("a server cannot revoke itself".to_string()));
+    }
+    let url = format!("{}{}", server.base_url.trim_end_matches('/'), REVOKE_PATH);
+    let request = state.http.post(&url).timeout(PEER_TIMEOUT);
+    let notified = match state.signer.send_signed(&state.http, request).await {
+        Ok(response) => check_response(response, &server.name).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = notified {
+        tracing::warn!(target: "federation", "Could not tell {} its peering was revoked: {}", server.name, e);
+    }
+    state.store.delete_server(&server.id).await?;
+    tracing::info!(target: "federation", "Revoked peering with {}", server.name);
+    Ok(())
+}
+
+/// Fetch `server`'s discovery document again and cache the protocol version
+/// and capabilities it announces, which decide what is sent to it.
+pub async fn negotiate(state: &AppState, server: &Server) -> Result<(), AppError> {
+    let document = fetch_discovery(&state.http, &server.base_url).await?;
+    if document.server != server.name {
+        return Err(AppError::BadRequest(format!(
+            "{} belongs to {}, not {}",
+            server.base_url, document.server, server.name
+        )));
+    }
+    let changed = server.protocol_version.as_deref() != Some(document.protocol_version.as_str())
+        || server.capabilities != document.capabilities;
+    if !changed {
+        return Ok(());
+    }
+    state
+        .store
+        .set_server_protocol(&server.id, &document.protocol_version, document.capabilities.as_deref())
+        .await?;
+    if protocol::is_compatible(&document.protocol_version) {
+        tracing::info!(
+            target: "federation",
+            "{} speaks federation protocol {} with {}",
+            server.name,
+            document.protocol_version,
+            document.capabilities.as_ref().map_or("every capability".to_string(), |c| format!("capabilities [{}]", c.join(", ")))
+        );
+    } else {
+        tracing::warn!(
+            target: "federation",
+            "{} speaks federation protocol {}, which is incompatible with ours ({}); nothing will be sent to it",
+            server.name,
+            document.protocol_version,
+            PROTOCOL_VERSION
+        );
+    }
+    Ok(())
+}
+
+fn spawn_negotiate(state: &AppState, server: Server) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = negotiate(&state, &server).await {
+            tracing::warn!(target: "federation", "Could not learn the protocol version of {}: {}", server.name, e);
+        }
+    });
+}
+
+/// `POST /federation/peering`. The requester is not a peer yet, so its
+/// signature is checked against the key its discovery document publishes.
+pub async fn receive_request(
+    State(state): State<AppState>,
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<&'static str>, AppError> {
+    let payload: PeeringRequestBody =
+        serde_json::from_slice(&body).map_err(|e| AppError::BadRequest(format!("invalid peering request: {}", e)))?;
+    ensure_new_peer(&state, &payload.server).await?;
+    // Nothing is verified yet, so keep the caller from filling the list or
+    // pointing us at our own network. The limit is checked again when the
+    // request is stored, as others may have arrived during the fetch.
+    let pending: Vec<PeeringRequest> = state
+        .store
+        .list_peering_requests()
+        .await?
+        .into_iter()
+        .filter(|r| r.direction == PeeringDirection::Incoming && r.status == PeeringStatus::Pending)
+        .collect();
+    if pending.len() >= MAX_PENDING_REQUESTS && !pending.iter().any(|r| r.server_name == payload.server) {
+        return Err(too_many_requests(&payload.server));
+    }
+    let http = untrusted_client(&state, &payload.base_url).await?;
+    let document = fetch_discovery(&http, &payload.base_url).await?;
+    ensure_compatible(&document)?;
+    if document.base_url != payload.base_url {
+        untrusted_client(&state, &document.base_url).await?;
+    }
+    if document.server != payload.server {
+        return Err(AppError::BadRequest(format!(
+            "{} belongs to {}, not {}",
+            payload.base_url, document.server, payload.server
+        )));
+    }
+    let target = uri.path_and_query().map(|p| p.as_str()).unwrap_or_default();
+    signing::verify_with_key(&state, &method, target, &headers, &body, &document.server, &document.public_key)?;
+
+    let now = now()?;
+    let saved = state
+        .store
+        .save_peering_request(
+            &PeeringRequest {
+                id: Uuid::new_v4(),
+                direction: PeeringDirection::Incoming,
+                server_name: document.server,
+                base_url: document.base_url,
+                public_key: document.public_key,
+                status: PeeringStatus::Pending,
+                created_at: now.clone(),
+                updated_at: now,
+            },
+            Some(MAX_PENDING_REQUESTS),
+        )
+        .await?;
+    if !saved {
+        return Err(too_many_requests(&payload.server));
+    }
+    tracing::info!(target: "federation", "{} asks to peer, waiting for an admin", payload.server);
+    Ok(Json("ok"))
+}
+
+/// `POST /federation/peering/decision`: the answer to a request we sent,
+/// signed with the key we saw when we sent it.
+pub async fn receive_decision(
+    State(state): State<AppState>,
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<&'static str>, AppError> {
+    let decision: PeeringDecision =
+        serde_json::from_slice(&body).map_err(|e| AppError::BadRequest(format!("invalid peering decision: {}", e)))?;
+    let request = state
+        .store
+        .get_pending_peering_request(PeeringDirection::Outgoing, &decision.server)
+        .await?
+        .ok_or_else(|| AppError::BadRequest(format!("no pending peering request to {}", decision.server)))?;
+    let target = uri.path_and_query().map(|p| p.as_str()).unwrap_or_default();
+    signing::verify_with_key(&state, &method, target, &headers, &body, &request.server_name, &request.public_key)?;
+
+    let status = if decision.approved {
+        if state.store.get_server_by_name(&request.server_name).await?.is_none() {
+            let token = Uuid::new_v4().simple().to_string();
+            let server = state.store.create_server(&request.server_name, &request.base_url, &token).await?;
+            state.store.set_server_public_key(&server.id, &request.public_key).await?;
+            spawn_negotiate(&state, server);
+        }
+        tracing::info!(target: "federation", "{} approved our peering request", request.server_name);
+        PeeringStatus::Approved
+    } else {
+        tracing::info!(target: "federation", "{} rejected our peering request", request.server_name);
+        PeeringStatus::Rejected
+    };
+    state.store.set_peering_request_status(request.id, status, &now()?).await?;
+    Ok(Json("ok"))
+}
+
+/// `POST /federation/peering/revoke`: the caller no longer peers with us.
+pub async fn receive_revoke(
+    State(state): State<AppState>,
+    FederationCaller(caller): FederationCaller,
+) -> Result<Json<&'static str>, AppError> {
+    let caller = caller.ok_or(AppError::Unauthorized)?;
+    state.store.delete_server(&caller.id).await?;
+    tracing::info!(target: "federation", "{} revoked its peering with us", caller.name);
+    Ok(Json("ok"))
+}
+
+async fn pending_incoming(state: &AppState, id: Uuid) -> Result<PeeringRequest, AppError> {
+    state
+        .store
+        .get_peering_request(id)
+        .await?
+        .filter(|r| r.direction == PeeringDirection::Incoming && r.status == PeeringStatus::Pending)
+        .ok_or_else(|| AppError::BadRequest("no such pending peering request".to_string()))
+}
+
+fn ensure_compatible(document: &DiscoveryDocument) -> Result<(), AppError> {
+    if protocol::is_compatible(&document.protocol_version) {
+        return Ok(());
+    }
+    Err(AppError::BadRequest(format!(
+        "{} speaks federation protocol {}, which is incompatible with ours ({})",
+        document.server, document.protocol_version, PROTOCOL_VERSION
+    )))
+}
+
+async fn ensure_new_peer(state: &AppState, server_name: &str) -> Result<(), AppError> {
+    if server_name == state.config.server_name {
+        return Err(AppError::BadRequest("a server cannot peer with itself".to_string()));
+    }
+    if state.store.get_server_by_name(server_name).await?.is_some() {
+        return Err(AppError::BadRequest(format!("already peered with {}", server_name)));
+    }
+    Ok(())
+}
+
+fn too_many_requests(server: &str) -> AppError {
+    tracing::warn!(target: "federation", "Turning away {}: too many pending peering requests", server);
+    AppError::BadRequest("too many pending peering requests".to_string())
+}
+
+/// A client for fetching from a base URL an unauthenticated caller named.
+/// Only http(s) is allowed, and unless private peers are allowed, the host
+/// must be or resolve to public addresses only. The client connects to the
+/// addresses that were checked, so the name is not resolved again, and does
+/// not follow redirects.
+async fn untrusted_client(state: &AppState, base_url: &str) -> Result<reqwest::Client, AppError> {
+    let invalid = || AppError::BadRequest(format!("invalid base URL: {}", base_url));
+    let url = reqwest::Url::parse(base_url).map_err(|_| invalid())?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(AppError::BadRequest("base URL must use http or https".to_string()));
+    }
+    let builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none());
+    if state.config.federation_allow_private_peers {
+        return builder.build().map_err(AppError::from);
+    }
+
+    let host = url.host_str().ok_or_else(invalid)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = url.port_or_known_default().unwrap_or(80);
+    let (builder, addresses) = match host.parse::<IpAddr>() {
+        Ok(ip) => (builder, vec![SocketAddr::new(ip, port)]),
+        Err(_) => {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|_| AppError::BadRequest(format!("cannot resolve {}", host)))?
+                .collect();
+            (builder.resolve_to_addrs(host, &addresses), addresses)
+        }
+    };
+    if addresses.is_empty() || !addresses.iter().all(|address| is_public(address.ip())) {
+        tracing::warn!(target: "federation", "Refusing peering with {}: not a public address", base_url);
+        return Err(AppError::BadRequest(format!("{} is not a public address", host)));
+    }
+    builder.build().map_err(AppError::from)
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let shared = ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64;
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || shared)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public(IpAddr::V4(v4)),
+            None => !(ip.is_loopback() || ip.is_unspecified() || ip.is_unique_local() || ip.is_unicast_link_local()),
+        },
+    }
+}
+
+async fn send_decision(state: &AppState, request: &PeeringRequest, approved: bool) -> Result<(), AppError> {
+    let url = format!("{}{}", request.base_url.trim_end_matches('/'), DECISION_PATH);
+    let decision = PeeringDecision {
+        server: state.config.server_name.clone(),
+        approved,
+    };
+    let response = state
+        .signer
+        .send_signed(&state.http, state.http.post(&url).json(&decision).timeout(PEER_TIMEOUT))
+        .await?;
+    check_response(response, &request.server_name).await
+}
+
+async fn check_response(response: reqwest::Response, server_name: &str) -> Result<(), AppError> {
+    if response.status().is_success() {
+        return Ok(());
+    }
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    Err(AppError::BadRequest(format!("{} answered {}: {}", server_name, status, body)))
+}
+
+fn now() -> Result<String, AppError> {
+    OffsetDateTime::now_utc().format(&Rfc3339).map_err(|e| AppError::Internal(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_public_addresses_count_as_public() {
+        for ip in ["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        let internal = [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+            "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1",
+        ];
+        for ip in internal {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+}
@@ -0,0 +1,389 @@
+//! Presence between servers. Each server pushes a delta to its peers when
+//! one of its users' presence changes. Deltas are numbered, so a peer that
+//! misses one notices the gap and fetches the full presence again. A slow
+//! reconciliation of every peer catches anything else that was lost.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use time::format_description::well_known::Rfc3339;
+use uuid::Uuid;
+
+use crate::{
+    api::AppState,
+    domain::{PresenceState, PresenceStatus, Server},
+    error::AppError,
+    federation::{
+        backfill, membership, outbox, peering,
+        protocol::{FederatedChannel, FederatedLastSeen, FederatedPresence, FederatedUser, PresenceDelta, PresenceResponse},
+    },
+    presence::{DeltaOutcome, FeedPosition},
+    storage::DynStore,
+};
+
+/// How often local presence is checked without being woken, to catch
+/// users going idle and custom statuses expiring.
+const LOCAL_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+const PEER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// What peers may know about our local users' presence.
+#[derive(Clone, Default, PartialEq)]
+struct LocalPresence {
+    /// Connected users who are not invisible.
+    users: BTreeMap<Uuid, FederatedPresence>,
+    last_seen: BTreeMap<Uuid, FederatedLastSeen>,
+}
+
+impl LocalPresence {
+    async fn load(state: &AppState) -> Result<Self, AppError> {
+        let now = time::OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        let mut users = BTreeMap::new();
+        for user_id in state.presence.online_user_ids() {
+            let Some(user) = state.store.get_user_by_id(user_id).await? else {
+                continue;
+            };
+            if !user.is_local {
+                continue;
+            }
+            let chosen = state.store.get_user_presence(user.id).await?;
+            let status = state.presence.local_status(user.id, chosen.as_ref(), None, &now);
+            // Invisible users come back as offline
+            if status.state != PresenceState::Offline {
+                users.insert(user.id, FederatedPresence { username: user.username, status });
+            }
+        }
+        let last_seen = state
+            .store
+            .list_users()
+            .await?
+            .into_iter()
+            .filter(|user| user.is_local)
+            .filter_map(|user| {
+                let last_seen_at = user.last_seen_at?;
+                Some((user.id, FederatedLastSeen { username: user.username, last_seen_at }))
+            })
+            .collect();
+        Ok(Self { users, last_seen })
+    }
+
+    /// What changed since `before`. Users who are no longer listed come
+    /// back as `Offline`.
+    fn changes_since(&self, before: &Self) -> Self {
+        let mut users: BTreeMap<Uuid, FederatedPresence> = self
+            .users
+            .iter()
+            .filter(|(id, presence)| before.users.get(id) != Some(presence))
+            .map(|(id, presence)| (*id, presence.clone()))
+            .collect();
+        for (id, gone) in &before.users {
+            if !self.users.contains_key(id) {
+                let status = PresenceStatus::new(PresenceState::Offline);
+                users.insert(*id, FederatedPresence { username: gone.username.clone(), status });
+            }
+        }
+        let last_seen = self
+            .last_seen
+            .iter()
+            .filter(|(id, seen)| before.last_seen.get(id) != Some(seen))
+            .map(|(id, seen)| (*id, seen.clone()))
+            .collect();
+        Self { users, last_seen }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.last_seen.is_empty()
+    }
+
+    /// The presence and last-seen lists, without the users in `hidden`.
+    fn visible_to(&self, hidden: &[String]) -> (Vec<FederatedPresence>, Vec<FederatedLastSeen>) {
+        let visible = |id: &Uuid| !hidden.contains(&id.to_string());
+        let users = self.users.iter().filter(|(id, _)| visible(id)).map(|(_, p)| p.clone()).collect();
+        let last_seen = self.last_seen.iter().filter(|(id, _)| visible(id)).map(|(_, s)| s.clone()).collect();
+        (users, last_seen)
+    }
+}
+
+/// The full presence of our local users, except those in `hidden`.
+pub async fn report(state: &AppState, hidden: &[String]) -> Result<PresenceResponse, AppError> {
+    // Take the position first: the report is then at least as new as it,
+    // and deltas after it only repeat what the report already says.
+    let position = state.presence.position();
+    let (users, last_seen) = LocalPresence::load(state).await?.visible_to(hidden);
+    Ok(PresenceResponse {
+        online_users: users.iter().map(|presence| presence.username.clone()).collect(),
+        users,
+        last_seen,
+        epoch: Some(position.epoch),
+        seq: position.seq,
+    })
+}
+
+/// Push local presence changes to every peer as they happen. Also turns
+/// idle users away.
+pub async fn publish_task(state: AppState) {
+    let idle_after = Duration::from_secs(state.config.presence_idle_secs);
+    let mut published = LocalPresence::load(&state).await.unwrap_or_else(|e| {
+        tracing::warn!(target: "presence", "Failed to load local presence: {}", e);
+        LocalPresence::default()
+    });
+    loop {
+        tokio::select! {
+            _ = state.presence.changed() => {}
+            _ = tokio::time::sleep(LOCAL_CHECK_INTERVAL) => {}
+        }
+        if state.presence.update_idle(idle_after) {
+            crate::websocket::notify_presence_changed(&state.message_broadcaster);
+        }
+
+        let current = match LocalPresence::load(&state).await {
+            Ok(current) => current,
+            Err(e) => {
+                tracing::warn!(target: "presence", "Failed to load local presence: {}", e);
+                continue;
+            }
+        };
+        let changes = current.changes_since(&published);
+        published = current;
+        if changes.is_empty() {
+            continue;
+        }
+
+        let position = state.presence.next_position();
+        let servers = match state.store.list_servers().await {
+            Ok(servers) => servers,
+            Err(e) => {
+                // Peers will see the gap and catch up
+                tracing::warn!(target: "presence", "Failed to list servers for presence push: {}", e);
+                continue;
+            }
+        };
+        tracing::debug!(target: "presence", "Pushing presence delta {} to {} servers", position.seq, servers.len());
+        // Wait for every peer before the next delta so each gets them in order
+        let pushes = servers
+            .iter()
+            .filter(|server| server.name != state.config.server_name)
+            .map(|server| push_delta(&state, server, &changes, &position));
+        futures_util::future::join_all(pushes).await;
+    }
+}
+
+async fn push_delta(state: &AppState, server: &Server, changes: &LocalPresence, position: &FeedPosition) {
+    let hidden = match state.store.get_hidden_user_ids(server.id).await {
+        Ok(hidden) => hidden,
+        Err(e) => {
+            tracing::warn!(target: "presence", "Failed to load users hidden from {}: {}", server.name, e);
+            return;
+        }
+    };
+    let (users, last_seen) = changes.visible_to(&hidden);
+    let delta = PresenceDelta {
+        epoch: position.epoch.clone(),
+        seq: position.seq,
+        users,
+        last_seen,
+    };
+    let push = outbox::send_presence_delta(&state.http, &state.signer, server, &delta);
+    match tokio::time::timeout(PEER_TIMEOUT, push).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            tracing::debug!(target: "presence", "Presence delta to {} failed: {}", server.name, e);
+        }
+        Err(_) => {
+            tracing::debug!(target: "presence", "Presence delta to {} timed out", server.name);
+        }
+    }
+}
+
+/// Apply a delta pushed by `server`. If it shows that we missed some, fetch
+/// the server's full presence in the background.
+pub async fn apply_delta(state: &AppState, server: Server, delta: PresenceDelta) -> Result<(), AppError> {
+    let position = FeedPosition {
+        epoch: delta.epoch,
+        seq: delta.seq,
+    };
+    let mut changed = match state.presence.apply_remote_delta(&server.name, position, delta.users) {
+        DeltaOutcome::Stale => return Ok(()),
+        DeltaOutcome::Applied { changed } => changed,
+        DeltaOutcome::Gap { changed } => {
+            tracing::info!(target: "presence", "Missed presence deltas from {}, fetching its full presence", server.name);
+            let state = state.clone();
+            let server = server.clone();
+            tokio::spawn(async move {
+                match resync_peer(&state, &server).await {
+                    Ok(true) => crate::websocket::notify_presence_changed(&state.message_broadcaster),
+                    Ok(false) => {}
+                    Err(e) => tracing::debug!(target: "presence", "Presence resync with {} failed: {}", server.name, e),
+                }
+            });
+            changed
+        }
+    };
+    if sync_last_seen(&state.store, server.id, delta.last_seen).await? {
+        changed = true;
+    }
+    if changed {
+        crate::websocket::notify_presence_changed(&state.message_broadcaster);
+    }
+    Ok(())
+}
+
+/// Replace what we know about `server`'s presence with its full report.
+/// Returns true if anything changed.
+pub async fn resync_peer(state: &AppState, server: &Server) -> Result<bool, AppError> {
+    let url = format!("{}/federation/presence", server.base_url.trim_end_matches('/'));
+    let response = state.signer.send(&state.http, server, state.http.get(&url).timeout(PEER_TIMEOUT)).await?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(AppError::Internal(format!("presence query failed with {}: {}", status, body)));
+    }
+    let report = response.json::<PresenceResponse>().await?;
+    // Servers without presence states only list usernames
+    let users = if report.users.is_empty() {
+        report
+            .online_users
+            .into_iter()
+            .map(|username| FederatedPresence {
+                username,
+                status: PresenceStatus::new(PresenceState::Online),
+            })
+            .collect()
+    } else {
+        report.users
+    };
+    tracing::debug!(target: "presence", "Synced {} online users from server '{}'", users.len(), server.name);
+    let position = report.epoch.map(|epoch| FeedPosition { epoch, seq: report.seq });
+    let mut changed = state.presence.set_remote_presence(&server.name, position, users);
+    if sync_last_seen(&state.store, server.id, report.last_seen).await? {
+        changed = true;
+    }
+    Ok(changed)
+}
+
+/// Every `PRESENCE_RECONCILE_SECS`, fetch the full presence, channels and
+/// users of every peer, the rosters of the channels they own, and the
+/// protocol version and capabilities they announce.
+pub async fn reconcile_task(state: AppState) {
+    let mut interval = tokio::time::interval(Duration::from_secs(state.config.presence_reconcile_secs));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let servers = match state.store.list_servers().await {
+            Ok(servers) => servers,
+            Err(e) => {
+                tracing::warn!(target: "presence", "Failed to list servers for presence sync: {}", e);
+                continue;
+            }
+        };
+
+        for server in servers.iter().filter(|s| s.name != state.config.server_name) {
+            if let Err(e) = peering::negotiate(&state, server).await {
+                tracing::debug!(target: "federation", "Failed to fetch the discovery document of {}: {}", server.name, e);
+            }
+        }
+
+        let mut presence_changed = false;
+        for server in &servers {
+            match resync_peer(&state, server).await {
+                Ok(changed) => presence_changed |= changed,
+                Err(e) => {
+                    tracing::debug!(target: "presence", "Failed to fetch presence from {}: {}", server.name, e);
+                }
+            }
+        }
+        if presence_changed {
+            crate::websocket::notify_presence_changed(&state.message_broadcaster);
+        }
+
+        for server in &servers {
+            sync_channels(&state, server).await;
+            membership::sync_rosters(&state, server).await;
+            sync_display_names(&state, server).await;
+        }
+    }
+}
+
+/// Store the last-seen times a peer reported for its users. Returns true if
+/// any of them moved forward.
+async fn sync_last_seen(
+    store: &DynStore,
+    server_id: Uuid,
+    reports: Vec<FederatedLastSeen>,
+) -> Result<bool, AppError> {
+    let mut changed = false;
+    for report in reports {
+        // Normalize to UTC so timestamps from every server compare as strings
+        let Some(last_seen_at) = time::OffsetDateTime::parse(&report.last_seen_at, &Rfc3339)
+            .ok()
+            .and_then(|at| at.to_offset(time::UtcOffset::UTC).format(&Rfc3339).ok())
+        else {
+            continue;
+        };
+        let Some(user) = store.get_user_by_name_and_server(&report.username, Some(server_id)).await? else {
+            continue;
+        };
+        if user.last_seen_at.as_deref() == Some(last_seen_at.as_str()) {
+            continue;
+        }
+        if store.update_last_seen(&user.id, &last_seen_at).await? {
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+/// Create the channels `server` lists that we don't know yet.
+async fn sync_channels(state: &AppState, server: &Server) {
+    let url = format!("{}/federation/channels", server.base_url.trim_end_matches('/'));
+    match state.signer.send(&state.http, server, state.http.get(&url).timeout(PEER_TIMEOUT)).await {
+        Ok(response) if response.status().is_success() => match response.json::<Vec<FederatedChannel>>().await {
+            Ok(channels) => {
+                for ch in channels {
+                    if state.store.get_channel_by_name_origin(&ch.name, &ch.origin_server).await.ok().flatten().is_none() {
+                        match state.store.create_channel(&ch.name, &ch.origin_server).await {
+                            Ok(channel) => {
+                                tracing::info!(target: "federation", "Auto-synced channel '{}' from server '{}'", ch.name, ch.origin_server);
+                                backfill::spawn(state, channel);
+                            }
+                            Err(e) => tracing::warn!(target: "federation", "Failed to create synced channel '{}': {}", ch.name, e),
+                        }
+                    }
+                }
+            }
+            Err(e) => tracing::warn!(target: "federation", "Failed to parse channels from {}: {}", server.name, e),
+        },
+        Ok(response) => {
+            tracing::debug!(target: "federation", "Channel sync from {} returned {}", server.name, response.status());
+        }
+        Err(e) => {
+            tracing::debug!(target: "federation", "Channel sync from {} failed: {}", server.name, e);
+        }
+    }
+}
+
+/// Update the display names of `server`'s users that we know.
+async fn sync_display_names(state: &AppState, server: &Server) {
+    let url = format!("{}/federation/users", server.base_url.trim_end_matches('/'));
+    let response = state.signer.send(&state.http, server, state.http.get(&url).timeout(PEER_TIMEOUT)).await;
+    let Ok(response) = response else {
+        return;
+    };
+    if !response.status().is_success() {
+        return;
+    }
+    match response.json::<Vec<FederatedUser>>().await {
+        Ok(remote_users) => {
+            for ru in remote_users {
+                if let Ok(Some(local_ref)) = state.store.get_user_by_name_and_server(&ru.username, Some(server.id)).await {
+                    if local_ref.display_name != ru.display_name {
+                        let _ = state.store.update_user_display_name(&local_ref.id, ru.display_name.as_deref()).await;
+                    }
+                }
+            }
+        }
+        Err(e) => tracing::debug!(target: "federation", "Failed to parse users from {}: {}", server.name, e),
+    }
+}
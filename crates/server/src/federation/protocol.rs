@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::domain::MessageKind;
+use crate::domain::{MessageKind, PresenceStatus, Server};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FederatedUser {
@@ -25,12 +25,152 @@ pub struct FederatedMessage {
     pub author: FederatedUser,
     pub recipient: Option<FederatedUser>,
     pub channel: Option<FederatedChannel>,
+    /// Id of the channel message this one replies to, shared by all peers.
+    #[serde(default)]
+    pub thread_root_id: Option<String>,
+    #[serde(default)]
+    pub attachments: Vec<FederatedAttachment>,
+}
+
+impl FederatedMessage {
+    /// Whether `server` may deliver this message: the author's own server,
+    /// or for a channel message the channel's origin relaying it on.
+    pub fn may_be_sent_by(&self, server: &str) -> bool {
+        self.author.server == server
+            || (self.kind == MessageKind::Channel
+                && self.channel.as_ref().is_some_and(|c| c.origin_server == server))
+    }
+}
+
+/// Metadata for a file sent with a message. Peers fetch the blob itself from
+/// `origin_server` via `/federation/blobs/:hash` when a user first opens it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FederatedAttachment {
+    pub id: String,
+    pub hash: String,
+    pub size: u64,
+    pub content_type: String,
+    pub filename: String,
+    pub origin_server: String,
+}
+
+/// A new body for an existing message. `editor` is the author, or a
+/// moderator on the channel's origin server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FederatedMessageEdit {
+    pub message_id: String,
+    pub body: String,
+    pub edited_at: String,
+    pub author: FederatedUser,
+    pub editor: FederatedUser,
+    pub channel: Option<FederatedChannel>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FederatedMessageDelete {
+    pub message_id: String,
+    pub deleted_at: String,
+    pub author: FederatedUser,
+    pub editor: FederatedUser,
+    pub channel: Option<FederatedChannel>,
+}
+
+/// A reaction added to or removed from a message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FederatedReaction {
+    pub message_id: String,
+    pub emoji: String,
+    pub reactor: FederatedUser,
+    pub added: bool,
+    pub channel: Option<FederatedChannel>,
+}
+
+/// A message pinned or unpinned in a channel.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FederatedPin {
+    pub message_id: String,
+    pub channel: FederatedChannel,
+    pub pinner: FederatedUser,
+    pub pinned: bool,
+    pub pinned_at: String,
+}
+
+/// A local user's presence as shown to peers: never `Invisible`, and only
+/// users who are connected are listed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FederatedPresence {
+    pub username: String,
+    #[serde(flatten)]
+    pub status: PresenceStatus,
+}
+
+/// When a local user's last connection closed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FederatedLastSeen {
+    pub username: String,
+    pub last_seen_at: String,
+}
+
+/// Response of `GET /federation/presence`. `online_users` repeats the
+/// usernames in `users` for servers that predate presence states.
+/// `last_seen` covers every local user with a recorded last-seen time.
+/// `epoch` and `seq` give the position in the sender's delta feed that the
+/// report is at; servers without a feed leave them out.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceResponse {
+    pub online_users: Vec<String>,
+    #[serde(default)]
+    pub users: Vec<FederatedPresence>,
+    #[serde(default)]
+    pub last_seen: Vec<FederatedLastSeen>,
+    #[serde(default)]
+    pub epoch: Option<String>,
+    #[serde(default)]
+    pub seq: u64,
+}
+
+/// Presence changes a server pushes to `POST /federation/presence`. Users
+/// who went offline are listed as `offline`. `seq` goes up by one with each
+/// delta and starts over at 1 under a new `epoch` when the server restarts,
+/// so a receiver that sees anything else has missed a delta.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceDelta {
+    pub epoch: String,
+    pub seq: u64,
+    pub users: Vec<FederatedPresence>,
+    #[serde(default)]
+    pub last_seen: Vec<FederatedLastSeen>,
 }
 
+/// A user started or stopped typing in a channel, or in a DM to `recipient`.
+/// Exactly one of `channel` and `recipient` is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FederatedTyping {
+    pub sender: FederatedUser,
+    pub channel: Option<FederatedChannel>,
+    pub recipient: Option<FederatedUser>,
+    pub typing: bool,
+}
+
+/// A change to a channel's member list. The channel's origin owns the list:
+/// a member's server asks the origin to add or remove its own user, and the
+/// origin tells a member's server when that member joins, leaves or is
+/// kicked. Peers that predate leaves only ever send joins.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FederatedChannelMembership {
     pub channel: FederatedChannel,
     pub member: FederatedUser,
+    #[serde(default)]
+    pub action: MembershipAction,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MembershipAction {
+    #[default]
+    Join,
+    Leave,
+    Kick,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -48,3 +188,173 @@ pub struct FederatedChannelCallEvent {
     pub participant: FederatedUser,
     pub participant_user_id: String,
 }
+
+/// Served at `/.well-known/beringchat/key`: the Ed25519 public key (base64)
+/// that `server` signs its federation requests with.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicKeyDocument {
+    pub server: String,
+    pub algorithm: String,
+    pub public_key: String,
+}
+
+/// Version of the federation protocol this server speaks, `major.minor`,
+/// advertised in the discovery document and sent with every federation
+/// request. Minor versions only add things; servers with different major
+/// versions cannot talk to each other.
+pub const PROTOCOL_VERSION: &str = "1.0";
+pub const VERSION_HEADER: &str = "x-federation-version";
+
+/// Whether a server speaking `version` can talk to this one.
+pub fn is_compatible(version: &str) -> bool {
+    major_version(version).is_some() && major_version(version) == major_version(PROTOCOL_VERSION)
+}
+
+fn major_version(version: &str) -> Option<u32> {
+    version.split('.').next()?.trim().parse().ok()
+}
+
+/// Optional features of the protocol. Servers announce the ones they handle
+/// in their discovery document, and leave the others out of what they send
+/// to a peer, or send a plainer form instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    Attachments,
+    Threads,
+    Reactions,
+    Edits,
+    Deletes,
+    Pins,
+    Typing,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 7] = [
+        Capability::Attachments,
+        Capability::Threads,
+        Capability::Reactions,
+        Capability::Edits,
+        Capability::Deletes,
+        Capability::Pins,
+        Capability::Typing,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Capability::Attachments => "attachments",
+            Capability::Threads => "threads",
+            Capability::Reactions => "reactions",
+            Capability::Edits => "edits",
+            Capability::Deletes => "deletes",
+            Capability::Pins => "pins",
+            Capability::Typing => "typing",
+        }
+    }
+
+    /// Whether `server` announced this capability. Peers whose capabilities
+    /// are not known are assumed to handle everything, as they did before
+    /// capabilities were announced.
+    pub fn supported_by(self, server: &Server) -> bool {
+        server
+            .capabilities
+            .as_ref()
+            .is_none_or(|capabilities| capabilities.iter().any(|c| c == self.as_str()))
+    }
+}
+
+/// Served at `/.well-known/beringchat`: what another server needs to know
+/// to peer with this one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryDocument {
+    pub server: String,
+    pub base_url: String,
+    pub protocol_version: String,
+    /// Absent from servers that predate capabilities.
+    #[serde(default)]
+    pub capabilities: Option<Vec<String>>,
+    pub algorithm: String,
+    pub public_key: String,
+    pub endpoints: DiscoveryEndpoints,
+}
+
+/// Paths relative to the server's `base_url`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscoveryEndpoints {
+    pub federation: String,
+    pub peering: String,
+    pub key: String,
+}
+
+/// Sent, signed, by a server asking to peer. The receiver checks the
+/// signature against the key in the requester's discovery document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeeringRequestBody {
+    pub server: String,
+    pub base_url: String,
+}
+
+/// The answer to a peering request, signed by the server that was asked.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeeringDecision {
+    pub server: String,
+    pub approved: bool,
+}
+
+/// A page of a channel's history, served by the channel's origin so that a
+/// server which just learned of the channel can backfill it. Messages are
+/// oldest first; `prev_cursor` is passed as `before` for the page before, and
+/// is absent at the start of the history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FederatedHistoryPage {
+    pub messages: Vec<FederatedMessage>,
+    pub prev_cursor: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_same_major_version_is_compatible() {
+        assert!(is_compatible("1.0"));
+        assert!(is_compatible("1.7"));
+        assert!(!is_compatible("2.0"));
+        assert!(!is_compatible("0.9"));
+        assert!(!is_compatible("one"));
+    }
+
+    fn message(kind: MessageKind, channel: Option<FederatedChannel>) -> FederatedMessage {
+        FederatedMessage {
+            message_id: "00000000-0000-0000-0000-000000000001".to_string(),
+            sent_at: "2024-01-01T00:00:00Z".to_string(),
+            kind,
+            body: "hi".to_string(),
+            author: FederatedUser {
+                username: "alice".to_string(),
+                server: "a".to_string(),
+                display_name: None,
+            },
+            recipient: None,
+            channel,
+            thread_root_id: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn only_the_author_server_or_channel_origin_may_send_a_message() {
+        let general = FederatedChannel {
+            name: "general".to_string(),
+            origin_server: "b".to_string(),
+        };
+        let in_channel = message(MessageKind::Channel, Some(general.clone()));
+        assert!(in_channel.may_be_sent_by("a"));
+        assert!(in_channel.may_be_sent_by("b"));
+        assert!(!in_channel.may_be_sent_by("c"));
+
+        // A DM naming a channel gives that channel's origin no say over it
+        let dm = message(MessageKind::Dm, Some(general));
+        assert!(dm.may_be_sent_by("a"));
+        assert!(!dm.may_be_sent_by("b"));
+    }
+}
@@ -0,0 +1,604 @@
+//! Signed server-to-server requests. Every server holds an Ed25519 key and
+//! publishes the public half at `/.well-known/beringchat/key`. Outgoing
+//! federation requests carry a `Signature` header, in the style of HTTP
+//! signatures, over the method and path, the `Date`, a SHA-256 `Digest` of
+//! the body and a random nonce. The receiver looks up the key of the server
+//! named in `keyId`, refuses requests dated too far from its own clock and
+//! remembers nonces for that long, so a captured request cannot be replayed.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRequestParts, OriginalUri, Request, State},
+    http::{request::Parts, HeaderMap, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use reqwest::{Client, RequestBuilder};
+use sha2::{Digest, Sha256};
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+use uuid::Uuid;
+
+use crate::{
+    api::AppState,
+    domain::{PeerStatus, Server},
+    error::AppError,
+    federation::protocol::{self, PublicKeyDocument},
+};
+
+pub const KEY_PATH: &str = "/.well-known/beringchat/key";
+pub const ALGORITHM: &str = "ed25519";
+const NONCE_HEADER: &str = "x-federation-nonce";
+/// The headers every signature covers, in signing order.
+const SIGNED_HEADERS: &str = "(request-target) host date digest x-federation-nonce";
+/// Requests dated further than this from our clock are refused. Nonces are
+/// remembered for twice as long, which covers every date still accepted.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+/// A peer's key is fetched again at most this often when its signatures
+/// stop verifying, in case it was replaced.
+const KEY_REFETCH_INTERVAL: Duration = Duration::from_secs(60);
+/// Largest signed request body that is buffered for verification.
+const MAX_BODY_BYTES: usize = 4 * 1024 * 1024;
+
+/// This server's Ed25519 signing key.
+pub struct ServerKey(SigningKey);
+
+impl ServerKey {
+    /// Read the base64 secret key at `path`, or generate one and write it
+    /// there (readable by the owner only) if the file does not exist yet.
+    pub fn load_or_generate(path: &Path) -> Result<Self, AppError> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                let bytes = STANDARD
+                    .decode(contents.trim())
+                    .ok()
+                    .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                    .ok_or_else(|| AppError::Internal(format!("{} is not a base64 Ed25519 key", path.display())))?;
+                Ok(Self(SigningKey::from_bytes(&bytes)))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let key = SigningKey::generate(&mut rand_core::OsRng);
+                if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                    std::fs::create_dir_all(dir)
+                        .map_err(|e| AppError::Internal(format!("cannot create {}: {}", dir.display(), e)))?;
+                }
+                write_secret(path, &STANDARD.encode(key.to_bytes()))
+                    .map_err(|e| AppError::Internal(format!("cannot write {}: {}", path.display(), e)))?;
+                tracing::info!(target: "federation", "Generated a new federation key in {}", path.display());
+                Ok(Self(key))
+            }
+            Err(e) => Err(AppError::Internal(format!("cannot read {}: {}", path.display(), e))),
+        }
+    }
+
+    pub fn public_key(&self) -> String {
+        STANDARD.encode(self.0.verifying_key().to_bytes())
+    }
+}
+
+#[cfg(unix)]
+fn write_secret(path: &Path, contents: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_secret(path: &Path, contents: &str) -> std::io::Result<()> {
+    std::fs::write(path, contents)
+}
+
+/// Signs outgoing federation requests as this server.
+#[derive(Clone)]
+pub struct RequestSigner {
+    inner: Arc<SignerInner>,
+}
+
+struct SignerInner {
+    key: ServerKey,
+    server_name: String,
+    /// Still sent along for peers that only check `X-Federation-Token`.
+    token: String,
+}
+
+impl RequestSigner {
+    pub fn new(key: ServerKey, server_name: &str, token: &str) -> Self {
+        Self {
+            inner: Arc::new(SignerInner {
+                key,
+                server_name: server_name.to_string(),
+                token: token.to_string(),
+            }),
+        }
+    }
+
+    pub fn public_key(&self) -> String {
+        self.inner.key.public_key()
+    }
+
+    fn verifying_key(&self) -> VerifyingKey {
+        self.inner.key.0.verifying_key()
+    }
+
+    /// Build `request` for `server`, sign it and send it with `http`.
+    /// Nothing is sent to a suspended peer, or to one speaking an
+    /// incompatible protocol version.
+    pub async fn send(
+        &self,
+        http: &Client,
+        server: &Server,
+        request: RequestBuilder,
+    ) -> Result<reqwest::Response, AppError> {
+        if server.status == PeerStatus::Suspended {
+            return Err(AppError::BadRequest(format!("peer {} is suspended", server.name)));
+        }
+        if let Some(version) = server.protocol_version.as_deref().filter(|v| !protocol::is_compatible(v)) {
+            return Err(AppError::BadRequest(format!(
+                "peer {} speaks incompatible federation protocol {}",
+                server.name, version
+            )));
+        }
+        self.send_signed(http, request).await
+    }
+
+    /// Like `send`, for servers that are not peers (yet), such as during
+    /// the peering handshake.
+    pub async fn send_signed(&self, http: &Client, request: RequestBuilder) -> Result<reqwest::Response, AppError> {
+        let mut request = request.build()?;
+        self.sign(&mut request, OffsetDateTime::now_utc())?;
+        Ok(http.execute(request).await?)
+    }
+
+    fn sign(&self, request: &mut reqwest::Request, now: OffsetDateTime) -> Result<(), AppError> {
+        let body = request.body().and_then(|body| body.as_bytes()).unwrap_or_default();
+        let digest = body_digest(body);
+        let date = now.format(&Rfc2822).map_err(|e| AppError::Internal(e.to_string()))?;
+        let nonce = Uuid::new_v4().simple().to_string();
+        let url = request.url();
+        let target = match url.query() {
+            Some(query) => format!("{}?{}", url.path(), query),
+            None => url.path().to_string(),
+        };
+        let host = url_host(url).ok_or_else(|| AppError::Internal(format!("no host in {}", url)))?;
+        let signed = signing_string(request.method().as_str(), &target, &host, &date, &digest, &nonce);
+        let signature = STANDARD.encode(self.inner.key.0.sign(signed.as_bytes()).to_bytes());
+        let header = format!(
+            "keyId=\"{}\",algorithm=\"{}\",headers=\"{}\",signature=\"{}\"",
+            self.inner.server_name, ALGORITHM, SIGNED_HEADERS, signature
+        );
+
+        let headers = request.headers_mut();
+        for (name, value) in [
+            ("date", date),
+            ("digest", digest),
+            (NONCE_HEADER, nonce),
+            ("signature", header),
+            ("x-federation-token", self.inner.token.clone()),
+            (protocol::VERSION_HEADER, protocol::PROTOCOL_VERSION.to_string()),
+        ] {
+            let value = HeaderValue::from_str(&value).map_err(|e| AppError::Internal(e.to_string()))?;
+            headers.insert(name, value);
+        }
+        Ok(())
+    }
+}
+
+fn body_digest(body: &[u8]) -> String {
+    format!("SHA-256={}", STANDARD.encode(Sha256::digest(body)))
+}
+
+/// `host[:port]` of `url`, as sent in the `Host` header.
+fn url_host(url: &reqwest::Url) -> Option<String> {
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+/// The host our peers address us by, taken from `BASE_URL`. Signatures
+/// cover it, so a request signed for one server does not verify at another.
+fn own_host(state: &AppState) -> String {
+    reqwest::Url::parse(&state.config.base_url)
+        .ok()
+        .and_then(|url| url_host(&url))
+        .unwrap_or_default()
+}
+
+fn signing_string(method: &str, target: &str, host: &str, date: &str, digest: &str, nonce: &str) -> String {
+    format!(
+        "(request-target): {} {}\nhost: {}\ndate: {}\ndigest: {}\n{}: {}",
+        method.to_ascii_lowercase(),
+        target,
+        host,
+        date,
+        digest,
+        NONCE_HEADER,
+        nonce
+    )
+}
+
+/// Nonces of recently accepted requests, and when each peer's key was last
+/// fetched.
+#[derive(Clone, Default)]
+pub struct SignatureVerifier {
+    inner: Arc<Mutex<VerifierState>>,
+}
+
+#[derive(Default)]
+struct VerifierState {
+    seen: HashMap<String, Instant>,
+    key_fetches: HashMap<String, Instant>,
+}
+
+impl SignatureVerifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record `nonce` for `server`. Returns false if it was already used.
+    fn remember_nonce(&self, server: &str, nonce: &str, now: Instant) -> bool {
+        let mut state = self.inner.lock().expect("verifier mutex");
+        state.seen.retain(|_, seen_at| now.duration_since(*seen_at) < 2 * MAX_CLOCK_SKEW);
+        state.seen.insert(format!("{}:{}", server, nonce), now).is_none()
+    }
+
+    /// Whether the key of `server` may be fetched again now.
+    fn may_fetch_key(&self, server: &str, now: Instant) -> bool {
+        let mut state = self.inner.lock().expect("verifier mutex");
+        match state.key_fetches.get(server) {
+            Some(last) if now.duration_since(*last) < KEY_REFETCH_INTERVAL => false,
+            _ => {
+                state.key_fetches.insert(server.to_string(), now);
+                true
+            }
+        }
+    }
+}
+
+/// The parts of a `Signature` header.
+struct SignatureHeader {
+    key_id: String,
+    algorithm: String,
+    headers: String,
+    signature: Signature,
+}
+
+fn parse_signature_header(value: &str) -> Option<SignatureHeader> {
+    let mut fields = HashMap::new();
+    for field in value.split(',') {
+        let (name, value) = field.trim().split_once('=')?;
+        let value = value.strip_prefix('"')?.strip_suffix('"')?;
+        fields.insert(name, value);
+    }
+    let signature = STANDARD.decode(fields.get("signature")?).ok()?;
+    Some(SignatureHeader {
+        key_id: fields.get("keyId")?.to_string(),
+        algorithm: fields.get("algorithm")?.to_string(),
+        headers: fields.get("headers")?.to_string(),
+        signature: Signature::from_slice(&signature).ok()?,
+    })
+}
+
+/// A signed request, checked up to the point where the signer's key is
+/// needed.
+struct SignedRequest {
+    header: SignatureHeader,
+    nonce: String,
+    signed: String,
+}
+
+/// Check everything about a request's signature that does not need the
+/// signer's key: the header itself, the date against `now`, and the body
+/// digest. The signed string is rebuilt with `host`, ours, rather than the
+/// `Host` header. Returns why the request is refused otherwise.
+fn check_request(
+    method: &Method,
+    target: &str,
+    host: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    now: OffsetDateTime,
+) -> Result<SignedRequest, &'static str> {
+    let get = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let header = get("signature").and_then(parse_signature_header).ok_or("malformed signature header")?;
+    if header.algorithm != ALGORITHM || header.headers != SIGNED_HEADERS {
+        return Err("unsupported signature algorithm or header list");
+    }
+    let date = get("date").ok_or("missing date")?;
+    let sent_at = OffsetDateTime::parse(date, &Rfc2822).map_err(|_| "malformed date")?;
+    if (now - sent_at).unsigned_abs() > MAX_CLOCK_SKEW {
+        return Err("date too far from our clock");
+    }
+    let digest = get("digest").ok_or("missing digest")?;
+    if digest != body_digest(body) {
+        return Err("body does not match digest");
+    }
+    let nonce = get(NONCE_HEADER).filter(|nonce| !nonce.is_empty()).ok_or("missing nonce")?;
+    let signed = signing_string(method.as_str(), target, host, date, digest, nonce);
+    Ok(SignedRequest {
+        header,
+        nonce: nonce.to_string(),
+        signed,
+    })
+}
+
+/// The server a federation request was signed by, set by
+/// `verify_requests` once the signature checks out.
+#[derive(Clone)]
+pub struct SignedBy(pub Server);
+
+/// Middleware for the federation routes. Requests with a `Signature` header
+/// are only let through if it verifies, and carry the signing server as
+/// `SignedBy`. Unsigned requests pass on to `FederationCaller`, which turns
+/// them away unless `FEDERATION_ALLOW_UNSIGNED` is set.
+pub async fn verify_requests(State(state): State<AppState>, request: Request, next: Next) -> Result<Response, AppError> {
+    if !request.headers().contains_key("signature") {
+        return Ok(next.run(request).await);
+    }
+    // The federation router is nested, so its own URI has lost the prefix
+    let target = request
+        .extensions()
+        .get::<OriginalUri>()
+        .map(|uri| uri.0.clone())
+        .unwrap_or_else(|| request.uri().clone());
+    let target = target.path_and_query().map(|p| p.as_str().to_string()).unwrap_or_default();
+    let (mut parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, MAX_BODY_BYTES)
+        .await
+        .map_err(|_| AppError::BadRequest("request body too large".to_string()))?;
+
+    let server = verify(&state, &parts.method, &target, &parts.headers, &body).await?;
+    parts.extensions.insert(SignedBy(server));
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
+}
+
+async fn verify(
+    state: &AppState,
+    method: &Method,
+    target: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Server, AppError> {
+    let request = check_request(method, target, &own_host(state), headers, body, OffsetDateTime::now_utc()).map_err(|reason| {
+        tracing::warn!(target: "federation", "Refusing signed {} {}: {}", method, target, reason);
+        AppError::Unauthorized
+    })?;
+    let key_id = &request.header.key_id;
+    let server = state.store.get_server_by_name(key_id).await?.ok_or_else(|| {
+        tracing::warn!(target: "federation", "Refusing {} {} signed by unknown server {}", method, target, key_id);
+        AppError::Unauthorized
+    })?;
+    if server.status == PeerStatus::Suspended {
+        tracing::debug!(target: "federation", "Refusing {} {} from suspended peer {}", method, target, server.name);
+        return Err(AppError::Unauthorized);
+    }
+
+    let mut verified = peer_key(state, &server)
+        .await?
+        .is_some_and(|key| key.verify_strict(request.signed.as_bytes(), &request.header.signature).is_ok());
+    if !verified {
+        // The peer may have a new key since we last fetched it
+        if let Some(key) = refresh_peer_key(state, &server).await? {
+            verified = key.verify_strict(request.signed.as_bytes(), &request.header.signature).is_ok();
+        }
+    }
+    if !verified {
+        tracing::warn!(target: "federation", "Refusing {} {}: bad signature from {}", method, target, server.name);
+        return Err(AppError::Unauthorized);
+    }
+    if !state.verifier.remember_nonce(&server.name, &request.nonce, Instant::now()) {
+        tracing::warn!(target: "federation", "Refusing {} {}: replayed request from {}", method, target, server.name);
+        return Err(AppError::Unauthorized);
+    }
+    Ok(server)
+}
+
+/// Verify a request signed by `server_name`, which is not a peer yet, with
+/// the key it was introduced with. Used by the peering handshake, which
+/// runs before the servers know each other.
+pub fn verify_with_key(
+    state: &AppState,
+    method: &Method,
+    target: &str,
+    headers: &HeaderMap,
+    body: &[u8],
+    server_name: &str,
+    public_key: &str,
+) -> Result<(), AppError> {
+    let request = check_request(method, target, &own_host(state), headers, body, OffsetDateTime::now_utc()).map_err(|reason| {
+        tracing::warn!(target: "federation", "Refusing signed {} {}: {}", method, target, reason);
+        AppError::Unauthorized
+    })?;
+    let verified = request.header.key_id == server_name
+        && decode_public_key(public_key)
+            .is_some_and(|key| key.verify_strict(request.signed.as_bytes(), &request.header.signature).is_ok());
+    if !verified {
+        tracing::warn!(target: "federation", "Refusing {} {}: not signed by {}'s key", method, target, server_name);
+        return Err(AppError::Unauthorized);
+    }
+    if !state.verifier.remember_nonce(server_name, &request.nonce, Instant::now()) {
+        tracing::warn!(target: "federation", "Refusing {} {}: replayed request from {}", method, target, server_name);
+        return Err(AppError::Unauthorized);
+    }
+    Ok(())
+}
+
+/// The key `server` signs with, fetched and stored the first time.
+async fn peer_key(state: &AppState, server: &Server) -> Result<Option<VerifyingKey>, AppError> {
+    if server.name == state.config.server_name {
+        return Ok(Some(state.signer.verifying_key()));
+    }
+    match server.public_key.as_deref().and_then(decode_public_key) {
+        Some(key) => Ok(Some(key)),
+        None => refresh_peer_key(state, server).await,
+    }
+}
+
+/// Fetch the key `server` publishes and store it, unless it was fetched
+/// very recently. Returns `None` if no usable key could be fetched.
+async fn refresh_peer_key(state: &AppState, server: &Server) -> Result<Option<VerifyingKey>, AppError> {
+    if server.name == state.config.server_name || !state.verifier.may_fetch_key(&server.name, Instant::now()) {
+        return Ok(None);
+    }
+    let url = format!("{}{}", server.base_url.trim_end_matches('/'), KEY_PATH);
+    let document = match state.http.get(&url).timeout(Duration::from_secs(10)).send().await {
+        Ok(response) if response.status().is_success() => response.json::<PublicKeyDocument>().await.ok(),
+        Ok(response) => {
+            tracing::warn!(target: "federation", "Fetching the key of {} failed: {}", server.name, response.status());
+            None
+        }
+        Err(e) => {
+            tracing::warn!(target: "federation", "Fetching the key of {} failed: {}", server.name, e);
+            None
+        }
+    };
+    let Some(document) = document else {
+        return Ok(None);
+    };
+    if document.server != server.name || document.algorithm != ALGORITHM {
+        tracing::warn!(target: "federation", "{} serves a key for '{}' ({}), ignoring it", url, document.server, document.algorithm);
+        return Ok(None);
+    }
+    let Some(key) = decode_public_key(&document.public_key) else {
+        return Ok(None);
+    };
+    if server.public_key.as_deref() != Some(document.public_key.as_str()) {
+        if server.public_key.is_some() {
+            tracing::warn!(target: "federation", "Federation key of {} has changed", server.name);
+        } else {
+            tracing::info!(target: "federation", "Stored federation key of {}", server.name);
+        }
+        state.store.set_server_public_key(&server.id, &document.public_key).await?;
+    }
+    Ok(Some(key))
+}
+
+pub fn decode_public_key(value: &str) -> Option<VerifyingKey> {
+    let bytes = <[u8; 32]>::try_from(STANDARD.decode(value).ok()?).ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+/// The peer behind a federation request. `None` means it was let in by a
+/// shared federation token rather than as a known server, which only
+/// happens for unsigned requests with `FEDERATION_ALLOW_UNSIGNED` set.
+pub struct FederationCaller(pub Option<Server>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for FederationCaller {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        if let Some(SignedBy(server)) = parts.extensions.get::<SignedBy>() {
+            return Ok(FederationCaller(Some(server.clone())));
+        }
+        if !state.config.federation_allow_unsigned {
+            tracing::debug!(target: "federation", "Refusing unsigned request to {}", parts.uri);
+            return Err(AppError::Unauthorized);
+        }
+        validate_federation_token(state, &parts.headers).await.map(FederationCaller)
+    }
+}
+
+/// Extract the federation token from headers, then validate it against:
+/// 1. The `servers` table (known server tokens) — returns `Some(server)` if found
+/// 2. The `federation_tokens` table (additional accepted tokens) — returns `None` for server
+/// 3. The primary `SERVER_TOKEN` env var — returns `None` for server
+///
+/// Returns `Err(Unauthorized)` if neither matches.
+async fn validate_federation_token(state: &AppState, headers: &HeaderMap) -> Result<Option<Server>, AppError> {
+    let token = headers
+        .get("x-federation-token")
+        .and_then(|value| value.to_str().ok())
+        .ok_or(AppError::Unauthorized)?;
+
+    // Check servers table first
+    if let Some(server) = state.store.get_server_by_token(token).await? {
+        if server.status == PeerStatus::Suspended {
+            return Err(AppError::Unauthorized);
+        }
+        return Ok(Some(server));
+    }
+
+    // Check federation_tokens table
+    if state.store.is_valid_federation_token(token).await? {
+        return Ok(None);
+    }
+
+    // Check primary server token (this server's own token authenticates too)
+    if token == state.config.server_token {
+        return Ok(None);
+    }
+
+    tracing::debug!(target: "federation", "No server or federation token matches provided token");
+    Err(AppError::Unauthorized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_request(signer: &RequestSigner, body: &str, now: OffsetDateTime) -> reqwest::Request {
+        let mut request = Client::new()
+            .post("http://b.example/federation/messages?x=1")
+            .body(body.to_string())
+            .build()
+            .expect("request");
+        signer.sign(&mut request, now).expect("sign");
+        request
+    }
+
+    fn check(request: &reqwest::Request, body: &str, now: OffsetDateTime) -> Result<SignedRequest, &'static str> {
+        check_at("b.example", request, body, now)
+    }
+
+    fn check_at(host: &str, request: &reqwest::Request, body: &str, now: OffsetDateTime) -> Result<SignedRequest, &'static str> {
+        let target = "/federation/messages?x=1";
+        check_request(request.method(), target, host, request.headers(), body.as_bytes(), now)
+    }
+
+    #[test]
+    fn signatures_cover_the_body_and_expire() {
+        let signer = RequestSigner::new(ServerKey(SigningKey::from_bytes(&[7; 32])), "a", "token-a");
+        let now = OffsetDateTime::now_utc().replace_nanosecond(0).expect("now");
+        let request = signed_request(&signer, "{\"body\":\"hi\"}", now);
+
+        let checked = check(&request, "{\"body\":\"hi\"}", now).expect("valid");
+        assert_eq!(checked.header.key_id, "a");
+        assert!(signer.verifying_key().verify_strict(checked.signed.as_bytes(), &checked.header.signature).is_ok());
+
+        assert_eq!(check(&request, "{\"body\":\"bye\"}", now).err(), Some("body does not match digest"));
+        let late = now + MAX_CLOCK_SKEW + Duration::from_secs(1);
+        assert_eq!(check(&request, "{\"body\":\"hi\"}", late).err(), Some("date too far from our clock"));
+
+        let other = RequestSigner::new(ServerKey(SigningKey::from_bytes(&[8; 32])), "a", "token-a");
+        assert!(other.verifying_key().verify_strict(checked.signed.as_bytes(), &checked.header.signature).is_err());
+    }
+
+    #[test]
+    fn signatures_do_not_verify_at_another_server() {
+        let signer = RequestSigner::new(ServerKey(SigningKey::from_bytes(&[7; 32])), "a", "token-a");
+        let now = OffsetDateTime::now_utc().replace_nanosecond(0).expect("now");
+        let request = signed_request(&signer, "{}", now);
+
+        let replayed = check_at("c.example:8080", &request, "{}", now).expect("well formed");
+        assert!(signer.verifying_key().verify_strict(replayed.signed.as_bytes(), &replayed.header.signature).is_err());
+    }
+
+    #[test]
+    fn nonces_are_accepted_once() {
+        let verifier = SignatureVerifier::new();
+        let now = Instant::now();
+        assert!(verifier.remember_nonce("a", "n1", now));
+        assert!(!verifier.remember_nonce("a", "n1", now));
+        assert!(verifier.remember_nonce("b", "n1", now));
+    }
+}
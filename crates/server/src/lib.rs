@@ -1,11 +1,17 @@
 pub mod api;
 pub mod auth;
+pub mod backup;
+pub mod blobs;
 pub mod channel_call;
 pub mod config;
 pub mod domain;
 pub mod error;
+pub mod export;
 pub mod federation;
+pub mod mentions;
 pub mod presence;
+pub mod retention;
 pub mod storage;
+pub mod typing;
 pub mod websocket;
 pub mod ws_bridge;
@@ -1,5 +1,6 @@
-use federated_server::{api, config::Config, storage::SqliteStore};
+use federated_server::{api, backup, config::{Config, StorageBackend}, federation::signing::ServerKey, storage::{self, DynStore}};
 use std::net::SocketAddr;
+use std::path::Path;
 use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
@@ -8,27 +9,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_env_filter(EnvFilter::from_default_env())
         .init();
 
-    let config = Config::from_env();
-    let store = SqliteStore::new(&config.database_path)?;
-    store.init()?;
+    let config = Config::from_env()?;
+    let args: Vec<String> = std::env::args().collect();
+
+    // Restore swaps the database file, so it runs before anything opens it.
+    if args.get(1).map(String::as_str) == Some("restore") {
+        return restore(&config, args.get(2));
+    }
+
+    let store = storage::open(&config)?;
+
+    // Subcommands operate on the database and exit without starting the server.
+    match args.get(1).map(String::as_str) {
+        None | Some("serve") => {}
+        Some("migrations") => return print_migrations(&store).await,
+        Some("backup") => return run_backup(&store, &config, args.get(2)).await,
+        Some(other) => {
+            return Err(format!("unknown command '{}' (expected: serve, migrations, backup, restore)", other).into())
+        }
+    }
+
+    store.init().await?;
     // Disabled: auto-seeding of federation peers. Use Admin UI to manually add servers.
-    // store.seed_initial_data(&config.server_name)?;
+    // store.seed_initial_data(&config.server_name).await?;
 
     // Ensure this server is present in the servers table and update its
     // base_url/token if they differ from the current environment. This
     // helps reconcile tokens when DB was reused across runs or when
     // docker-compose seeds differ from the runtime env.
-    let self_server = store.ensure_server(&config.server_name, &config.base_url, &config.server_token)?;
+    let self_server = store.ensure_server(&config.server_name, &config.base_url, &config.server_token).await?;
     if self_server.token != config.server_token || self_server.base_url != config.base_url {
-        let _ = store.update_server(&self_server.id, &config.server_name, &config.base_url, &config.server_token)?;
+        let _ = store.update_server(&self_server.id, &config.server_name, &config.base_url, &config.server_token).await?;
         tracing::info!(target: "startup", "Updated local server record '{}' with current base_url/token (masked)", config.server_name);
     }
 
     // Ensure admin user exists with the configured password
-    ensure_admin_user(&store, &config);
+    ensure_admin_user(&store, &config).await;
 
     // Log discovered/seeded federation peers at startup (mask tokens for safety).
-    if let Ok(servers) = store.list_servers() {
+    if let Ok(servers) = store.list_servers().await {
         for s in servers {
             let masked = if s.token.len() > 4 {
                 format!("***{}", &s.token[s.token.len() - 4..])
@@ -39,7 +58,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
-    let app = api::router(store, config.clone());
+    let key = ServerKey::load_or_generate(Path::new(&config.federation_key_path))?;
+    let app = api::router(store, config.clone(), key);
 
     let addr: SocketAddr = "0.0.0.0:8080".parse()?;
     let listener = tokio::net::TcpListener::bind(addr).await?;
@@ -49,7 +69,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-fn ensure_admin_user(store: &SqliteStore, config: &Config) {
+async fn print_migrations(store: &DynStore) -> Result<(), Box<dyn std::error::Error>> {
+    for m in store.migration_status().await? {
+        println!(
+            "{:>4}  {:<32} {}",
+            m.version,
+            m.name,
+            m.applied_at.as_deref().unwrap_or("pending")
+        );
+    }
+    Ok(())
+}
+
+/// `backup` writes a rotated snapshot into `BACKUP_DIR`; `backup <path>`
+/// writes to exactly that path instead.
+async fn run_backup(store: &DynStore, config: &Config, path: Option<&String>) -> Result<(), Box<dyn std::error::Error>> {
+    match path {
+        Some(path) => {
+            store.backup_to(Path::new(path)).await?;
+            println!("{}", path);
+        }
+        None => println!("{}", backup::create_backup(store.as_ref(), config).await?.path),
+    }
+    Ok(())
+}
+
+fn restore(config: &Config, backup_path: Option<&String>) -> Result<(), Box<dyn std::error::Error>> {
+    let backup_path = backup_path.ok_or("usage: federated-server restore <backup-file>")?;
+    if config.storage_backend != StorageBackend::Sqlite {
+        return Err("restore is only supported for the sqlite storage backend".into());
+    }
+    let version = backup::restore(Path::new(backup_path), Path::new(&config.database_path))?;
+    println!(
+        "restored {} into {} (schema version {}); previous database kept as {}.pre-restore",
+        backup_path, config.database_path, version, config.database_path
+    );
+    Ok(())
+}
+
+async fn ensure_admin_user(store: &DynStore, config: &Config) {
     let password_hash = match bcrypt::hash(&config.admin_password, bcrypt::DEFAULT_COST) {
         Ok(h) => h,
         Err(e) => {
@@ -58,10 +116,10 @@ fn ensure_admin_user(store: &SqliteStore, config: &Config) {
         }
     };
 
-    match store.get_user_by_name_and_server(&config.admin_username, None) {
+    match store.get_user_by_name_and_server(&config.admin_username, None).await {
         Ok(Some(user)) => {
             // Update password to match config on every startup
-            if let Err(e) = store.set_user_password(&user.id, &password_hash) {
+            if let Err(e) = store.set_user_password(&user.id, &password_hash).await {
                 tracing::error!(target: "startup", "Failed to update admin user password: {}", e);
             } else {
                 tracing::info!(target: "startup", "Admin user '{}' password synced from environment", config.admin_username);
@@ -74,7 +132,7 @@ fn ensure_admin_user(store: &SqliteStore, config: &Config) {
                 true,
                 None,
                 Some(&password_hash),
-            ) {
+            ).await {
                 Ok(user) => {
                     tracing::info!(target: "startup", "Created admin user '{}' (id: {})", config.admin_username, user.id);
                 }
@@ -0,0 +1,155 @@
+use crate::{
+    api::AppState,
+    domain::{Mention, MentionKind, Message, MessageKind, User},
+    error::AppError,
+};
+
+/// A mention as written in a message body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MentionToken {
+    /// `@name`, or `@name@server` for a user on another server.
+    User { username: String, server: Option<String> },
+    /// `@channel`: every member of the channel.
+    Channel,
+    /// `@here`: the channel members who are online.
+    Here,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// Take a run of name characters from the start of `s`. A trailing `.` is
+/// sentence punctuation rather than part of the name.
+fn take_name(s: &str) -> &str {
+    let end = s.find(|c: char| !is_name_char(c)).unwrap_or(s.len());
+    s[..end].trim_end_matches('.')
+}
+
+/// Find the mentions in a message body, in order of first appearance and
+/// without repeats. An `@` only starts a mention at the beginning of the body
+/// or after a character that cannot be part of a name, so email addresses
+/// are not mistaken for mentions.
+pub fn parse(body: &str) -> Vec<MentionToken> {
+    let mut tokens = Vec::new();
+    let mut prev: Option<char> = None;
+    for (i, c) in body.char_indices() {
+        let starts_mention = c == '@' && !prev.is_some_and(|p| is_name_char(p) || p == '@');
+        prev = Some(c);
+        if !starts_mention {
+            continue;
+        }
+        let rest = &body[i + 1..];
+        let username = take_name(rest);
+        if username.is_empty() {
+            continue;
+        }
+        let server = rest[username.len()..]
+            .strip_prefix('@')
+            .map(take_name)
+            .filter(|s| !s.is_empty());
+        let token = match (username, server) {
+            (name, None) if name.eq_ignore_ascii_case("channel") => MentionToken::Channel,
+            (name, None) if name.eq_ignore_ascii_case("here") => MentionToken::Here,
+            (name, server) => MentionToken::User {
+                username: name.to_string(),
+                server: server.map(str::to_string),
+            },
+        };
+        if !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+/// Resolve the mentions in a newly stored message, record them and notify
+/// the mentioned users. Each server records mentions of its own users only;
+/// peers do the same when the message reaches them.
+///
+/// A bare `@name` refers to a user on the author's server. In DMs only the
+/// other participant can be mentioned, and `@channel`/`@here` do nothing.
+pub async fn record(state: &AppState, message: &Message, author: &User) -> Result<Vec<Mention>, AppError> {
+    let tokens = parse(&message.body);
+    if tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut mentioned: Vec<(User, MentionKind)> = Vec::new();
+    for token in &tokens {
+        if let MentionToken::User { username, server } = token {
+            let server_id = match server.as_deref() {
+                None => author.server_id,
+                Some(name) if name == state.config.server_name => None,
+                Some(name) => match state.store.get_server_by_name(name).await? {
+                    Some(server) => Some(server.id),
+                    None => continue,
+                },
+            };
+            if let Some(user) = state.store.get_user_by_name_and_server(username, server_id).await? {
+                mentioned.push((user, MentionKind::User));
+            }
+        }
+    }
+    if let Some(channel_id) = message.channel_id {
+        let everyone = tokens.contains(&MentionToken::Channel);
+        let here = tokens.contains(&MentionToken::Here);
+        if everyone || here {
+            for member in state.store.list_channel_members(channel_id).await? {
+                if everyone {
+                    mentioned.push((member, MentionKind::Channel));
+                } else if member.is_local && state.presence.is_online(member.id) {
+                    mentioned.push((member, MentionKind::Here));
+                }
+            }
+        }
+    }
+
+    let mut mentions: Vec<Mention> = Vec::new();
+    for (user, kind) in mentioned {
+        if !user.is_local || user.id == message.author_user_id || mentions.iter().any(|m| m.user_id == user.id) {
+            continue;
+        }
+        if message.kind == MessageKind::Dm && message.recipient_user_id != Some(user.id) {
+            continue;
+        }
+        mentions.push(Mention {
+            message_id: message.id,
+            user_id: user.id,
+            kind,
+            sent_at: message.sent_at.clone(),
+        });
+    }
+    if mentions.is_empty() {
+        return Ok(mentions);
+    }
+
+    state.store.add_mentions(&mentions).await?;
+    for mention in &mentions {
+        crate::websocket::notify_mention(&state.message_broadcaster, message, mention);
+    }
+    tracing::debug!(target: "mentions", "message_id={} mentions {} users", message.id, mentions.len());
+    Ok(mentions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(username: &str, server: Option<&str>) -> MentionToken {
+        MentionToken::User {
+            username: username.to_string(),
+            server: server.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn parses_user_server_and_group_mentions() {
+        let tokens = parse("@alice, ask @bob@server_b. @here and @channel! mail carol@example.com @alice");
+        assert_eq!(
+            tokens,
+            vec![user("alice", None), user("bob", Some("server_b")), MentionToken::Here, MentionToken::Channel]
+        );
+        assert!(parse("@ @@ a@b").is_empty());
+    }
+}
@@ -1,11 +1,72 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 use uuid::Uuid;
 
-#[derive(Clone, Default)]
+use crate::{
+    api::AppState,
+    domain::{PresenceState, PresenceStatus, UserPresence},
+    error::AppError,
+    federation::protocol::FederatedPresence,
+    storage::DynStore,
+    websocket::MessageBroadcaster,
+};
+
+/// A local user's open connections and when their clients last reported
+/// activity.
+struct Connections {
+    count: usize,
+    last_active: Instant,
+    idle: bool,
+}
+
+/// Where a peer's presence feed is at: its `epoch` changes whenever it
+/// restarts and `seq` counts the deltas sent since.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FeedPosition {
+    pub epoch: String,
+    pub seq: u64,
+}
+
+/// What happened to a presence delta from a peer.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeltaOutcome {
+    /// It was older than what we have and was ignored.
+    Stale,
+    /// It was the next one in sequence.
+    Applied { changed: bool },
+    /// It was applied, but deltas before it were missed, so the peer's full
+    /// presence should be fetched again.
+    Gap { changed: bool },
+}
+
+#[derive(Clone)]
 pub struct PresenceStore {
-    inner: Arc<Mutex<HashMap<Uuid, usize>>>,
-    remote: Arc<Mutex<HashSet<String>>>,
+    inner: Arc<Mutex<HashMap<Uuid, Connections>>>,
+    /// Remote users reported online by their servers, keyed by `username@server`.
+    remote: Arc<Mutex<HashMap<String, PresenceStatus>>>,
+    /// How far we are into each peer's presence feed, by server name.
+    feeds: Arc<Mutex<HashMap<String, FeedPosition>>>,
+    /// Our own feed: a fresh epoch per process and the last delta sent.
+    epoch: Arc<str>,
+    seq: Arc<AtomicU64>,
+    /// Wakes the publisher when a local user's presence may have changed.
+    changed: Arc<Notify>,
+}
+
+impl Default for PresenceStore {
+    fn default() -> Self {
+        Self {
+            inner: Arc::default(),
+            remote: Arc::default(),
+            feeds: Arc::default(),
+            epoch: Uuid::new_v4().to_string().into(),
+            seq: Arc::default(),
+            changed: Arc::default(),
+        }
+    }
 }
 
 impl PresenceStore {
@@ -13,68 +74,336 @@ impl PresenceStore {
         Self::default()
     }
 
-    pub fn set_remote_users_online(&self, usernames: Vec<String>) {
+    /// The position of the last delta of our own feed.
+    pub fn position(&self) -> FeedPosition {
+        FeedPosition {
+            epoch: self.epoch.to_string(),
+            seq: self.seq.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Reserve the position of the next delta of our own feed.
+    pub fn next_position(&self) -> FeedPosition {
+        FeedPosition {
+            epoch: self.epoch.to_string(),
+            seq: self.seq.fetch_add(1, Ordering::SeqCst) + 1,
+        }
+    }
+
+    /// Tell the publisher that a local user's presence may have changed.
+    pub fn notify_changed(&self) {
+        self.changed.notify_one();
+    }
+
+    /// Wait for `notify_changed`. A call made while nobody was waiting
+    /// wakes the next wait immediately.
+    pub async fn changed(&self) {
+        self.changed.notified().await;
+    }
+
+    /// Replace what we know about the users of `server_name` with its full
+    /// report, taken at `position` of its feed if it has one. A report
+    /// older than the deltas already applied is ignored. Returns true if
+    /// anything changed.
+    pub fn set_remote_presence(
+        &self,
+        server_name: &str,
+        position: Option<FeedPosition>,
+        users: Vec<FederatedPresence>,
+    ) -> bool {
+        let mut feeds = self.feeds.lock().expect("presence feeds mutex");
+        if let Some(position) = position {
+            if feeds
+                .get(server_name)
+                .is_some_and(|known| known.epoch == position.epoch && known.seq > position.seq)
+            {
+                return false;
+            }
+            feeds.insert(server_name.to_string(), position);
+        }
         let mut remote = self.remote.lock().expect("remote presence mutex");
-        // Add all usernames (they already include @server suffix)
-        for username in usernames {
-            remote.insert(username);
+        let suffix = format!("@{}", server_name);
+        let before: HashMap<String, PresenceStatus> = remote
+            .iter()
+            .filter(|(key, _)| key.ends_with(&suffix))
+            .map(|(key, presence)| (key.clone(), presence.clone()))
+            .collect();
+        let after: HashMap<String, PresenceStatus> = users
+            .into_iter()
+            .map(|presence| (format!("{}@{}", presence.username, server_name), presence.status))
+            .collect();
+        if before == after {
+            return false;
         }
+        remote.retain(|key, _| !key.ends_with(&suffix));
+        remote.extend(after);
+        true
     }
-    
-    pub fn clear_remote_server(&self, server_name: &str) -> bool {
+
+    /// Apply the changes `server_name` pushed at `position` of its feed.
+    /// Users reported `Offline` are forgotten.
+    pub fn apply_remote_delta(
+        &self,
+        server_name: &str,
+        position: FeedPosition,
+        users: Vec<FederatedPresence>,
+    ) -> DeltaOutcome {
+        let mut feeds = self.feeds.lock().expect("presence feeds mutex");
+        let in_sequence = match feeds.get(server_name) {
+            Some(known) if known.epoch == position.epoch => {
+                if position.seq <= known.seq {
+                    return DeltaOutcome::Stale;
+                }
+                position.seq == known.seq + 1
+            }
+            _ => false,
+        };
+        feeds.insert(server_name.to_string(), position);
+
         let mut remote = self.remote.lock().expect("remote presence mutex");
-        let len_before = remote.len();
-        remote.retain(|username| !username.ends_with(&format!("@{}", server_name)));
-        remote.len() != len_before
+        let mut changed = false;
+        for presence in users {
+            let key = format!("{}@{}", presence.username, server_name);
+            changed |= if presence.status.state == PresenceState::Offline {
+                remote.remove(&key).is_some()
+            } else {
+                remote.insert(key, presence.status.clone()) != Some(presence.status)
+            };
+        }
+        if in_sequence {
+            DeltaOutcome::Applied { changed }
+        } else {
+            DeltaOutcome::Gap { changed }
+        }
     }
 
     pub fn is_remote_user_online(&self, username: &str) -> bool {
         let remote = self.remote.lock().expect("remote presence mutex");
-        remote.contains(username)
+        remote.contains_key(username)
+    }
+
+    /// The last reported presence of `username@server`, if they are online.
+    pub fn remote_presence(&self, username: &str) -> Option<PresenceStatus> {
+        let remote = self.remote.lock().expect("remote presence mutex");
+        remote.get(username).cloned()
     }
 
     pub fn mark_online(&self, user_id: Uuid) {
         let mut map = self.inner.lock().expect("presence mutex");
-        let count = map.entry(user_id).or_insert(0);
-        *count += 1;
+        let entry = map.entry(user_id).or_insert(Connections {
+            count: 0,
+            last_active: Instant::now(),
+            idle: false,
+        });
+        entry.count += 1;
+        entry.last_active = Instant::now();
+        entry.idle = false;
+        drop(map);
+        self.notify_changed();
     }
 
     pub fn mark_offline(&self, user_id: Uuid) {
         let mut map = self.inner.lock().expect("presence mutex");
-        if let Some(count) = map.get_mut(&user_id) {
-            if *count <= 1 {
+        if let Some(entry) = map.get_mut(&user_id) {
+            if entry.count <= 1 {
                 map.remove(&user_id);
+                drop(map);
+                self.notify_changed();
             } else {
-                *count -= 1;
+                entry.count -= 1;
             }
         }
     }
 
     pub fn is_online(&self, user_id: Uuid) -> bool {
         let map = self.inner.lock().expect("presence mutex");
-        map.get(&user_id).map(|count| *count > 0).unwrap_or(false)
+        map.get(&user_id).map(|entry| entry.count > 0).unwrap_or(false)
     }
 
     pub fn online_user_ids(&self) -> Vec<Uuid> {
         let map = self.inner.lock().expect("presence mutex");
         map.keys().cloned().collect()
     }
+
+    /// A client of `user_id` reported activity. Returns true if the user was
+    /// idle until now.
+    pub fn record_activity(&self, user_id: Uuid) -> bool {
+        let mut map = self.inner.lock().expect("presence mutex");
+        let was_idle = match map.get_mut(&user_id) {
+            Some(entry) => {
+                entry.last_active = Instant::now();
+                std::mem::replace(&mut entry.idle, false)
+            }
+            None => false,
+        };
+        drop(map);
+        if was_idle {
+            self.notify_changed();
+        }
+        was_idle
+    }
+
+    /// Mark users idle who have not been active for `idle_after`. Returns true
+    /// if anyone went idle.
+    pub fn update_idle(&self, idle_after: Duration) -> bool {
+        let mut map = self.inner.lock().expect("presence mutex");
+        let mut changed = false;
+        for entry in map.values_mut() {
+            if !entry.idle && entry.last_active.elapsed() >= idle_after {
+                entry.idle = true;
+                changed = true;
+            }
+        }
+        drop(map);
+        if changed {
+            self.notify_changed();
+        }
+        changed
+    }
+
+    /// The state of a local user given the state they chose: `Offline` with
+    /// no connection, and `Away` instead of `Online` while idle.
+    pub fn local_state(&self, user_id: Uuid, chosen: Option<&UserPresence>) -> PresenceState {
+        let map = self.inner.lock().expect("presence mutex");
+        let Some(entry) = map.get(&user_id) else {
+            return PresenceState::Offline;
+        };
+        match chosen.map_or(PresenceState::Online, |p| p.state) {
+            PresenceState::Online | PresenceState::Offline if entry.idle => PresenceState::Away,
+            PresenceState::Online | PresenceState::Offline => PresenceState::Online,
+            chosen => chosen,
+        }
+    }
+
+    /// How a local user's presence looks to `viewer_id`, or to peers when
+    /// there is no viewer. Invisible users appear offline to everyone but
+    /// themselves. The custom status shows until it expires, `now` being
+    /// the current RFC3339 time.
+    pub fn local_status(
+        &self,
+        user_id: Uuid,
+        chosen: Option<&UserPresence>,
+        viewer_id: Option<Uuid>,
+        now: &str,
+    ) -> PresenceStatus {
+        let mut state = self.local_state(user_id, chosen);
+        if state == PresenceState::Invisible && viewer_id != Some(user_id) {
+            state = PresenceState::Offline;
+        }
+        chosen.map_or_else(|| PresenceStatus::new(state), |p| p.status(state)).expire(now)
+    }
 }
 
+/// Keeps a user online for as long as one of their connections is open.
+/// When the last one closes, `last_seen_at` is recorded, except for
+/// invisible users, who were never seen.
 pub struct PresenceGuard {
-    store: PresenceStore,
+    presence: PresenceStore,
+    store: DynStore,
+    broadcaster: MessageBroadcaster,
     pub user_id: Uuid,
 }
 
 impl PresenceGuard {
-    pub fn new(store: PresenceStore, user_id: Uuid) -> Self {
-        store.mark_online(user_id);
-        Self { store, user_id }
+    pub fn new(state: &AppState, user_id: Uuid) -> Self {
+        state.presence.mark_online(user_id);
+        Self {
+            presence: state.presence.clone(),
+            store: state.store.clone(),
+            broadcaster: state.message_broadcaster.clone(),
+            user_id,
+        }
     }
 }
 
 impl Drop for PresenceGuard {
     fn drop(&mut self) {
-        self.store.mark_offline(self.user_id);
+        self.presence.mark_offline(self.user_id);
+        if self.presence.is_online(self.user_id) {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let store = self.store.clone();
+        let broadcaster = self.broadcaster.clone();
+        let presence = self.presence.clone();
+        let user_id = self.user_id;
+        runtime.spawn(async move {
+            if let Err(e) = record_last_seen(&store, user_id).await {
+                tracing::warn!(target: "presence", "Failed to record last seen for {}: {}", user_id, e);
+                return;
+            }
+            presence.notify_changed();
+            crate::websocket::notify_presence_changed(&broadcaster);
+        });
+    }
+}
+
+async fn record_last_seen(store: &DynStore, user_id: Uuid) -> Result<(), AppError> {
+    let chosen = store.get_user_presence(user_id).await?;
+    if chosen.is_some_and(|p| p.state == PresenceState::Invisible) {
+        return Ok(());
+    }
+    let now = time::OffsetDateTime::now_utc()
+        .format(&time::format_description::well_known::Rfc3339)
+        .map_err(|e| AppError::Internal(e.to_string()))?;
+    store.update_last_seen(&user_id, &now).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_users_show_as_away_until_active_again() {
+        let store = PresenceStore::new();
+        let user_id = Uuid::new_v4();
+        assert_eq!(store.local_state(user_id, None), PresenceState::Offline);
+
+        store.mark_online(user_id);
+        assert_eq!(store.local_state(user_id, None), PresenceState::Online);
+        assert!(store.update_idle(Duration::ZERO));
+        assert!(!store.update_idle(Duration::ZERO));
+        assert_eq!(store.local_state(user_id, None), PresenceState::Away);
+
+        let dnd = UserPresence {
+            user_id,
+            state: PresenceState::Dnd,
+            status_text: None,
+            status_emoji: None,
+            status_expires_at: None,
+            updated_at: "2024-01-01T00:00:00Z".to_string(),
+        };
+        assert_eq!(store.local_state(user_id, Some(&dnd)), PresenceState::Dnd);
+        assert!(store.record_activity(user_id));
+        assert_eq!(store.local_state(user_id, None), PresenceState::Online);
+    }
+
+    #[test]
+    fn missed_deltas_are_reported_as_a_gap() {
+        let store = PresenceStore::new();
+        let at = |epoch: &str, seq| FeedPosition { epoch: epoch.to_string(), seq };
+        let user = |username: &str, state| FederatedPresence {
+            username: username.to_string(),
+            status: PresenceStatus::new(state),
+        };
+
+        assert!(store.set_remote_presence("b", Some(at("e1", 3)), vec![user("alice", PresenceState::Online)]));
+        assert_eq!(
+            store.apply_remote_delta("b", at("e1", 4), vec![user("bob", PresenceState::Dnd)]),
+            DeltaOutcome::Applied { changed: true }
+        );
+        assert_eq!(store.apply_remote_delta("b", at("e1", 4), Vec::new()), DeltaOutcome::Stale);
+        assert!(!store.set_remote_presence("b", Some(at("e1", 3)), Vec::new()));
+        assert_eq!(
+            store.apply_remote_delta("b", at("e1", 6), vec![user("alice", PresenceState::Offline)]),
+            DeltaOutcome::Gap { changed: true }
+        );
+        assert!(!store.is_remote_user_online("alice@b"));
+        assert!(store.is_remote_user_online("bob@b"));
+        // A restarted peer starts a new feed that we have not seen yet
+        assert_eq!(store.apply_remote_delta("b", at("e2", 1), Vec::new()), DeltaOutcome::Gap { changed: false });
     }
 }
@@ -0,0 +1,222 @@
+use serde::Serialize;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use uuid::Uuid;
+
+use crate::{
+    config::Config,
+    domain::RetentionPolicy,
+    error::AppError,
+    federation::delivery::outbox_time,
+    storage::{DynStore, Store},
+};
+
+/// How long delivered outbox entries are kept so authors can see that their
+/// messages reached each peer.
+const DELIVERED_OUTBOX_DAYS: i64 = 7;
+
+/// The limits that apply to one channel or to DMs after falling back to the
+/// server default.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct RetentionRule {
+    pub max_age_days: Option<u32>,
+    pub max_messages: Option<u32>,
+}
+
+impl RetentionRule {
+    pub fn server_default(config: &Config) -> Self {
+        Self {
+            max_age_days: config.retention_days,
+            max_messages: config.retention_max_messages,
+        }
+    }
+
+    fn from_policy(policy: &RetentionPolicy) -> Self {
+        Self {
+            max_age_days: policy.max_age_days,
+            max_messages: policy.max_messages,
+        }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.max_age_days.is_none() && self.max_messages.is_none()
+    }
+
+    fn cutoff(&self, now: OffsetDateTime) -> Result<Option<String>, AppError> {
+        self.max_age_days
+            .map(|days| {
+                (now - time::Duration::days(i64::from(days)))
+                    .format(&Rfc3339)
+                    .map_err(|e| AppError::Internal(e.to_string()))
+            })
+            .transpose()
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct PruneReport {
+    pub channels: Vec<ChannelPruned>,
+    pub dms: usize,
+    pub total: usize,
+    /// Delivered outbox entries removed; not counted in `total`.
+    pub outbox_entries: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChannelPruned {
+    pub channel_id: Uuid,
+    pub channel_name: String,
+    pub deleted: usize,
+}
+
+/// Apply every channel's effective policy, then the DM policy, once, and
+/// forget outbox entries delivered more than `DELIVERED_OUTBOX_DAYS` ago.
+pub async fn prune(store: &dyn Store, config: &Config) -> Result<PruneReport, AppError> {
+    let policies = store.list_retention_policies().await?;
+    let default = RetentionRule::server_default(config);
+    let rule_for = |channel_id: Option<Uuid>| {
+        policies
+            .iter()
+            .find(|p| p.channel_id == channel_id)
+            .map(RetentionRule::from_policy)
+            .unwrap_or(default)
+    };
+    let now = OffsetDateTime::now_utc();
+    let mut report = PruneReport::default();
+
+    for channel in store.list_channels().await? {
+        let rule = rule_for(Some(channel.id));
+        if rule.is_unlimited() {
+            continue;
+        }
+        let deleted = store
+            .prune_messages(Some(channel.id), rule.cutoff(now)?.as_deref(), rule.max_messages)
+            .await?;
+        if deleted > 0 {
+            tracing::info!(target: "retention", "Pruned {} messages from channel '{}' ({})", deleted, channel.name, channel.id);
+            report.total += deleted;
+            report.channels.push(ChannelPruned {
+                channel_id: channel.id,
+                channel_name: channel.name,
+                deleted,
+            });
+        }
+    }
+
+    let rule = rule_for(None);
+    if !rule.is_unlimited() {
+        report.dms = store
+            .prune_messages(None, rule.cutoff(now)?.as_deref(), rule.max_messages)
+            .await?;
+        if report.dms > 0 {
+            tracing::info!(target: "retention", "Pruned {} direct messages", report.dms);
+            report.total += report.dms;
+        }
+    }
+
+    report.outbox_entries = store
+        .prune_delivered_outbox(&outbox_time(now - time::Duration::days(DELIVERED_OUTBOX_DAYS)))
+        .await?;
+    if report.outbox_entries > 0 {
+        tracing::info!(target: "retention", "Removed {} delivered outbox entries", report.outbox_entries);
+    }
+
+    Ok(report)
+}
+
+pub async fn retention_task(store: DynStore, config: Config) {
+    let interval = tokio::time::Duration::from_secs(config.retention_interval_secs);
+    loop {
+        tokio::time::sleep(interval).await;
+        match prune(store.as_ref(), &config).await {
+            Ok(report) if report.total > 0 => {
+                tracing::info!(target: "retention", "Retention pass removed {} messages", report.total);
+            }
+            Ok(_) => tracing::debug!(target: "retention", "Retention pass found nothing to remove"),
+            Err(e) => tracing::warn!(target: "retention", "Retention pass failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::{DeliveryStatus, MessageKind};
+    use crate::storage::SqliteStore;
+
+    #[tokio::test]
+    async fn channel_policy_overrides_server_default() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let bob = store.create_user("bob", true, None).await.expect("bob");
+        let keep = store.create_channel("keep", "local").await.expect("channel");
+        let trim = store.create_channel("trim", "local").await.expect("channel");
+        for i in 0..3 {
+            let sent_at = format!("2020-01-01T00:00:0{}Z", i);
+            for channel in [&keep, &trim] {
+                store
+                    .create_message(MessageKind::Channel, "x", alice.id, None, Some(channel.id), None, &sent_at)
+                    .await
+                    .expect("message");
+            }
+            store
+                .create_message(MessageKind::Dm, "x", alice.id, Some(bob.id), None, None, &sent_at)
+                .await
+                .expect("dm");
+        }
+        store.set_retention_policy(Some(keep.id), None, None).await.expect("policy");
+        store.set_retention_policy(None, None, Some(1)).await.expect("policy");
+
+        let mut config = Config::from_env().expect("config");
+        config.retention_days = Some(30);
+        config.retention_max_messages = None;
+        let report = prune(&store, &config).await.expect("prune");
+
+        assert_eq!(report.channels.len(), 1);
+        assert_eq!(report.channels[0].channel_id, trim.id);
+        assert_eq!(report.dms, 2);
+        assert_eq!(report.total, 5);
+    }
+
+    #[tokio::test]
+    async fn forgets_delivered_outbox_entries_after_a_while() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let peer = store.create_server("b", "http://b", "token-b").await.expect("server");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let message = store
+            .create_message(MessageKind::Dm, "hi", alice.id, None, None, None, "2020-01-01T00:00:00Z")
+            .await
+            .expect("message");
+        let long_ago = "2020-01-01T00:00:00.000000Z";
+        let mut old = store
+            .enqueue_outbox(peer.id, "/federation/messages", "{}", Some(message.id), long_ago)
+            .await
+            .expect("old");
+        old.status = DeliveryStatus::Delivered;
+        store.update_outbox_entry(&old).await.expect("delivered");
+        let mut recent = store
+            .enqueue_outbox(peer.id, "/federation/messages", "{}", Some(message.id), long_ago)
+            .await
+            .expect("recent");
+        recent.status = DeliveryStatus::Delivered;
+        recent.updated_at = outbox_time(OffsetDateTime::now_utc());
+        store.update_outbox_entry(&recent).await.expect("delivered");
+        let pending = store
+            .enqueue_outbox(peer.id, "/federation/messages", "{}", Some(message.id), long_ago)
+            .await
+            .expect("pending");
+
+        let mut config = Config::from_env().expect("config");
+        config.retention_days = None;
+        config.retention_max_messages = None;
+        let report = prune(&store, &config).await.expect("prune");
+
+        assert_eq!(report.outbox_entries, 1);
+        assert_eq!(report.total, 0);
+        assert!(store.get_outbox_entry(old.id).await.expect("get").is_none());
+        assert!(store.get_outbox_entry(recent.id).await.expect("get").is_some());
+        assert!(store.get_outbox_entry(pending.id).await.expect("get").is_some());
+    }
+}
@@ -0,0 +1,596 @@
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::domain::MigrationStatus;
+use crate::error::AppError;
+
+/// A single schema change. Migrations are applied in ascending `version`
+/// order, each inside its own transaction, and recorded in `schema_version`.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    apply: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+/// Every migration this binary knows about. Append new entries at the end with
+/// the next version number; never edit or reorder one that has shipped.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        apply: initial_schema,
+    },
+    Migration {
+        version: 2,
+        name: "users_display_name",
+        apply: |tx| add_column_if_missing(tx, "users", "display_name", "TEXT"),
+    },
+    Migration {
+        version: 3,
+        name: "users_password_hash",
+        apply: |tx| add_column_if_missing(tx, "users", "password_hash", "TEXT"),
+    },
+    Migration {
+        version: 4,
+        name: "messages_fts",
+        apply: messages_fts,
+    },
+    Migration {
+        version: 5,
+        name: "retention_policies",
+        apply: |tx| {
+            // `scope` is a channel id, or 'dm' for the policy covering all DMs.
+            tx.execute_batch(
+                "CREATE TABLE retention_policies (
+                    scope TEXT PRIMARY KEY,
+                    max_age_days INTEGER,
+                    max_messages INTEGER,
+                    updated_at TEXT NOT NULL
+                );",
+            )
+        },
+    },
+    Migration {
+        version: 6,
+        name: "read_markers",
+        apply: |tx| {
+            // `conversation` is a channel id, or 'dm:<other user id>'.
+            tx.execute_batch(
+                "CREATE TABLE read_markers (
+                    user_id TEXT NOT NULL,
+                    conversation TEXT NOT NULL,
+                    message_id TEXT NOT NULL,
+                    sent_at TEXT NOT NULL,
+                    updated_at TEXT NOT NULL,
+                    PRIMARY KEY(user_id, conversation)
+                );",
+            )
+        },
+    },
+    Migration {
+        version: 7,
+        name: "message_edits",
+        apply: |tx| {
+            add_column_if_missing(tx, "messages", "edited_at", "TEXT")?;
+            add_column_if_missing(tx, "messages", "deleted_at", "TEXT")?;
+            tx.execute_batch(
+                "CREATE TABLE message_edits (
+                    message_id TEXT NOT NULL,
+                    previous_body TEXT NOT NULL,
+                    edited_at TEXT NOT NULL,
+                    editor_user_id TEXT NOT NULL
+                );
+                CREATE INDEX message_edits_message ON message_edits (message_id, edited_at);",
+            )
+        },
+    },
+    Migration {
+        version: 8,
+        name: "message_reactions",
+        apply: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE message_reactions (
+                    message_id TEXT NOT NULL,
+                    user_id TEXT NOT NULL,
+                    emoji TEXT NOT NULL,
+                    created_at TEXT NOT NULL,
+                    PRIMARY KEY(message_id, user_id, emoji)
+                );",
+            )
+        },
+    },
+    Migration {
+        version: 9,
+        name: "message_threads",
+        apply: |tx| {
+            add_column_if_missing(tx, "messages", "thread_root_id", "TEXT")?;
+            tx.execute_batch("CREATE INDEX messages_thread ON messages (thread_root_id, sent_at);")
+        },
+    },
+    Migration {
+        version: 10,
+        name: "attachments",
+        apply: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE attachments (
+                    id TEXT PRIMARY KEY,
+                    hash TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    content_type TEXT NOT NULL,
+                    filename TEXT NOT NULL,
+                    uploader_user_id TEXT NOT NULL,
+                    origin_server TEXT,
+                    created_at TEXT NOT NULL
+                );
+                CREATE INDEX attachments_hash ON attachments (hash);
+                CREATE TABLE message_attachments (
+                    message_id TEXT NOT NULL,
+                    attachment_id TEXT NOT NULL,
+                    PRIMARY KEY(message_id, attachment_id)
+                );
+                CREATE INDEX message_attachments_attachment ON message_attachments (attachment_id);",
+            )
+        },
+    },
+    Migration {
+        version: 11,
+        name: "channel_pins",
+        apply: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE channel_pins (
+                    channel_id TEXT NOT NULL,
+                    message_id TEXT NOT NULL,
+                    pinned_by_user_id TEXT NOT NULL,
+                    pinned_at TEXT NOT NULL,
+                    PRIMARY KEY(channel_id, message_id)
+                );",
+            )
+        },
+    },
+    Migration {
+        version: 12,
+        name: "mentions",
+        apply: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE mentions (
+                    message_id TEXT NOT NULL,
+                    user_id TEXT NOT NULL,
+                    kind TEXT NOT NULL,
+                    sent_at TEXT NOT NULL,
+                    PRIMARY KEY(message_id, user_id)
+                );
+                CREATE INDEX mentions_user ON mentions (user_id, sent_at);",
+            )
+        },
+    },
+    Migration {
+        version: 13,
+        name: "user_presence",
+        apply: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE user_presence (
+                    user_id TEXT PRIMARY KEY,
+                    state TEXT NOT NULL,
+                    status_text TEXT,
+                    status_emoji TEXT,
+                    status_expires_at TEXT,
+                    updated_at TEXT NOT NULL
+                );",
+            )
+        },
+    },
+    Migration {
+        version: 14,
+        name: "users_last_seen",
+        apply: |tx| add_column_if_missing(tx, "users", "last_seen_at", "TEXT"),
+    },
+    Migration {
+        version: 15,
+        name: "federation_outbox",
+        apply: |tx| {
+            tx.execute_batch(
+                "CREATE TABLE federation_outbox (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    server_id TEXT NOT NULL,
+                    path TEXT NOT NULL,
+                    payload TEXT NOT NULL,
+                    message_id TEXT,
+                    status TEXT NOT NULL,
+                    attempts INTEGER NOT NULL DEFAULT 0,
+                    next_attempt_at TEXT NOT NULL,
+                    last_error TEXT,
+                    created_at TEXT NOT NULL,
+                    updated_at TEXT NOT NULL
+                );
+                CREATE INDEX federation_outbox_queue ON federation_outbox (server_id, status, id);
+                CREATE INDEX federation_outbox_message ON federation_outbox (message_id);",
+            )
+        },
+    },
+    Migration {
+        version: 16,
+        name: "servers_public_key",
+        apply: |tx| tx.execute_batch("ALTER TABLE servers ADD COLUMN public_key TEXT;"),
+    },
+    Migration {
+        version: 17,
+        name: "peering",
+        apply: |tx| {
+            tx.execute_batch(
+                "ALTER TABLE servers ADD COLUMN status TEXT NOT NULL DEFAULT 'active';
+                CREATE TABLE peering_requests (
+                    id TEXT PRIMARY KEY,
+                    direction TEXT NOT NULL,
+                    server_name TEXT NOT NULL,
+                    base_url TEXT NOT NULL,
+                    public_key TEXT NOT NULL,
+                    status TEXT NOT NULL,
+                    created_at TEXT NOT NULL,
+                    updated_at TEXT NOT NULL
+                );
+                CREATE INDEX peering_requests_server ON peering_requests (direction, server_name, status);",
+            )
+        },
+    },
+    Migration {
+        version: 18,
+        name: "server_protocol",
+        apply: |tx| {
+            tx.execute_batch(
+                "ALTER TABLE servers ADD COLUMN protocol_version TEXT;
+                ALTER TABLE servers ADD COLUMN capabilities TEXT;",
+            )
+        },
+    },
+    Migration {
+        version: 19,
+        name: "messages_author",
+        apply: |tx| tx.execute_batch("CREATE INDEX messages_author ON messages (author_user_id, sent_at);"),
+    },
+    Migration {
+        version: 20,
+        name: "messages_fts_rowid",
+        apply: messages_fts_rowid,
+    },
+    Migration {
+        version: 21,
+        name: "outbox_fixed_width_times",
+        // Outbox times are compared as text; rewrite them with a fixed six
+        // fractional digits (SQLite keeps milliseconds)
+        apply: |tx| {
+            for column in ["next_attempt_at", "created_at", "updated_at"] {
+                tx.execute(
+                    &format!(
+                        "UPDATE federation_outbox SET {column} = strftime('%Y-%m-%dT%H:%M:%f', {column}) || '000Z'
+                         WHERE strftime('%Y-%m-%dT%H:%M:%f', {column}) IS NOT NULL"
+                    ),
+                    [],
+                )?;
+            }
+            Ok(())
+        },
+    },
+];
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// Bring the database up to `latest_version()`. Fails without touching the
+/// schema if the database was written by a newer binary.
+pub fn run(conn: &mut Connection) -> Result<(), AppError> {
+    ensure_version_table(conn)?;
+
+    let current = current_version(conn)?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(AppError::Internal(format!(
+            "database schema version {} is newer than this binary supports ({}); refusing to start",
+            current, latest
+        )));
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = conn.transaction()?;
+        (migration.apply)(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (?1, ?2, ?3)",
+            params![migration.version, migration.name, now()],
+        )?;
+        tx.commit()?;
+        tracing::info!(target: "startup", "Applied schema migration {} ({})", migration.version, migration.name);
+    }
+    Ok(())
+}
+
+/// Known migrations merged with what the database has recorded. Versions the
+/// database has but this binary does not know about are included as well.
+pub fn status(conn: &Connection) -> Result<Vec<MigrationStatus>, AppError> {
+    let has_table: bool = conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'",
+            [],
+            |_| Ok(true),
+        )
+        .optional()?
+        .unwrap_or(false);
+
+    let mut applied = Vec::new();
+    if has_table {
+        let mut stmt = conn.prepare("SELECT version, name, applied_at FROM schema_version ORDER BY version")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+        })?;
+        for row in rows {
+            applied.push(row?);
+        }
+    }
+
+    let mut result: Vec<MigrationStatus> = MIGRATIONS
+        .iter()
+        .map(|m| MigrationStatus {
+            version: m.version,
+            name: m.name.to_string(),
+            applied_at: applied
+                .iter()
+                .find(|(version, _, _)| *version == m.version)
+                .map(|(_, _, at)| at.clone()),
+        })
+        .collect();
+    for (version, name, applied_at) in applied {
+        if !MIGRATIONS.iter().any(|m| m.version == version) {
+            result.push(MigrationStatus {
+                version,
+                name,
+                applied_at: Some(applied_at),
+            });
+        }
+    }
+    Ok(result)
+}
+
+fn ensure_version_table(conn: &Connection) -> Result<(), AppError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TEXT NOT NULL
+        );",
+    )?;
+    Ok(())
+}
+
+pub fn current_version(conn: &Connection) -> Result<i64, AppError> {
+    let version: Option<i64> = conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| row.get(0))?;
+    Ok(version.unwrap_or(0))
+}
+
+fn now() -> String {
+    OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default()
+}
+
+/// Databases created before versioned migrations may already have columns
+/// that were added with ad-hoc `ALTER TABLE`s, so check before adding.
+fn add_column_if_missing(tx: &Transaction, table: &str, column: &str, decl: &str) -> rusqlite::Result<()> {
+    let mut stmt = tx.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        tx.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, decl))?;
+    }
+    Ok(())
+}
+
+fn initial_schema(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS servers (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            base_url TEXT NOT NULL,
+            token TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS users (
+            id TEXT PRIMARY KEY,
+            username TEXT NOT NULL,
+            token TEXT NOT NULL,
+            server_id TEXT,
+            is_local INTEGER NOT NULL,
+            UNIQUE(username, server_id)
+        );
+        CREATE TABLE IF NOT EXISTS channels (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            origin_server TEXT NOT NULL,
+            UNIQUE(name, origin_server)
+        );
+        CREATE TABLE IF NOT EXISTS channel_members (
+            channel_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            PRIMARY KEY(channel_id, user_id)
+        );
+        CREATE TABLE IF NOT EXISTS messages (
+            id TEXT PRIMARY KEY,
+            kind TEXT NOT NULL,
+            body TEXT NOT NULL,
+            author_user_id TEXT NOT NULL,
+            recipient_user_id TEXT,
+            channel_id TEXT,
+            sent_at TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS federation_tokens (
+            id TEXT PRIMARY KEY,
+            token TEXT NOT NULL UNIQUE,
+            label TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS server_hidden_users (
+            server_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            PRIMARY KEY(server_id, user_id)
+        );
+        CREATE TABLE IF NOT EXISTS server_hidden_channels (
+            server_id TEXT NOT NULL,
+            channel_id TEXT NOT NULL,
+            PRIMARY KEY(server_id, channel_id)
+        );
+        ",
+    )
+}
+
+/// FTS5 index over `messages.body`, kept in sync by triggers so every insert
+/// path (including federated `INSERT OR IGNORE`) is covered. Replaced by
+/// `messages_fts_rowid`: the index has no way to look rows up by
+/// `message_id`, so every edit and delete scanned all of it.
+fn messages_fts(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        CREATE VIRTUAL TABLE messages_fts USING fts5(
+            body,
+            message_id UNINDEXED,
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+        INSERT INTO messages_fts (body, message_id) SELECT body, id FROM messages;
+        CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts (body, message_id) VALUES (new.body, new.id);
+        END;
+        CREATE TRIGGER messages_fts_update AFTER UPDATE OF body ON messages BEGIN
+            UPDATE messages_fts SET body = new.body WHERE message_id = old.id;
+        END;
+        CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+            DELETE FROM messages_fts WHERE message_id = old.id;
+        END;
+        ",
+    )
+}
+
+/// Give `messages` an `INTEGER PRIMARY KEY` (`seq`), which `VACUUM` keeps
+/// stable, and rebuild the FTS5 index as an external-content table keyed on
+/// it, so the triggers find a message's entry directly instead of scanning.
+fn messages_fts_rowid(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "
+        DROP TRIGGER messages_fts_insert;
+        DROP TRIGGER messages_fts_update;
+        DROP TRIGGER messages_fts_delete;
+        DROP TABLE messages_fts;
+
+        CREATE TABLE messages_new (
+            seq INTEGER PRIMARY KEY,
+            id TEXT NOT NULL UNIQUE,
+            kind TEXT NOT NULL,
+            body TEXT NOT NULL,
+            author_user_id TEXT NOT NULL,
+            recipient_user_id TEXT,
+            channel_id TEXT,
+            sent_at TEXT NOT NULL,
+            edited_at TEXT,
+            deleted_at TEXT,
+            thread_root_id TEXT
+        );
+        INSERT INTO messages_new
+            (id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id)
+        SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+        FROM messages ORDER BY rowid;
+        DROP TABLE messages;
+        ALTER TABLE messages_new RENAME TO messages;
+        CREATE INDEX messages_thread ON messages (thread_root_id, sent_at);
+        CREATE INDEX messages_author ON messages (author_user_id, sent_at);
+
+        CREATE VIRTUAL TABLE messages_fts USING fts5(
+            body,
+            content = 'messages',
+            content_rowid = 'seq',
+            tokenize = 'unicode61 remove_diacritics 2'
+        );
+        INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');
+        CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+            INSERT INTO messages_fts (rowid, body) VALUES (new.seq, new.body);
+        END;
+        CREATE TRIGGER messages_fts_update AFTER UPDATE OF body ON messages BEGIN
+            INSERT INTO messages_fts (messages_fts, rowid, body) VALUES ('delete', old.seq, old.body);
+            INSERT INTO messages_fts (rowid, body) VALUES (new.seq, new.body);
+        END;
+        CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+            INSERT INTO messages_fts (messages_fts, rowid, body) VALUES ('delete', old.seq, old.body);
+        END;
+        ",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_is_idempotent_and_records_versions() {
+        let mut conn = Connection::open_in_memory().expect("conn");
+        run(&mut conn).expect("first run");
+        run(&mut conn).expect("second run");
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        assert!(status(&conn).unwrap().iter().all(|m| m.applied_at.is_some()));
+    }
+
+    #[test]
+    fn upgrades_legacy_database_with_existing_columns() {
+        let mut conn = Connection::open_in_memory().expect("conn");
+        conn.execute_batch(
+            "CREATE TABLE users (
+                id TEXT PRIMARY KEY, username TEXT NOT NULL, token TEXT NOT NULL,
+                server_id TEXT, is_local INTEGER NOT NULL, display_name TEXT,
+                UNIQUE(username, server_id)
+            );",
+        )
+        .expect("legacy schema");
+        run(&mut conn).expect("migrate");
+        conn.execute(
+            "INSERT INTO users (id, username, token, is_local, display_name, password_hash) VALUES ('1', 'a', '', 1, 'A', 'h')",
+            [],
+        )
+        .expect("both columns present");
+    }
+
+    #[test]
+    fn refuses_newer_database() {
+        let mut conn = Connection::open_in_memory().expect("conn");
+        run(&mut conn).expect("migrate");
+        conn.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (?1, 'future', '')",
+            params![latest_version() + 1],
+        )
+        .unwrap();
+        assert!(run(&mut conn).is_err());
+        assert_eq!(status(&conn).unwrap().last().unwrap().name, "future");
+    }
+
+    #[test]
+    fn rewrites_outbox_times_to_a_fixed_width() {
+        let mut conn = Connection::open_in_memory().expect("conn");
+        run(&mut conn).expect("migrate");
+        conn.execute_batch(
+            "INSERT INTO federation_outbox
+                (server_id, path, payload, status, attempts, next_attempt_at, created_at, updated_at)
+             VALUES ('s', '/p', '', 'pending', 1, '2024-01-01T00:00:02.5Z', '2024-01-01T00:00:00Z', '2024-01-01T02:00:00.123456789+02:00');
+             DELETE FROM schema_version WHERE version = 21;",
+        )
+        .expect("old times");
+        run(&mut conn).expect("migrate again");
+        let times: (String, String, String) = conn
+            .query_row("SELECT next_attempt_at, created_at, updated_at FROM federation_outbox", [], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .expect("row");
+        assert_eq!(
+            times,
+            (
+                "2024-01-01T00:00:02.500000Z".to_string(),
+                "2024-01-01T00:00:00.000000Z".to_string(),
+                "2024-01-01T00:00:00.123000Z".to_string()
+            )
+        );
+    }
+}
@@ -1,3 +1,388 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::config::{Config, StorageBackend};
+use crate::domain::{
+    Attachment, Channel, ChannelPin, ConversationRef, DeliveryStatus, FederationToken, HistoryPage, Mention, Message,
+    MessageCursor, MessageDelivery, MessageEdit, MessageKind, MessageSearch, MigrationStatus, OutboxEntry, PeerStatus,
+    PeeringDirection, PeeringRequest, PeeringStatus, ReactionCount, ReadMarker, RetentionPolicy, SearchHit, Server, ThreadSummary, UnreadCount, User, UserPresence,
+};
+use crate::error::AppError;
+
+pub mod migrations;
+#[cfg(feature = "postgres")]
+pub mod postgres;
 pub mod sqlite;
 
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresStore;
 pub use sqlite::SqliteStore;
+
+/// Shared handle to whichever backend `Config` selected.
+pub type DynStore = Arc<dyn Store>;
+
+/// Everything the server persists. Handlers only see this trait, so a backend
+/// is swapped by changing `STORAGE_BACKEND` rather than touching call sites.
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Apply any pending schema migrations.
+    async fn init(&self) -> Result<(), AppError>;
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, AppError>;
+
+    /// Write a consistent snapshot of the live database to `dest`. Only the
+    /// SQLite backends support this; PostgreSQL deployments should use `pg_dump`.
+    async fn backup_to(&self, _dest: &Path) -> Result<(), AppError> {
+        Err(AppError::BadRequest(
+            "online backup is only supported by the SQLite storage backend".to_string(),
+        ))
+    }
+
+    async fn create_server(&self, name: &str, base_url: &str, token: &str) -> Result<Server, AppError>;
+    async fn get_server_by_name(&self, name: &str) -> Result<Option<Server>, AppError>;
+    async fn get_server_by_id(&self, id: &Uuid) -> Result<Option<Server>, AppError>;
+    async fn get_server_by_token(&self, token: &str) -> Result<Option<Server>, AppError>;
+    async fn list_servers(&self) -> Result<Vec<Server>, AppError>;
+    async fn update_server(&self, id: &Uuid, name: &str, base_url: &str, token: &str) -> Result<Server, AppError>;
+    async fn delete_server(&self, id: &Uuid) -> Result<(), AppError>;
+    /// Remember the public key a peer signs its requests with. Changing the
+    /// peer's `base_url` with `update_server` forgets it again.
+    async fn set_server_public_key(&self, id: &Uuid, public_key: &str) -> Result<(), AppError>;
+    async fn set_server_status(&self, id: &Uuid, status: PeerStatus) -> Result<(), AppError>;
+    /// Cache what the peer's discovery document announced. `capabilities`
+    /// is `None` for peers that predate them.
+    async fn set_server_protocol(
+        &self,
+        id: &Uuid,
+        protocol_version: &str,
+        capabilities: Option<&[String]>,
+    ) -> Result<(), AppError>;
+
+    /// Store a peering request. A pending request in the same direction
+    /// with the same server is replaced. With `max_pending`, nothing is
+    /// stored and `false` is returned if that many requests in the same
+    /// direction are already pending from other servers; the count and the
+    /// insert are one atomic step.
+    async fn save_peering_request(&self, request: &PeeringRequest, max_pending: Option<usize>) -> Result<bool, AppError>;
+    async fn get_peering_request(&self, id: Uuid) -> Result<Option<PeeringRequest>, AppError>;
+    async fn get_pending_peering_request(
+        &self,
+        direction: PeeringDirection,
+        server_name: &str,
+    ) -> Result<Option<PeeringRequest>, AppError>;
+    /// Newest first.
+    async fn list_peering_requests(&self) -> Result<Vec<PeeringRequest>, AppError>;
+    async fn set_peering_request_status(&self, id: Uuid, status: PeeringStatus, now: &str) -> Result<(), AppError>;
+
+    async fn ensure_server(&self, name: &str, base_url: &str, token: &str) -> Result<Server, AppError> {
+        if let Some(existing) = self.get_server_by_name(name).await? {
+            return Ok(existing);
+        }
+        self.create_server(name, base_url, token).await
+    }
+
+    async fn seed_initial_data(&self, server_name: &str) -> Result<(), AppError> {
+        tracing::info!("Seeding federation references for server '{}'", server_name);
+
+        // Create federation references to other servers if not present
+        let other_servers = match server_name {
+            "a" => vec![
+                ("b", "http://server_b:8080", "token-b"),
+                ("c", "http://server_c:8080", "token-c"),
+            ],
+            "b" => vec![
+                ("a", "http://server_a:8080", "token-a"),
+                ("c", "http://server_c:8080", "token-c"),
+            ],
+            "c" => vec![
+                ("a", "http://server_a:8080", "token-a"),
+                ("b", "http://server_b:8080", "token-b"),
+            ],
+            _ => vec![],
+        };
+
+        for (name, url, token) in other_servers {
+            self.ensure_server(name, url, token).await?;
+            tracing::debug!("Federation reference to server '{}' ensured", name);
+        }
+
+        Ok(())
+    }
+
+    async fn create_user_with_password(
+        &self,
+        username: &str,
+        is_local: bool,
+        server_id: Option<Uuid>,
+        password_hash: Option<&str>,
+    ) -> Result<User, AppError>;
+    async fn list_users(&self) -> Result<Vec<User>, AppError>;
+    async fn get_user_by_token(&self, token: &str) -> Result<Option<User>, AppError>;
+    async fn get_user_by_name_and_server(&self, username: &str, server_id: Option<Uuid>) -> Result<Option<User>, AppError>;
+    async fn get_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, AppError>;
+    async fn update_user(&self, id: &Uuid, username: &str, display_name: Option<&str>) -> Result<User, AppError>;
+    async fn update_user_display_name(&self, id: &Uuid, display_name: Option<&str>) -> Result<(), AppError>;
+    /// Record when the user was last seen. Returns `false`, changing nothing,
+    /// unless `at` is later than the time already stored.
+    async fn update_last_seen(&self, id: &Uuid, at: &str) -> Result<bool, AppError>;
+    async fn set_user_password(&self, user_id: &Uuid, hash: &str) -> Result<(), AppError>;
+    async fn get_user_password_hash(&self, user_id: &Uuid) -> Result<Option<String>, AppError>;
+    async fn delete_user(&self, id: &Uuid) -> Result<(), AppError>;
+
+    async fn create_user(&self, username: &str, is_local: bool, server_id: Option<Uuid>) -> Result<User, AppError> {
+        self.create_user_with_password(username, is_local, server_id, None).await
+    }
+
+    async fn create_channel(&self, name: &str, origin_server: &str) -> Result<Channel, AppError>;
+    async fn list_channels(&self) -> Result<Vec<Channel>, AppError>;
+    async fn get_channel_by_name_origin(&self, name: &str, origin_server: &str) -> Result<Option<Channel>, AppError>;
+    async fn get_channel_by_id(&self, id: Uuid) -> Result<Option<Channel>, AppError>;
+    async fn update_channel(&self, id: &Uuid, name: &str) -> Result<Channel, AppError>;
+    async fn delete_channel(&self, id: &Uuid) -> Result<(), AppError>;
+    async fn add_channel_member(&self, channel_id: Uuid, user_id: Uuid) -> Result<(), AppError>;
+    async fn remove_channel_member(&self, channel_id: Uuid, user_id: Uuid) -> Result<(), AppError>;
+    async fn list_channel_member_servers(&self, channel_id: Uuid) -> Result<Vec<Server>, AppError>;
+    async fn is_channel_member(&self, channel_id: Uuid, user_id: Uuid) -> Result<bool, AppError>;
+    async fn list_channel_members(&self, channel_id: Uuid) -> Result<Vec<User>, AppError>;
+    /// Channels the user is a member of.
+    async fn list_channels_for_user(&self, user_id: Uuid) -> Result<Vec<Channel>, AppError>;
+
+    #[allow(clippy::too_many_arguments)]
+    async fn create_message(
+        &self,
+        kind: MessageKind,
+        body: &str,
+        author_user_id: Uuid,
+        recipient_user_id: Option<Uuid>,
+        channel_id: Option<Uuid>,
+        thread_root_id: Option<Uuid>,
+        sent_at: &str,
+    ) -> Result<Message, AppError>;
+    /// Insert a message under a caller-supplied id. Returns `None` if a
+    /// message with that id already exists, which makes federation retries safe.
+    #[allow(clippy::too_many_arguments)]
+    async fn create_message_with_id(
+        &self,
+        id_str: &str,
+        kind: MessageKind,
+        body: &str,
+        author_user_id: Uuid,
+        recipient_user_id: Option<Uuid>,
+        channel_id: Option<Uuid>,
+        thread_root_id: Option<Uuid>,
+        sent_at: &str,
+    ) -> Result<Option<Message>, AppError>;
+    async fn list_messages_for_user(&self, user_id: Uuid, limit: usize) -> Result<Vec<Message>, AppError>;
+    async fn get_message_by_id(&self, id: Uuid) -> Result<Option<Message>, AppError>;
+    /// Up to `limit` messages from one page of a channel, oldest first.
+    /// Thread replies are left out; see `list_thread_messages`.
+    async fn list_channel_messages(&self, channel_id: Uuid, page: &HistoryPage, limit: usize) -> Result<Vec<Message>, AppError>;
+    /// Up to `limit` messages from one page of a DM conversation, oldest first.
+    async fn list_dm_messages(
+        &self,
+        user_id: Uuid,
+        other_user_id: Uuid,
+        page: &HistoryPage,
+        limit: usize,
+    ) -> Result<Vec<Message>, AppError>;
+    /// Up to `limit` messages the user wrote, from one page across all
+    /// channels, threads and DMs, oldest first.
+    async fn list_messages_by_author(&self, user_id: Uuid, page: &HistoryPage, limit: usize) -> Result<Vec<Message>, AppError>;
+    /// Users the user has sent DMs to or received DMs from.
+    async fn list_dm_partner_ids(&self, user_id: Uuid) -> Result<Vec<Uuid>, AppError>;
+    /// Up to `limit` replies from one page of a thread, oldest first.
+    async fn list_thread_messages(&self, root_id: Uuid, page: &HistoryPage, limit: usize) -> Result<Vec<Message>, AppError>;
+    /// Summaries for those of the given messages that have replies.
+    async fn thread_summaries(&self, root_ids: &[Uuid]) -> Result<Vec<ThreadSummary>, AppError>;
+    /// Threads in a channel, most recently replied to first.
+    async fn list_active_threads(&self, channel_id: Uuid, limit: usize) -> Result<Vec<ThreadSummary>, AppError>;
+    /// Replace a message body, keeping the previous body in its edit history.
+    /// Returns `None` if the message is missing or deleted, or already has an
+    /// edit at or after `edited_at`, so replayed federated edits are no-ops.
+    async fn edit_message(
+        &self,
+        id: Uuid,
+        body: &str,
+        editor_user_id: Uuid,
+        edited_at: &str,
+    ) -> Result<Option<Message>, AppError>;
+    /// Turn a message into a tombstone: the body and edit history are erased
+    /// and `deleted_at` is set. Returns `None` if it is missing or already deleted.
+    async fn delete_message(&self, id: Uuid, deleted_at: &str) -> Result<Option<Message>, AppError>;
+    /// Superseded versions of a message, oldest first.
+    async fn list_message_edits(&self, id: Uuid) -> Result<Vec<MessageEdit>, AppError>;
+    /// Record a reaction. Returns `false` if the user already reacted with that emoji.
+    async fn add_reaction(&self, message_id: Uuid, user_id: Uuid, emoji: &str) -> Result<bool, AppError>;
+    /// Returns `false` if there was no such reaction.
+    async fn remove_reaction(&self, message_id: Uuid, user_id: Uuid, emoji: &str) -> Result<bool, AppError>;
+    /// Reaction totals for the given messages, in the order each emoji was
+    /// first used on a message. `reacted` is relative to `viewer_id`.
+    async fn reaction_counts(&self, message_ids: &[Uuid], viewer_id: Uuid) -> Result<Vec<ReactionCount>, AppError>;
+    /// Pin a channel message. Returns `false` if it was already pinned.
+    async fn pin_message(&self, pin: &ChannelPin) -> Result<bool, AppError>;
+    /// Returns `false` if the message was not pinned.
+    async fn unpin_message(&self, channel_id: Uuid, message_id: Uuid) -> Result<bool, AppError>;
+    /// Pins in a channel, most recent first.
+    async fn list_channel_pins(&self, channel_id: Uuid) -> Result<Vec<ChannelPin>, AppError>;
+    /// Record who a message mentions. Existing mentions are left alone.
+    async fn add_mentions(&self, mentions: &[Mention]) -> Result<(), AppError>;
+    /// Mentions of the user in messages that still exist, newest first.
+    async fn list_mentions(&self, user_id: Uuid, limit: usize) -> Result<Vec<Mention>, AppError>;
+    /// The presence state and custom status a local user chose, if any.
+    async fn get_user_presence(&self, user_id: Uuid) -> Result<Option<UserPresence>, AppError>;
+    async fn set_user_presence(&self, presence: &UserPresence) -> Result<(), AppError>;
+    async fn list_user_presence(&self) -> Result<Vec<UserPresence>, AppError>;
+    /// Returns `false` if an attachment with that id already exists.
+    async fn create_attachment(&self, attachment: &Attachment) -> Result<bool, AppError>;
+    async fn get_attachment(&self, id: Uuid) -> Result<Option<Attachment>, AppError>;
+    /// Link attachments to a message. Existing links are left alone.
+    async fn attach_to_message(&self, message_id: Uuid, attachment_ids: &[Uuid]) -> Result<(), AppError>;
+    /// Attachments of the given messages as `(message_id, attachment)` pairs,
+    /// in the order they were uploaded.
+    async fn list_message_attachments(&self, message_ids: &[Uuid]) -> Result<Vec<(Uuid, Attachment)>, AppError>;
+    /// Messages an attachment is linked to.
+    async fn list_attachment_message_ids(&self, attachment_id: Uuid) -> Result<Vec<Uuid>, AppError>;
+    async fn has_attachment_with_hash(&self, hash: &str) -> Result<bool, AppError>;
+    /// Delete attachments created before `created_before` that no message
+    /// links to. Returns the number deleted.
+    async fn delete_orphaned_attachments(&self, created_before: &str) -> Result<usize, AppError>;
+    /// Every blob hash still referenced by an attachment.
+    async fn list_attachment_hashes(&self) -> Result<Vec<String>, AppError>;
+    /// Full-text search over message bodies, newest first. Only DMs the user
+    /// sent or received and messages in channels they belong to are returned.
+    async fn search_messages(&self, user_id: Uuid, search: &MessageSearch) -> Result<Vec<SearchHit>, AppError>;
+
+    async fn list_retention_policies(&self) -> Result<Vec<RetentionPolicy>, AppError>;
+    /// Create or replace the policy for a channel, or for DMs when `channel_id` is `None`.
+    async fn set_retention_policy(
+        &self,
+        channel_id: Option<Uuid>,
+        max_age_days: Option<u32>,
+        max_messages: Option<u32>,
+    ) -> Result<RetentionPolicy, AppError>;
+    async fn delete_retention_policy(&self, channel_id: Option<Uuid>) -> Result<(), AppError>;
+    /// Delete messages in a channel (or all DM conversations when `channel_id`
+    /// is `None`) sent before `older_than`, and all but the newest `keep_last`
+    /// per conversation. In channels only thread roots count towards
+    /// `keep_last`, and a pruned root takes its replies with it. Returns the
+    /// number of messages removed.
+    async fn prune_messages(
+        &self,
+        channel_id: Option<Uuid>,
+        older_than: Option<&str>,
+        keep_last: Option<u32>,
+    ) -> Result<usize, AppError>;
+
+    /// Move the user's read marker in a conversation forward to `cursor`.
+    /// Positions behind the stored marker are ignored; the marker in effect
+    /// afterwards is returned either way.
+    async fn advance_read_marker(
+        &self,
+        user_id: Uuid,
+        conversation: ConversationRef,
+        cursor: &MessageCursor,
+    ) -> Result<ReadMarker, AppError>;
+    /// Unread counts for every channel the user belongs to and every DM
+    /// conversation, skipping the user's own messages. Mentions count the
+    /// unread messages with a recorded mention of the user. Conversations
+    /// with nothing unread are omitted.
+    async fn unread_counts(&self, user_id: Uuid) -> Result<Vec<UnreadCount>, AppError>;
+
+    async fn create_federation_token(&self, label: &str) -> Result<FederationToken, AppError>;
+    async fn list_federation_tokens(&self) -> Result<Vec<FederationToken>, AppError>;
+    async fn delete_federation_token(&self, id: &Uuid) -> Result<(), AppError>;
+    async fn is_valid_federation_token(&self, token: &str) -> Result<bool, AppError>;
+
+    /// Queue a federation request for a peer. `now` (RFC3339) is when it is
+    /// first due.
+    async fn enqueue_outbox(
+        &self,
+        server_id: Uuid,
+        path: &str,
+        payload: &str,
+        message_id: Option<Uuid>,
+        now: &str,
+    ) -> Result<OutboxEntry, AppError>;
+    /// The oldest pending entry of each peer, for peers whose oldest entry is
+    /// due by `now`.
+    async fn due_outbox_entries(&self, now: &str) -> Result<Vec<OutboxEntry>, AppError>;
+    /// Store the outcome of a delivery attempt. Delivered entries lose their
+    /// payload, and are removed unless they carry a message.
+    async fn update_outbox_entry(&self, entry: &OutboxEntry) -> Result<(), AppError>;
+    async fn get_outbox_entry(&self, id: i64) -> Result<Option<OutboxEntry>, AppError>;
+    /// Entries in `status`, oldest first.
+    async fn list_outbox_entries(&self, status: DeliveryStatus, limit: usize) -> Result<Vec<OutboxEntry>, AppError>;
+    /// Delivery of the given messages to each peer they were queued for.
+    async fn message_deliveries(&self, message_ids: &[Uuid]) -> Result<Vec<MessageDelivery>, AppError>;
+    /// Remove delivered entries last updated before `older_than`. Returns
+    /// the number removed.
+    async fn prune_delivered_outbox(&self, older_than: &str) -> Result<usize, AppError>;
+
+    async fn get_hidden_user_ids(&self, server_id: Uuid) -> Result<Vec<String>, AppError>;
+    async fn get_hidden_channel_ids(&self, server_id: Uuid) -> Result<Vec<String>, AppError>;
+    async fn set_hidden_users(&self, server_id: Uuid, user_ids: &[String]) -> Result<(), AppError>;
+    async fn set_hidden_channels(&self, server_id: Uuid, channel_ids: &[String]) -> Result<(), AppError>;
+}
+
+/// Backends mark matches with these control characters so the excerpt can be
+/// HTML-escaped before the markers are turned into `<mark>` tags.
+pub(crate) const SNIPPET_START: char = '\u{2}';
+pub(crate) const SNIPPET_END: char = '\u{3}';
+
+pub(crate) fn render_snippet(raw: &str) -> String {
+    let mut out = String::with_capacity(raw.len() + 16);
+    for c in raw.chars() {
+        match c {
+            SNIPPET_START => out.push_str("<mark>"),
+            SNIPPET_END => out.push_str("</mark>"),
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Key under which a read marker is stored: the channel id, or `dm:<user id>`.
+pub(crate) fn conversation_key(conversation: ConversationRef) -> String {
+    match conversation {
+        ConversationRef::Channel(id) => id.to_string(),
+        ConversationRef::Dm(id) => format!("dm:{}", id),
+    }
+}
+
+pub(crate) fn parse_conversation_key(key: &str) -> Option<ConversationRef> {
+    match key.strip_prefix("dm:") {
+        Some(id) => Uuid::parse_str(id).ok().map(ConversationRef::Dm),
+        None => Uuid::parse_str(key).ok().map(ConversationRef::Channel),
+    }
+}
+
+/// Open the backend selected by `config.storage_backend`. Migrations are not
+/// run here; call `Store::init` once the handle exists.
+pub fn open(config: &Config) -> Result<DynStore, AppError> {
+    match config.storage_backend {
+        StorageBackend::Sqlite => Ok(Arc::new(SqliteStore::with_pool_size(
+            &config.database_path,
+            config.database_pool_size,
+        )?)),
+        StorageBackend::Memory => Ok(Arc::new(SqliteStore::in_memory()?)),
+        #[cfg(feature = "postgres")]
+        StorageBackend::Postgres => {
+            let url = config
+                .database_url
+                .as_deref()
+                .ok_or_else(|| AppError::Internal("STORAGE_BACKEND=postgres requires DATABASE_URL".to_string()))?;
+            Ok(Arc::new(PostgresStore::connect(url, config.database_pool_size)?))
+        }
+        #[cfg(not(feature = "postgres"))]
+        StorageBackend::Postgres => Err(AppError::Internal(
+            "this binary was built without the `postgres` feature".to_string(),
+        )),
+    }
+}
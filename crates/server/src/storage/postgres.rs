@@ -0,0 +1,2513 @@
+use crate::domain::{
+    Attachment, Channel, ChannelPin, ConversationRef, DeliveryStatus, FederationToken, HistoryPage, Mention, MentionKind, Message,
+    MessageCursor, MessageDelivery, MessageEdit, MessageKind, MessageSearch, MigrationStatus, OutboxEntry, PeerStatus,
+    PeeringDirection, PeeringRequest, PeeringStatus, PresenceState, ReactionCount, ReadMarker, RetentionPolicy, SearchHit, Server, ThreadSummary, UnreadCount, User, UserPresence,
+};
+use crate::error::AppError;
+use crate::storage::{
+    conversation_key, parse_conversation_key, render_snippet, Store, SNIPPET_END, SNIPPET_START,
+};
+use async_trait::async_trait;
+use deadpool_postgres::{Manager, ManagerConfig, Object, Pool, RecyclingMethod};
+use tokio_postgres::{NoTls, Row};
+use uuid::Uuid;
+
+/// Schema migrations for the PostgreSQL backend. Version numbers track the
+/// SQLite list in `storage::migrations` so both backends report the same
+/// schema version for the same feature set.
+const MIGRATIONS: &[(i64, &str, &str)] = &[
+    (
+        1,
+        "initial_schema",
+        "
+        CREATE TABLE IF NOT EXISTS servers (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            base_url TEXT NOT NULL,
+            token TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS users (
+            id TEXT PRIMARY KEY,
+            username TEXT NOT NULL,
+            token TEXT NOT NULL,
+            server_id TEXT,
+            is_local BOOLEAN NOT NULL,
+            UNIQUE(username, server_id)
+        );
+        CREATE TABLE IF NOT EXISTS channels (
+            id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            origin_server TEXT NOT NULL,
+            UNIQUE(name, origin_server)
+        );
+        CREATE TABLE IF NOT EXISTS channel_members (
+            channel_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            PRIMARY KEY(channel_id, user_id)
+        );
+        CREATE TABLE IF NOT EXISTS messages (
+            id TEXT PRIMARY KEY,
+            kind TEXT NOT NULL,
+            body TEXT NOT NULL,
+            author_user_id TEXT NOT NULL,
+            recipient_user_id TEXT,
+            channel_id TEXT,
+            sent_at TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS federation_tokens (
+            id TEXT PRIMARY KEY,
+            token TEXT NOT NULL UNIQUE,
+            label TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS server_hidden_users (
+            server_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            PRIMARY KEY(server_id, user_id)
+        );
+        CREATE TABLE IF NOT EXISTS server_hidden_channels (
+            server_id TEXT NOT NULL,
+            channel_id TEXT NOT NULL,
+            PRIMARY KEY(server_id, channel_id)
+        );
+        ",
+    ),
+    (2, "users_display_name", "ALTER TABLE users ADD COLUMN IF NOT EXISTS display_name TEXT;"),
+    (3, "users_password_hash", "ALTER TABLE users ADD COLUMN IF NOT EXISTS password_hash TEXT;"),
+    (
+        4,
+        "messages_fts",
+        "CREATE INDEX IF NOT EXISTS messages_body_fts ON messages USING GIN (to_tsvector('simple', body));",
+    ),
+    (
+        5,
+        "retention_policies",
+        "CREATE TABLE IF NOT EXISTS retention_policies (
+            scope TEXT PRIMARY KEY,
+            max_age_days BIGINT,
+            max_messages BIGINT,
+            updated_at TEXT NOT NULL
+        );",
+    ),
+    (
+        6,
+        "read_markers",
+        "CREATE TABLE IF NOT EXISTS read_markers (
+            user_id TEXT NOT NULL,
+            conversation TEXT NOT NULL,
+            message_id TEXT NOT NULL,
+            sent_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL,
+            PRIMARY KEY(user_id, conversation)
+        );",
+    ),
+    (
+        7,
+        "message_edits",
+        "ALTER TABLE messages ADD COLUMN IF NOT EXISTS edited_at TEXT;
+        ALTER TABLE messages ADD COLUMN IF NOT EXISTS deleted_at TEXT;
+        CREATE TABLE IF NOT EXISTS message_edits (
+            message_id TEXT NOT NULL,
+            previous_body TEXT NOT NULL,
+            edited_at TEXT NOT NULL,
+            editor_user_id TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS message_edits_message ON message_edits (message_id, edited_at);",
+    ),
+    (
+        8,
+        "message_reactions",
+        "CREATE TABLE IF NOT EXISTS message_reactions (
+            message_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            emoji TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            PRIMARY KEY(message_id, user_id, emoji)
+        );",
+    ),
+    (
+        9,
+        "message_threads",
+        "ALTER TABLE messages ADD COLUMN IF NOT EXISTS thread_root_id TEXT;
+        CREATE INDEX IF NOT EXISTS messages_thread ON messages (thread_root_id, sent_at);",
+    ),
+    (
+        10,
+        "attachments",
+        "CREATE TABLE IF NOT EXISTS attachments (
+            id TEXT PRIMARY KEY,
+            hash TEXT NOT NULL,
+            size BIGINT NOT NULL,
+            content_type TEXT NOT NULL,
+            filename TEXT NOT NULL,
+            uploader_user_id TEXT NOT NULL,
+            origin_server TEXT,
+            created_at TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS attachments_hash ON attachments (hash);
+        CREATE TABLE IF NOT EXISTS message_attachments (
+            message_id TEXT NOT NULL,
+            attachment_id TEXT NOT NULL,
+            PRIMARY KEY(message_id, attachment_id)
+        );
+        CREATE INDEX IF NOT EXISTS message_attachments_attachment ON message_attachments (attachment_id);",
+    ),
+    (
+        11,
+        "channel_pins",
+        "CREATE TABLE IF NOT EXISTS channel_pins (
+            channel_id TEXT NOT NULL,
+            message_id TEXT NOT NULL,
+            pinned_by_user_id TEXT NOT NULL,
+            pinned_at TEXT NOT NULL,
+            PRIMARY KEY(channel_id, message_id)
+        );",
+    ),
+    (
+        12,
+        "mentions",
+        "CREATE TABLE IF NOT EXISTS mentions (
+            message_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            sent_at TEXT NOT NULL,
+            PRIMARY KEY(message_id, user_id)
+        );
+        CREATE INDEX IF NOT EXISTS mentions_user ON mentions (user_id, sent_at);",
+    ),
+    (
+        13,
+        "user_presence",
+        "CREATE TABLE IF NOT EXISTS user_presence (
+            user_id TEXT PRIMARY KEY,
+            state TEXT NOT NULL,
+            status_text TEXT,
+            status_emoji TEXT,
+            status_expires_at TEXT,
+            updated_at TEXT NOT NULL
+        );",
+    ),
+    (14, "users_last_seen", "ALTER TABLE users ADD COLUMN IF NOT EXISTS last_seen_at TEXT;"),
+    (
+        15,
+        "federation_outbox",
+        "CREATE TABLE IF NOT EXISTS federation_outbox (
+            id BIGSERIAL PRIMARY KEY,
+            server_id TEXT NOT NULL,
+            path TEXT NOT NULL,
+            payload TEXT NOT NULL,
+            message_id TEXT,
+            status TEXT NOT NULL,
+            attempts INTEGER NOT NULL DEFAULT 0,
+            next_attempt_at TEXT NOT NULL,
+            last_error TEXT,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS federation_outbox_queue ON federation_outbox (server_id, status, id);
+        CREATE INDEX IF NOT EXISTS federation_outbox_message ON federation_outbox (message_id);",
+    ),
+    (16, "servers_public_key", "ALTER TABLE servers ADD COLUMN IF NOT EXISTS public_key TEXT;"),
+    (
+        17,
+        "peering",
+        "ALTER TABLE servers ADD COLUMN IF NOT EXISTS status TEXT NOT NULL DEFAULT 'active';
+        CREATE TABLE IF NOT EXISTS peering_requests (
+            id TEXT PRIMARY KEY,
+            direction TEXT NOT NULL,
+            server_name TEXT NOT NULL,
+            base_url TEXT NOT NULL,
+            public_key TEXT NOT NULL,
+            status TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS peering_requests_server ON peering_requests (direction, server_name, status);",
+    ),
+    (
+        18,
+        "server_protocol",
+        "ALTER TABLE servers ADD COLUMN IF NOT EXISTS protocol_version TEXT;
+        ALTER TABLE servers ADD COLUMN IF NOT EXISTS capabilities TEXT;",
+    ),
+    (
+        19,
+        "messages_author",
+        "CREATE INDEX IF NOT EXISTS messages_author ON messages (author_user_id, sent_at);",
+    ),
+    (
+        20,
+        "outbox_fixed_width_times",
+        "UPDATE federation_outbox SET
+            next_attempt_at = to_char(next_attempt_at::timestamptz AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'),
+            created_at = to_char(created_at::timestamptz AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"'),
+            updated_at = to_char(updated_at::timestamptz AT TIME ZONE 'UTC', 'YYYY-MM-DD\"T\"HH24:MI:SS.US\"Z\"');",
+    ),
+];
+
+/// PostgreSQL-backed store for larger deployments, on a deadpool of async
+/// `tokio-postgres` clients.
+#[derive(Clone)]
+pub struct PostgresStore {
+    pool: Pool,
+}
+
+impl PostgresStore {
+    /// Connections are opened lazily, so a bad URL or unreachable server
+    /// surfaces on the first call (normally `init`).
+    pub fn connect(url: &str, pool_size: u32) -> Result<Self, AppError> {
+        let config: tokio_postgres::Config = url
+            .parse()
+            .map_err(|e| AppError::Internal(format!("invalid DATABASE_URL: {}", e)))?;
+        let manager = Manager::from_config(
+            config,
+            NoTls,
+            ManagerConfig {
+                recycling_method: RecyclingMethod::Fast,
+            },
+        );
+        let pool = Pool::builder(manager)
+            .max_size(pool_size.max(1) as usize)
+            .build()
+            .map_err(|e| AppError::Internal(format!("postgres pool: {}", e)))?;
+        Ok(Self { pool })
+    }
+
+    async fn conn(&self) -> Result<Object, AppError> {
+        Ok(self.pool.get().await?)
+    }
+}
+
+#[async_trait]
+impl Store for PostgresStore {
+    async fn init(&self) -> Result<(), AppError> {
+        let mut conn = self.conn().await?;
+        conn.batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_version (
+                version BIGINT PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at TEXT NOT NULL
+            );",
+        )
+        .await?;
+        let current: i64 = conn
+            .query_one("SELECT COALESCE(MAX(version), 0) FROM schema_version", &[])
+            .await?
+            .get(0);
+        let latest = MIGRATIONS.last().map(|m| m.0).unwrap_or(0);
+        if current > latest {
+            return Err(AppError::Internal(format!(
+                "database schema version {} is newer than this binary supports ({}); refusing to start",
+                current, latest
+            )));
+        }
+        for (version, name, sql) in MIGRATIONS.iter().filter(|m| m.0 > current) {
+            let tx = conn.transaction().await?;
+            tx.batch_execute(sql).await?;
+            tx.execute(
+                "INSERT INTO schema_version (version, name, applied_at) VALUES ($1, $2, $3)",
+                &[version, name, &now()],
+            )
+            .await?;
+            tx.commit().await?;
+            tracing::info!(target: "startup", "Applied schema migration {} ({})", version, name);
+        }
+        Ok(())
+    }
+
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, AppError> {
+        let conn = self.conn().await?;
+        let has_table: bool = conn
+            .query_one("SELECT to_regclass('schema_version') IS NOT NULL", &[])
+            .await?
+            .get(0);
+        let applied: Vec<(i64, String, String)> = if has_table {
+            conn.query("SELECT version, name, applied_at FROM schema_version ORDER BY version", &[])
+                .await?
+                .iter()
+                .map(|row| (row.get(0), row.get(1), row.get(2)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let mut result: Vec<MigrationStatus> = MIGRATIONS
+            .iter()
+            .map(|(version, name, _)| MigrationStatus {
+                version: *version,
+                name: name.to_string(),
+                applied_at: applied.iter().find(|a| a.0 == *version).map(|a| a.2.clone()),
+            })
+            .collect();
+        for (version, name, applied_at) in applied {
+            if !MIGRATIONS.iter().any(|m| m.0 == version) {
+                result.push(MigrationStatus {
+                    version,
+                    name,
+                    applied_at: Some(applied_at),
+                });
+            }
+        }
+        Ok(result)
+    }
+
+    async fn create_server(&self, name: &str, base_url: &str, token: &str) -> Result<Server, AppError> {
+        let server = Server {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            base_url: base_url.to_string(),
+            token: token.to_string(),
+            public_key: None,
+            status: PeerStatus::Active,
+            protocol_version: None,
+            capabilities: None,
+        };
+        self.conn()
+            .await?
+            .execute(
+                "INSERT INTO servers (id, name, base_url, token) VALUES ($1, $2, $3, $4)",
+                &[&server.id.to_string(), &server.name, &server.base_url, &server.token],
+            )
+            .await?;
+        Ok(server)
+    }
+
+    async fn get_server_by_name(&self, name: &str) -> Result<Option<Server>, AppError> {
+        self.conn()
+            .await?
+            .query_opt(&format!("SELECT {} FROM servers WHERE name = $1", SERVER_COLUMNS), &[&name])
+            .await?
+            .map(|row| row_to_server(&row))
+            .transpose()
+    }
+
+    async fn get_server_by_id(&self, id: &Uuid) -> Result<Option<Server>, AppError> {
+        self.conn()
+            .await?
+            .query_opt(&format!("SELECT {} FROM servers WHERE id = $1", SERVER_COLUMNS), &[&id.to_string()])
+            .await?
+            .map(|row| row_to_server(&row))
+            .transpose()
+    }
+
+    async fn get_server_by_token(&self, token: &str) -> Result<Option<Server>, AppError> {
+        self.conn()
+            .await?
+            .query_opt(&format!("SELECT {} FROM servers WHERE token = $1", SERVER_COLUMNS), &[&token])
+            .await?
+            .map(|row| row_to_server(&row))
+            .transpose()
+    }
+
+    async fn list_servers(&self) -> Result<Vec<Server>, AppError> {
+        self.conn()
+            .await?
+            .query(&format!("SELECT {} FROM servers ORDER BY name", SERVER_COLUMNS), &[])
+            .await?
+            .iter()
+            .map(row_to_server)
+            .collect()
+    }
+
+    async fn update_server(&self, id: &Uuid, name: &str, base_url: &str, token: &str) -> Result<Server, AppError> {
+        let row = self
+            .conn()
+            .await?
+            .query_one(
+                &format!(
+                    "UPDATE servers
+                     SET name = $1, token = $3,
+                         public_key = CASE WHEN base_url = $2 THEN public_key END,
+                         base_url = $2
+                     WHERE id = $4
+                     RETURNING {}",
+                    SERVER_COLUMNS
+                ),
+                &[&name, &base_url, &token, &id.to_string()],
+            )
+            .await?;
+        row_to_server(&row)
+    }
+
+    async fn set_server_public_key(&self, id: &Uuid, public_key: &str) -> Result<(), AppError> {
+        self.conn()
+            .await?
+            .execute("UPDATE servers SET public_key = $1 WHERE id = $2", &[&public_key, &id.to_string()])
+            .await?;
+        Ok(())
+    }
+
+    async fn set_server_status(&self, id: &Uuid, status: PeerStatus) -> Result<(), AppError> {
+        self.conn()
+            .await?
+            .execute("UPDATE servers SET status = $1 WHERE id = $2", &[&status.as_str(), &id.to_string()])
+            .await?;
+        Ok(())
+    }
+
+    async fn set_server_protocol(
+        &self,
+        id: &Uuid,
+        protocol_version: &str,
+        capabilities: Option<&[String]>,
+    ) -> Result<(), AppError> {
+        let capabilities = capabilities.map(|c| c.join(","));
+        self.conn()
+            .await?
+            .execute(
+                "UPDATE servers SET protocol_version = $1, capabilities = $2 WHERE id = $3",
+                &[&protocol_version, &capabilities, &id.to_string()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn save_peering_request(&self, request: &PeeringRequest, max_pending: Option<usize>) -> Result<bool, AppError> {
+        let mut conn = self.conn().await?;
+        let tx = conn.transaction().await?;
+        if max_pending.is_some() {
+            // Concurrent saves wait here, so each counts what the others stored
+            tx.execute("LOCK TABLE peering_requests IN SHARE ROW EXCLUSIVE MODE", &[]).await?;
+        }
+        tx.execute(
+            "DELETE FROM peering_requests WHERE direction = $1 AND server_name = $2 AND status = 'pending'",
+            &[&request.direction.as_str(), &request.server_name],
+        )
+        .await?;
+        if let Some(max_pending) = max_pending {
+            let pending: i64 = tx
+                .query_one(
+                    "SELECT COUNT(*) FROM peering_requests WHERE direction = $1 AND status = 'pending'",
+                    &[&request.direction.as_str()],
+                )
+                .await?
+                .get(0);
+            if pending as usize >= max_pending {
+                return Ok(false);
+            }
+        }
+        tx.execute(
+            "INSERT INTO peering_requests
+                (id, direction, server_name, base_url, public_key, status, created_at, updated_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            &[
+                &request.id.to_string(),
+                &request.direction.as_str(),
+                &request.server_name,
+                &request.base_url,
+                &request.public_key,
+                &request.status.as_str(),
+                &request.created_at,
+                &request.updated_at,
+            ],
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    async fn get_peering_request(&self, id: Uuid) -> Result<Option<PeeringRequest>, AppError> {
+        self.conn()
+            .await?
+            .query_opt(
+                &format!("SELECT {} FROM peering_requests WHERE id = $1", PEERING_COLUMNS),
+                &[&id.to_string()],
+            )
+            .await?
+            .as_ref()
+            .map(row_to_peering_request)
+            .transpose()
+    }
+
+    async fn get_pending_peering_request(
+        &self,
+        direction: PeeringDirection,
+        server_name: &str,
+    ) -> Result<Option<PeeringRequest>, AppError> {
+        self.conn()
+            .await?
+            .query_opt(
+                &format!(
+                    "SELECT {} FROM peering_requests
+                     WHERE direction = $1 AND server_name = $2 AND status = 'pending'",
+                    PEERING_COLUMNS
+                ),
+                &[&direction.as_str(), &server_name],
+            )
+            .await?
+            .as_ref()
+            .map(row_to_peering_request)
+            .transpose()
+    }
+
+    async fn list_peering_requests(&self) -> Result<Vec<PeeringRequest>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                &format!(
+                    "SELECT {} FROM peering_requests ORDER BY created_at COLLATE \"C\" DESC, id",
+                    PEERING_COLUMNS
+                ),
+                &[],
+            )
+            .await?
+            .iter()
+            .map(row_to_peering_request)
+            .collect()
+    }
+
+    async fn set_peering_request_status(&self, id: Uuid, status: PeeringStatus, now: &str) -> Result<(), AppError> {
+        self.conn()
+            .await?
+            .execute(
+                "UPDATE peering_requests SET status = $1, updated_at = $2 WHERE id = $3",
+                &[&status.as_str(), &now, &id.to_string()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_server(&self, id: &Uuid) -> Result<(), AppError> {
+        let id = id.to_string();
+        let mut conn = self.conn().await?;
+        let tx = conn.transaction().await?;
+        tx.execute("DELETE FROM server_hidden_users WHERE server_id = $1", &[&id]).await?;
+        tx.execute("DELETE FROM server_hidden_channels WHERE server_id = $1", &[&id]).await?;
+        tx.execute("DELETE FROM federation_outbox WHERE server_id = $1", &[&id]).await?;
+        tx.execute("DELETE FROM servers WHERE id = $1", &[&id]).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn create_user_with_password(
+        &self,
+        username: &str,
+        is_local: bool,
+        server_id: Option<Uuid>,
+        password_hash: Option<&str>,
+    ) -> Result<User, AppError> {
+        let token = if is_local {
+            Uuid::new_v4().to_string()
+        } else {
+            "".to_string()
+        };
+        let user = User {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            token,
+            server_id,
+            is_local,
+            display_name: None,
+            last_seen_at: None,
+        };
+        self.conn()
+            .await?
+            .execute(
+                "INSERT INTO users (id, username, token, server_id, is_local, password_hash) VALUES ($1, $2, $3, $4, $5, $6)",
+                &[
+                    &user.id.to_string(),
+                    &user.username,
+                    &user.token,
+                    &user.server_id.map(|s| s.to_string()),
+                    &user.is_local,
+                    &password_hash,
+                ],
+            )
+            .await?;
+        Ok(user)
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT id, username, token, server_id, is_local, display_name, last_seen_at FROM users ORDER BY username",
+                &[],
+            )
+            .await?
+            .iter()
+            .map(row_to_user)
+            .collect()
+    }
+
+    async fn get_user_by_token(&self, token: &str) -> Result<Option<User>, AppError> {
+        self.conn()
+            .await?
+            .query_opt(
+                "SELECT id, username, token, server_id, is_local, display_name, last_seen_at FROM users WHERE token = $1",
+                &[&token],
+            )
+            .await?
+            .map(|row| row_to_user(&row))
+            .transpose()
+    }
+
+    async fn get_user_by_name_and_server(&self, username: &str, server_id: Option<Uuid>) -> Result<Option<User>, AppError> {
+        self.conn()
+            .await?
+            .query_opt(
+                "SELECT id, username, token, server_id, is_local, display_name, last_seen_at FROM users
+                 WHERE username = $1 AND COALESCE(server_id, '') = COALESCE($2, '')",
+                &[&username, &server_id.map(|s| s.to_string())],
+            )
+            .await?
+            .map(|row| row_to_user(&row))
+            .transpose()
+    }
+
+    async fn get_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, AppError> {
+        self.conn()
+            .await?
+            .query_opt(
+                "SELECT id, username, token, server_id, is_local, display_name, last_seen_at FROM users WHERE id = $1",
+                &[&user_id.to_string()],
+            )
+            .await?
+            .map(|row| row_to_user(&row))
+            .transpose()
+    }
+
+    async fn update_user(&self, id: &Uuid, username: &str, display_name: Option<&str>) -> Result<User, AppError> {
+        let row = self
+            .conn()
+            .await?
+            .query_one(
+                "UPDATE users SET username = $1, display_name = $2 WHERE id = $3
+                 RETURNING id, username, token, server_id, is_local, display_name, last_seen_at",
+                &[&username, &display_name, &id.to_string()],
+            )
+            .await?;
+        row_to_user(&row)
+    }
+
+    async fn update_user_display_name(&self, id: &Uuid, display_name: Option<&str>) -> Result<(), AppError> {
+        self.conn()
+            .await?
+            .execute("UPDATE users SET display_name = $1 WHERE id = $2", &[&display_name, &id.to_string()])
+            .await?;
+        Ok(())
+    }
+
+    async fn update_last_seen(&self, id: &Uuid, at: &str) -> Result<bool, AppError> {
+        let updated = self
+            .conn()
+            .await?
+            .execute(
+                "UPDATE users SET last_seen_at = $1
+                 WHERE id = $2 AND (last_seen_at IS NULL OR last_seen_at COLLATE \"C\" < $1 COLLATE \"C\")",
+                &[&at, &id.to_string()],
+            )
+            .await?;
+        Ok(updated > 0)
+    }
+
+    async fn set_user_password(&self, user_id: &Uuid, hash: &str) -> Result<(), AppError> {
+        self.conn()
+            .await?
+            .execute("UPDATE users SET password_hash = $1 WHERE id = $2", &[&hash, &user_id.to_string()])
+            .await?;
+        Ok(())
+    }
+
+    async fn get_user_password_hash(&self, user_id: &Uuid) -> Result<Option<String>, AppError> {
+        Ok(self
+            .conn()
+            .await?
+            .query_opt("SELECT password_hash FROM users WHERE id = $1", &[&user_id.to_string()])
+            .await?
+            .and_then(|row| row.get::<_, Option<String>>(0)))
+    }
+
+    async fn delete_user(&self, id: &Uuid) -> Result<(), AppError> {
+        let conn = self.conn().await?;
+        conn.execute("DELETE FROM users WHERE id = $1", &[&id.to_string()]).await?;
+        conn.execute("DELETE FROM user_presence WHERE user_id = $1", &[&id.to_string()]).await?;
+        Ok(())
+    }
+
+    async fn create_channel(&self, name: &str, origin_server: &str) -> Result<Channel, AppError> {
+        let channel = Channel {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            origin_server: origin_server.to_string(),
+        };
+        self.conn()
+            .await?
+            .execute(
+                "INSERT INTO channels (id, name, origin_server) VALUES ($1, $2, $3)",
+                &[&channel.id.to_string(), &channel.name, &channel.origin_server],
+            )
+            .await?;
+        Ok(channel)
+    }
+
+    async fn list_channels(&self) -> Result<Vec<Channel>, AppError> {
+        self.conn()
+            .await?
+            .query("SELECT id, name, origin_server FROM channels ORDER BY name", &[])
+            .await?
+            .iter()
+            .map(row_to_channel)
+            .collect()
+    }
+
+    async fn get_channel_by_name_origin(&self, name: &str, origin_server: &str) -> Result<Option<Channel>, AppError> {
+        self.conn()
+            .await?
+            .query_opt(
+                "SELECT id, name, origin_server FROM channels WHERE name = $1 AND origin_server = $2",
+                &[&name, &origin_server],
+            )
+            .await?
+            .map(|row| row_to_channel(&row))
+            .transpose()
+    }
+
+    async fn get_channel_by_id(&self, id: Uuid) -> Result<Option<Channel>, AppError> {
+        self.conn()
+            .await?
+            .query_opt("SELECT id, name, origin_server FROM channels WHERE id = $1", &[&id.to_string()])
+            .await?
+            .map(|row| row_to_channel(&row))
+            .transpose()
+    }
+
+    async fn update_channel(&self, id: &Uuid, name: &str) -> Result<Channel, AppError> {
+        let row = self
+            .conn()
+            .await?
+            .query_one(
+                "UPDATE channels SET name = $1 WHERE id = $2 RETURNING id, name, origin_server",
+                &[&name, &id.to_string()],
+            )
+            .await?;
+        row_to_channel(&row)
+    }
+
+    async fn delete_channel(&self, id: &Uuid) -> Result<(), AppError> {
+        let id = id.to_string();
+        let mut conn = self.conn().await?;
+        let tx = conn.transaction().await?;
+        tx.execute("DELETE FROM retention_policies WHERE scope = $1", &[&id]).await?;
+        tx.execute("DELETE FROM read_markers WHERE conversation = $1", &[&id]).await?;
+        tx.execute("DELETE FROM channel_pins WHERE channel_id = $1", &[&id]).await?;
+        tx.execute("DELETE FROM channels WHERE id = $1", &[&id]).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn add_channel_member(&self, channel_id: Uuid, user_id: Uuid) -> Result<(), AppError> {
+        self.conn()
+            .await?
+            .execute(
+                "INSERT INTO channel_members (channel_id, user_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&channel_id.to_string(), &user_id.to_string()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_channel_member(&self, channel_id: Uuid, user_id: Uuid) -> Result<(), AppError> {
+        self.conn()
+            .await?
+            .execute(
+                "DELETE FROM channel_members WHERE channel_id = $1 AND user_id = $2",
+                &[&channel_id.to_string(), &user_id.to_string()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn list_channel_member_servers(&self, channel_id: Uuid) -> Result<Vec<Server>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT DISTINCT s.id, s.name, s.base_url, s.token, s.public_key, s.status, s.protocol_version, s.capabilities
+                 FROM channel_members cm
+                 JOIN users u ON cm.user_id = u.id
+                 JOIN servers s ON u.server_id = s.id
+                 WHERE cm.channel_id = $1 AND u.server_id IS NOT NULL",
+                &[&channel_id.to_string()],
+            )
+            .await?
+            .iter()
+            .map(row_to_server)
+            .collect()
+    }
+
+    async fn is_channel_member(&self, channel_id: Uuid, user_id: Uuid) -> Result<bool, AppError> {
+        Ok(self
+            .conn()
+            .await?
+            .query_opt(
+                "SELECT 1 FROM channel_members WHERE channel_id = $1 AND user_id = $2",
+                &[&channel_id.to_string(), &user_id.to_string()],
+            )
+            .await?
+            .is_some())
+    }
+
+    async fn list_channel_members(&self, channel_id: Uuid) -> Result<Vec<User>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT u.id, u.username, u.token, u.server_id, u.is_local, u.display_name, u.last_seen_at
+                 FROM channel_members cm
+                 JOIN users u ON u.id = cm.user_id
+                 WHERE cm.channel_id = $1
+                 ORDER BY u.username",
+                &[&channel_id.to_string()],
+            )
+            .await?
+            .iter()
+            .map(row_to_user)
+            .collect()
+    }
+
+    async fn list_channels_for_user(&self, user_id: Uuid) -> Result<Vec<Channel>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT c.id, c.name, c.origin_server
+                 FROM channel_members cm
+                 JOIN channels c ON c.id = cm.channel_id
+                 WHERE cm.user_id = $1
+                 ORDER BY c.name",
+                &[&user_id.to_string()],
+            )
+            .await?
+            .iter()
+            .map(row_to_channel)
+            .collect()
+    }
+
+    async fn create_message(
+        &self,
+        kind: MessageKind,
+        body: &str,
+        author_user_id: Uuid,
+        recipient_user_id: Option<Uuid>,
+        channel_id: Option<Uuid>,
+        thread_root_id: Option<Uuid>,
+        sent_at: &str,
+    ) -> Result<Message, AppError> {
+        let message = Message {
+            id: Uuid::new_v4(),
+            kind,
+            body: body.to_string(),
+            author_user_id,
+            recipient_user_id,
+            channel_id,
+            sent_at: sent_at.to_string(),
+            edited_at: None,
+            deleted_at: None,
+            thread_root_id,
+        };
+        insert_message(&self.conn().await?, &message, false).await?;
+        Ok(message)
+    }
+
+    async fn create_message_with_id(
+        &self,
+        id_str: &str,
+        kind: MessageKind,
+        body: &str,
+        author_user_id: Uuid,
+        recipient_user_id: Option<Uuid>,
+        channel_id: Option<Uuid>,
+        thread_root_id: Option<Uuid>,
+        sent_at: &str,
+    ) -> Result<Option<Message>, AppError> {
+        let id = Uuid::parse_str(id_str)
+            .map_err(|_| AppError::BadRequest("invalid message_id".to_string()))?;
+        let message = Message {
+            id,
+            kind,
+            body: body.to_string(),
+            author_user_id,
+            recipient_user_id,
+            channel_id,
+            sent_at: sent_at.to_string(),
+            edited_at: None,
+            deleted_at: None,
+            thread_root_id,
+        };
+        if insert_message(&self.conn().await?, &message, true).await? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(message))
+    }
+
+    async fn list_messages_for_user(&self, user_id: Uuid, limit: usize) -> Result<Vec<Message>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                 FROM messages
+                 WHERE (recipient_user_id = $1
+                        OR channel_id IN (SELECT channel_id FROM channel_members WHERE user_id = $1))
+                   AND deleted_at IS NULL
+                 ORDER BY sent_at DESC
+                 LIMIT $2",
+                &[&user_id.to_string(), &(limit as i64)],
+            )
+            .await?
+            .iter()
+            .map(row_to_message)
+            .collect()
+    }
+
+    async fn get_message_by_id(&self, id: Uuid) -> Result<Option<Message>, AppError> {
+        self.conn()
+            .await?
+            .query_opt(
+                "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                 FROM messages WHERE id = $1",
+                &[&id.to_string()],
+            )
+            .await?
+            .map(|row| row_to_message(&row))
+            .transpose()
+    }
+
+    async fn list_channel_messages(&self, channel_id: Uuid, page: &HistoryPage, limit: usize) -> Result<Vec<Message>, AppError> {
+        let (condition, order) = page_clause(page);
+        let (cursor_at, cursor_id) = page_cursor(page);
+        let rows = self
+            .conn()
+            .await?
+            .query(
+                &format!(
+                    "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                     FROM messages
+                     WHERE channel_id = $1 AND thread_root_id IS NULL AND {}
+                     ORDER BY sent_at COLLATE \"C\" {}, id COLLATE \"C\" {}
+                     LIMIT $4",
+                    condition, order, order
+                ),
+                &[&channel_id.to_string(), &cursor_at, &cursor_id, &(limit as i64)],
+            )
+            .await?;
+        collect_page(&rows, order)
+    }
+
+    async fn list_dm_messages(
+        &self,
+        user_id: Uuid,
+        other_user_id: Uuid,
+        page: &HistoryPage,
+        limit: usize,
+    ) -> Result<Vec<Message>, AppError> {
+        let (condition, order) = page_clause(page);
+        let (cursor_at, cursor_id) = page_cursor(page);
+        let rows = self
+            .conn()
+            .await?
+            .query(
+                &format!(
+                    "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                     FROM messages
+                     WHERE ((author_user_id = $1 AND recipient_user_id = $5)
+                            OR (author_user_id = $5 AND recipient_user_id = $1))
+                       AND {}
+                     ORDER BY sent_at COLLATE \"C\" {}, id COLLATE \"C\" {}
+                     LIMIT $4",
+                    condition, order, order
+                ),
+                &[&user_id.to_string(), &cursor_at, &cursor_id, &(limit as i64), &other_user_id.to_string()],
+            )
+            .await?;
+        collect_page(&rows, order)
+    }
+
+    async fn list_messages_by_author(&self, user_id: Uuid, page: &HistoryPage, limit: usize) -> Result<Vec<Message>, AppError> {
+        let (condition, order) = page_clause(page);
+        let (cursor_at, cursor_id) = page_cursor(page);
+        let rows = self
+            .conn()
+            .await?
+            .query(
+                &format!(
+                    "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                     FROM messages
+                     WHERE author_user_id = $1 AND {}
+                     ORDER BY sent_at COLLATE \"C\" {}, id COLLATE \"C\" {}
+                     LIMIT $4",
+                    condition, order, order
+                ),
+                &[&user_id.to_string(), &cursor_at, &cursor_id, &(limit as i64)],
+            )
+            .await?;
+        collect_page(&rows, order)
+    }
+
+    async fn list_dm_partner_ids(&self, user_id: Uuid) -> Result<Vec<Uuid>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT recipient_user_id FROM messages WHERE kind = 'dm' AND author_user_id = $1
+                 UNION
+                 SELECT author_user_id FROM messages WHERE kind = 'dm' AND recipient_user_id = $1",
+                &[&user_id.to_string()],
+            )
+            .await?
+            .iter()
+            .map(|row| parse_uuid(row.get(0)))
+            .collect()
+    }
+
+    async fn list_thread_messages(&self, root_id: Uuid, page: &HistoryPage, limit: usize) -> Result<Vec<Message>, AppError> {
+        let (condition, order) = page_clause(page);
+        let (cursor_at, cursor_id) = page_cursor(page);
+        let rows = self
+            .conn()
+            .await?
+            .query(
+                &format!(
+                    "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                     FROM messages
+                     WHERE thread_root_id = $1 AND {}
+                     ORDER BY sent_at COLLATE \"C\" {}, id COLLATE \"C\" {}
+                     LIMIT $4",
+                    condition, order, order
+                ),
+                &[&root_id.to_string(), &cursor_at, &cursor_id, &(limit as i64)],
+            )
+            .await?;
+        collect_page(&rows, order)
+    }
+
+    async fn thread_summaries(&self, root_ids: &[Uuid]) -> Result<Vec<ThreadSummary>, AppError> {
+        if root_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ids: Vec<String> = root_ids.iter().map(Uuid::to_string).collect();
+        self.conn()
+            .await?
+            .query(
+                "SELECT thread_root_id, COUNT(*), MAX(sent_at COLLATE \"C\")
+                 FROM messages
+                 WHERE thread_root_id = ANY($1) AND deleted_at IS NULL
+                 GROUP BY thread_root_id",
+                &[&ids],
+            )
+            .await?
+            .iter()
+            .map(row_to_thread_summary)
+            .collect()
+    }
+
+    async fn list_active_threads(&self, channel_id: Uuid, limit: usize) -> Result<Vec<ThreadSummary>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT thread_root_id, COUNT(*), MAX(sent_at COLLATE \"C\")
+                 FROM messages
+                 WHERE channel_id = $1 AND thread_root_id IS NOT NULL AND deleted_at IS NULL
+                 GROUP BY thread_root_id
+                 ORDER BY MAX(sent_at COLLATE \"C\") DESC, thread_root_id COLLATE \"C\"
+                 LIMIT $2",
+                &[&channel_id.to_string(), &(limit as i64)],
+            )
+            .await?
+            .iter()
+            .map(row_to_thread_summary)
+            .collect()
+    }
+
+    async fn edit_message(
+        &self,
+        id: Uuid,
+        body: &str,
+        editor_user_id: Uuid,
+        edited_at: &str,
+    ) -> Result<Option<Message>, AppError> {
+        let mut conn = self.conn().await?;
+        let tx = conn.transaction().await?;
+        let current = tx
+            .query_opt(
+                "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                 FROM messages WHERE id = $1 FOR UPDATE",
+                &[&id.to_string()],
+            )
+            .await?
+            .map(|row| row_to_message(&row))
+            .transpose()?;
+        let Some(current) = current else {
+            return Ok(None);
+        };
+        if current.deleted_at.is_some() || current.edited_at.as_deref().is_some_and(|at| at >= edited_at) {
+            return Ok(None);
+        }
+        tx.execute(
+            "INSERT INTO message_edits (message_id, previous_body, edited_at, editor_user_id) VALUES ($1, $2, $3, $4)",
+            &[&id.to_string(), &current.body, &edited_at, &editor_user_id.to_string()],
+        )
+        .await?;
+        tx.execute(
+            "UPDATE messages SET body = $1, edited_at = $2 WHERE id = $3",
+            &[&body, &edited_at, &id.to_string()],
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(Some(Message {
+            body: body.to_string(),
+            edited_at: Some(edited_at.to_string()),
+            ..current
+        }))
+    }
+
+    async fn delete_message(&self, id: Uuid, deleted_at: &str) -> Result<Option<Message>, AppError> {
+        let mut conn = self.conn().await?;
+        let tx = conn.transaction().await?;
+        let current = tx
+            .query_opt(
+                "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                 FROM messages WHERE id = $1 FOR UPDATE",
+                &[&id.to_string()],
+            )
+            .await?
+            .map(|row| row_to_message(&row))
+            .transpose()?;
+        let Some(current) = current.filter(|m| m.deleted_at.is_none()) else {
+            return Ok(None);
+        };
+        tx.execute("DELETE FROM message_edits WHERE message_id = $1", &[&id.to_string()]).await?;
+        tx.execute("DELETE FROM message_reactions WHERE message_id = $1", &[&id.to_string()]).await?;
+        tx.execute("DELETE FROM message_attachments WHERE message_id = $1", &[&id.to_string()]).await?;
+        tx.execute("DELETE FROM channel_pins WHERE message_id = $1", &[&id.to_string()]).await?;
+        tx.execute("DELETE FROM mentions WHERE message_id = $1", &[&id.to_string()]).await?;
+        tx.execute(
+            "UPDATE messages SET body = '', deleted_at = $1 WHERE id = $2",
+            &[&deleted_at, &id.to_string()],
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(Some(Message {
+            body: String::new(),
+            deleted_at: Some(deleted_at.to_string()),
+            ..current
+        }))
+    }
+
+    async fn list_message_edits(&self, id: Uuid) -> Result<Vec<MessageEdit>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT previous_body, edited_at, editor_user_id FROM message_edits
+                 WHERE message_id = $1 ORDER BY edited_at COLLATE \"C\"",
+                &[&id.to_string()],
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(MessageEdit {
+                    message_id: id,
+                    previous_body: row.get(0),
+                    edited_at: row.get(1),
+                    editor_user_id: parse_uuid(row.get(2))?,
+                })
+            })
+            .collect()
+    }
+
+    async fn add_reaction(&self, message_id: Uuid, user_id: Uuid, emoji: &str) -> Result<bool, AppError> {
+        let inserted = self
+            .conn()
+            .await?
+            .execute(
+                "INSERT INTO message_reactions (message_id, user_id, emoji, created_at) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT DO NOTHING",
+                &[&message_id.to_string(), &user_id.to_string(), &emoji, &now()],
+            )
+            .await?;
+        Ok(inserted > 0)
+    }
+
+    async fn remove_reaction(&self, message_id: Uuid, user_id: Uuid, emoji: &str) -> Result<bool, AppError> {
+        let removed = self
+            .conn()
+            .await?
+            .execute(
+                "DELETE FROM message_reactions WHERE message_id = $1 AND user_id = $2 AND emoji = $3",
+                &[&message_id.to_string(), &user_id.to_string(), &emoji],
+            )
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn reaction_counts(&self, message_ids: &[Uuid], viewer_id: Uuid) -> Result<Vec<ReactionCount>, AppError> {
+        if message_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ids: Vec<String> = message_ids.iter().map(Uuid::to_string).collect();
+        self.conn()
+            .await?
+            .query(
+                "SELECT message_id, emoji, COUNT(*), BOOL_OR(user_id = $2)
+                 FROM message_reactions
+                 WHERE message_id = ANY($1)
+                 GROUP BY message_id, emoji
+                 ORDER BY message_id, MIN(created_at COLLATE \"C\"), emoji",
+                &[&ids, &viewer_id.to_string()],
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(ReactionCount {
+                    message_id: parse_uuid(row.get(0))?,
+                    emoji: row.get(1),
+                    count: row.get::<_, i64>(2) as u64,
+                    reacted: row.get(3),
+                })
+            })
+            .collect()
+    }
+
+    async fn pin_message(&self, pin: &ChannelPin) -> Result<bool, AppError> {
+        let inserted = self
+            .conn()
+            .await?
+            .execute(
+                "INSERT INTO channel_pins (channel_id, message_id, pinned_by_user_id, pinned_at) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT DO NOTHING",
+                &[
+                    &pin.channel_id.to_string(),
+                    &pin.message_id.to_string(),
+                    &pin.pinned_by_user_id.to_string(),
+                    &pin.pinned_at,
+                ],
+            )
+            .await?;
+        Ok(inserted > 0)
+    }
+
+    async fn unpin_message(&self, channel_id: Uuid, message_id: Uuid) -> Result<bool, AppError> {
+        let removed = self
+            .conn()
+            .await?
+            .execute(
+                "DELETE FROM channel_pins WHERE channel_id = $1 AND message_id = $2",
+                &[&channel_id.to_string(), &message_id.to_string()],
+            )
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn list_channel_pins(&self, channel_id: Uuid) -> Result<Vec<ChannelPin>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT channel_id, message_id, pinned_by_user_id, pinned_at FROM channel_pins
+                 WHERE channel_id = $1 ORDER BY pinned_at COLLATE \"C\" DESC, message_id COLLATE \"C\" DESC",
+                &[&channel_id.to_string()],
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(ChannelPin {
+                    channel_id: parse_uuid(row.get(0))?,
+                    message_id: parse_uuid(row.get(1))?,
+                    pinned_by_user_id: parse_uuid(row.get(2))?,
+                    pinned_at: row.get(3),
+                })
+            })
+            .collect()
+    }
+
+    async fn add_mentions(&self, mentions: &[Mention]) -> Result<(), AppError> {
+        let mut conn = self.conn().await?;
+        let tx = conn.transaction().await?;
+        for mention in mentions {
+            tx.execute(
+                "INSERT INTO mentions (message_id, user_id, kind, sent_at) VALUES ($1, $2, $3, $4) ON CONFLICT DO NOTHING",
+                &[
+                    &mention.message_id.to_string(),
+                    &mention.user_id.to_string(),
+                    &mention.kind.as_str(),
+                    &mention.sent_at,
+                ],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn list_mentions(&self, user_id: Uuid, limit: usize) -> Result<Vec<Mention>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT mn.message_id, mn.user_id, mn.kind, mn.sent_at
+                 FROM mentions mn
+                 JOIN messages m ON m.id = mn.message_id
+                 WHERE mn.user_id = $1 AND m.deleted_at IS NULL
+                 ORDER BY mn.sent_at COLLATE \"C\" DESC, mn.message_id COLLATE \"C\" DESC
+                 LIMIT $2",
+                &[&user_id.to_string(), &(limit as i64)],
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                let kind: String = row.get(2);
+                Ok(Mention {
+                    message_id: parse_uuid(row.get(0))?,
+                    user_id: parse_uuid(row.get(1))?,
+                    kind: MentionKind::parse(&kind)
+                        .ok_or_else(|| AppError::Internal(format!("invalid mention kind in database: {}", kind)))?,
+                    sent_at: row.get(3),
+                })
+            })
+            .collect()
+    }
+
+    async fn get_user_presence(&self, user_id: Uuid) -> Result<Option<UserPresence>, AppError> {
+        self.conn()
+            .await?
+            .query_opt(
+                "SELECT user_id, state, status_text, status_emoji, status_expires_at, updated_at
+                 FROM user_presence WHERE user_id = $1",
+                &[&user_id.to_string()],
+            )
+            .await?
+            .map(|row| row_to_user_presence(&row))
+            .transpose()
+    }
+
+    async fn set_user_presence(&self, presence: &UserPresence) -> Result<(), AppError> {
+        self.conn()
+            .await?
+            .execute(
+                "INSERT INTO user_presence (user_id, state, status_text, status_emoji, status_expires_at, updated_at)
+                 VALUES ($1, $2, $3, $4, $5, $6)
+                 ON CONFLICT (user_id) DO UPDATE SET
+                    state = EXCLUDED.state,
+                    status_text = EXCLUDED.status_text,
+                    status_emoji = EXCLUDED.status_emoji,
+                    status_expires_at = EXCLUDED.status_expires_at,
+                    updated_at = EXCLUDED.updated_at",
+                &[
+                    &presence.user_id.to_string(),
+                    &presence.state.as_str(),
+                    &presence.status_text,
+                    &presence.status_emoji,
+                    &presence.status_expires_at,
+                    &presence.updated_at,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn list_user_presence(&self) -> Result<Vec<UserPresence>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT user_id, state, status_text, status_emoji, status_expires_at, updated_at FROM user_presence",
+                &[],
+            )
+            .await?
+            .iter()
+            .map(row_to_user_presence)
+            .collect()
+    }
+
+    async fn create_attachment(&self, attachment: &Attachment) -> Result<bool, AppError> {
+        let inserted = self
+            .conn()
+            .await?
+            .execute(
+                "INSERT INTO attachments
+                    (id, hash, size, content_type, filename, uploader_user_id, origin_server, created_at)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                 ON CONFLICT (id) DO NOTHING",
+                &[
+                    &attachment.id.to_string(),
+                    &attachment.hash,
+                    &(attachment.size as i64),
+                    &attachment.content_type,
+                    &attachment.filename,
+                    &attachment.uploader_user_id.to_string(),
+                    &attachment.origin_server,
+                    &attachment.created_at,
+                ],
+            )
+            .await?;
+        Ok(inserted > 0)
+    }
+
+    async fn get_attachment(&self, id: Uuid) -> Result<Option<Attachment>, AppError> {
+        self.conn()
+            .await?
+            .query_opt("SELECT a.id, a.hash, a.size, a.content_type, a.filename, a.uploader_user_id, a.origin_server, a.created_at FROM attachments a WHERE a.id = $1", &[&id.to_string()])
+            .await?
+            .map(|row| row_to_attachment(&row, 0))
+            .transpose()
+    }
+
+    async fn attach_to_message(&self, message_id: Uuid, attachment_ids: &[Uuid]) -> Result<(), AppError> {
+        let mut conn = self.conn().await?;
+        let tx = conn.transaction().await?;
+        for attachment_id in attachment_ids {
+            tx.execute(
+                "INSERT INTO message_attachments (message_id, attachment_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&message_id.to_string(), &attachment_id.to_string()],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn list_message_attachments(&self, message_ids: &[Uuid]) -> Result<Vec<(Uuid, Attachment)>, AppError> {
+        if message_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ids: Vec<String> = message_ids.iter().map(Uuid::to_string).collect();
+        self.conn()
+            .await?
+            .query(
+                "SELECT ma.message_id, a.id, a.hash, a.size, a.content_type, a.filename, a.uploader_user_id, a.origin_server, a.created_at
+                 FROM message_attachments ma
+                 JOIN attachments a ON a.id = ma.attachment_id
+                 WHERE ma.message_id = ANY($1)
+                 ORDER BY a.created_at COLLATE \"C\", a.id COLLATE \"C\"",
+                &[&ids],
+            )
+            .await?
+            .iter()
+            .map(|row| Ok((parse_uuid(row.get(0))?, row_to_attachment(row, 1)?)))
+            .collect()
+    }
+
+    async fn list_attachment_message_ids(&self, attachment_id: Uuid) -> Result<Vec<Uuid>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT message_id FROM message_attachments WHERE attachment_id = $1",
+                &[&attachment_id.to_string()],
+            )
+            .await?
+            .iter()
+            .map(|row| parse_uuid(row.get(0)))
+            .collect()
+    }
+
+    async fn has_attachment_with_hash(&self, hash: &str) -> Result<bool, AppError> {
+        Ok(self
+            .conn()
+            .await?
+            .query_opt("SELECT 1 FROM attachments WHERE hash = $1 LIMIT 1", &[&hash])
+            .await?
+            .is_some())
+    }
+
+    async fn delete_orphaned_attachments(&self, created_before: &str) -> Result<usize, AppError> {
+        let deleted = self
+            .conn()
+            .await?
+            .execute(
+                "DELETE FROM attachments
+                 WHERE created_at < $1 COLLATE \"C\"
+                   AND id NOT IN (SELECT attachment_id FROM message_attachments)",
+                &[&created_before],
+            )
+            .await?;
+        Ok(deleted as usize)
+    }
+
+    async fn list_attachment_hashes(&self) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .conn()
+            .await?
+            .query("SELECT DISTINCT hash FROM attachments", &[])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    async fn search_messages(&self, user_id: Uuid, search: &MessageSearch) -> Result<Vec<SearchHit>, AppError> {
+        if search.query.trim().is_empty() {
+            return Err(AppError::BadRequest("search query is empty".to_string()));
+        }
+        let headline_options = format!(
+            "StartSel={}, StopSel={}, MaxFragments=1, MaxWords=24, MinWords=8",
+            SNIPPET_START, SNIPPET_END
+        );
+        self.conn()
+            .await?
+            .query(
+                "SELECT m.id, m.kind, m.body, m.author_user_id, m.recipient_user_id, m.channel_id, m.sent_at,
+                        m.edited_at, m.deleted_at, m.thread_root_id,
+                        ts_headline('simple', m.body, q.query, $9)
+                 FROM messages m, plainto_tsquery('simple', $1) AS q(query)
+                 WHERE to_tsvector('simple', m.body) @@ q.query
+                   AND m.deleted_at IS NULL
+                   AND ((m.kind = 'dm' AND (m.author_user_id = $2 OR m.recipient_user_id = $2))
+                        OR (m.kind = 'channel'
+                            AND m.channel_id IN (SELECT channel_id FROM channel_members WHERE user_id = $2)))
+                   AND ($3::TEXT IS NULL OR m.channel_id = $3)
+                   AND ($4::TEXT IS NULL OR (m.kind = 'dm' AND (m.author_user_id = $4 OR m.recipient_user_id = $4)))
+                   AND ($5::TEXT IS NULL OR m.author_user_id = $5)
+                   AND ($6::TEXT IS NULL OR m.sent_at >= $6)
+                   AND ($7::TEXT IS NULL OR m.sent_at < $7)
+                 ORDER BY m.sent_at DESC
+                 LIMIT $8",
+                &[
+                    &search.query,
+                    &user_id.to_string(),
+                    &search.channel_id.map(|id| id.to_string()),
+                    &search.dm_user_id.map(|id| id.to_string()),
+                    &search.author_user_id.map(|id| id.to_string()),
+                    &search.since,
+                    &search.until,
+                    &(search.limit as i64),
+                    &headline_options,
+                ],
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(SearchHit {
+                    message: row_to_message(row)?,
+                    snippet: render_snippet(&row.get::<_, String>(10)),
+                })
+            })
+            .collect()
+    }
+
+    async fn list_retention_policies(&self) -> Result<Vec<RetentionPolicy>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT scope, max_age_days, max_messages, updated_at FROM retention_policies ORDER BY scope",
+                &[],
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                let scope: String = row.get(0);
+                Ok(RetentionPolicy {
+                    channel_id: if scope == "dm" { None } else { Some(parse_uuid(&scope)?) },
+                    max_age_days: row.get::<_, Option<i64>>(1).map(|v| v as u32),
+                    max_messages: row.get::<_, Option<i64>>(2).map(|v| v as u32),
+                    updated_at: row.get(3),
+                })
+            })
+            .collect()
+    }
+
+    async fn set_retention_policy(
+        &self,
+        channel_id: Option<Uuid>,
+        max_age_days: Option<u32>,
+        max_messages: Option<u32>,
+    ) -> Result<RetentionPolicy, AppError> {
+        let policy = RetentionPolicy {
+            channel_id,
+            max_age_days,
+            max_messages,
+            updated_at: now(),
+        };
+        self.conn()
+            .await?
+            .execute(
+                "INSERT INTO retention_policies (scope, max_age_days, max_messages, updated_at)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (scope) DO UPDATE SET
+                    max_age_days = EXCLUDED.max_age_days,
+                    max_messages = EXCLUDED.max_messages,
+                    updated_at = EXCLUDED.updated_at",
+                &[
+                    &retention_scope(channel_id),
+                    &max_age_days.map(i64::from),
+                    &max_messages.map(i64::from),
+                    &policy.updated_at,
+                ],
+            )
+            .await?;
+        Ok(policy)
+    }
+
+    async fn delete_retention_policy(&self, channel_id: Option<Uuid>) -> Result<(), AppError> {
+        self.conn()
+            .await?
+            .execute("DELETE FROM retention_policies WHERE scope = $1", &[&retention_scope(channel_id)])
+            .await?;
+        Ok(())
+    }
+
+    async fn prune_messages(
+        &self,
+        channel_id: Option<Uuid>,
+        older_than: Option<&str>,
+        keep_last: Option<u32>,
+    ) -> Result<usize, AppError> {
+        let mut conn = self.conn().await?;
+        let tx = conn.transaction().await?;
+        let keep_last = keep_last.map(i64::from);
+        let mut deleted = 0;
+        match channel_id {
+            Some(channel_id) => {
+                let channel_id = channel_id.to_string();
+                // Replies go with their root, whatever their own age
+                if let Some(cutoff) = older_than {
+                    deleted += tx
+                        .execute(
+                            "DELETE FROM messages
+                             WHERE channel_id = $1
+                               AND (sent_at < $2 COLLATE \"C\"
+                                    OR thread_root_id IN (
+                                        SELECT id FROM messages
+                                        WHERE channel_id = $1 AND thread_root_id IS NULL AND sent_at < $2 COLLATE \"C\"
+                                    ))",
+                            &[&channel_id, &cutoff],
+                        )
+                        .await?;
+                }
+                if let Some(keep) = keep_last {
+                    deleted += tx
+                        .execute(
+                            "WITH pruned AS (
+                                 SELECT id FROM messages
+                                 WHERE channel_id = $1 AND thread_root_id IS NULL
+                                 ORDER BY sent_at COLLATE \"C\" DESC, id COLLATE \"C\" DESC OFFSET $2
+                             )
+                             DELETE FROM messages
+                             WHERE channel_id = $1
+                               AND (id IN (SELECT id FROM pruned) OR thread_root_id IN (SELECT id FROM pruned))",
+                            &[&channel_id, &keep],
+                        )
+                        .await?;
+                }
+            }
+            None => {
+                if let Some(cutoff) = older_than {
+                    deleted += tx
+                        .execute(
+                            "DELETE FROM messages WHERE kind = 'dm' AND sent_at < $1 COLLATE \"C\"",
+                            &[&cutoff],
+                        )
+                        .await?;
+                }
+                if let Some(keep) = keep_last {
+                    deleted += tx
+                        .execute(
+                            "DELETE FROM messages WHERE id IN (
+                                 SELECT id FROM (
+                                     SELECT id, ROW_NUMBER() OVER (
+                                         PARTITION BY LEAST(author_user_id, recipient_user_id),
+                                                      GREATEST(author_user_id, recipient_user_id)
+                                         ORDER BY sent_at COLLATE \"C\" DESC, id COLLATE \"C\" DESC
+                                     ) AS position
+                                     FROM messages WHERE kind = 'dm'
+                                 ) ranked WHERE position > $1
+                             )",
+                            &[&keep],
+                        )
+                        .await?;
+                }
+            }
+        }
+        if deleted > 0 {
+            tx.execute("DELETE FROM message_edits WHERE message_id NOT IN (SELECT id FROM messages)", &[])
+                .await?;
+            tx.execute("DELETE FROM message_reactions WHERE message_id NOT IN (SELECT id FROM messages)", &[])
+                .await?;
+            tx.execute("DELETE FROM message_attachments WHERE message_id NOT IN (SELECT id FROM messages)", &[])
+                .await?;
+            tx.execute("DELETE FROM channel_pins WHERE message_id NOT IN (SELECT id FROM messages)", &[])
+                .await?;
+            tx.execute("DELETE FROM mentions WHERE message_id NOT IN (SELECT id FROM messages)", &[])
+                .await?;
+            tx.execute(
+                "DELETE FROM federation_outbox WHERE message_id IS NOT NULL AND message_id NOT IN (SELECT id FROM messages)",
+                &[],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(deleted as usize)
+    }
+
+    async fn advance_read_marker(
+        &self,
+        user_id: Uuid,
+        conversation: ConversationRef,
+        cursor: &MessageCursor,
+    ) -> Result<ReadMarker, AppError> {
+        let conn = self.conn().await?;
+        let key = conversation_key(conversation);
+        let user_id = user_id.to_string();
+        let advanced = conn
+            .query_opt(
+                "INSERT INTO read_markers AS r (user_id, conversation, message_id, sent_at, updated_at)
+                 VALUES ($1, $2, $3, $4, $5)
+                 ON CONFLICT (user_id, conversation) DO UPDATE SET
+                    message_id = EXCLUDED.message_id,
+                    sent_at = EXCLUDED.sent_at,
+                    updated_at = EXCLUDED.updated_at
+                 WHERE EXCLUDED.sent_at > r.sent_at COLLATE \"C\"
+                    OR (EXCLUDED.sent_at = r.sent_at AND EXCLUDED.message_id > r.message_id COLLATE \"C\")
+                 RETURNING message_id, sent_at, updated_at",
+                &[&user_id, &key, &cursor.id.to_string(), &cursor.sent_at, &now()],
+            )
+            .await?;
+        // RETURNING yields nothing when the stored marker was already further along.
+        let row = match advanced {
+            Some(row) => row,
+            None => {
+                conn.query_one(
+                    "SELECT message_id, sent_at, updated_at FROM read_markers WHERE user_id = $1 AND conversation = $2",
+                    &[&user_id, &key],
+                )
+                .await?
+            }
+        };
+        Ok(ReadMarker {
+            conversation,
+            message_id: parse_uuid(row.get(0))?,
+            sent_at: row.get(1),
+            updated_at: row.get(2),
+        })
+    }
+
+    async fn unread_counts(&self, user_id: Uuid) -> Result<Vec<UnreadCount>, AppError> {
+        let rows = self
+            .conn()
+            .await?
+            .query(
+                "SELECT m.channel_id, COUNT(*), COUNT(*) FILTER (WHERE EXISTS (SELECT 1 FROM mentions mn WHERE mn.message_id = m.id AND mn.user_id = $1))
+                 FROM messages m
+                 JOIN channel_members cm ON cm.channel_id = m.channel_id AND cm.user_id = $1
+                 LEFT JOIN read_markers r ON r.user_id = $1 AND r.conversation = m.channel_id
+                 WHERE m.kind = 'channel' AND m.author_user_id != $1 AND m.deleted_at IS NULL
+                   AND m.thread_root_id IS NULL
+                   AND (r.sent_at IS NULL OR m.sent_at > r.sent_at COLLATE \"C\"
+                        OR (m.sent_at = r.sent_at AND m.id > r.message_id COLLATE \"C\"))
+                 GROUP BY m.channel_id
+                 UNION ALL
+                 SELECT 'dm:' || m.author_user_id, COUNT(*), COUNT(*) FILTER (WHERE EXISTS (SELECT 1 FROM mentions mn WHERE mn.message_id = m.id AND mn.user_id = $1))
+                 FROM messages m
+                 LEFT JOIN read_markers r ON r.user_id = $1 AND r.conversation = 'dm:' || m.author_user_id
+                 WHERE m.kind = 'dm' AND m.recipient_user_id = $1 AND m.author_user_id != $1 AND m.deleted_at IS NULL
+                   AND (r.sent_at IS NULL OR m.sent_at > r.sent_at COLLATE \"C\"
+                        OR (m.sent_at = r.sent_at AND m.id > r.message_id COLLATE \"C\"))
+                 GROUP BY m.author_user_id",
+                &[&user_id.to_string()],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let key: String = row.get(0);
+                Some(UnreadCount {
+                    conversation: parse_conversation_key(&key)?,
+                    unread: row.get::<_, i64>(1) as u64,
+                    mentions: row.get::<_, i64>(2) as u64,
+                })
+            })
+            .collect())
+    }
+
+    async fn create_federation_token(&self, label: &str) -> Result<FederationToken, AppError> {
+        let token = FederationToken {
+            id: Uuid::new_v4(),
+            token: Uuid::new_v4().to_string(),
+            label: label.to_string(),
+            created_at: now(),
+        };
+        self.conn()
+            .await?
+            .execute(
+                "INSERT INTO federation_tokens (id, token, label, created_at) VALUES ($1, $2, $3, $4)",
+                &[&token.id.to_string(), &token.token, &token.label, &token.created_at],
+            )
+            .await?;
+        Ok(token)
+    }
+
+    async fn list_federation_tokens(&self) -> Result<Vec<FederationToken>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                "SELECT id, token, label, created_at FROM federation_tokens ORDER BY created_at DESC",
+                &[],
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                Ok(FederationToken {
+                    id: parse_uuid(row.get(0))?,
+                    token: row.get(1),
+                    label: row.get(2),
+                    created_at: row.get(3),
+                })
+            })
+            .collect()
+    }
+
+    async fn delete_federation_token(&self, id: &Uuid) -> Result<(), AppError> {
+        self.conn()
+            .await?
+            .execute("DELETE FROM federation_tokens WHERE id = $1", &[&id.to_string()])
+            .await?;
+        Ok(())
+    }
+
+    async fn is_valid_federation_token(&self, token: &str) -> Result<bool, AppError> {
+        Ok(self
+            .conn()
+            .await?
+            .query_opt("SELECT 1 FROM federation_tokens WHERE token = $1", &[&token])
+            .await?
+            .is_some())
+    }
+
+    async fn enqueue_outbox(
+        &self,
+        server_id: Uuid,
+        path: &str,
+        payload: &str,
+        message_id: Option<Uuid>,
+        now: &str,
+    ) -> Result<OutboxEntry, AppError> {
+        let row = self
+            .conn()
+            .await?
+            .query_one(
+                &format!(
+                    "INSERT INTO federation_outbox AS o
+                        (server_id, path, payload, message_id, status, attempts, next_attempt_at, created_at, updated_at)
+                     VALUES ($1, $2, $3, $4, $5, 0, $6, $6, $6)
+                     RETURNING {}",
+                    OUTBOX_COLUMNS
+                ),
+                &[
+                    &server_id.to_string(),
+                    &path,
+                    &payload,
+                    &message_id.map(|id| id.to_string()),
+                    &DeliveryStatus::Pending.as_str(),
+                    &now,
+                ],
+            )
+            .await?;
+        row_to_outbox_entry(&row)
+    }
+
+    async fn due_outbox_entries(&self, now: &str) -> Result<Vec<OutboxEntry>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                &format!(
+                    "SELECT {} FROM federation_outbox o
+                     WHERE o.status = 'pending'
+                       AND o.id = (SELECT MIN(id) FROM federation_outbox
+                                   WHERE server_id = o.server_id AND status = 'pending')
+                       AND o.next_attempt_at COLLATE \"C\" <= $1
+                       AND o.server_id NOT IN (SELECT id FROM servers WHERE status = 'suspended')
+                     ORDER BY o.id",
+                    OUTBOX_COLUMNS
+                ),
+                &[&now],
+            )
+            .await?
+            .iter()
+            .map(row_to_outbox_entry)
+            .collect()
+    }
+
+    async fn update_outbox_entry(&self, entry: &OutboxEntry) -> Result<(), AppError> {
+        let conn = self.conn().await?;
+        if entry.status == DeliveryStatus::Delivered && entry.message_id.is_none() {
+            conn.execute("DELETE FROM federation_outbox WHERE id = $1", &[&entry.id]).await?;
+            return Ok(());
+        }
+        let payload = if entry.status == DeliveryStatus::Delivered { "" } else { entry.payload.as_str() };
+        conn.execute(
+            "UPDATE federation_outbox
+             SET payload = $2, status = $3, attempts = $4, next_attempt_at = $5, last_error = $6, updated_at = $7
+             WHERE id = $1",
+            &[
+                &entry.id,
+                &payload,
+                &entry.status.as_str(),
+                &(entry.attempts as i32),
+                &entry.next_attempt_at,
+                &entry.last_error,
+                &entry.updated_at,
+            ],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn get_outbox_entry(&self, id: i64) -> Result<Option<OutboxEntry>, AppError> {
+        self.conn()
+            .await?
+            .query_opt(&format!("SELECT {} FROM federation_outbox o WHERE o.id = $1", OUTBOX_COLUMNS), &[&id])
+            .await?
+            .map(|row| row_to_outbox_entry(&row))
+            .transpose()
+    }
+
+    async fn list_outbox_entries(&self, status: DeliveryStatus, limit: usize) -> Result<Vec<OutboxEntry>, AppError> {
+        self.conn()
+            .await?
+            .query(
+                &format!(
+                    "SELECT {} FROM federation_outbox o WHERE o.status = $1 ORDER BY o.id LIMIT $2",
+                    OUTBOX_COLUMNS
+                ),
+                &[&status.as_str(), &(limit as i64)],
+            )
+            .await?
+            .iter()
+            .map(row_to_outbox_entry)
+            .collect()
+    }
+
+    async fn message_deliveries(&self, message_ids: &[Uuid]) -> Result<Vec<MessageDelivery>, AppError> {
+        if message_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ids: Vec<String> = message_ids.iter().map(Uuid::to_string).collect();
+        self.conn()
+            .await?
+            .query(
+                "SELECT o.message_id, s.name, o.status, o.attempts, o.last_error, o.updated_at
+                 FROM federation_outbox o JOIN servers s ON s.id = o.server_id
+                 WHERE o.message_id = ANY($1)
+                 ORDER BY o.message_id, s.name COLLATE \"C\"",
+                &[&ids],
+            )
+            .await?
+            .iter()
+            .map(|row| {
+                let status: String = row.get(2);
+                Ok(MessageDelivery {
+                    message_id: parse_uuid(row.get(0))?,
+                    server_name: row.get(1),
+                    status: DeliveryStatus::parse(&status)
+                        .ok_or_else(|| AppError::Internal(format!("invalid delivery status in database: {}", status)))?,
+                    attempts: row.get::<_, i32>(3) as u32,
+                    last_error: row.get(4),
+                    updated_at: row.get(5),
+                })
+            })
+            .collect()
+    }
+
+    async fn prune_delivered_outbox(&self, older_than: &str) -> Result<usize, AppError> {
+        let deleted = self
+            .conn()
+            .await?
+            .execute(
+                "DELETE FROM federation_outbox WHERE status = 'delivered' AND updated_at COLLATE \"C\" < $1",
+                &[&older_than],
+            )
+            .await?;
+        Ok(deleted as usize)
+    }
+
+    async fn get_hidden_user_ids(&self, server_id: Uuid) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .conn()
+            .await?
+            .query("SELECT user_id FROM server_hidden_users WHERE server_id = $1", &[&server_id.to_string()])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    async fn get_hidden_channel_ids(&self, server_id: Uuid) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .conn()
+            .await?
+            .query("SELECT channel_id FROM server_hidden_channels WHERE server_id = $1", &[&server_id.to_string()])
+            .await?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    async fn set_hidden_users(&self, server_id: Uuid, user_ids: &[String]) -> Result<(), AppError> {
+        let server_id = server_id.to_string();
+        let mut conn = self.conn().await?;
+        let tx = conn.transaction().await?;
+        tx.execute("DELETE FROM server_hidden_users WHERE server_id = $1", &[&server_id]).await?;
+        for uid in user_ids {
+            tx.execute(
+                "INSERT INTO server_hidden_users (server_id, user_id) VALUES ($1, $2)",
+                &[&server_id, uid],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn set_hidden_channels(&self, server_id: Uuid, channel_ids: &[String]) -> Result<(), AppError> {
+        let server_id = server_id.to_string();
+        let mut conn = self.conn().await?;
+        let tx = conn.transaction().await?;
+        tx.execute("DELETE FROM server_hidden_channels WHERE server_id = $1", &[&server_id]).await?;
+        for cid in channel_ids {
+            tx.execute(
+                "INSERT INTO server_hidden_channels (server_id, channel_id) VALUES ($1, $2)",
+                &[&server_id, cid],
+            )
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+}
+
+fn now() -> String {
+    time::OffsetDateTime::now_utc()
+        .format(&time::format_description::well_known::Rfc3339)
+        .unwrap_or_default()
+}
+
+/// Returns the number of rows written; with `ignore_duplicate` an existing id
+/// is left untouched and 0 is returned.
+async fn insert_message(conn: &Object, message: &Message, ignore_duplicate: bool) -> Result<u64, AppError> {
+    let sql = format!(
+        "INSERT INTO messages (id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, thread_root_id)
+         VALUES ($1, $2, $3, $4, $5, $6, $7, $8){}",
+        if ignore_duplicate { " ON CONFLICT (id) DO NOTHING" } else { "" }
+    );
+    let kind = match message.kind {
+        MessageKind::Dm => "dm",
+        MessageKind::Channel => "channel",
+    };
+    Ok(conn.execute(
+        sql.as_str(),
+        &[
+            &message.id.to_string(),
+            &kind,
+            &message.body,
+            &message.author_user_id.to_string(),
+            &message.recipient_user_id.map(|id| id.to_string()),
+            &message.channel_id.map(|id| id.to_string()),
+            &message.sent_at,
+            &message.thread_root_id.map(|id| id.to_string()),
+        ],
+    )
+    .await?)
+}
+
+/// Key under which a retention policy is stored: the channel id, or `dm`.
+fn retention_scope(channel_id: Option<Uuid>) -> String {
+    channel_id.map(|id| id.to_string()).unwrap_or_else(|| "dm".to_string())
+}
+
+/// Keyset condition and sort order for one page of history. The cursor is
+/// bound as `$2` (sent_at) and `$3` (id); both are NULL for the latest page.
+/// Comparisons use the "C" collation so they match the byte order SQLite uses.
+fn page_clause(page: &HistoryPage) -> (&'static str, &'static str) {
+    match page {
+        HistoryPage::Latest | HistoryPage::Before(_) => (
+            "($2::TEXT IS NULL OR sent_at < $2 COLLATE \"C\"
+              OR (sent_at = $2 AND id < $3::TEXT COLLATE \"C\"))",
+            "DESC",
+        ),
+        HistoryPage::After(_) => (
+            "(sent_at > $2::TEXT COLLATE \"C\" OR (sent_at = $2 AND id > $3::TEXT COLLATE \"C\"))",
+            "ASC",
+        ),
+    }
+}
+
+fn page_cursor(page: &HistoryPage) -> (Option<String>, Option<String>) {
+    match page {
+        HistoryPage::Latest => (None, None),
+        HistoryPage::Before(cursor) | HistoryPage::After(cursor) => {
+            (Some(cursor.sent_at.clone()), Some(cursor.id.to_string()))
+        }
+    }
+}
+
+/// Pages read newest-first are flipped so callers always get oldest first.
+/// Read attachment columns starting at `offset`.
+fn row_to_attachment(row: &Row, offset: usize) -> Result<Attachment, AppError> {
+    Ok(Attachment {
+        id: parse_uuid(row.get(offset))?,
+        hash: row.get(offset + 1),
+        size: row.get::<_, i64>(offset + 2) as u64,
+        content_type: row.get(offset + 3),
+        filename: row.get(offset + 4),
+        uploader_user_id: parse_uuid(row.get(offset + 5))?,
+        origin_server: row.get(offset + 6),
+        created_at: row.get(offset + 7),
+    })
+}
+
+fn row_to_thread_summary(row: &Row) -> Result<ThreadSummary, AppError> {
+    Ok(ThreadSummary {
+        root_id: parse_uuid(row.get(0))?,
+        reply_count: row.get::<_, i64>(1) as u64,
+        last_reply_at: row.get(2),
+    })
+}
+
+fn row_to_user_presence(row: &Row) -> Result<UserPresence, AppError> {
+    let state: String = row.get(1);
+    Ok(UserPresence {
+        user_id: parse_uuid(row.get(0))?,
+        state: PresenceState::parse(&state)
+            .ok_or_else(|| AppError::Internal(format!("invalid presence state in database: {}", state)))?,
+        status_text: row.get(2),
+        status_emoji: row.get(3),
+        status_expires_at: row.get(4),
+        updated_at: row.get(5),
+    })
+}
+
+const OUTBOX_COLUMNS: &str = "o.id, o.server_id, o.path, o.payload, o.message_id, o.status, o.attempts,
+    o.next_attempt_at, o.last_error, o.created_at, o.updated_at";
+
+fn row_to_outbox_entry(row: &Row) -> Result<OutboxEntry, AppError> {
+    let status: String = row.get(5);
+    Ok(OutboxEntry {
+        id: row.get(0),
+        server_id: parse_uuid(row.get(1))?,
+        path: row.get(2),
+        payload: row.get(3),
+        message_id: parse_opt_uuid(row.get(4))?,
+        status: DeliveryStatus::parse(&status)
+            .ok_or_else(|| AppError::Internal(format!("invalid delivery status in database: {}", status)))?,
+        attempts: row.get::<_, i32>(6) as u32,
+        next_attempt_at: row.get(7),
+        last_error: row.get(8),
+        created_at: row.get(9),
+        updated_at: row.get(10),
+    })
+}
+
+fn collect_page(rows: &[Row], order: &str) -> Result<Vec<Message>, AppError> {
+    let mut messages = rows.iter().map(row_to_message).collect::<Result<Vec<_>, _>>()?;
+    if order == "DESC" {
+        messages.reverse();
+    }
+    Ok(messages)
+}
+
+fn parse_uuid(value: &str) -> Result<Uuid, AppError> {
+    Uuid::parse_str(value).map_err(|e| AppError::Internal(format!("invalid uuid in database: {}", e)))
+}
+
+fn parse_opt_uuid(value: Option<&str>) -> Result<Option<Uuid>, AppError> {
+    value.map(parse_uuid).transpose()
+}
+
+const SERVER_COLUMNS: &str = "id, name, base_url, token, public_key, status, protocol_version, capabilities";
+
+fn row_to_server(row: &Row) -> Result<Server, AppError> {
+    let status: String = row.get(5);
+    Ok(Server {
+        id: parse_uuid(row.get(0))?,
+        name: row.get(1),
+        base_url: row.get(2),
+        token: row.get(3),
+        public_key: row.get(4),
+        status: PeerStatus::parse(&status)
+            .ok_or_else(|| AppError::Internal(format!("invalid peer status in database: {}", status)))?,
+        protocol_version: row.get(6),
+        capabilities: row.get::<_, Option<String>>(7).map(|c| split_capabilities(&c)),
+    })
+}
+
+fn split_capabilities(capabilities: &str) -> Vec<String> {
+    capabilities.split(',').filter(|c| !c.is_empty()).map(str::to_string).collect()
+}
+
+const PEERING_COLUMNS: &str = "id, direction, server_name, base_url, public_key, status, created_at, updated_at";
+
+fn row_to_peering_request(row: &Row) -> Result<PeeringRequest, AppError> {
+    let direction: String = row.get(1);
+    let status: String = row.get(5);
+    Ok(PeeringRequest {
+        id: parse_uuid(row.get(0))?,
+        direction: PeeringDirection::parse(&direction)
+            .ok_or_else(|| AppError::Internal(format!("invalid peering direction in database: {}", direction)))?,
+        server_name: row.get(2),
+        base_url: row.get(3),
+        public_key: row.get(4),
+        status: PeeringStatus::parse(&status)
+            .ok_or_else(|| AppError::Internal(format!("invalid peering status in database: {}", status)))?,
+        created_at: row.get(6),
+        updated_at: row.get(7),
+    })
+}
+
+fn row_to_channel(row: &Row) -> Result<Channel, AppError> {
+    Ok(Channel {
+        id: parse_uuid(row.get(0))?,
+        name: row.get(1),
+        origin_server: row.get(2),
+    })
+}
+
+fn row_to_user(row: &Row) -> Result<User, AppError> {
+    Ok(User {
+        id: parse_uuid(row.get(0))?,
+        username: row.get(1),
+        token: row.get(2),
+        server_id: parse_opt_uuid(row.get(3))?,
+        is_local: row.get(4),
+        display_name: row.get(5),
+        last_seen_at: row.get(6),
+    })
+}
+
+fn row_to_message(row: &Row) -> Result<Message, AppError> {
+    let kind = match row.get::<_, &str>(1) {
+        "dm" => MessageKind::Dm,
+        "channel" => MessageKind::Channel,
+        other => return Err(AppError::Internal(format!("unknown message kind '{}'", other))),
+    };
+    Ok(Message {
+        id: parse_uuid(row.get(0))?,
+        kind,
+        body: row.get(2),
+        author_user_id: parse_uuid(row.get(3))?,
+        recipient_user_id: parse_opt_uuid(row.get(4))?,
+        channel_id: parse_opt_uuid(row.get(5))?,
+        sent_at: row.get(6),
+        edited_at: row.get(7),
+        deleted_at: row.get(8),
+        thread_root_id: parse_opt_uuid(row.get(9))?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs against `TEST_DATABASE_URL` (e.g. a local `postgres://` URL) and
+    /// is skipped when that variable is unset.
+    #[tokio::test]
+    async fn round_trips_users_channels_and_messages() {
+        let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+            return;
+        };
+        let store = PostgresStore::connect(&url, 2).expect("connect");
+        store.init().await.expect("init");
+        store.init().await.expect("init is idempotent");
+
+        let suffix = Uuid::new_v4().simple().to_string();
+        let alice = store.create_user(&format!("alice-{}", suffix), true, None).await.expect("alice");
+        let channel = store.create_channel(&format!("general-{}", suffix), "local").await.expect("channel");
+        store.add_channel_member(channel.id, alice.id).await.expect("member");
+        let message = store
+            .create_message(MessageKind::Channel, "hello", alice.id, None, Some(channel.id), None, "2024-01-01T00:00:00Z")
+            .await
+            .expect("message");
+        let duplicate = store
+            .create_message_with_id(&message.id.to_string(), MessageKind::Channel, "hello", alice.id, None, Some(channel.id), None, "2024-01-01T00:00:00Z")
+            .await
+            .expect("duplicate");
+        assert!(duplicate.is_none());
+
+        let page = store.list_channel_messages(channel.id, &HistoryPage::Latest, 10).await.expect("page");
+        assert_eq!(page.len(), 1);
+        let older = store
+            .list_channel_messages(channel.id, &HistoryPage::Before(MessageCursor::of(&page[0])), 10)
+            .await
+            .expect("older");
+        assert!(older.is_empty());
+
+        assert_eq!(store.list_channels_for_user(alice.id).await.expect("memberships").len(), 1);
+
+        let inbox = store.list_messages_for_user(alice.id, 10).await.expect("inbox");
+        assert_eq!(inbox.len(), 1);
+        assert_eq!(store.get_user_by_token(&alice.token).await.expect("lookup").map(|u| u.id), Some(alice.id));
+
+        let search = MessageSearch {
+            query: "Hello".to_string(),
+            limit: 10,
+            ..Default::default()
+        };
+        let hits = store.search_messages(alice.id, &search).await.expect("search");
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].snippet, "<mark>hello</mark>");
+
+        let newer = store
+            .create_message(MessageKind::Channel, "newer", alice.id, None, Some(channel.id), None, "2024-01-02T00:00:00Z")
+            .await
+            .expect("message");
+        store.set_retention_policy(Some(channel.id), None, Some(1)).await.expect("policy");
+        assert!(store.list_retention_policies().await.expect("policies").iter().any(|p| p.channel_id == Some(channel.id)));
+        assert_eq!(store.prune_messages(Some(channel.id), None, Some(1)).await.expect("prune"), 1);
+        let reply = store
+            .create_message(MessageKind::Channel, "reply", alice.id, None, Some(channel.id), Some(newer.id), "2024-01-07T00:00:00Z")
+            .await
+            .expect("reply");
+        let history = store.list_channel_messages(channel.id, &HistoryPage::Latest, 10).await.expect("history");
+        assert!(history.iter().all(|m| m.id != reply.id));
+        let thread = store.list_thread_messages(newer.id, &HistoryPage::Latest, 10).await.expect("thread");
+        assert_eq!(thread[0].thread_root_id, Some(newer.id));
+        let active = store.list_active_threads(channel.id, 10).await.expect("active");
+        assert_eq!((active[0].root_id, active[0].reply_count), (newer.id, 1));
+        assert_eq!(store.thread_summaries(&[newer.id]).await.expect("summaries")[0].last_reply_at, reply.sent_at);
+        let attachment = Attachment {
+            id: Uuid::new_v4(),
+            hash: format!("{}{}", suffix, suffix),
+            size: 4,
+            content_type: "text/plain".to_string(),
+            filename: "notes.txt".to_string(),
+            uploader_user_id: alice.id,
+            origin_server: Some("remote".to_string()),
+            created_at: "2000-01-01T00:00:00Z".to_string(),
+        };
+        assert!(store.create_attachment(&attachment).await.expect("attachment"));
+        store.attach_to_message(reply.id, &[attachment.id]).await.expect("attach");
+        let linked = store.list_message_attachments(&[reply.id]).await.expect("attachments");
+        assert_eq!((linked[0].0, linked[0].1.origin_server.as_deref()), (reply.id, Some("remote")));
+        assert_eq!(store.list_attachment_message_ids(attachment.id).await.expect("ids"), vec![reply.id]);
+        store.delete_message(reply.id, "2024-01-08T00:00:00Z").await.expect("delete reply");
+        assert!(store.delete_orphaned_attachments("2000-01-02T00:00:00Z").await.expect("gc") >= 1);
+        assert!(!store.has_attachment_with_hash(&attachment.hash).await.expect("hash"));
+        let pin = ChannelPin {
+            channel_id: channel.id,
+            message_id: newer.id,
+            pinned_by_user_id: alice.id,
+            pinned_at: "2024-01-08T00:00:00Z".to_string(),
+        };
+        assert!(store.pin_message(&pin).await.expect("pin"));
+        assert!(!store.pin_message(&pin).await.expect("repeat"));
+        assert_eq!(store.list_channel_pins(channel.id).await.expect("pins")[0].message_id, newer.id);
+        assert!(store.unpin_message(channel.id, newer.id).await.expect("unpin"));
+        assert!(store.pin_message(&pin).await.expect("pin again"));
+        let latest = store
+            .create_message(MessageKind::Channel, "latest", alice.id, None, Some(channel.id), None, "2024-01-09T00:00:00Z")
+            .await
+            .expect("latest");
+        store
+            .create_message(MessageKind::Channel, "late reply", alice.id, None, Some(channel.id), Some(newer.id), "2024-01-10T00:00:00Z")
+            .await
+            .expect("late reply");
+        assert_eq!(store.prune_messages(Some(channel.id), None, Some(1)).await.expect("prune roots"), 3);
+        assert!(store.list_thread_messages(newer.id, &HistoryPage::Latest, 10).await.expect("thread").is_empty());
+        let history = store.list_channel_messages(channel.id, &HistoryPage::Latest, 10).await.expect("history");
+        assert_eq!(history.iter().map(|m| m.id).collect::<Vec<_>>(), vec![latest.id]);
+        assert!(store.list_channel_pins(channel.id).await.expect("pins").is_empty());
+        store.delete_channel(&channel.id).await.expect("delete channel");
+        assert!(!store.list_retention_policies().await.expect("policies").iter().any(|p| p.channel_id == Some(channel.id)));
+        assert!(store.list_channel_pins(channel.id).await.expect("pins").is_empty());
+
+        let bob = store.create_user(&format!("bob-{}", suffix), true, None).await.expect("bob");
+        let mention = format!("ping @{}", alice.username);
+        let first = store
+            .create_message(MessageKind::Dm, &mention, bob.id, Some(alice.id), None, None, "2024-01-03T00:00:00Z")
+            .await
+            .expect("dm");
+        let second = store
+            .create_message(MessageKind::Dm, "later", bob.id, Some(alice.id), None, None, "2024-01-04T00:00:00Z")
+            .await
+            .expect("dm");
+        let dm = ConversationRef::Dm(bob.id);
+        assert_eq!(store.list_dm_partner_ids(alice.id).await.expect("partners"), vec![bob.id]);
+        let authored = store.list_messages_by_author(bob.id, &HistoryPage::Latest, 10).await.expect("authored");
+        assert_eq!(authored.iter().map(|m| m.id).collect::<Vec<_>>(), vec![first.id, second.id]);
+        let mention = Mention {
+            message_id: first.id,
+            user_id: alice.id,
+            kind: MentionKind::User,
+            sent_at: first.sent_at.clone(),
+        };
+        store.add_mentions(&[mention.clone(), mention]).await.expect("mentions");
+        let counts = store.unread_counts(alice.id).await.expect("counts");
+        let count = counts.iter().find(|c| c.conversation == dm).expect("dm count");
+        assert_eq!((count.unread, count.mentions), (2, 1));
+        assert_eq!(store.list_mentions(alice.id, 10).await.expect("list")[0].message_id, first.id);
+        let mut presence = UserPresence {
+            user_id: alice.id,
+            state: PresenceState::Dnd,
+            status_text: Some("focus".to_string()),
+            status_emoji: None,
+            status_expires_at: None,
+            updated_at: first.sent_at.clone(),
+        };
+        store.set_user_presence(&presence).await.expect("presence");
+        presence.state = PresenceState::Invisible;
+        store.set_user_presence(&presence).await.expect("update presence");
+        let stored = store.get_user_presence(alice.id).await.expect("get").expect("presence");
+        assert_eq!((stored.state, stored.status_text.as_deref()), (PresenceState::Invisible, Some("focus")));
+        assert!(store.update_last_seen(&alice.id, "2024-01-05T00:00:00Z").await.expect("last seen"));
+        assert!(!store.update_last_seen(&alice.id, "2024-01-04T00:00:00Z").await.expect("older"));
+        let seen = store.get_user_by_id(alice.id).await.expect("get").expect("alice");
+        assert_eq!(seen.last_seen_at.as_deref(), Some("2024-01-05T00:00:00Z"));
+        let peer = store.create_server(&format!("peer-{}", suffix), "http://peer", "token").await.expect("server");
+        let mut entry = store
+            .enqueue_outbox(peer.id, "/federation/messages", "{}", Some(second.id), "2024-01-05T00:00:00Z")
+            .await
+            .expect("enqueue");
+        let queued = store.enqueue_outbox(peer.id, "/federation/typing", "{}", None, "2024-01-05T00:00:00Z").await.expect("enqueue");
+        let due_for_peer = || async {
+            let due = store.due_outbox_entries("2024-01-05T00:00:00Z").await.expect("due");
+            due.into_iter().filter(|e| e.server_id == peer.id).map(|e| e.id).collect::<Vec<_>>()
+        };
+        assert_eq!(due_for_peer().await, vec![entry.id]);
+        entry.status = DeliveryStatus::Delivered;
+        store.update_outbox_entry(&entry).await.expect("delivered");
+        assert_eq!(due_for_peer().await, vec![queued.id]);
+        store.set_server_status(&peer.id, PeerStatus::Suspended).await.expect("suspend");
+        assert!(due_for_peer().await.is_empty());
+        store.set_server_status(&peer.id, PeerStatus::Active).await.expect("resume");
+        let capabilities = vec!["reactions".to_string(), "typing".to_string()];
+        store.set_server_protocol(&peer.id, "1.2", Some(&capabilities)).await.expect("protocol");
+        let cached = store.get_server_by_id(&peer.id).await.expect("get").expect("peer");
+        assert_eq!((cached.protocol_version.as_deref(), cached.capabilities), (Some("1.2"), Some(capabilities)));
+        let request = PeeringRequest {
+            id: Uuid::new_v4(),
+            direction: PeeringDirection::Incoming,
+            server_name: peer.name.clone(),
+            base_url: "http://peer".to_string(),
+            public_key: "key".to_string(),
+            status: PeeringStatus::Pending,
+            created_at: "2024-01-05T00:00:00Z".to_string(),
+            updated_at: "2024-01-05T00:00:00Z".to_string(),
+        };
+        assert!(store.save_peering_request(&request, Some(1000)).await.expect("peering request"));
+        store.set_peering_request_status(request.id, PeeringStatus::Approved, "2024-01-05T00:00:01Z").await.expect("approve");
+        let stored = store.get_peering_request(request.id).await.expect("get").expect("request");
+        assert_eq!((stored.direction, stored.status), (PeeringDirection::Incoming, PeeringStatus::Approved));
+        assert!(store
+            .get_pending_peering_request(PeeringDirection::Incoming, &peer.name)
+            .await
+            .expect("pending")
+            .is_none());
+        let deliveries = store.message_deliveries(&[second.id]).await.expect("deliveries");
+        assert_eq!((deliveries[0].server_name.as_str(), deliveries[0].status), (peer.name.as_str(), DeliveryStatus::Delivered));
+        assert!(store.prune_delivered_outbox("2024-01-06T00:00:00.000000Z").await.expect("prune") >= 1);
+        assert!(store.get_outbox_entry(entry.id).await.expect("get").is_none());
+        assert!(store.get_outbox_entry(queued.id).await.expect("get").is_some());
+        store.advance_read_marker(alice.id, dm, &MessageCursor::of(&second)).await.expect("advance");
+        let marker = store.advance_read_marker(alice.id, dm, &MessageCursor::of(&first)).await.expect("stale");
+        assert_eq!(marker.message_id, second.id);
+        let counts = store.unread_counts(alice.id).await.expect("counts");
+        assert!(!counts.iter().any(|c| c.conversation == dm));
+
+        store
+            .edit_message(second.id, "edited", bob.id, "2024-01-05T00:00:00Z")
+            .await
+            .expect("edit")
+            .expect("applied");
+        assert_eq!(store.list_message_edits(second.id).await.expect("edits")[0].previous_body, "later");
+        assert!(store.add_reaction(second.id, alice.id, "👍").await.expect("react"));
+        assert!(!store.add_reaction(second.id, alice.id, "👍").await.expect("repeat"));
+        let counts = store.reaction_counts(&[second.id], bob.id).await.expect("counts");
+        assert_eq!((counts[0].count, counts[0].reacted), (1, false));
+        let tombstone = store.delete_message(second.id, "2024-01-06T00:00:00Z").await.expect("delete").expect("applied");
+        assert!(tombstone.body.is_empty());
+        assert!(store.list_message_edits(second.id).await.expect("edits").is_empty());
+        assert!(store.reaction_counts(&[second.id], alice.id).await.expect("counts").is_empty());
+
+        assert!(store.get_message_by_id(second.id).await.expect("lookup").and_then(|m| m.deleted_at).is_some());
+    }
+}
@@ -1,733 +1,2277 @@
-use crate::domain::{Channel, FederationToken, Message, MessageKind, Server, User};
+use crate::domain::{
+    Attachment, Channel, ChannelPin, ConversationRef, DeliveryStatus, FederationToken, HistoryPage, Mention, MentionKind, Message,
+    MessageCursor, MessageDelivery, MessageEdit, MessageKind, MessageSearch, MigrationStatus, OutboxEntry, PeerStatus,
+    PeeringDirection, PeeringRequest, PeeringStatus, PresenceState, ReactionCount, ReadMarker, RetentionPolicy, SearchHit, Server, ThreadSummary, UnreadCount, User, UserPresence,
+};
 use crate::error::AppError;
-use rusqlite::{params, Connection, OptionalExtension};
-use std::sync::{Arc, Mutex};
+use crate::storage::{
+    conversation_key, migrations, parse_conversation_key, render_snippet, Store, SNIPPET_END,
+    SNIPPET_START,
+};
+use async_trait::async_trait;
+use rusqlite::backup::{Backup, StepResult};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use std::path::Path;
+use std::time::Duration;
 use uuid::Uuid;
 
+const DEFAULT_POOL_SIZE: u32 = 8;
+
+/// SQLite-backed store. Connections come from an r2d2 pool and every call
+/// runs on tokio's blocking thread pool, so a slow query only ties up its own
+/// connection instead of the async executor.
 #[derive(Clone)]
 pub struct SqliteStore {
-    conn: Arc<Mutex<Connection>>,
+    pool: r2d2::Pool<ConnectionManager>,
 }
 
 impl SqliteStore {
     pub fn new(path: &str) -> Result<Self, AppError> {
-        let conn = Connection::open(path)?;
-        Ok(Self {
-            conn: Arc::new(Mutex::new(conn)),
+        Self::with_pool_size(path, DEFAULT_POOL_SIZE)
+    }
+
+    pub fn with_pool_size(path: &str, pool_size: u32) -> Result<Self, AppError> {
+        let manager = ConnectionManager {
+            path: path.to_string(),
+        };
+        let pool = r2d2::Pool::builder()
+            .max_size(pool_size.max(1))
+            .build(manager)?;
+        Ok(Self { pool })
+    }
+
+    /// A private database that lives only as long as this store. Backed by a
+    /// single connection that is never recycled, since closing the last
+    /// connection to `:memory:` discards its contents.
+    pub fn in_memory() -> Result<Self, AppError> {
+        let manager = ConnectionManager {
+            path: ":memory:".to_string(),
+        };
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .build(manager)?;
+        Ok(Self { pool })
+    }
+
+    /// Run `f` with a pooled connection on the blocking thread pool.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T, AppError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, AppError> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = pool.get()?;
+            f(&mut conn)
         })
+        .await
+        .map_err(|e| AppError::Internal(format!("database task failed: {}", e)))?
     }
 
-    pub fn init(&self) -> Result<(), AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute_batch(
-            "
-            CREATE TABLE IF NOT EXISTS servers (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL UNIQUE,
-                base_url TEXT NOT NULL,
-                token TEXT NOT NULL
-            );
-            CREATE TABLE IF NOT EXISTS users (
-                id TEXT PRIMARY KEY,
-                username TEXT NOT NULL,
-                token TEXT NOT NULL,
-                server_id TEXT,
-                is_local INTEGER NOT NULL,
-                UNIQUE(username, server_id)
-            );
-            CREATE TABLE IF NOT EXISTS channels (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL,
-                origin_server TEXT NOT NULL,
-                UNIQUE(name, origin_server)
-            );
-            CREATE TABLE IF NOT EXISTS channel_members (
-                channel_id TEXT NOT NULL,
-                user_id TEXT NOT NULL,
-                PRIMARY KEY(channel_id, user_id)
-            );
-            CREATE TABLE IF NOT EXISTS messages (
-                id TEXT PRIMARY KEY,
-                kind TEXT NOT NULL,
-                body TEXT NOT NULL,
-                author_user_id TEXT NOT NULL,
-                recipient_user_id TEXT,
-                channel_id TEXT,
-                sent_at TEXT NOT NULL
-            );
-            CREATE TABLE IF NOT EXISTS federation_tokens (
-                id TEXT PRIMARY KEY,
-                token TEXT NOT NULL UNIQUE,
-                label TEXT NOT NULL,
-                created_at TEXT NOT NULL
-            );
-            CREATE TABLE IF NOT EXISTS server_hidden_users (
-                server_id TEXT NOT NULL,
-                user_id TEXT NOT NULL,
-                PRIMARY KEY(server_id, user_id)
-            );
-            CREATE TABLE IF NOT EXISTS server_hidden_channels (
-                server_id TEXT NOT NULL,
-                channel_id TEXT NOT NULL,
-                PRIMARY KEY(server_id, channel_id)
-            );
-            ",
-        )?;
-        // Migration: add display_name column if not present
-        let _ = conn.execute_batch("ALTER TABLE users ADD COLUMN display_name TEXT;");
-        // Migration: add password_hash column if not present
-        let _ = conn.execute_batch("ALTER TABLE users ADD COLUMN password_hash TEXT;");
-        Ok(())
-    }
-
-    pub fn ensure_server(&self, name: &str, base_url: &str, token: &str) -> Result<Server, AppError> {
-        if let Some(existing) = self.get_server_by_name(name)? {
-            return Ok(existing);
-        }
-        self.create_server(name, base_url, token)
+}
+
+#[async_trait]
+impl Store for SqliteStore {
+    async fn init(&self) -> Result<(), AppError> {
+        self.with_conn(migrations::run).await
     }
 
-    pub fn create_server(&self, name: &str, base_url: &str, token: &str) -> Result<Server, AppError> {
-        let id = Uuid::new_v4();
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute(
-            "INSERT INTO servers (id, name, base_url, token) VALUES (?1, ?2, ?3, ?4)",
-            params![id.to_string(), name, base_url, token],
-        )?;
-        Ok(Server {
-            id,
+    async fn migration_status(&self) -> Result<Vec<MigrationStatus>, AppError> {
+        self.with_conn(|conn| migrations::status(conn)).await
+    }
+
+    async fn backup_to(&self, dest: &Path) -> Result<(), AppError> {
+        let dest = dest.to_path_buf();
+        self.with_conn(move |conn| {
+            // Write beside the target and rename, so a partial file never
+            // carries the final name.
+            let tmp = dest.with_extension("partial");
+            let mut dst = Connection::open(&tmp)?;
+            {
+                let backup = Backup::new(conn, &mut dst)?;
+                let mut attempts = 0;
+                loop {
+                    // Copy every page in one step so the snapshot comes from a
+                    // single read transaction. In WAL mode writers carry on.
+                    match backup.step(-1)? {
+                        StepResult::Done => break,
+                        StepResult::Busy | StepResult::Locked if attempts < 100 => {
+                            attempts += 1;
+                            std::thread::sleep(Duration::from_millis(50));
+                        }
+                        StepResult::Busy | StepResult::Locked => {
+                            return Err(AppError::Internal("database stayed locked during backup".to_string()));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            drop(dst);
+            std::fs::rename(&tmp, &dest).map_err(|e| AppError::Internal(format!("backup rename failed: {}", e)))?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn create_server(&self, name: &str, base_url: &str, token: &str) -> Result<Server, AppError> {
+        let server = Server {
+            id: Uuid::new_v4(),
             name: name.to_string(),
             base_url: base_url.to_string(),
             token: token.to_string(),
+            public_key: None,
+            status: PeerStatus::Active,
+            protocol_version: None,
+            capabilities: None,
+        };
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO servers (id, name, base_url, token) VALUES (?1, ?2, ?3, ?4)",
+                params![server.id.to_string(), server.name, server.base_url, server.token],
+            )?;
+            Ok(server)
         })
+        .await
     }
 
-    pub fn seed_initial_data(&self, server_name: &str) -> Result<(), AppError> {
-        tracing::info!("Seeding federation references for server '{}'", server_name);
-        
-        // Create federation references to other servers if not present
-        let other_servers = match server_name {
-            "a" => vec![
-                ("b", "http://server_b:8080", "token-b"),
-                ("c", "http://server_c:8080", "token-c"),
-            ],
-            "b" => vec![
-                ("a", "http://server_a:8080", "token-a"),
-                ("c", "http://server_c:8080", "token-c"),
-            ],
-            "c" => vec![
-                ("a", "http://server_a:8080", "token-a"),
-                ("b", "http://server_b:8080", "token-b"),
-            ],
-            _ => vec![],
-        };
+    async fn get_server_by_name(&self, name: &str) -> Result<Option<Server>, AppError> {
+        let name = name.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                &format!("SELECT {} FROM servers WHERE name = ?1", SERVER_COLUMNS),
+                params![name],
+                row_to_server,
+            )
+            .optional()
+            .map_err(AppError::from)
+        })
+        .await
+    }
 
-        for (name, url, token) in other_servers {
-            self.ensure_server(name, url, token)?;
-            tracing::debug!("Federation reference to server '{}' ensured", name);
-        }
+    async fn get_server_by_id(&self, id: &Uuid) -> Result<Option<Server>, AppError> {
+        let id = *id;
+        self.with_conn(move |conn| {
+            conn.query_row(
+                &format!("SELECT {} FROM servers WHERE id = ?1", SERVER_COLUMNS),
+                params![id.to_string()],
+                row_to_server,
+            )
+            .optional()
+            .map_err(AppError::from)
+        })
+        .await
+    }
 
-        Ok(())
-    }
-
-    pub fn get_server_by_name(&self, name: &str) -> Result<Option<Server>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.query_row(
-            "SELECT id, name, base_url, token FROM servers WHERE name = ?1",
-            params![name],
-            |row| row_to_server(row),
-        )
-        .optional()
-        .map_err(AppError::from)
-    }
-
-    pub fn get_server_by_id(&self, id: &Uuid) -> Result<Option<Server>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.query_row(
-            "SELECT id, name, base_url, token FROM servers WHERE id = ?1",
-            params![id.to_string()],
-            |row| row_to_server(row),
-        )
-        .optional()
-        .map_err(AppError::from)
-    }
-
-    pub fn get_server_by_token(&self, token: &str) -> Result<Option<Server>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.query_row(
-            "SELECT id, name, base_url, token FROM servers WHERE token = ?1",
-            params![token],
-            |row| row_to_server(row),
-        )
-        .optional()
-        .map_err(AppError::from)
-    }
-
-    pub fn list_servers(&self) -> Result<Vec<Server>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        let mut stmt = conn.prepare(
-            "SELECT id, name, base_url, token FROM servers ORDER BY name",
-        )?;
-        let rows = stmt.query_map([], |row| row_to_server(row))?;
-        let mut servers = Vec::new();
-        for row in rows {
-            servers.push(row?);
-        }
-        Ok(servers)
+    async fn get_server_by_token(&self, token: &str) -> Result<Option<Server>, AppError> {
+        let token = token.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                &format!("SELECT {} FROM servers WHERE token = ?1", SERVER_COLUMNS),
+                params![token],
+                row_to_server,
+            )
+            .optional()
+            .map_err(AppError::from)
+        })
+        .await
     }
 
-    pub fn create_user(
-        &self,
-        username: &str,
-        is_local: bool,
-        server_id: Option<Uuid>,
-    ) -> Result<User, AppError> {
-        self.create_user_with_password(username, is_local, server_id, None)
+    async fn list_servers(&self) -> Result<Vec<Server>, AppError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(&format!("SELECT {} FROM servers ORDER BY name", SERVER_COLUMNS))?;
+            let rows = stmt.query_map([], row_to_server)?;
+            let mut servers = Vec::new();
+            for row in rows {
+                servers.push(row?);
+            }
+            Ok(servers)
+        })
+        .await
     }
 
-    pub fn create_user_with_password(
+    async fn create_user_with_password(
         &self,
         username: &str,
         is_local: bool,
         server_id: Option<Uuid>,
         password_hash: Option<&str>,
     ) -> Result<User, AppError> {
-        let id = Uuid::new_v4();
         let token = if is_local {
             Uuid::new_v4().to_string()
         } else {
             "".to_string()
         };
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute(
-            "INSERT INTO users (id, username, token, server_id, is_local, password_hash) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
-            params![id.to_string(), username, token, server_id.map(|s| s.to_string()), is_local as i32, password_hash],
-        )?;
-        Ok(User {
-            id,
+        let user = User {
+            id: Uuid::new_v4(),
             username: username.to_string(),
             token,
             server_id,
-            is_local,
-            display_name: None,
+            is_local,
+            display_name: None,
+            last_seen_at: None,
+        };
+        let password_hash = password_hash.map(str::to_string);
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO users (id, username, token, server_id, is_local, password_hash) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![user.id.to_string(), user.username, user.token, user.server_id.map(|s| s.to_string()), user.is_local as i32, password_hash],
+            )?;
+            Ok(user)
+        })
+        .await
+    }
+
+    async fn list_users(&self) -> Result<Vec<User>, AppError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, username, token, server_id, is_local, display_name, last_seen_at FROM users ORDER BY username",
+            )?;
+            let rows = stmt.query_map([], row_to_user)?;
+            let mut users = Vec::new();
+            for row in rows {
+                users.push(row?);
+            }
+            Ok(users)
+        })
+        .await
+    }
+
+    async fn get_user_by_token(&self, token: &str) -> Result<Option<User>, AppError> {
+        let token = token.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT id, username, token, server_id, is_local, display_name, last_seen_at FROM users WHERE token = ?1",
+                params![token],
+                row_to_user,
+            )
+            .optional()
+            .map_err(AppError::from)
+        })
+        .await
+    }
+
+    async fn get_user_by_name_and_server(
+        &self,
+        username: &str,
+        server_id: Option<Uuid>,
+    ) -> Result<Option<User>, AppError> {
+        let username = username.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT id, username, token, server_id, is_local, display_name, last_seen_at FROM users WHERE username = ?1 AND COALESCE(server_id, '') = COALESCE(?2, '')",
+                params![username, server_id.map(|s| s.to_string())],
+                row_to_user,
+            )
+            .optional()
+            .map_err(AppError::from)
+        })
+        .await
+    }
+
+    async fn get_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, AppError> {
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT id, username, token, server_id, is_local, display_name, last_seen_at FROM users WHERE id = ?1",
+                params![user_id.to_string()],
+                row_to_user,
+            )
+            .optional()
+            .map_err(AppError::from)
+        })
+        .await
+    }
+
+    async fn create_channel(&self, name: &str, origin_server: &str) -> Result<Channel, AppError> {
+        let channel = Channel {
+            id: Uuid::new_v4(),
+            name: name.to_string(),
+            origin_server: origin_server.to_string(),
+        };
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO channels (id, name, origin_server) VALUES (?1, ?2, ?3)",
+                params![channel.id.to_string(), channel.name, channel.origin_server],
+            )?;
+            Ok(channel)
+        })
+        .await
+    }
+
+    async fn list_channels(&self) -> Result<Vec<Channel>, AppError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, name, origin_server FROM channels ORDER BY name",
+            )?;
+            let rows = stmt.query_map([], row_to_channel)?;
+            let mut channels = Vec::new();
+            for row in rows {
+                channels.push(row?);
+            }
+            Ok(channels)
+        })
+        .await
+    }
+
+    async fn get_channel_by_name_origin(
+        &self,
+        name: &str,
+        origin_server: &str,
+    ) -> Result<Option<Channel>, AppError> {
+        let name = name.to_string();
+        let origin_server = origin_server.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT id, name, origin_server FROM channels WHERE name = ?1 AND origin_server = ?2",
+                params![name, origin_server],
+                row_to_channel,
+            )
+            .optional()
+            .map_err(AppError::from)
+        })
+        .await
+    }
+
+    async fn get_channel_by_id(&self, id: Uuid) -> Result<Option<Channel>, AppError> {
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT id, name, origin_server FROM channels WHERE id = ?1",
+                params![id.to_string()],
+                row_to_channel,
+            )
+            .optional()
+            .map_err(AppError::from)
+        })
+        .await
+    }
+
+    async fn add_channel_member(&self, channel_id: Uuid, user_id: Uuid) -> Result<(), AppError> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO channel_members (channel_id, user_id) VALUES (?1, ?2)",
+                params![channel_id.to_string(), user_id.to_string()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_channel_member_servers(&self, channel_id: Uuid) -> Result<Vec<Server>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "
+                SELECT DISTINCT s.id, s.name, s.base_url, s.token, s.public_key, s.status, s.protocol_version, s.capabilities
+                FROM channel_members cm
+                JOIN users u ON cm.user_id = u.id
+                JOIN servers s ON u.server_id = s.id
+                WHERE cm.channel_id = ?1 AND u.server_id IS NOT NULL
+                ",
+            )?;
+            let rows = stmt.query_map(params![channel_id.to_string()], row_to_server)?;
+
+            let mut servers = Vec::new();
+            for row in rows {
+                servers.push(row?);
+            }
+            Ok(servers)
+        })
+        .await
+    }
+
+    async fn is_channel_member(&self, channel_id: Uuid, user_id: Uuid) -> Result<bool, AppError> {
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT 1 FROM channel_members WHERE channel_id = ?1 AND user_id = ?2",
+                    params![channel_id.to_string(), user_id.to_string()],
+                    |_| Ok(()),
+                )
+                .optional()?
+                .is_some())
+        })
+        .await
+    }
+
+    async fn list_channel_members(&self, channel_id: Uuid) -> Result<Vec<User>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT u.id, u.username, u.token, u.server_id, u.is_local, u.display_name, u.last_seen_at
+                 FROM channel_members cm
+                 JOIN users u ON u.id = cm.user_id
+                 WHERE cm.channel_id = ?1
+                 ORDER BY u.username",
+            )?;
+            let rows = stmt.query_map(params![channel_id.to_string()], row_to_user)?;
+            let mut users = Vec::new();
+            for row in rows {
+                users.push(row?);
+            }
+            Ok(users)
+        })
+        .await
+    }
+
+    async fn list_channels_for_user(&self, user_id: Uuid) -> Result<Vec<Channel>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT c.id, c.name, c.origin_server
+                 FROM channel_members cm
+                 JOIN channels c ON c.id = cm.channel_id
+                 WHERE cm.user_id = ?1
+                 ORDER BY c.name",
+            )?;
+            let rows = stmt.query_map(params![user_id.to_string()], row_to_channel)?;
+            let mut channels = Vec::new();
+            for row in rows {
+                channels.push(row?);
+            }
+            Ok(channels)
+        })
+        .await
+    }
+
+    async fn create_message(
+        &self,
+        kind: MessageKind,
+        body: &str,
+        author_user_id: Uuid,
+        recipient_user_id: Option<Uuid>,
+        channel_id: Option<Uuid>,
+        thread_root_id: Option<Uuid>,
+        sent_at: &str,
+    ) -> Result<Message, AppError> {
+        let message = Message {
+            id: Uuid::new_v4(),
+            kind,
+            body: body.to_string(),
+            author_user_id,
+            recipient_user_id,
+            channel_id,
+            sent_at: sent_at.to_string(),
+            edited_at: None,
+            deleted_at: None,
+            thread_root_id,
+        };
+        self.with_conn(move |conn| {
+            insert_message(conn, "INSERT", &message)?;
+            Ok(message)
+        })
+        .await
+    }
+
+    async fn create_message_with_id(
+        &self,
+        id_str: &str,
+        kind: MessageKind,
+        body: &str,
+        author_user_id: Uuid,
+        recipient_user_id: Option<Uuid>,
+        channel_id: Option<Uuid>,
+        thread_root_id: Option<Uuid>,
+        sent_at: &str,
+    ) -> Result<Option<Message>, AppError> {
+        let id = Uuid::parse_str(id_str)
+            .map_err(|_| AppError::BadRequest("invalid message_id".to_string()))?;
+        let message = Message {
+            id,
+            kind,
+            body: body.to_string(),
+            author_user_id,
+            recipient_user_id,
+            channel_id,
+            sent_at: sent_at.to_string(),
+            edited_at: None,
+            deleted_at: None,
+            thread_root_id,
+        };
+
+        self.with_conn(move |conn| {
+            let inserted = insert_message(conn, "INSERT OR IGNORE", &message)?;
+            if inserted == 0 {
+                return Ok(None);
+            }
+            Ok(Some(message))
+        })
+        .await
+    }
+
+    async fn list_messages_for_user(&self, user_id: Uuid, limit: usize) -> Result<Vec<Message>, AppError> {
+        self.with_conn(move |conn| {
+            let mut messages = Vec::new();
+
+            let mut stmt = conn.prepare(
+                "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                 FROM messages
+                 WHERE recipient_user_id = ?1 AND deleted_at IS NULL
+                 ORDER BY sent_at DESC
+                 LIMIT ?2",
+            )?;
+            let rows = stmt.query_map(params![user_id.to_string(), limit as i64], row_to_message)?;
+            for row in rows {
+                messages.push(row?);
+            }
+
+            let channel_ids = channel_ids_for_user(conn, user_id)?;
+            for channel_id in channel_ids {
+                let mut stmt = conn.prepare(
+                    "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                     FROM messages
+                     WHERE channel_id = ?1 AND deleted_at IS NULL
+                     ORDER BY sent_at DESC
+                     LIMIT ?2",
+                )?;
+                let rows = stmt.query_map(params![channel_id.to_string(), limit as i64], row_to_message)?;
+                for row in rows {
+                    messages.push(row?);
+                }
+            }
+
+            messages.sort_by(|a, b| b.sent_at.cmp(&a.sent_at));
+            messages.truncate(limit);
+            Ok(messages)
+        })
+        .await
+    }
+
+    async fn update_last_seen(&self, id: &Uuid, at: &str) -> Result<bool, AppError> {
+        let id = *id;
+        let at = at.to_string();
+        self.with_conn(move |conn| {
+            let updated = conn.execute(
+                "UPDATE users SET last_seen_at = ?1 WHERE id = ?2 AND (last_seen_at IS NULL OR last_seen_at < ?1)",
+                params![at, id.to_string()],
+            )?;
+            Ok(updated > 0)
+        })
+        .await
+    }
+
+    async fn set_user_password(&self, user_id: &Uuid, hash: &str) -> Result<(), AppError> {
+        let user_id = *user_id;
+        let hash = hash.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE users SET password_hash = ?1 WHERE id = ?2",
+                params![hash, user_id.to_string()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn get_user_password_hash(&self, user_id: &Uuid) -> Result<Option<String>, AppError> {
+        let user_id = *user_id;
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT password_hash FROM users WHERE id = ?1",
+                params![user_id.to_string()],
+                |row| row.get::<_, Option<String>>(0),
+            )
+            .optional()
+            .map(|opt| opt.flatten())
+            .map_err(AppError::from)
+        })
+        .await
+    }
+
+    async fn delete_user(&self, id: &Uuid) -> Result<(), AppError> {
+        let id = *id;
+        self.with_conn(move |conn| {
+            conn.execute("DELETE FROM users WHERE id = ?1", params![id.to_string()])?;
+            conn.execute("DELETE FROM user_presence WHERE user_id = ?1", params![id.to_string()])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_user(&self, id: &Uuid, username: &str, display_name: Option<&str>) -> Result<User, AppError> {
+        let id = *id;
+        let username = username.to_string();
+        let display_name = display_name.map(str::to_string);
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE users SET username = ?1, display_name = ?2 WHERE id = ?3",
+                params![username, display_name, id.to_string()],
+            )?;
+            conn.query_row(
+                "SELECT id, username, token, server_id, is_local, display_name, last_seen_at FROM users WHERE id = ?1",
+                params![id.to_string()],
+                row_to_user,
+            )
+            .map_err(AppError::from)
+        })
+        .await
+    }
+
+    async fn update_user_display_name(&self, id: &Uuid, display_name: Option<&str>) -> Result<(), AppError> {
+        let id = *id;
+        let display_name = display_name.map(str::to_string);
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE users SET display_name = ?1 WHERE id = ?2",
+                params![display_name, id.to_string()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_server(&self, id: &Uuid) -> Result<(), AppError> {
+        let id = *id;
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM server_hidden_users WHERE server_id = ?1", params![id.to_string()])?;
+            tx.execute("DELETE FROM server_hidden_channels WHERE server_id = ?1", params![id.to_string()])?;
+            tx.execute("DELETE FROM federation_outbox WHERE server_id = ?1", params![id.to_string()])?;
+            tx.execute("DELETE FROM servers WHERE id = ?1", params![id.to_string()])?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_server(&self, id: &Uuid, name: &str, base_url: &str, token: &str) -> Result<Server, AppError> {
+        let id = *id;
+        let name = name.to_string();
+        let base_url = base_url.to_string();
+        let token = token.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE servers
+                 SET name = ?1, token = ?3,
+                     public_key = CASE WHEN base_url = ?2 THEN public_key END,
+                     base_url = ?2
+                 WHERE id = ?4",
+                params![name, base_url, token, id.to_string()],
+            )?;
+            let server = conn.query_row(
+                &format!("SELECT {} FROM servers WHERE id = ?1", SERVER_COLUMNS),
+                params![id.to_string()],
+                row_to_server,
+            )?;
+            Ok(server)
+        })
+        .await
+    }
+
+    async fn set_server_public_key(&self, id: &Uuid, public_key: &str) -> Result<(), AppError> {
+        let id = *id;
+        let public_key = public_key.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE servers SET public_key = ?1 WHERE id = ?2",
+                params![public_key, id.to_string()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_server_status(&self, id: &Uuid, status: PeerStatus) -> Result<(), AppError> {
+        let id = *id;
+        self.with_conn(move |conn| {
+            conn.execute("UPDATE servers SET status = ?1 WHERE id = ?2", params![status.as_str(), id.to_string()])?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn set_server_protocol(
+        &self,
+        id: &Uuid,
+        protocol_version: &str,
+        capabilities: Option<&[String]>,
+    ) -> Result<(), AppError> {
+        let id = *id;
+        let protocol_version = protocol_version.to_string();
+        let capabilities = capabilities.map(|c| c.join(","));
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE servers SET protocol_version = ?1, capabilities = ?2 WHERE id = ?3",
+                params![protocol_version, capabilities, id.to_string()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn save_peering_request(&self, request: &PeeringRequest, max_pending: Option<usize>) -> Result<bool, AppError> {
+        let request = request.clone();
+        self.with_conn(move |conn| {
+            // Take the write lock up front so concurrent saves count in turn
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            tx.execute(
+                "DELETE FROM peering_requests WHERE direction = ?1 AND server_name = ?2 AND status = 'pending'",
+                params![request.direction.as_str(), request.server_name],
+            )?;
+            if let Some(max_pending) = max_pending {
+                let pending: i64 = tx.query_row(
+                    "SELECT COUNT(*) FROM peering_requests WHERE direction = ?1 AND status = 'pending'",
+                    params![request.direction.as_str()],
+                    |row| row.get(0),
+                )?;
+                if pending as usize >= max_pending {
+                    return Ok(false);
+                }
+            }
+            tx.execute(
+                "INSERT INTO peering_requests
+                    (id, direction, server_name, base_url, public_key, status, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    request.id.to_string(),
+                    request.direction.as_str(),
+                    request.server_name,
+                    request.base_url,
+                    request.public_key,
+                    request.status.as_str(),
+                    request.created_at,
+                    request.updated_at,
+                ],
+            )?;
+            tx.commit()?;
+            Ok(true)
+        })
+        .await
+    }
+
+    async fn get_peering_request(&self, id: Uuid) -> Result<Option<PeeringRequest>, AppError> {
+        self.with_conn(move |conn| {
+            let request = conn
+                .query_row(
+                    &format!("SELECT {} FROM peering_requests WHERE id = ?1", PEERING_COLUMNS),
+                    params![id.to_string()],
+                    row_to_peering_request,
+                )
+                .optional()?;
+            Ok(request)
+        })
+        .await
+    }
+
+    async fn get_pending_peering_request(
+        &self,
+        direction: PeeringDirection,
+        server_name: &str,
+    ) -> Result<Option<PeeringRequest>, AppError> {
+        let server_name = server_name.to_string();
+        self.with_conn(move |conn| {
+            let request = conn
+                .query_row(
+                    &format!(
+                        "SELECT {} FROM peering_requests
+                         WHERE direction = ?1 AND server_name = ?2 AND status = 'pending'",
+                        PEERING_COLUMNS
+                    ),
+                    params![direction.as_str(), server_name],
+                    row_to_peering_request,
+                )
+                .optional()?;
+            Ok(request)
+        })
+        .await
+    }
+
+    async fn list_peering_requests(&self) -> Result<Vec<PeeringRequest>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM peering_requests ORDER BY created_at DESC, id",
+                PEERING_COLUMNS
+            ))?;
+            let rows = stmt.query_map([], row_to_peering_request)?;
+            let mut requests = Vec::new();
+            for row in rows {
+                requests.push(row?);
+            }
+            Ok(requests)
+        })
+        .await
+    }
+
+    async fn set_peering_request_status(&self, id: Uuid, status: PeeringStatus, now: &str) -> Result<(), AppError> {
+        let now = now.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "UPDATE peering_requests SET status = ?1, updated_at = ?2 WHERE id = ?3",
+                params![status.as_str(), now, id.to_string()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_channel(&self, id: &Uuid) -> Result<(), AppError> {
+        let id = *id;
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM retention_policies WHERE scope = ?1", params![id.to_string()])?;
+            tx.execute("DELETE FROM read_markers WHERE conversation = ?1", params![id.to_string()])?;
+            tx.execute("DELETE FROM channel_pins WHERE channel_id = ?1", params![id.to_string()])?;
+            tx.execute("DELETE FROM channels WHERE id = ?1", params![id.to_string()])?;
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn update_channel(&self, id: &Uuid, name: &str) -> Result<Channel, AppError> {
+        let id = *id;
+        let name = name.to_string();
+        self.with_conn(move |conn| {
+            conn.execute("UPDATE channels SET name = ?1 WHERE id = ?2", params![name, id.to_string()])?;
+            let channel = conn.query_row(
+                "SELECT id, name, origin_server FROM channels WHERE id = ?1",
+                params![id.to_string()],
+                row_to_channel,
+            )?;
+            Ok(channel)
+        })
+        .await
+    }
+
+    async fn get_message_by_id(&self, id: Uuid) -> Result<Option<Message>, AppError> {
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                     FROM messages WHERE id = ?1",
+                    params![id.to_string()],
+                    row_to_message,
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn list_channel_messages(&self, channel_id: Uuid, page: &HistoryPage, limit: usize) -> Result<Vec<Message>, AppError> {
+        let page = page.clone();
+        self.with_conn(move |conn| {
+            let (condition, order) = page_clause(&page);
+            let (cursor_at, cursor_id) = page_cursor(&page);
+            let mut stmt = conn.prepare(&format!(
+                "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                 FROM messages
+                 WHERE channel_id = ?1 AND thread_root_id IS NULL AND {}
+                 ORDER BY sent_at {}, id {}
+                 LIMIT ?4",
+                condition, order, order
+            ))?;
+            let rows = stmt.query_map(
+                params![channel_id.to_string(), cursor_at, cursor_id, limit as i64],
+                row_to_message,
+            )?;
+            collect_page(rows, order)
+        })
+        .await
+    }
+
+    async fn list_dm_messages(
+        &self,
+        user_id: Uuid,
+        other_user_id: Uuid,
+        page: &HistoryPage,
+        limit: usize,
+    ) -> Result<Vec<Message>, AppError> {
+        let page = page.clone();
+        self.with_conn(move |conn| {
+            let (condition, order) = page_clause(&page);
+            let (cursor_at, cursor_id) = page_cursor(&page);
+            let mut stmt = conn.prepare(&format!(
+                "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                 FROM messages
+                 WHERE ((author_user_id = ?1 AND recipient_user_id = ?5)
+                        OR (author_user_id = ?5 AND recipient_user_id = ?1))
+                   AND {}
+                 ORDER BY sent_at {}, id {}
+                 LIMIT ?4",
+                condition, order, order
+            ))?;
+            let rows = stmt.query_map(
+                params![user_id.to_string(), cursor_at, cursor_id, limit as i64, other_user_id.to_string()],
+                row_to_message,
+            )?;
+            collect_page(rows, order)
+        })
+        .await
+    }
+
+    async fn list_messages_by_author(&self, user_id: Uuid, page: &HistoryPage, limit: usize) -> Result<Vec<Message>, AppError> {
+        let page = page.clone();
+        self.with_conn(move |conn| {
+            let (condition, order) = page_clause(&page);
+            let (cursor_at, cursor_id) = page_cursor(&page);
+            let mut stmt = conn.prepare(&format!(
+                "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                 FROM messages
+                 WHERE author_user_id = ?1 AND {}
+                 ORDER BY sent_at {}, id {}
+                 LIMIT ?4",
+                condition, order, order
+            ))?;
+            let rows = stmt.query_map(
+                params![user_id.to_string(), cursor_at, cursor_id, limit as i64],
+                row_to_message,
+            )?;
+            collect_page(rows, order)
+        })
+        .await
+    }
+
+    async fn list_dm_partner_ids(&self, user_id: Uuid) -> Result<Vec<Uuid>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT recipient_user_id FROM messages WHERE kind = 'dm' AND author_user_id = ?1
+                 UNION
+                 SELECT author_user_id FROM messages WHERE kind = 'dm' AND recipient_user_id = ?1",
+            )?;
+            let rows = stmt.query_map(params![user_id.to_string()], |row| {
+                Uuid::parse_str(row.get::<_, String>(0)?.as_str()).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+                })
+            })?;
+            let mut ids = Vec::new();
+            for row in rows {
+                ids.push(row?);
+            }
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn list_thread_messages(&self, root_id: Uuid, page: &HistoryPage, limit: usize) -> Result<Vec<Message>, AppError> {
+        let page = page.clone();
+        self.with_conn(move |conn| {
+            let (condition, order) = page_clause(&page);
+            let (cursor_at, cursor_id) = page_cursor(&page);
+            let mut stmt = conn.prepare(&format!(
+                "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                 FROM messages
+                 WHERE thread_root_id = ?1 AND {}
+                 ORDER BY sent_at {}, id {}
+                 LIMIT ?4",
+                condition, order, order
+            ))?;
+            let rows = stmt.query_map(
+                params![root_id.to_string(), cursor_at, cursor_id, limit as i64],
+                row_to_message,
+            )?;
+            collect_page(rows, order)
+        })
+        .await
+    }
+
+    async fn thread_summaries(&self, root_ids: &[Uuid]) -> Result<Vec<ThreadSummary>, AppError> {
+        if root_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ids = serde_json::to_string(&root_ids.iter().map(Uuid::to_string).collect::<Vec<_>>())
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT thread_root_id, COUNT(*), MAX(sent_at)
+                 FROM messages
+                 WHERE thread_root_id IN (SELECT value FROM json_each(?1)) AND deleted_at IS NULL
+                 GROUP BY thread_root_id",
+            )?;
+            let rows = stmt.query_map(params![ids], row_to_thread_summary)?;
+            let mut summaries = Vec::new();
+            for row in rows {
+                summaries.push(row?);
+            }
+            Ok(summaries)
+        })
+        .await
+    }
+
+    async fn list_active_threads(&self, channel_id: Uuid, limit: usize) -> Result<Vec<ThreadSummary>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT thread_root_id, COUNT(*), MAX(sent_at)
+                 FROM messages
+                 WHERE channel_id = ?1 AND thread_root_id IS NOT NULL AND deleted_at IS NULL
+                 GROUP BY thread_root_id
+                 ORDER BY MAX(sent_at) DESC, thread_root_id
+                 LIMIT ?2",
+            )?;
+            let rows = stmt.query_map(params![channel_id.to_string(), limit as i64], row_to_thread_summary)?;
+            let mut summaries = Vec::new();
+            for row in rows {
+                summaries.push(row?);
+            }
+            Ok(summaries)
+        })
+        .await
+    }
+
+    async fn edit_message(
+        &self,
+        id: Uuid,
+        body: &str,
+        editor_user_id: Uuid,
+        edited_at: &str,
+    ) -> Result<Option<Message>, AppError> {
+        let body = body.to_string();
+        let edited_at = edited_at.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let current = tx
+                .query_row(
+                    "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                     FROM messages WHERE id = ?1",
+                    params![id.to_string()],
+                    row_to_message,
+                )
+                .optional()?;
+            let Some(current) = current else {
+                return Ok(None);
+            };
+            if current.deleted_at.is_some() || current.edited_at.as_deref().is_some_and(|at| at >= edited_at.as_str()) {
+                return Ok(None);
+            }
+            tx.execute(
+                "INSERT INTO message_edits (message_id, previous_body, edited_at, editor_user_id) VALUES (?1, ?2, ?3, ?4)",
+                params![id.to_string(), current.body, edited_at, editor_user_id.to_string()],
+            )?;
+            tx.execute(
+                "UPDATE messages SET body = ?1, edited_at = ?2 WHERE id = ?3",
+                params![body, edited_at, id.to_string()],
+            )?;
+            tx.commit()?;
+            Ok(Some(Message {
+                body,
+                edited_at: Some(edited_at),
+                ..current
+            }))
+        })
+        .await
+    }
+
+    async fn delete_message(&self, id: Uuid, deleted_at: &str) -> Result<Option<Message>, AppError> {
+        let deleted_at = deleted_at.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let current = tx
+                .query_row(
+                    "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, edited_at, deleted_at, thread_root_id
+                     FROM messages WHERE id = ?1",
+                    params![id.to_string()],
+                    row_to_message,
+                )
+                .optional()?;
+            let Some(current) = current.filter(|m| m.deleted_at.is_none()) else {
+                return Ok(None);
+            };
+            tx.execute("DELETE FROM message_edits WHERE message_id = ?1", params![id.to_string()])?;
+            tx.execute("DELETE FROM message_reactions WHERE message_id = ?1", params![id.to_string()])?;
+            tx.execute("DELETE FROM message_attachments WHERE message_id = ?1", params![id.to_string()])?;
+            tx.execute("DELETE FROM channel_pins WHERE message_id = ?1", params![id.to_string()])?;
+            tx.execute("DELETE FROM mentions WHERE message_id = ?1", params![id.to_string()])?;
+            tx.execute(
+                "UPDATE messages SET body = '', deleted_at = ?1 WHERE id = ?2",
+                params![deleted_at, id.to_string()],
+            )?;
+            tx.commit()?;
+            Ok(Some(Message {
+                body: String::new(),
+                deleted_at: Some(deleted_at),
+                ..current
+            }))
+        })
+        .await
+    }
+
+    async fn list_message_edits(&self, id: Uuid) -> Result<Vec<MessageEdit>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT previous_body, edited_at, editor_user_id FROM message_edits
+                 WHERE message_id = ?1 ORDER BY edited_at",
+            )?;
+            let rows = stmt.query_map(params![id.to_string()], |row| {
+                Ok(MessageEdit {
+                    message_id: id,
+                    previous_body: row.get(0)?,
+                    edited_at: row.get(1)?,
+                    editor_user_id: Uuid::parse_str(row.get::<_, String>(2)?.as_str()).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(e))
+                    })?,
+                })
+            })?;
+            let mut edits = Vec::new();
+            for row in rows {
+                edits.push(row?);
+            }
+            Ok(edits)
+        })
+        .await
+    }
+
+    async fn add_reaction(&self, message_id: Uuid, user_id: Uuid, emoji: &str) -> Result<bool, AppError> {
+        let emoji = emoji.to_string();
+        let created_at = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        self.with_conn(move |conn| {
+            let inserted = conn.execute(
+                "INSERT OR IGNORE INTO message_reactions (message_id, user_id, emoji, created_at) VALUES (?1, ?2, ?3, ?4)",
+                params![message_id.to_string(), user_id.to_string(), emoji, created_at],
+            )?;
+            Ok(inserted > 0)
+        })
+        .await
+    }
+
+    async fn remove_reaction(&self, message_id: Uuid, user_id: Uuid, emoji: &str) -> Result<bool, AppError> {
+        let emoji = emoji.to_string();
+        self.with_conn(move |conn| {
+            let removed = conn.execute(
+                "DELETE FROM message_reactions WHERE message_id = ?1 AND user_id = ?2 AND emoji = ?3",
+                params![message_id.to_string(), user_id.to_string(), emoji],
+            )?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn reaction_counts(&self, message_ids: &[Uuid], viewer_id: Uuid) -> Result<Vec<ReactionCount>, AppError> {
+        if message_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        // The ids travel as one JSON array so the statement stays fixed-size.
+        let ids = serde_json::to_string(&message_ids.iter().map(Uuid::to_string).collect::<Vec<_>>())
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT message_id, emoji, COUNT(*), MAX(user_id = ?2)
+                 FROM message_reactions
+                 WHERE message_id IN (SELECT value FROM json_each(?1))
+                 GROUP BY message_id, emoji
+                 ORDER BY message_id, MIN(created_at), emoji",
+            )?;
+            let rows = stmt.query_map(params![ids, viewer_id.to_string()], |row| {
+                Ok(ReactionCount {
+                    message_id: Uuid::parse_str(row.get::<_, String>(0)?.as_str()).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+                    })?,
+                    emoji: row.get(1)?,
+                    count: row.get::<_, i64>(2)? as u64,
+                    reacted: row.get(3)?,
+                })
+            })?;
+            let mut counts = Vec::new();
+            for row in rows {
+                counts.push(row?);
+            }
+            Ok(counts)
+        })
+        .await
+    }
+
+    async fn pin_message(&self, pin: &ChannelPin) -> Result<bool, AppError> {
+        let pin = pin.clone();
+        self.with_conn(move |conn| {
+            let inserted = conn.execute(
+                "INSERT OR IGNORE INTO channel_pins (channel_id, message_id, pinned_by_user_id, pinned_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    pin.channel_id.to_string(),
+                    pin.message_id.to_string(),
+                    pin.pinned_by_user_id.to_string(),
+                    pin.pinned_at
+                ],
+            )?;
+            Ok(inserted > 0)
+        })
+        .await
+    }
+
+    async fn unpin_message(&self, channel_id: Uuid, message_id: Uuid) -> Result<bool, AppError> {
+        self.with_conn(move |conn| {
+            let removed = conn.execute(
+                "DELETE FROM channel_pins WHERE channel_id = ?1 AND message_id = ?2",
+                params![channel_id.to_string(), message_id.to_string()],
+            )?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn list_channel_pins(&self, channel_id: Uuid) -> Result<Vec<ChannelPin>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT channel_id, message_id, pinned_by_user_id, pinned_at FROM channel_pins
+                 WHERE channel_id = ?1 ORDER BY pinned_at DESC, message_id DESC",
+            )?;
+            let rows = stmt.query_map(params![channel_id.to_string()], row_to_pin)?;
+            let mut pins = Vec::new();
+            for row in rows {
+                pins.push(row?);
+            }
+            Ok(pins)
+        })
+        .await
+    }
+
+    async fn add_mentions(&self, mentions: &[Mention]) -> Result<(), AppError> {
+        let mentions = mentions.to_vec();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            for mention in &mentions {
+                tx.execute(
+                    "INSERT OR IGNORE INTO mentions (message_id, user_id, kind, sent_at) VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        mention.message_id.to_string(),
+                        mention.user_id.to_string(),
+                        mention.kind.as_str(),
+                        mention.sent_at
+                    ],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_mentions(&self, user_id: Uuid, limit: usize) -> Result<Vec<Mention>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT mn.message_id, mn.user_id, mn.kind, mn.sent_at
+                 FROM mentions mn
+                 JOIN messages m ON m.id = mn.message_id
+                 WHERE mn.user_id = ?1 AND m.deleted_at IS NULL
+                 ORDER BY mn.sent_at DESC, mn.message_id DESC
+                 LIMIT ?2",
+            )?;
+            let rows = stmt.query_map(params![user_id.to_string(), limit as i64], row_to_mention)?;
+            let mut mentions = Vec::new();
+            for row in rows {
+                mentions.push(row?);
+            }
+            Ok(mentions)
+        })
+        .await
+    }
+
+    async fn get_user_presence(&self, user_id: Uuid) -> Result<Option<UserPresence>, AppError> {
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT user_id, state, status_text, status_emoji, status_expires_at, updated_at
+                     FROM user_presence WHERE user_id = ?1",
+                    params![user_id.to_string()],
+                    row_to_user_presence,
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn set_user_presence(&self, presence: &UserPresence) -> Result<(), AppError> {
+        let presence = presence.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO user_presence (user_id, state, status_text, status_emoji, status_expires_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT (user_id) DO UPDATE SET
+                    state = excluded.state,
+                    status_text = excluded.status_text,
+                    status_emoji = excluded.status_emoji,
+                    status_expires_at = excluded.status_expires_at,
+                    updated_at = excluded.updated_at",
+                params![
+                    presence.user_id.to_string(),
+                    presence.state.as_str(),
+                    presence.status_text,
+                    presence.status_emoji,
+                    presence.status_expires_at,
+                    presence.updated_at,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_user_presence(&self) -> Result<Vec<UserPresence>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT user_id, state, status_text, status_emoji, status_expires_at, updated_at FROM user_presence",
+            )?;
+            let rows = stmt.query_map([], row_to_user_presence)?;
+            let mut presence = Vec::new();
+            for row in rows {
+                presence.push(row?);
+            }
+            Ok(presence)
+        })
+        .await
+    }
+
+    async fn create_attachment(&self, attachment: &Attachment) -> Result<bool, AppError> {
+        let attachment = attachment.clone();
+        self.with_conn(move |conn| {
+            let inserted = conn.execute(
+                "INSERT OR IGNORE INTO attachments
+                    (id, hash, size, content_type, filename, uploader_user_id, origin_server, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                params![
+                    attachment.id.to_string(),
+                    attachment.hash,
+                    attachment.size as i64,
+                    attachment.content_type,
+                    attachment.filename,
+                    attachment.uploader_user_id.to_string(),
+                    attachment.origin_server,
+                    attachment.created_at,
+                ],
+            )?;
+            Ok(inserted > 0)
+        })
+        .await
+    }
+
+    async fn get_attachment(&self, id: Uuid) -> Result<Option<Attachment>, AppError> {
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    "SELECT a.id, a.hash, a.size, a.content_type, a.filename, a.uploader_user_id, a.origin_server, a.created_at FROM attachments a WHERE a.id = ?1",
+                    params![id.to_string()],
+                    |row| row_to_attachment(row, 0),
+                )
+                .optional()?)
+        })
+        .await
+    }
+
+    async fn attach_to_message(&self, message_id: Uuid, attachment_ids: &[Uuid]) -> Result<(), AppError> {
+        let attachment_ids = attachment_ids.to_vec();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            for attachment_id in &attachment_ids {
+                tx.execute(
+                    "INSERT OR IGNORE INTO message_attachments (message_id, attachment_id) VALUES (?1, ?2)",
+                    params![message_id.to_string(), attachment_id.to_string()],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_message_attachments(&self, message_ids: &[Uuid]) -> Result<Vec<(Uuid, Attachment)>, AppError> {
+        if message_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let ids = serde_json::to_string(&message_ids.iter().map(Uuid::to_string).collect::<Vec<_>>())
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT ma.message_id, a.id, a.hash, a.size, a.content_type, a.filename, a.uploader_user_id, a.origin_server, a.created_at
+                 FROM message_attachments ma
+                 JOIN attachments a ON a.id = ma.attachment_id
+                 WHERE ma.message_id IN (SELECT value FROM json_each(?1))
+                 ORDER BY a.created_at, a.id",
+            )?;
+            let rows = stmt.query_map(params![ids], |row| {
+                let message_id = Uuid::parse_str(row.get::<_, String>(0)?.as_str()).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+                })?;
+                Ok((message_id, row_to_attachment(row, 1)?))
+            })?;
+            let mut attachments = Vec::new();
+            for row in rows {
+                attachments.push(row?);
+            }
+            Ok(attachments)
+        })
+        .await
+    }
+
+    async fn list_attachment_message_ids(&self, attachment_id: Uuid) -> Result<Vec<Uuid>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare("SELECT message_id FROM message_attachments WHERE attachment_id = ?1")?;
+            let rows = stmt.query_map(params![attachment_id.to_string()], |row| {
+                Uuid::parse_str(row.get::<_, String>(0)?.as_str()).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+                })
+            })?;
+            let mut ids = Vec::new();
+            for row in rows {
+                ids.push(row?);
+            }
+            Ok(ids)
+        })
+        .await
+    }
+
+    async fn has_attachment_with_hash(&self, hash: &str) -> Result<bool, AppError> {
+        let hash = hash.to_string();
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row("SELECT 1 FROM attachments WHERE hash = ?1 LIMIT 1", params![hash], |_| Ok(()))
+                .optional()?
+                .is_some())
+        })
+        .await
+    }
+
+    async fn delete_orphaned_attachments(&self, created_before: &str) -> Result<usize, AppError> {
+        let created_before = created_before.to_string();
+        self.with_conn(move |conn| {
+            Ok(conn.execute(
+                "DELETE FROM attachments
+                 WHERE created_at < ?1
+                   AND id NOT IN (SELECT attachment_id FROM message_attachments)",
+                params![created_before],
+            )?)
+        })
+        .await
+    }
+
+    async fn list_attachment_hashes(&self) -> Result<Vec<String>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare("SELECT DISTINCT hash FROM attachments")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            let mut hashes = Vec::new();
+            for row in rows {
+                hashes.push(row?);
+            }
+            Ok(hashes)
+        })
+        .await
+    }
+
+    async fn search_messages(&self, user_id: Uuid, search: &MessageSearch) -> Result<Vec<SearchHit>, AppError> {
+        let query = fts_query(&search.query)
+            .ok_or_else(|| AppError::BadRequest("search query is empty".to_string()))?;
+        let search = search.clone();
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT m.id, m.kind, m.body, m.author_user_id, m.recipient_user_id, m.channel_id, m.sent_at,
+                        m.edited_at, m.deleted_at, m.thread_root_id,
+                        snippet(messages_fts, 0, ?9, ?10, '…', 16)
+                 FROM messages_fts
+                 JOIN messages m ON m.seq = messages_fts.rowid
+                 WHERE messages_fts MATCH ?1
+                   AND m.deleted_at IS NULL
+                   AND ((m.kind = 'dm' AND (m.author_user_id = ?2 OR m.recipient_user_id = ?2))
+                        OR (m.kind = 'channel'
+                            AND m.channel_id IN (SELECT channel_id FROM channel_members WHERE user_id = ?2)))
+                   AND (?3 IS NULL OR m.channel_id = ?3)
+                   AND (?4 IS NULL OR (m.kind = 'dm' AND (m.author_user_id = ?4 OR m.recipient_user_id = ?4)))
+                   AND (?5 IS NULL OR m.author_user_id = ?5)
+                   AND (?6 IS NULL OR m.sent_at >= ?6)
+                   AND (?7 IS NULL OR m.sent_at < ?7)
+                 ORDER BY m.sent_at DESC
+                 LIMIT ?8",
+            )?;
+            let rows = stmt.query_map(
+                params![
+                    query,
+                    user_id.to_string(),
+                    search.channel_id.map(|id| id.to_string()),
+                    search.dm_user_id.map(|id| id.to_string()),
+                    search.author_user_id.map(|id| id.to_string()),
+                    search.since,
+                    search.until,
+                    search.limit as i64,
+                    SNIPPET_START.to_string(),
+                    SNIPPET_END.to_string(),
+                ],
+                |row| {
+                    Ok(SearchHit {
+                        message: row_to_message(row)?,
+                        snippet: render_snippet(&row.get::<_, String>(10)?),
+                    })
+                },
+            )?;
+            let mut hits = Vec::new();
+            for row in rows {
+                hits.push(row?);
+            }
+            Ok(hits)
+        })
+        .await
+    }
+
+    async fn list_retention_policies(&self) -> Result<Vec<RetentionPolicy>, AppError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT scope, max_age_days, max_messages, updated_at FROM retention_policies ORDER BY scope",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok(RetentionPolicy {
+                    channel_id: scope_to_channel(&row.get::<_, String>(0)?)?,
+                    max_age_days: row.get(1)?,
+                    max_messages: row.get(2)?,
+                    updated_at: row.get(3)?,
+                })
+            })?;
+            let mut policies = Vec::new();
+            for row in rows {
+                policies.push(row?);
+            }
+            Ok(policies)
+        })
+        .await
+    }
+
+    async fn set_retention_policy(
+        &self,
+        channel_id: Option<Uuid>,
+        max_age_days: Option<u32>,
+        max_messages: Option<u32>,
+    ) -> Result<RetentionPolicy, AppError> {
+        let policy = RetentionPolicy {
+            channel_id,
+            max_age_days,
+            max_messages,
+            updated_at: time::OffsetDateTime::now_utc()
+                .format(&time::format_description::well_known::Rfc3339)
+                .unwrap_or_default(),
+        };
+        let row = policy.clone();
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO retention_policies (scope, max_age_days, max_messages, updated_at)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(scope) DO UPDATE SET
+                    max_age_days = excluded.max_age_days,
+                    max_messages = excluded.max_messages,
+                    updated_at = excluded.updated_at",
+                params![retention_scope(row.channel_id), row.max_age_days, row.max_messages, row.updated_at],
+            )?;
+            Ok(())
+        })
+        .await?;
+        Ok(policy)
+    }
+
+    async fn delete_retention_policy(&self, channel_id: Option<Uuid>) -> Result<(), AppError> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM retention_policies WHERE scope = ?1",
+                params![retention_scope(channel_id)],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn prune_messages(
+        &self,
+        channel_id: Option<Uuid>,
+        older_than: Option<&str>,
+        keep_last: Option<u32>,
+    ) -> Result<usize, AppError> {
+        let older_than = older_than.map(str::to_string);
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let mut deleted = 0;
+            match channel_id {
+                Some(channel_id) => {
+                    let channel_id = channel_id.to_string();
+                    // Replies go with their root, whatever their own age
+                    if let Some(cutoff) = &older_than {
+                        deleted += tx.execute(
+                            "DELETE FROM messages
+                             WHERE channel_id = ?1
+                               AND (sent_at < ?2
+                                    OR thread_root_id IN (
+                                        SELECT id FROM messages
+                                        WHERE channel_id = ?1 AND thread_root_id IS NULL AND sent_at < ?2
+                                    ))",
+                            params![channel_id, cutoff],
+                        )?;
+                    }
+                    if let Some(keep) = keep_last {
+                        deleted += tx.execute(
+                            "WITH pruned AS (
+                                 SELECT id FROM messages
+                                 WHERE channel_id = ?1 AND thread_root_id IS NULL
+                                 ORDER BY sent_at DESC, id DESC LIMIT -1 OFFSET ?2
+                             )
+                             DELETE FROM messages
+                             WHERE channel_id = ?1
+                               AND (id IN (SELECT id FROM pruned) OR thread_root_id IN (SELECT id FROM pruned))",
+                            params![channel_id, keep],
+                        )?;
+                    }
+                }
+                None => {
+                    if let Some(cutoff) = &older_than {
+                        deleted += tx.execute(
+                            "DELETE FROM messages WHERE kind = 'dm' AND sent_at < ?1",
+                            params![cutoff],
+                        )?;
+                    }
+                    if let Some(keep) = keep_last {
+                        deleted += tx.execute(
+                            "DELETE FROM messages WHERE id IN (
+                                 SELECT id FROM (
+                                     SELECT id, ROW_NUMBER() OVER (
+                                         PARTITION BY MIN(author_user_id, recipient_user_id),
+                                                      MAX(author_user_id, recipient_user_id)
+                                         ORDER BY sent_at DESC, id DESC
+                                     ) AS position
+                                     FROM messages WHERE kind = 'dm'
+                                 ) WHERE position > ?1
+                             )",
+                            params![keep],
+                        )?;
+                    }
+                }
+            }
+            if deleted > 0 {
+                tx.execute("DELETE FROM message_edits WHERE message_id NOT IN (SELECT id FROM messages)", [])?;
+                tx.execute("DELETE FROM message_reactions WHERE message_id NOT IN (SELECT id FROM messages)", [])?;
+                tx.execute("DELETE FROM message_attachments WHERE message_id NOT IN (SELECT id FROM messages)", [])?;
+                tx.execute("DELETE FROM channel_pins WHERE message_id NOT IN (SELECT id FROM messages)", [])?;
+                tx.execute("DELETE FROM mentions WHERE message_id NOT IN (SELECT id FROM messages)", [])?;
+                tx.execute(
+                    "DELETE FROM federation_outbox WHERE message_id IS NOT NULL AND message_id NOT IN (SELECT id FROM messages)",
+                    [],
+                )?;
+            }
+            tx.commit()?;
+            Ok(deleted)
+        })
+        .await
+    }
+
+    async fn advance_read_marker(
+        &self,
+        user_id: Uuid,
+        conversation: ConversationRef,
+        cursor: &MessageCursor,
+    ) -> Result<ReadMarker, AppError> {
+        let cursor = cursor.clone();
+        let updated_at = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        self.with_conn(move |conn| {
+            let key = conversation_key(conversation);
+            conn.execute(
+                "INSERT INTO read_markers (user_id, conversation, message_id, sent_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (user_id, conversation) DO UPDATE SET
+                    message_id = excluded.message_id,
+                    sent_at = excluded.sent_at,
+                    updated_at = excluded.updated_at
+                 WHERE excluded.sent_at > read_markers.sent_at
+                    OR (excluded.sent_at = read_markers.sent_at AND excluded.message_id > read_markers.message_id)",
+                params![user_id.to_string(), key, cursor.id.to_string(), cursor.sent_at, updated_at],
+            )?;
+            let marker = conn.query_row(
+                "SELECT message_id, sent_at, updated_at FROM read_markers WHERE user_id = ?1 AND conversation = ?2",
+                params![user_id.to_string(), key],
+                |row| {
+                    Ok(ReadMarker {
+                        conversation,
+                        message_id: Uuid::parse_str(row.get::<_, String>(0)?.as_str()).map_err(|e| {
+                            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+                        })?,
+                        sent_at: row.get(1)?,
+                        updated_at: row.get(2)?,
+                    })
+                },
+            )?;
+            Ok(marker)
+        })
+        .await
+    }
+
+    async fn unread_counts(&self, user_id: Uuid) -> Result<Vec<UnreadCount>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT m.channel_id, COUNT(*), SUM(EXISTS (SELECT 1 FROM mentions mn WHERE mn.message_id = m.id AND mn.user_id = ?1))
+                 FROM messages m
+                 JOIN channel_members cm ON cm.channel_id = m.channel_id AND cm.user_id = ?1
+                 LEFT JOIN read_markers r ON r.user_id = ?1 AND r.conversation = m.channel_id
+                 WHERE m.kind = 'channel' AND m.author_user_id != ?1 AND m.deleted_at IS NULL
+                   AND m.thread_root_id IS NULL
+                   AND (r.sent_at IS NULL OR m.sent_at > r.sent_at OR (m.sent_at = r.sent_at AND m.id > r.message_id))
+                 GROUP BY m.channel_id
+                 UNION ALL
+                 SELECT 'dm:' || m.author_user_id, COUNT(*), SUM(EXISTS (SELECT 1 FROM mentions mn WHERE mn.message_id = m.id AND mn.user_id = ?1))
+                 FROM messages m
+                 LEFT JOIN read_markers r ON r.user_id = ?1 AND r.conversation = 'dm:' || m.author_user_id
+                 WHERE m.kind = 'dm' AND m.recipient_user_id = ?1 AND m.author_user_id != ?1 AND m.deleted_at IS NULL
+                   AND (r.sent_at IS NULL OR m.sent_at > r.sent_at OR (m.sent_at = r.sent_at AND m.id > r.message_id))
+                 GROUP BY m.author_user_id",
+            )?;
+            let rows = stmt.query_map(params![user_id.to_string()], |row| {
+                let key: String = row.get(0)?;
+                Ok((key, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?))
+            })?;
+            let mut counts = Vec::new();
+            for row in rows {
+                let (key, unread, mentions) = row?;
+                if let Some(conversation) = parse_conversation_key(&key) {
+                    counts.push(UnreadCount {
+                        conversation,
+                        unread: unread as u64,
+                        mentions: mentions as u64,
+                    });
+                }
+            }
+            Ok(counts)
+        })
+        .await
+    }
+
+    async fn create_federation_token(&self, label: &str) -> Result<FederationToken, AppError> {
+        let created_at = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        let token = FederationToken {
+            id: Uuid::new_v4(),
+            token: Uuid::new_v4().to_string(),
+            label: label.to_string(),
+            created_at,
+        };
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO federation_tokens (id, token, label, created_at) VALUES (?1, ?2, ?3, ?4)",
+                params![token.id.to_string(), token.token, token.label, token.created_at],
+            )?;
+            Ok(token)
+        })
+        .await
+    }
+
+    async fn list_federation_tokens(&self) -> Result<Vec<FederationToken>, AppError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT id, token, label, created_at FROM federation_tokens ORDER BY created_at DESC",
+            )?;
+            let rows = stmt.query_map([], |row| {
+                Ok(FederationToken {
+                    id: Uuid::parse_str(row.get::<_, String>(0)?.as_str()).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+                    })?,
+                    token: row.get(1)?,
+                    label: row.get(2)?,
+                    created_at: row.get(3)?,
+                })
+            })?;
+            let mut tokens = Vec::new();
+            for row in rows {
+                tokens.push(row?);
+            }
+            Ok(tokens)
+        })
+        .await
+    }
+
+    async fn delete_federation_token(&self, id: &Uuid) -> Result<(), AppError> {
+        let id = *id;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM federation_tokens WHERE id = ?1",
+                params![id.to_string()],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn is_valid_federation_token(&self, token: &str) -> Result<bool, AppError> {
+        let token = token.to_string();
+        self.with_conn(move |conn| {
+            let count: i64 = conn.query_row(
+                "SELECT COUNT(*) FROM federation_tokens WHERE token = ?1",
+                params![token],
+                |row| row.get(0),
+            )?;
+            Ok(count > 0)
+        })
+        .await
+    }
+
+    async fn enqueue_outbox(
+        &self,
+        server_id: Uuid,
+        path: &str,
+        payload: &str,
+        message_id: Option<Uuid>,
+        now: &str,
+    ) -> Result<OutboxEntry, AppError> {
+        let mut entry = OutboxEntry {
+            id: 0,
+            server_id,
+            path: path.to_string(),
+            payload: payload.to_string(),
+            message_id,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now.to_string(),
+            last_error: None,
+            created_at: now.to_string(),
+            updated_at: now.to_string(),
+        };
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO federation_outbox
+                    (server_id, path, payload, message_id, status, attempts, next_attempt_at, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, 0, ?6, ?6, ?6)",
+                params![
+                    entry.server_id.to_string(),
+                    entry.path,
+                    entry.payload,
+                    entry.message_id.map(|id| id.to_string()),
+                    entry.status.as_str(),
+                    entry.created_at,
+                ],
+            )?;
+            entry.id = conn.last_insert_rowid();
+            Ok(entry)
         })
+        .await
     }
 
-    pub fn list_users(&self) -> Result<Vec<User>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        let mut stmt = conn.prepare(
-            "SELECT id, username, token, server_id, is_local, display_name FROM users ORDER BY username",
-        )?;
-        let rows = stmt.query_map([], |row| row_to_user(row))?;
-        let mut users = Vec::new();
-        for row in rows {
-            users.push(row?);
-        }
-        Ok(users)
+    async fn due_outbox_entries(&self, now: &str) -> Result<Vec<OutboxEntry>, AppError> {
+        let now = now.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM federation_outbox o
+                 WHERE o.status = 'pending'
+                   AND o.id = (SELECT MIN(id) FROM federation_outbox
+                               WHERE server_id = o.server_id AND status = 'pending')
+                   AND o.next_attempt_at <= ?1
+                   AND o.server_id NOT IN (SELECT id FROM servers WHERE status = 'suspended')
+                 ORDER BY o.id",
+                OUTBOX_COLUMNS
+            ))?;
+            let rows = stmt.query_map(params![now], row_to_outbox_entry)?;
+            let mut entries = Vec::new();
+            for row in rows {
+                entries.push(row?);
+            }
+            Ok(entries)
+        })
+        .await
     }
 
-    pub fn get_user_by_token(&self, token: &str) -> Result<Option<User>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.query_row(
-            "SELECT id, username, token, server_id, is_local, display_name FROM users WHERE token = ?1",
-            params![token],
-            |row| row_to_user(row),
-        )
-        .optional()
-        .map_err(AppError::from)
+    async fn update_outbox_entry(&self, entry: &OutboxEntry) -> Result<(), AppError> {
+        let entry = entry.clone();
+        self.with_conn(move |conn| {
+            if entry.status == DeliveryStatus::Delivered && entry.message_id.is_none() {
+                conn.execute("DELETE FROM federation_outbox WHERE id = ?1", params![entry.id])?;
+                return Ok(());
+            }
+            let payload = if entry.status == DeliveryStatus::Delivered { "" } else { entry.payload.as_str() };
+            conn.execute(
+                "UPDATE federation_outbox
+                 SET payload = ?2, status = ?3, attempts = ?4, next_attempt_at = ?5, last_error = ?6, updated_at = ?7
+                 WHERE id = ?1",
+                params![
+                    entry.id,
+                    payload,
+                    entry.status.as_str(),
+                    entry.attempts,
+                    entry.next_attempt_at,
+                    entry.last_error,
+                    entry.updated_at,
+                ],
+            )?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn get_user_by_name_and_server(
-        &self,
-        username: &str,
-        server_id: Option<Uuid>,
-    ) -> Result<Option<User>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.query_row(
-            "SELECT id, username, token, server_id, is_local, display_name FROM users WHERE username = ?1 AND COALESCE(server_id, '') = COALESCE(?2, '')",
-            params![username, server_id.map(|s| s.to_string())],
-            |row| row_to_user(row),
-        )
-        .optional()
-        .map_err(AppError::from)
-    }
-
-    pub fn get_user_by_id(&self, user_id: Uuid) -> Result<Option<User>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.query_row(
-            "SELECT id, username, token, server_id, is_local, display_name FROM users WHERE id = ?1",
-            params![user_id.to_string()],
-            |row| row_to_user(row),
-        )
-        .optional()
-        .map_err(AppError::from)
-    }
-
-    pub fn create_channel(&self, name: &str, origin_server: &str) -> Result<Channel, AppError> {
-        let id = Uuid::new_v4();
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute(
-            "INSERT INTO channels (id, name, origin_server) VALUES (?1, ?2, ?3)",
-            params![id.to_string(), name, origin_server],
-        )?;
-        Ok(Channel {
-            id,
-            name: name.to_string(),
-            origin_server: origin_server.to_string(),
+    async fn get_outbox_entry(&self, id: i64) -> Result<Option<OutboxEntry>, AppError> {
+        self.with_conn(move |conn| {
+            Ok(conn
+                .query_row(
+                    &format!("SELECT {} FROM federation_outbox o WHERE o.id = ?1", OUTBOX_COLUMNS),
+                    params![id],
+                    row_to_outbox_entry,
+                )
+                .optional()?)
         })
+        .await
     }
 
-    pub fn list_channels(&self) -> Result<Vec<Channel>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        let mut stmt = conn.prepare(
-            "SELECT id, name, origin_server FROM channels ORDER BY name",
-        )?;
-        let rows = stmt.query_map([], |row| row_to_channel(row))?;
-        let mut channels = Vec::new();
-        for row in rows {
-            channels.push(row?);
-        }
-        Ok(channels)
+    async fn list_outbox_entries(&self, status: DeliveryStatus, limit: usize) -> Result<Vec<OutboxEntry>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT {} FROM federation_outbox o WHERE o.status = ?1 ORDER BY o.id LIMIT ?2",
+                OUTBOX_COLUMNS
+            ))?;
+            let rows = stmt.query_map(params![status.as_str(), limit as i64], row_to_outbox_entry)?;
+            let mut entries = Vec::new();
+            for row in rows {
+                entries.push(row?);
+            }
+            Ok(entries)
+        })
+        .await
     }
 
-    pub fn get_channel_by_name_origin(
-        &self,
-        name: &str,
-        origin_server: &str,
-    ) -> Result<Option<Channel>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.query_row(
-            "SELECT id, name, origin_server FROM channels WHERE name = ?1 AND origin_server = ?2",
-            params![name, origin_server],
-            |row| row_to_channel(row),
-        )
-        .optional()
-        .map_err(AppError::from)
-    }
-
-    pub fn get_channel_by_id(&self, id: Uuid) -> Result<Option<Channel>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.query_row(
-            "SELECT id, name, origin_server FROM channels WHERE id = ?1",
-            params![id.to_string()],
-            |row| row_to_channel(row),
-        )
-        .optional()
-        .map_err(AppError::from)
-    }
-
-    pub fn add_channel_member(&self, channel_id: Uuid, user_id: Uuid) -> Result<(), AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute(
-            "INSERT OR IGNORE INTO channel_members (channel_id, user_id) VALUES (?1, ?2)",
-            params![channel_id.to_string(), user_id.to_string()],
-        )?;
-        Ok(())
-    }
-
-    pub fn list_channel_member_servers(&self, channel_id: Uuid) -> Result<Vec<Server>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        let mut stmt = conn.prepare(
-            "
-            SELECT DISTINCT s.id, s.name, s.base_url, s.token
-            FROM channel_members cm
-            JOIN users u ON cm.user_id = u.id
-            JOIN servers s ON u.server_id = s.id
-            WHERE cm.channel_id = ?1 AND u.server_id IS NOT NULL
-            ",
-        )?;
-        let rows = stmt.query_map(params![channel_id.to_string()], |row| row_to_server(row))?;
-
-        let mut servers = Vec::new();
-        for row in rows {
-            servers.push(row?);
+    async fn message_deliveries(&self, message_ids: &[Uuid]) -> Result<Vec<MessageDelivery>, AppError> {
+        if message_ids.is_empty() {
+            return Ok(Vec::new());
         }
-        Ok(servers)
+        let ids = serde_json::to_string(&message_ids.iter().map(Uuid::to_string).collect::<Vec<_>>())
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT o.message_id, s.name, o.status, o.attempts, o.last_error, o.updated_at
+                 FROM federation_outbox o JOIN servers s ON s.id = o.server_id
+                 WHERE o.message_id IN (SELECT value FROM json_each(?1))
+                 ORDER BY o.message_id, s.name",
+            )?;
+            let rows = stmt.query_map(params![ids], |row| {
+                let status: String = row.get(2)?;
+                Ok(MessageDelivery {
+                    message_id: Uuid::parse_str(row.get::<_, String>(0)?.as_str()).map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+                    })?,
+                    server_name: row.get(1)?,
+                    status: DeliveryStatus::parse(&status).ok_or_else(|| {
+                        rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(std::fmt::Error))
+                    })?,
+                    attempts: row.get(3)?,
+                    last_error: row.get(4)?,
+                    updated_at: row.get(5)?,
+                })
+            })?;
+            let mut deliveries = Vec::new();
+            for row in rows {
+                deliveries.push(row?);
+            }
+            Ok(deliveries)
+        })
+        .await
     }
 
-    pub fn create_message(
-        &self,
-        kind: MessageKind,
-        body: &str,
-        author_user_id: Uuid,
-        recipient_user_id: Option<Uuid>,
-        channel_id: Option<Uuid>,
-        sent_at: &str,
-    ) -> Result<Message, AppError> {
-        let id = Uuid::new_v4();
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute(
-            "INSERT INTO messages (id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-            params![
-                id.to_string(),
-                match kind {
-                    MessageKind::Dm => "dm",
-                    MessageKind::Channel => "channel",
-                },
-                body,
-                author_user_id.to_string(),
-                recipient_user_id.map(|id| id.to_string()),
-                channel_id.map(|id| id.to_string()),
-                sent_at,
-            ],
-        )?;
-        Ok(Message {
-            id,
-            kind,
-            body: body.to_string(),
-            author_user_id,
-            recipient_user_id,
-            channel_id,
-            sent_at: sent_at.to_string(),
+    async fn prune_delivered_outbox(&self, older_than: &str) -> Result<usize, AppError> {
+        let older_than = older_than.to_string();
+        self.with_conn(move |conn| {
+            Ok(conn.execute(
+                "DELETE FROM federation_outbox WHERE status = 'delivered' AND updated_at < ?1",
+                params![older_than],
+            )?)
         })
+        .await
     }
 
-    pub fn create_message_with_id(
-        &self,
-        id_str: &str,
-        kind: MessageKind,
-        body: &str,
-        author_user_id: Uuid,
-        recipient_user_id: Option<Uuid>,
-        channel_id: Option<Uuid>,
-        sent_at: &str,
-    ) -> Result<Option<Message>, AppError> {
-        let id = Uuid::parse_str(id_str)
-            .map_err(|_| AppError::BadRequest("invalid message_id".to_string()))?;
-
-        let conn = self.conn.lock().expect("db mutex");
-
-        let inserted = conn.execute(
-            "INSERT OR IGNORE INTO messages (id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-            params![
-                id.to_string(),
-                match kind {
-                    MessageKind::Dm => "dm",
-                    MessageKind::Channel => "channel",
-                },
-                body,
-                author_user_id.to_string(),
-                recipient_user_id.map(|id| id.to_string()),
-                channel_id.map(|id| id.to_string()),
-                sent_at,
-            ],
-        )?;
-
-        if inserted == 0 {
-            return Ok(None);
-        }
-
-        Ok(Some(Message {
-            id,
-            kind,
-            body: body.to_string(),
-            author_user_id,
-            recipient_user_id,
-            channel_id,
-            sent_at: sent_at.to_string(),
-        }))
-    }
-
-    pub fn list_messages_for_user(&self, user_id: Uuid, limit: usize) -> Result<Vec<Message>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        let mut messages = Vec::new();
-
-        let mut stmt = conn.prepare(
-            "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at
-             FROM messages
-             WHERE recipient_user_id = ?1
-             ORDER BY sent_at DESC
-             LIMIT ?2",
-        )?;
-        let rows = stmt.query_map(params![user_id.to_string(), limit as i64], row_to_message)?;
-        for row in rows {
-            messages.push(row?);
-        }
+    async fn get_hidden_user_ids(&self, server_id: Uuid) -> Result<Vec<String>, AppError> {
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT user_id FROM server_hidden_users WHERE server_id = ?1",
+            )?;
+            let rows = stmt.query_map(params![server_id.to_string()], |row| row.get::<_, String>(0))?;
+            let mut ids = Vec::new();
+            for row in rows {
+                ids.push(row?);
+            }
+            Ok(ids)
+        })
+        .await
+    }
 
-        let channel_ids = self.list_channel_ids_for_user(user_id)?;
-        for channel_id in channel_ids {
+    async fn get_hidden_channel_ids(&self, server_id: Uuid) -> Result<Vec<String>, AppError> {
+        self.with_conn(move |conn| {
             let mut stmt = conn.prepare(
-                "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at
-                 FROM messages
-                 WHERE channel_id = ?1
-                 ORDER BY sent_at DESC
-                 LIMIT ?2",
+                "SELECT channel_id FROM server_hidden_channels WHERE server_id = ?1",
             )?;
-            let rows = stmt.query_map(params![channel_id.to_string(), limit as i64], row_to_message)?;
+            let rows = stmt.query_map(params![server_id.to_string()], |row| row.get::<_, String>(0))?;
+            let mut ids = Vec::new();
             for row in rows {
-                messages.push(row?);
+                ids.push(row?);
             }
-        }
+            Ok(ids)
+        })
+        .await
+    }
 
-        messages.sort_by(|a, b| b.sent_at.cmp(&a.sent_at));
-        messages.truncate(limit);
-        Ok(messages)
+    async fn set_hidden_users(&self, server_id: Uuid, user_ids: &[String]) -> Result<(), AppError> {
+        let user_ids = user_ids.to_vec();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM server_hidden_users WHERE server_id = ?1", params![server_id.to_string()])?;
+            for uid in &user_ids {
+                tx.execute(
+                    "INSERT INTO server_hidden_users (server_id, user_id) VALUES (?1, ?2)",
+                    params![server_id.to_string(), uid],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
     }
 
-    fn list_channel_ids_for_user(&self, user_id: Uuid) -> Result<Vec<Uuid>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        let mut stmt = conn.prepare(
-            "SELECT channel_id FROM channel_members WHERE user_id = ?1",
-        )?;
-        let rows = stmt.query_map(params![user_id.to_string()], |row| {
-            let id: String = row.get(0)?;
-            let uuid = Uuid::parse_str(&id).map_err(|e| {
-                rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
-            })?;
-            Ok(uuid)
-        })?;
-        let mut ids = Vec::new();
-        for row in rows {
-            ids.push(row?);
-        }
-        Ok(ids)
-    }
-
-    pub fn set_user_password(&self, user_id: &Uuid, hash: &str) -> Result<(), AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute(
-            "UPDATE users SET password_hash = ?1 WHERE id = ?2",
-            params![hash, user_id.to_string()],
-        )?;
-        Ok(())
-    }
-
-    pub fn get_user_password_hash(&self, user_id: &Uuid) -> Result<Option<String>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.query_row(
-            "SELECT password_hash FROM users WHERE id = ?1",
-            params![user_id.to_string()],
-            |row| row.get::<_, Option<String>>(0),
-        )
-        .optional()
-        .map(|opt| opt.flatten())
-        .map_err(AppError::from)
-    }
-
-    pub fn delete_user(&self, id: &Uuid) -> Result<(), AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute("DELETE FROM users WHERE id = ?1", params![id.to_string()])?;
-        Ok(())
-    }
-
-    pub fn update_user(&self, id: &Uuid, username: &str, display_name: Option<&str>) -> Result<User, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute(
-            "UPDATE users SET username = ?1, display_name = ?2 WHERE id = ?3",
-            params![username, display_name, id.to_string()],
-        )?;
-        conn.query_row(
-            "SELECT id, username, token, server_id, is_local, display_name FROM users WHERE id = ?1",
-            params![id.to_string()],
-            |row| row_to_user(row),
-        )
-        .map_err(AppError::from)
-    }
-
-    pub fn update_user_display_name(&self, id: &Uuid, display_name: Option<&str>) -> Result<(), AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute(
-            "UPDATE users SET display_name = ?1 WHERE id = ?2",
-            params![display_name, id.to_string()],
-        )?;
-        Ok(())
-    }
-
-    pub fn delete_server(&self, id: &Uuid) -> Result<(), AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute("DELETE FROM server_hidden_users WHERE server_id = ?1", params![id.to_string()])?;
-        conn.execute("DELETE FROM server_hidden_channels WHERE server_id = ?1", params![id.to_string()])?;
-        conn.execute("DELETE FROM servers WHERE id = ?1", params![id.to_string()])?;
-        Ok(())
-    }
-
-    pub fn update_server(&self, id: &Uuid, name: &str, base_url: &str, token: &str) -> Result<Server, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute(
-            "UPDATE servers SET name = ?1, base_url = ?2, token = ?3 WHERE id = ?4",
-            params![name, base_url, token, id.to_string()],
-        )?;
-        let server = conn.query_row(
-            "SELECT id, name, base_url, token FROM servers WHERE id = ?1",
-            params![id.to_string()],
-            |row| row_to_server(row),
-        )?;
-        Ok(server)
-    }
-
-    pub fn delete_channel(&self, id: &Uuid) -> Result<(), AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute("DELETE FROM channels WHERE id = ?1", params![id.to_string()])?;
-        Ok(())
-    }
-
-    pub fn update_channel(&self, id: &Uuid, name: &str) -> Result<Channel, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute("UPDATE channels SET name = ?1 WHERE id = ?2", params![name, id.to_string()])?;
-        let channel = conn.query_row(
-            "SELECT id, name, origin_server FROM channels WHERE id = ?1",
-            params![id.to_string()],
-            |row| row_to_channel(row),
-        )?;
-        Ok(channel)
-    }
-
-    pub fn list_channel_messages(&self, channel_id: Uuid) -> Result<Vec<Message>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        let mut stmt = conn.prepare(
-            "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at
-             FROM messages
-             WHERE channel_id = ?1
-             ORDER BY sent_at ASC",
-        )?;
-        let rows = stmt.query_map(params![channel_id.to_string()], row_to_message)?;
-        let mut messages = Vec::new();
-        for row in rows {
-            messages.push(row?);
-        }
-        Ok(messages)
-    }
-
-    pub fn list_dm_messages(&self, user_id: Uuid, other_user_id: Uuid) -> Result<Vec<Message>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        let mut stmt = conn.prepare(
-            "SELECT id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at
-             FROM messages
-             WHERE (author_user_id = ?1 AND recipient_user_id = ?2)
-                OR (author_user_id = ?2 AND recipient_user_id = ?1)
-             ORDER BY sent_at ASC",
-        )?;
-        let rows = stmt.query_map(params![user_id.to_string(), other_user_id.to_string()], row_to_message)?;
-        let mut messages = Vec::new();
-        for row in rows {
-            messages.push(row?);
-        }
-        Ok(messages)
+    async fn set_hidden_channels(&self, server_id: Uuid, channel_ids: &[String]) -> Result<(), AppError> {
+        let channel_ids = channel_ids.to_vec();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM server_hidden_channels WHERE server_id = ?1", params![server_id.to_string()])?;
+            for cid in &channel_ids {
+                tx.execute(
+                    "INSERT INTO server_hidden_channels (server_id, channel_id) VALUES (?1, ?2)",
+                    params![server_id.to_string(), cid],
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
     }
 
-    pub fn create_federation_token(&self, label: &str) -> Result<FederationToken, AppError> {
-        let id = Uuid::new_v4();
-        let token = Uuid::new_v4().to_string();
-        let created_at = time::OffsetDateTime::now_utc()
-            .format(&time::format_description::well_known::Rfc3339)
-            .unwrap_or_default();
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute(
-            "INSERT INTO federation_tokens (id, token, label, created_at) VALUES (?1, ?2, ?3, ?4)",
-            params![id.to_string(), token, label, created_at],
-        )?;
-        Ok(FederationToken {
-            id,
-            token,
-            label: label.to_string(),
-            created_at,
+    async fn remove_channel_member(&self, channel_id: Uuid, user_id: Uuid) -> Result<(), AppError> {
+        self.with_conn(move |conn| {
+            conn.execute(
+                "DELETE FROM channel_members WHERE channel_id = ?1 AND user_id = ?2",
+                params![channel_id.to_string(), user_id.to_string()],
+            )?;
+            Ok(())
         })
+        .await
     }
+}
 
-    pub fn list_federation_tokens(&self) -> Result<Vec<FederationToken>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        let mut stmt = conn.prepare(
-            "SELECT id, token, label, created_at FROM federation_tokens ORDER BY created_at DESC",
-        )?;
-        let rows = stmt.query_map([], |row| {
-            Ok(FederationToken {
-                id: Uuid::parse_str(row.get::<_, String>(0)?.as_str()).map_err(|e| {
-                    rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
-                })?,
-                token: row.get(1)?,
-                label: row.get(2)?,
-                created_at: row.get(3)?,
-            })
-        })?;
-        let mut tokens = Vec::new();
-        for row in rows {
-            tokens.push(row?);
-        }
-        Ok(tokens)
-    }
-
-    pub fn delete_federation_token(&self, id: &Uuid) -> Result<(), AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute(
-            "DELETE FROM federation_tokens WHERE id = ?1",
-            params![id.to_string()],
-        )?;
-        Ok(())
-    }
-
-    pub fn is_valid_federation_token(&self, token: &str) -> Result<bool, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        let count: i64 = conn.query_row(
-            "SELECT COUNT(*) FROM federation_tokens WHERE token = ?1",
-            params![token],
-            |row| row.get(0),
-        )?;
-        Ok(count > 0)
-    }
-
-    pub fn get_hidden_user_ids(&self, server_id: Uuid) -> Result<Vec<String>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        let mut stmt = conn.prepare(
-            "SELECT user_id FROM server_hidden_users WHERE server_id = ?1",
-        )?;
-        let rows = stmt.query_map(params![server_id.to_string()], |row| row.get::<_, String>(0))?;
-        let mut ids = Vec::new();
-        for row in rows {
-            ids.push(row?);
-        }
-        Ok(ids)
-    }
-
-    pub fn get_hidden_channel_ids(&self, server_id: Uuid) -> Result<Vec<String>, AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        let mut stmt = conn.prepare(
-            "SELECT channel_id FROM server_hidden_channels WHERE server_id = ?1",
-        )?;
-        let rows = stmt.query_map(params![server_id.to_string()], |row| row.get::<_, String>(0))?;
-        let mut ids = Vec::new();
-        for row in rows {
-            ids.push(row?);
+/// Opens pool connections in WAL mode so readers never block the writer, with
+/// a busy timeout to ride out the brief write lock between pooled writers.
+#[derive(Debug)]
+struct ConnectionManager {
+    path: String,
+}
+
+impl r2d2::ManageConnection for ConnectionManager {
+    type Connection = Connection;
+    type Error = rusqlite::Error;
+
+    fn connect(&self) -> Result<Connection, rusqlite::Error> {
+        let conn = Connection::open(&self.path)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        if self.path != ":memory:" {
+            conn.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
         }
-        Ok(ids)
+        conn.pragma_update(None, "synchronous", "NORMAL")?;
+        Ok(conn)
     }
 
-    pub fn set_hidden_users(&self, server_id: Uuid, user_ids: &[String]) -> Result<(), AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute("DELETE FROM server_hidden_users WHERE server_id = ?1", params![server_id.to_string()])?;
-        for uid in user_ids {
-            conn.execute(
-                "INSERT INTO server_hidden_users (server_id, user_id) VALUES (?1, ?2)",
-                params![server_id.to_string(), uid],
-            )?;
+    fn is_valid(&self, conn: &mut Connection) -> Result<(), rusqlite::Error> {
+        conn.execute_batch("")
+    }
+
+    fn has_broken(&self, _conn: &mut Connection) -> bool {
+        false
+    }
+}
+
+/// `verb` is `INSERT` or `INSERT OR IGNORE`; returns the number of rows written.
+fn insert_message(conn: &Connection, verb: &str, message: &Message) -> Result<usize, rusqlite::Error> {
+    conn.execute(
+        &format!(
+            "{} INTO messages (id, kind, body, author_user_id, recipient_user_id, channel_id, sent_at, thread_root_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            verb
+        ),
+        params![
+            message.id.to_string(),
+            match message.kind {
+                MessageKind::Dm => "dm",
+                MessageKind::Channel => "channel",
+            },
+            message.body,
+            message.author_user_id.to_string(),
+            message.recipient_user_id.map(|id| id.to_string()),
+            message.channel_id.map(|id| id.to_string()),
+            message.sent_at,
+            message.thread_root_id.map(|id| id.to_string()),
+        ],
+    )
+}
+
+/// Key under which a retention policy is stored: the channel id, or `dm`.
+fn retention_scope(channel_id: Option<Uuid>) -> String {
+    channel_id.map(|id| id.to_string()).unwrap_or_else(|| "dm".to_string())
+}
+
+fn scope_to_channel(scope: &str) -> Result<Option<Uuid>, rusqlite::Error> {
+    if scope == "dm" {
+        return Ok(None);
+    }
+    Uuid::parse_str(scope)
+        .map(Some)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+/// Keyset condition and sort order for one page of history. The cursor is
+/// bound as `?2` (sent_at) and `?3` (id); both are NULL for the latest page.
+fn page_clause(page: &HistoryPage) -> (&'static str, &'static str) {
+    match page {
+        HistoryPage::Latest | HistoryPage::Before(_) => {
+            ("(?2 IS NULL OR sent_at < ?2 OR (sent_at = ?2 AND id < ?3))", "DESC")
         }
-        Ok(())
+        HistoryPage::After(_) => ("(sent_at > ?2 OR (sent_at = ?2 AND id > ?3))", "ASC"),
     }
+}
 
-    pub fn set_hidden_channels(&self, server_id: Uuid, channel_ids: &[String]) -> Result<(), AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute("DELETE FROM server_hidden_channels WHERE server_id = ?1", params![server_id.to_string()])?;
-        for cid in channel_ids {
-            conn.execute(
-                "INSERT INTO server_hidden_channels (server_id, channel_id) VALUES (?1, ?2)",
-                params![server_id.to_string(), cid],
-            )?;
+fn page_cursor(page: &HistoryPage) -> (Option<String>, Option<String>) {
+    match page {
+        HistoryPage::Latest => (None, None),
+        HistoryPage::Before(cursor) | HistoryPage::After(cursor) => {
+            (Some(cursor.sent_at.clone()), Some(cursor.id.to_string()))
         }
-        Ok(())
     }
+}
+
+/// Pages read newest-first are flipped so callers always get oldest first.
+/// Read attachment columns starting at `offset`.
+fn row_to_attachment(row: &rusqlite::Row, offset: usize) -> Result<Attachment, rusqlite::Error> {
+    let parse = |idx: usize| -> Result<Uuid, rusqlite::Error> {
+        Uuid::parse_str(row.get::<_, String>(idx)?.as_str())
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(e)))
+    };
+    Ok(Attachment {
+        id: parse(offset)?,
+        hash: row.get(offset + 1)?,
+        size: row.get::<_, i64>(offset + 2)? as u64,
+        content_type: row.get(offset + 3)?,
+        filename: row.get(offset + 4)?,
+        uploader_user_id: parse(offset + 5)?,
+        origin_server: row.get(offset + 6)?,
+        created_at: row.get(offset + 7)?,
+    })
+}
+
+fn row_to_pin(row: &rusqlite::Row) -> Result<ChannelPin, rusqlite::Error> {
+    let parse = |idx: usize| -> Result<Uuid, rusqlite::Error> {
+        Uuid::parse_str(row.get::<_, String>(idx)?.as_str())
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(e)))
+    };
+    Ok(ChannelPin {
+        channel_id: parse(0)?,
+        message_id: parse(1)?,
+        pinned_by_user_id: parse(2)?,
+        pinned_at: row.get(3)?,
+    })
+}
+
+fn row_to_mention(row: &rusqlite::Row) -> Result<Mention, rusqlite::Error> {
+    let parse = |idx: usize| -> Result<Uuid, rusqlite::Error> {
+        Uuid::parse_str(row.get::<_, String>(idx)?.as_str())
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(e)))
+    };
+    let kind: String = row.get(2)?;
+    Ok(Mention {
+        message_id: parse(0)?,
+        user_id: parse(1)?,
+        kind: MentionKind::parse(&kind).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(std::fmt::Error))
+        })?,
+        sent_at: row.get(3)?,
+    })
+}
+
+fn row_to_user_presence(row: &rusqlite::Row) -> Result<UserPresence, rusqlite::Error> {
+    let state: String = row.get(1)?;
+    Ok(UserPresence {
+        user_id: Uuid::parse_str(row.get::<_, String>(0)?.as_str())
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))?,
+        state: PresenceState::parse(&state).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(std::fmt::Error))
+        })?,
+        status_text: row.get(2)?,
+        status_emoji: row.get(3)?,
+        status_expires_at: row.get(4)?,
+        updated_at: row.get(5)?,
+    })
+}
+
+const OUTBOX_COLUMNS: &str = "o.id, o.server_id, o.path, o.payload, o.message_id, o.status, o.attempts,
+    o.next_attempt_at, o.last_error, o.created_at, o.updated_at";
+
+fn row_to_outbox_entry(row: &rusqlite::Row) -> Result<OutboxEntry, rusqlite::Error> {
+    let parse_id = |idx: usize, value: String| {
+        Uuid::parse_str(&value)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(e)))
+    };
+    let status: String = row.get(5)?;
+    Ok(OutboxEntry {
+        id: row.get(0)?,
+        server_id: parse_id(1, row.get(1)?)?,
+        path: row.get(2)?,
+        payload: row.get(3)?,
+        message_id: row.get::<_, Option<String>>(4)?.map(|id| parse_id(4, id)).transpose()?,
+        status: DeliveryStatus::parse(&status).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Text, Box::new(std::fmt::Error))
+        })?,
+        attempts: row.get(6)?,
+        next_attempt_at: row.get(7)?,
+        last_error: row.get(8)?,
+        created_at: row.get(9)?,
+        updated_at: row.get(10)?,
+    })
+}
+
+fn row_to_thread_summary(row: &rusqlite::Row) -> Result<ThreadSummary, rusqlite::Error> {
+    Ok(ThreadSummary {
+        root_id: Uuid::parse_str(row.get::<_, String>(0)?.as_str()).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })?,
+        reply_count: row.get::<_, i64>(1)? as u64,
+        last_reply_at: row.get(2)?,
+    })
+}
+
+fn collect_page(
+    rows: impl Iterator<Item = rusqlite::Result<Message>>,
+    order: &str,
+) -> Result<Vec<Message>, AppError> {
+    let mut messages = Vec::new();
+    for row in rows {
+        messages.push(row?);
+    }
+    if order == "DESC" {
+        messages.reverse();
+    }
+    Ok(messages)
+}
+
+/// Turn free text into an FTS5 query that matches every word. Each word is
+/// quoted so operators and punctuation typed by users cannot cause syntax errors.
+fn fts_query(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
 
-    pub fn remove_channel_member(&self, channel_id: Uuid, user_id: Uuid) -> Result<(), AppError> {
-        let conn = self.conn.lock().expect("db mutex");
-        conn.execute(
-            "DELETE FROM channel_members WHERE channel_id = ?1 AND user_id = ?2",
-            params![channel_id.to_string(), user_id.to_string()],
-        )?;
-        Ok(())
+fn channel_ids_for_user(conn: &Connection, user_id: Uuid) -> Result<Vec<Uuid>, AppError> {
+    let mut stmt = conn.prepare(
+        "SELECT channel_id FROM channel_members WHERE user_id = ?1",
+    )?;
+    let rows = stmt.query_map(params![user_id.to_string()], |row| {
+        let id: String = row.get(0)?;
+        let uuid = Uuid::parse_str(&id).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+        Ok(uuid)
+    })?;
+    let mut ids = Vec::new();
+    for row in rows {
+        ids.push(row?);
     }
+    Ok(ids)
 }
 
+const SERVER_COLUMNS: &str = "id, name, base_url, token, public_key, status, protocol_version, capabilities";
+
 fn row_to_server(row: &rusqlite::Row) -> Result<Server, rusqlite::Error> {
+    let status: String = row.get(5)?;
     Ok(Server {
         id: Uuid::parse_str(row.get::<_, String>(0)?.as_str()).map_err(|e| {
             rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
@@ -735,6 +2279,38 @@ fn row_to_server(row: &rusqlite::Row) -> Result<Server, rusqlite::Error> {
         name: row.get(1)?,
         base_url: row.get(2)?,
         token: row.get(3)?,
+        public_key: row.get(4)?,
+        status: PeerStatus::parse(&status).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Text, Box::new(std::fmt::Error))
+        })?,
+        protocol_version: row.get(6)?,
+        capabilities: row.get::<_, Option<String>>(7)?.map(|c| split_capabilities(&c)),
+    })
+}
+
+fn split_capabilities(capabilities: &str) -> Vec<String> {
+    capabilities.split(',').filter(|c| !c.is_empty()).map(str::to_string).collect()
+}
+
+const PEERING_COLUMNS: &str = "id, direction, server_name, base_url, public_key, status, created_at, updated_at";
+
+fn row_to_peering_request(row: &rusqlite::Row) -> Result<PeeringRequest, rusqlite::Error> {
+    let invalid = |idx: usize| {
+        rusqlite::Error::FromSqlConversionFailure(idx, rusqlite::types::Type::Text, Box::new(std::fmt::Error))
+    };
+    let direction: String = row.get(1)?;
+    let status: String = row.get(5)?;
+    Ok(PeeringRequest {
+        id: Uuid::parse_str(row.get::<_, String>(0)?.as_str()).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })?,
+        direction: PeeringDirection::parse(&direction).ok_or_else(|| invalid(1))?,
+        server_name: row.get(2)?,
+        base_url: row.get(3)?,
+        public_key: row.get(4)?,
+        status: PeeringStatus::parse(&status).ok_or_else(|| invalid(5))?,
+        created_at: row.get(6)?,
+        updated_at: row.get(7)?,
     })
 }
 
@@ -767,6 +2343,7 @@ fn row_to_user(row: &rusqlite::Row) -> Result<User, rusqlite::Error> {
             .transpose()?,
         is_local: row.get::<_, i32>(4)? == 1,
         display_name: row.get(5)?,
+        last_seen_at: row.get(6)?,
     })
 }
 
@@ -776,6 +2353,7 @@ fn row_to_message(row: &rusqlite::Row) -> Result<Message, rusqlite::Error> {
     let author_string: String = row.get(3)?;
     let recipient_string: Option<String> = row.get(4)?;
     let channel_string: Option<String> = row.get(5)?;
+    let thread_root_string: Option<String> = row.get(9)?;
 
     let kind = match kind_string.as_str() {
         "dm" => MessageKind::Dm,
@@ -815,6 +2393,16 @@ fn row_to_message(row: &rusqlite::Row) -> Result<Message, rusqlite::Error> {
             })
             .transpose()?,
         sent_at: row.get(6)?,
+        edited_at: row.get(7)?,
+        deleted_at: row.get(8)?,
+        thread_root_id: thread_root_string
+            .as_deref()
+            .map(|value| {
+                Uuid::parse_str(value).map_err(|e| {
+                    rusqlite::Error::FromSqlConversionFailure(9, rusqlite::types::Type::Text, Box::new(e))
+                })
+            })
+            .transpose()?,
     })
 }
 
@@ -824,14 +2412,646 @@ mod tests {
     use super::*;
     use tempfile::NamedTempFile;
 
-    #[test]
-    fn can_create_local_user() {
+    #[tokio::test]
+    async fn can_create_local_user() {
         let file = NamedTempFile::new().expect("tempfile");
         let store = SqliteStore::new(file.path().to_str().unwrap()).expect("store");
-        store.init().expect("init");
-        let user = store.create_user("alice", true, None).expect("user");
+        store.init().await.expect("init");
+        let user = store.create_user("alice", true, None).await.expect("user");
         assert_eq!(user.username, "alice");
         assert!(user.is_local);
         assert!(!user.token.is_empty());
     }
+
+    #[tokio::test]
+    async fn inbox_includes_dms_and_channel_messages() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let bob = store.create_user("bob", true, None).await.expect("bob");
+        let channel = store.create_channel("general", "local").await.expect("channel");
+        store.add_channel_member(channel.id, alice.id).await.expect("member");
+        store
+            .create_message(MessageKind::Dm, "hi", bob.id, Some(alice.id), None, None, "2024-01-01T00:00:00Z")
+            .await
+            .expect("dm");
+        store
+            .create_message(MessageKind::Channel, "hello", bob.id, None, Some(channel.id), None, "2024-01-01T00:00:01Z")
+            .await
+            .expect("channel message");
+
+        let inbox = store.list_messages_for_user(alice.id, 50).await.expect("inbox");
+        assert_eq!(inbox.len(), 2);
+        assert_eq!(inbox[0].body, "hello");
+    }
+
+    #[tokio::test]
+    async fn search_only_returns_visible_messages() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let bob = store.create_user("bob", true, None).await.expect("bob");
+        let carol = store.create_user("carol", true, None).await.expect("carol");
+        let joined = store.create_channel("general", "local").await.expect("channel");
+        let other = store.create_channel("secret", "local").await.expect("channel");
+        store.add_channel_member(joined.id, alice.id).await.expect("member");
+        store
+            .create_message(MessageKind::Dm, "deploy <today>", bob.id, Some(alice.id), None, None, "2024-01-01T00:00:00Z")
+            .await
+            .expect("dm");
+        store
+            .create_message(MessageKind::Dm, "deploy tomorrow", bob.id, Some(carol.id), None, None, "2024-01-01T00:00:01Z")
+            .await
+            .expect("dm");
+        store
+            .create_message(MessageKind::Channel, "deploy done", bob.id, None, Some(joined.id), None, "2024-01-02T00:00:00Z")
+            .await
+            .expect("channel message");
+        store
+            .create_message(MessageKind::Channel, "deploy hidden", bob.id, None, Some(other.id), None, "2024-01-02T00:00:01Z")
+            .await
+            .expect("channel message");
+
+        let search = MessageSearch {
+            query: "Deploy\"".to_string(),
+            limit: 10,
+            ..Default::default()
+        };
+        let hits = store.search_messages(alice.id, &search).await.expect("search");
+        let bodies: Vec<&str> = hits.iter().map(|h| h.message.body.as_str()).collect();
+        assert_eq!(bodies, vec!["deploy done", "deploy <today>"]);
+        assert_eq!(hits[1].snippet, "<mark>deploy</mark> &lt;today&gt;");
+
+        let search = MessageSearch {
+            query: "deploy".to_string(),
+            dm_user_id: Some(bob.id),
+            until: Some("2024-01-02".to_string()),
+            limit: 10,
+            ..Default::default()
+        };
+        let hits = store.search_messages(alice.id, &search).await.expect("search");
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].message.kind, MessageKind::Dm);
+    }
+
+    #[tokio::test]
+    async fn channel_history_pages_by_cursor() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let channel = store.create_channel("general", "local").await.expect("channel");
+        for i in 0..5 {
+            // Two messages per timestamp so the id tie-breaker is exercised.
+            let sent_at = format!("2024-01-01T00:00:0{}Z", i / 2);
+            store
+                .create_message(MessageKind::Channel, &i.to_string(), alice.id, None, Some(channel.id), None, &sent_at)
+                .await
+                .expect("message");
+        }
+
+        let latest = store.list_channel_messages(channel.id, &HistoryPage::Latest, 2).await.expect("latest");
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[1].body, "4");
+
+        let mut seen: Vec<Message> = latest.clone();
+        let mut cursor = MessageCursor::of(&latest[0]);
+        loop {
+            let older = store
+                .list_channel_messages(channel.id, &HistoryPage::Before(cursor.clone()), 2)
+                .await
+                .expect("older");
+            if older.is_empty() {
+                break;
+            }
+            cursor = MessageCursor::of(&older[0]);
+            seen.splice(0..0, older);
+        }
+        assert_eq!(seen.len(), 5);
+        assert!(seen.windows(2).all(|w| (&w[0].sent_at, w[0].id) < (&w[1].sent_at, w[1].id)));
+
+        let newer = store
+            .list_channel_messages(channel.id, &HistoryPage::After(MessageCursor::of(&seen[0])), 10)
+            .await
+            .expect("newer");
+        assert_eq!(newer.len(), 4);
+        assert_eq!(newer[0].id, seen[1].id);
+    }
+
+    #[tokio::test]
+    async fn read_markers_only_advance_and_drive_unread_counts() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let bob = store.create_user("bob", true, None).await.expect("bob");
+        let joined = store.create_channel("general", "local").await.expect("channel");
+        let other = store.create_channel("random", "local").await.expect("channel");
+        store.add_channel_member(joined.id, alice.id).await.expect("member");
+        let mut posted = Vec::new();
+        for (i, body) in ["hi @alice", "hi @alicia", "@ALICE again"].iter().enumerate() {
+            let sent_at = format!("2024-01-01T00:00:0{}Z", i);
+            for channel in [&joined, &other] {
+                posted.push(
+                    store
+                        .create_message(MessageKind::Channel, body, bob.id, None, Some(channel.id), None, &sent_at)
+                        .await
+                        .expect("message"),
+                );
+            }
+        }
+        store
+            .create_message(MessageKind::Channel, "mine", alice.id, None, Some(joined.id), None, "2024-01-01T00:00:09Z")
+            .await
+            .expect("own message");
+        store
+            .create_message(MessageKind::Dm, "psst", bob.id, Some(alice.id), None, None, "2024-01-01T00:00:05Z")
+            .await
+            .expect("dm");
+
+        // Only recorded mentions count, not bodies that merely contain the name
+        let mentions: Vec<Mention> = [&posted[0], &posted[1], &posted[4]]
+            .into_iter()
+            .map(|m| Mention { message_id: m.id, user_id: alice.id, kind: MentionKind::User, sent_at: m.sent_at.clone() })
+            .collect();
+        store.add_mentions(&mentions).await.expect("mentions");
+
+        let counts = store.unread_counts(alice.id).await.expect("counts");
+        assert_eq!(counts.len(), 2);
+        let channel = counts.iter().find(|c| c.conversation == ConversationRef::Channel(joined.id)).expect("channel");
+        assert_eq!((channel.unread, channel.mentions), (3, 2));
+        let dm = counts.iter().find(|c| c.conversation == ConversationRef::Dm(bob.id)).expect("dm");
+        assert_eq!((dm.unread, dm.mentions), (1, 0));
+
+        let conversation = ConversationRef::Channel(joined.id);
+        store.advance_read_marker(alice.id, conversation, &MessageCursor::of(&posted[2])).await.expect("advance");
+        let marker = store
+            .advance_read_marker(alice.id, conversation, &MessageCursor::of(&posted[0]))
+            .await
+            .expect("stale advance");
+        assert_eq!(marker.message_id, posted[2].id);
+        let counts = store.unread_counts(alice.id).await.expect("counts");
+        let channel = counts.iter().find(|c| c.conversation == conversation).expect("channel");
+        assert_eq!((channel.unread, channel.mentions), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn edits_keep_history_and_deletes_leave_tombstones() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let channel = store.create_channel("general", "local").await.expect("channel");
+        store.add_channel_member(channel.id, alice.id).await.expect("member");
+        let message = store
+            .create_message(MessageKind::Channel, "frist", alice.id, None, Some(channel.id), None, "2024-01-01T00:00:00Z")
+            .await
+            .expect("message");
+
+        let edited = store
+            .edit_message(message.id, "first", alice.id, "2024-01-01T00:01:00Z")
+            .await
+            .expect("edit")
+            .expect("applied");
+        assert_eq!(edited.body, "first");
+        let replay = store.edit_message(message.id, "frist", alice.id, "2024-01-01T00:00:30Z").await.expect("replay");
+        assert!(replay.is_none());
+        let edits = store.list_message_edits(message.id).await.expect("edits");
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].previous_body, "frist");
+
+        let search = MessageSearch {
+            query: "first".to_string(),
+            limit: 10,
+            ..Default::default()
+        };
+        assert_eq!(store.search_messages(alice.id, &search).await.expect("search").len(), 1);
+
+        let tombstone = store
+            .delete_message(message.id, "2024-01-01T00:02:00Z")
+            .await
+            .expect("delete")
+            .expect("applied");
+        assert!(tombstone.body.is_empty());
+        assert!(store.delete_message(message.id, "2024-01-01T00:03:00Z").await.expect("again").is_none());
+        assert!(store.list_message_edits(message.id).await.expect("edits").is_empty());
+        assert!(store.search_messages(alice.id, &search).await.expect("search").is_empty());
+        let history = store.list_channel_messages(channel.id, &HistoryPage::Latest, 10).await.expect("history");
+        assert_eq!(history[0].deleted_at.as_deref(), Some("2024-01-01T00:02:00Z"));
+    }
+
+    #[tokio::test]
+    async fn reactions_are_counted_per_emoji_and_dropped_with_the_message() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let bob = store.create_user("bob", true, None).await.expect("bob");
+        let channel = store.create_channel("general", "local").await.expect("channel");
+        let message = store
+            .create_message(MessageKind::Channel, "hello", alice.id, None, Some(channel.id), None, "2024-01-01T00:00:00Z")
+            .await
+            .expect("message");
+
+        assert!(store.add_reaction(message.id, alice.id, "👍").await.expect("react"));
+        assert!(!store.add_reaction(message.id, alice.id, "👍").await.expect("repeat"));
+        assert!(store.add_reaction(message.id, bob.id, "👍").await.expect("react"));
+        assert!(store.add_reaction(message.id, bob.id, "🎉").await.expect("react"));
+
+        let counts = store.reaction_counts(&[message.id], alice.id).await.expect("counts");
+        assert_eq!(counts.len(), 2);
+        assert_eq!((counts[0].emoji.as_str(), counts[0].count, counts[0].reacted), ("👍", 2, true));
+        assert_eq!((counts[1].emoji.as_str(), counts[1].count, counts[1].reacted), ("🎉", 1, false));
+
+        assert!(store.remove_reaction(message.id, alice.id, "👍").await.expect("unreact"));
+        assert!(!store.remove_reaction(message.id, alice.id, "👍").await.expect("repeat"));
+        store.delete_message(message.id, "2024-01-01T00:01:00Z").await.expect("delete");
+        assert!(store.reaction_counts(&[message.id], bob.id).await.expect("counts").is_empty());
+    }
+
+    #[tokio::test]
+    async fn thread_replies_stay_out_of_channel_history() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let channel = store.create_channel("general", "local").await.expect("channel");
+        let root = store
+            .create_message(MessageKind::Channel, "root", alice.id, None, Some(channel.id), None, "2024-01-01T00:00:00Z")
+            .await
+            .expect("root");
+        let quiet = store
+            .create_message(MessageKind::Channel, "quiet", alice.id, None, Some(channel.id), None, "2024-01-01T00:00:01Z")
+            .await
+            .expect("quiet");
+        for (i, sent_at) in ["2024-01-01T00:00:02Z", "2024-01-01T00:00:03Z"].iter().enumerate() {
+            store
+                .create_message(MessageKind::Channel, &format!("reply {}", i), alice.id, None, Some(channel.id), Some(root.id), sent_at)
+                .await
+                .expect("reply");
+        }
+
+        let history = store.list_channel_messages(channel.id, &HistoryPage::Latest, 10).await.expect("history");
+        assert_eq!(history.iter().map(|m| m.id).collect::<Vec<_>>(), vec![root.id, quiet.id]);
+        let replies = store.list_thread_messages(root.id, &HistoryPage::Latest, 10).await.expect("thread");
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].thread_root_id, Some(root.id));
+
+        let summaries = store.thread_summaries(&[root.id, quiet.id]).await.expect("summaries");
+        assert_eq!(summaries.len(), 1);
+        assert_eq!((summaries[0].reply_count, summaries[0].last_reply_at.as_str()), (2, "2024-01-01T00:00:03Z"));
+        store.delete_message(replies[1].id, "2024-01-01T00:01:00Z").await.expect("delete");
+        let active = store.list_active_threads(channel.id, 10).await.expect("active");
+        assert_eq!((active[0].root_id, active[0].reply_count), (root.id, 1));
+    }
+
+    #[tokio::test]
+    async fn pruning_keeps_thread_roots_and_drops_replies_with_their_root() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let channel = store.create_channel("general", "local").await.expect("channel");
+        let old = store
+            .create_message(MessageKind::Channel, "old", alice.id, None, Some(channel.id), None, "2024-01-01T00:00:00Z")
+            .await
+            .expect("old");
+        let middle = store
+            .create_message(MessageKind::Channel, "middle", alice.id, None, Some(channel.id), None, "2024-01-02T00:00:00Z")
+            .await
+            .expect("middle");
+        let new = store
+            .create_message(MessageKind::Channel, "new", alice.id, None, Some(channel.id), None, "2024-01-03T00:00:00Z")
+            .await
+            .expect("new");
+        // Replies newer than every root must neither use up `keep_last` nor
+        // outlive their root
+        for (root, sent_at) in [(old.id, "2024-01-04T00:00:00Z"), (middle.id, "2024-01-05T00:00:00Z"), (new.id, "2024-01-06T00:00:00Z")] {
+            store
+                .create_message(MessageKind::Channel, "reply", alice.id, None, Some(channel.id), Some(root), sent_at)
+                .await
+                .expect("reply");
+        }
+
+        assert_eq!(store.prune_messages(Some(channel.id), None, Some(2)).await.expect("prune"), 2);
+        let history = store.list_channel_messages(channel.id, &HistoryPage::Latest, 10).await.expect("history");
+        assert_eq!(history.iter().map(|m| m.id).collect::<Vec<_>>(), vec![middle.id, new.id]);
+        assert!(store.list_thread_messages(old.id, &HistoryPage::Latest, 10).await.expect("thread").is_empty());
+
+        assert_eq!(store.prune_messages(Some(channel.id), Some("2024-01-03T00:00:00Z"), None).await.expect("prune"), 2);
+        assert_eq!(store.list_thread_messages(new.id, &HistoryPage::Latest, 10).await.expect("thread").len(), 1);
+        assert!(store.get_message_by_id(middle.id).await.expect("lookup").is_none());
+    }
+
+    #[tokio::test]
+    async fn search_index_follows_bulk_pruning_and_edits() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let channel = store.create_channel("general", "local").await.expect("channel");
+        store.add_channel_member(channel.id, alice.id).await.expect("member");
+        let start = time::OffsetDateTime::UNIX_EPOCH + time::Duration::days(20_000);
+        let mut last = None;
+        for i in 0..2000 {
+            let sent_at = (start + time::Duration::seconds(i)).format(&time::format_description::well_known::Rfc3339).unwrap();
+            last = Some(
+                store
+                    .create_message(MessageKind::Channel, &format!("deploy {}", i), alice.id, None, Some(channel.id), None, &sent_at)
+                    .await
+                    .expect("message"),
+            );
+        }
+
+        assert_eq!(store.prune_messages(Some(channel.id), None, Some(500)).await.expect("prune"), 1500);
+        let search = |query: &str| MessageSearch {
+            query: query.to_string(),
+            limit: 2000,
+            ..Default::default()
+        };
+        assert_eq!(store.search_messages(alice.id, &search("deploy")).await.expect("search").len(), 500);
+        assert!(store.search_messages(alice.id, &search("1499")).await.expect("search").is_empty());
+
+        let last = last.expect("last");
+        store.edit_message(last.id, "shipped", alice.id, "2030-01-01T00:00:00Z").await.expect("edit").expect("applied");
+        assert_eq!(store.search_messages(alice.id, &search("deploy")).await.expect("search").len(), 499);
+        let hits = store.search_messages(alice.id, &search("shipped")).await.expect("search");
+        assert_eq!(hits.iter().map(|h| h.message.id).collect::<Vec<_>>(), vec![last.id]);
+    }
+
+    #[tokio::test]
+    async fn orphaned_attachments_are_collected() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let message = store
+            .create_message(MessageKind::Dm, "file", alice.id, Some(alice.id), None, None, "2024-01-01T00:00:00Z")
+            .await
+            .expect("message");
+        let attachment = |filename: &str| Attachment {
+            id: Uuid::new_v4(),
+            hash: "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9".to_string(),
+            size: 11,
+            content_type: "text/plain".to_string(),
+            filename: filename.to_string(),
+            uploader_user_id: alice.id,
+            origin_server: None,
+            created_at: "2024-01-01T00:00:00Z".to_string(),
+        };
+        let sent = attachment("sent.txt");
+        let unsent = attachment("unsent.txt");
+        assert!(store.create_attachment(&sent).await.expect("create"));
+        assert!(!store.create_attachment(&sent).await.expect("repeat"));
+        assert!(store.create_attachment(&unsent).await.expect("create"));
+        store.attach_to_message(message.id, &[sent.id]).await.expect("attach");
+
+        let linked = store.list_message_attachments(&[message.id]).await.expect("list");
+        assert_eq!(linked.len(), 1);
+        assert_eq!((linked[0].0, linked[0].1.filename.as_str()), (message.id, "sent.txt"));
+        assert_eq!(store.delete_orphaned_attachments("2024-01-02T00:00:00Z").await.expect("gc"), 1);
+        assert!(store.get_attachment(unsent.id).await.expect("get").is_none());
+
+        store.delete_message(message.id, "2024-01-01T00:01:00Z").await.expect("delete");
+        assert_eq!(store.delete_orphaned_attachments("2024-01-02T00:00:00Z").await.expect("gc"), 1);
+        assert!(!store.has_attachment_with_hash(&sent.hash).await.expect("hash"));
+    }
+
+    #[tokio::test]
+    async fn pins_are_listed_newest_first_and_dropped_with_the_message() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let channel = store.create_channel("general", "local").await.expect("channel");
+        store.add_channel_member(channel.id, alice.id).await.expect("member");
+        assert!(store.is_channel_member(channel.id, alice.id).await.expect("member"));
+        let mut pins = Vec::new();
+        for (body, pinned_at) in [("first", "2024-01-01T00:01:00Z"), ("second", "2024-01-01T00:02:00Z")] {
+            let message = store
+                .create_message(MessageKind::Channel, body, alice.id, None, Some(channel.id), None, "2024-01-01T00:00:00Z")
+                .await
+                .expect("message");
+            let pin = ChannelPin {
+                channel_id: channel.id,
+                message_id: message.id,
+                pinned_by_user_id: alice.id,
+                pinned_at: pinned_at.to_string(),
+            };
+            assert!(store.pin_message(&pin).await.expect("pin"));
+            assert!(!store.pin_message(&pin).await.expect("repeat"));
+            pins.push(pin);
+        }
+
+        let listed = store.list_channel_pins(channel.id).await.expect("pins");
+        assert_eq!(listed.iter().map(|p| p.message_id).collect::<Vec<_>>(), vec![pins[1].message_id, pins[0].message_id]);
+        assert!(store.unpin_message(channel.id, pins[1].message_id).await.expect("unpin"));
+        assert!(!store.unpin_message(channel.id, pins[1].message_id).await.expect("repeat"));
+        store.delete_message(pins[0].message_id, "2024-01-01T00:03:00Z").await.expect("delete");
+        assert!(store.list_channel_pins(channel.id).await.expect("pins").is_empty());
+    }
+
+    #[tokio::test]
+    async fn user_presence_is_replaced_and_removed_with_the_user() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        assert!(store.get_user_presence(alice.id).await.expect("get").is_none());
+        let mut presence = UserPresence {
+            user_id: alice.id,
+            state: PresenceState::Dnd,
+            status_text: Some("focus".to_string()),
+            status_emoji: Some("🎧".to_string()),
+            status_expires_at: Some("2024-01-01T01:00:00Z".to_string()),
+            updated_at: "2024-01-01T00:00:00Z".to_string(),
+        };
+        store.set_user_presence(&presence).await.expect("set");
+        presence.state = PresenceState::Invisible;
+        presence.status_text = None;
+        store.set_user_presence(&presence).await.expect("replace");
+
+        let listed = store.list_user_presence().await.expect("list");
+        assert_eq!(listed.len(), 1);
+        assert_eq!((listed[0].state, listed[0].status_text.as_deref()), (PresenceState::Invisible, None));
+        store.delete_user(&alice.id).await.expect("delete");
+        assert!(store.list_user_presence().await.expect("list").is_empty());
+    }
+
+    #[tokio::test]
+    async fn last_seen_only_moves_forward() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        assert!(store.update_last_seen(&alice.id, "2024-01-01T00:05:00Z").await.expect("first"));
+        assert!(!store.update_last_seen(&alice.id, "2024-01-01T00:01:00Z").await.expect("older"));
+        let alice = store.get_user_by_id(alice.id).await.expect("get").expect("alice");
+        assert_eq!(alice.last_seen_at.as_deref(), Some("2024-01-01T00:05:00Z"));
+    }
+
+    #[tokio::test]
+    async fn peer_key_is_forgotten_when_its_base_url_changes() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let peer = store.create_server("b", "http://b", "token-b").await.expect("server");
+        store.set_server_public_key(&peer.id, "key-b").await.expect("key");
+        let renamed = store.update_server(&peer.id, "b", "http://b", "new-token").await.expect("update");
+        assert_eq!(renamed.public_key.as_deref(), Some("key-b"));
+        let moved = store.update_server(&peer.id, "b", "http://b2", "new-token").await.expect("move");
+        assert_eq!(moved.public_key, None);
+    }
+
+    #[tokio::test]
+    async fn outbox_delivers_one_entry_per_peer_in_order() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let peer = store.create_server("b", "http://b", "token-b").await.expect("server");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let message = store
+            .create_message(MessageKind::Dm, "hi", alice.id, None, None, None, "2024-01-01T00:00:00Z")
+            .await
+            .expect("message");
+        let now = "2024-01-01T00:00:00Z";
+        let mut first = store
+            .enqueue_outbox(peer.id, "/federation/messages", "{}", Some(message.id), now)
+            .await
+            .expect("first");
+        let second = store.enqueue_outbox(peer.id, "/federation/messages/edit", "{}", None, now).await.expect("second");
+
+        let due = store.due_outbox_entries(now).await.expect("due");
+        assert_eq!(due.iter().map(|e| e.id).collect::<Vec<_>>(), vec![first.id]);
+        // Not yet due: the peer's later entries wait behind it
+        first.attempts = 1;
+        first.next_attempt_at = "2024-01-01T00:00:02Z".to_string();
+        store.update_outbox_entry(&first).await.expect("retry");
+        assert!(store.due_outbox_entries(now).await.expect("due").is_empty());
+
+        first.status = DeliveryStatus::Delivered;
+        store.update_outbox_entry(&first).await.expect("delivered");
+        let due = store.due_outbox_entries(now).await.expect("due");
+        assert_eq!(due.iter().map(|e| e.id).collect::<Vec<_>>(), vec![second.id]);
+        let kept = store.get_outbox_entry(first.id).await.expect("get").expect("kept for status");
+        assert!(kept.payload.is_empty());
+
+        let deliveries = store.message_deliveries(&[message.id]).await.expect("deliveries");
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!((deliveries[0].server_name.as_str(), deliveries[0].status), ("b", DeliveryStatus::Delivered));
+    }
+
+    #[tokio::test]
+    async fn suspended_peers_hold_their_outbox() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let peer = store.create_server("b", "http://b", "token-b").await.expect("server");
+        let now = "2024-01-01T00:00:00Z";
+        let entry = store.enqueue_outbox(peer.id, "/federation/typing", "{}", None, now).await.expect("enqueue");
+        store.set_server_status(&peer.id, PeerStatus::Suspended).await.expect("suspend");
+        assert_eq!(store.get_server_by_id(&peer.id).await.expect("get").expect("peer").status, PeerStatus::Suspended);
+        assert!(store.due_outbox_entries(now).await.expect("due").is_empty());
+        store.set_server_status(&peer.id, PeerStatus::Active).await.expect("resume");
+        assert_eq!(store.due_outbox_entries(now).await.expect("due")[0].id, entry.id);
+    }
+
+    #[tokio::test]
+    async fn caches_what_a_peer_announces() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let peer = store.create_server("b", "http://b", "token-b").await.expect("server");
+        assert!(peer.protocol_version.is_none() && peer.capabilities.is_none());
+        let capabilities = vec!["reactions".to_string(), "typing".to_string()];
+        store.set_server_protocol(&peer.id, "1.2", Some(&capabilities)).await.expect("protocol");
+        let cached = store.get_server_by_name("b").await.expect("get").expect("peer");
+        assert_eq!((cached.protocol_version.as_deref(), cached.capabilities), (Some("1.2"), Some(capabilities)));
+
+        store.set_server_protocol(&peer.id, "1.0", Some(&[])).await.expect("none");
+        assert_eq!(store.get_server_by_name("b").await.expect("get").expect("peer").capabilities, Some(Vec::new()));
+        store.set_server_protocol(&peer.id, "1.0", None).await.expect("legacy");
+        assert!(store.get_server_by_name("b").await.expect("get").expect("peer").capabilities.is_none());
+    }
+
+    #[tokio::test]
+    async fn channel_member_servers_are_those_of_remote_members() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let b = store.create_server("b", "http://b", "token-b").await.expect("server b");
+        store.create_server("c", "http://c", "token-c").await.expect("server c");
+        let channel = store.create_channel("general", "a").await.expect("channel");
+        let local = store.create_user("alice", true, None).await.expect("alice");
+        let remote = store.create_user("bob", false, Some(b.id)).await.expect("bob");
+        store.add_channel_member(channel.id, local.id).await.expect("add alice");
+        store.add_channel_member(channel.id, remote.id).await.expect("add bob");
+        let names = |servers: Vec<Server>| servers.into_iter().map(|s| s.name).collect::<Vec<_>>();
+        assert_eq!(names(store.list_channel_member_servers(channel.id).await.expect("servers")), ["b"]);
+
+        store.remove_channel_member(channel.id, remote.id).await.expect("remove bob");
+        assert!(store.list_channel_member_servers(channel.id).await.expect("servers").is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_new_peering_request_replaces_the_pending_one() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let request = |public_key: &str, created_at: &str| PeeringRequest {
+            id: Uuid::new_v4(),
+            direction: PeeringDirection::Incoming,
+            server_name: "b".to_string(),
+            base_url: "http://b".to_string(),
+            public_key: public_key.to_string(),
+            status: PeeringStatus::Pending,
+            created_at: created_at.to_string(),
+            updated_at: created_at.to_string(),
+        };
+        let first = request("key-1", "2024-01-01T00:00:00Z");
+        assert!(store.save_peering_request(&first, None).await.expect("first"));
+        let second = request("key-2", "2024-01-02T00:00:00Z");
+        store.save_peering_request(&second, None).await.expect("second");
+        let pending = store
+            .get_pending_peering_request(PeeringDirection::Incoming, "b")
+            .await
+            .expect("pending")
+            .expect("request");
+        assert_eq!((pending.id, pending.public_key.as_str()), (second.id, "key-2"));
+        assert!(store.get_peering_request(first.id).await.expect("get").is_none());
+
+        store
+            .set_peering_request_status(second.id, PeeringStatus::Rejected, "2024-01-03T00:00:00Z")
+            .await
+            .expect("reject");
+        assert!(store.get_pending_peering_request(PeeringDirection::Incoming, "b").await.expect("pending").is_none());
+        // Decided requests are kept as history
+        let third = request("key-3", "2024-01-04T00:00:00Z");
+        store.save_peering_request(&third, Some(1)).await.expect("third");
+        let listed = store.list_peering_requests().await.expect("list");
+        assert_eq!(listed.iter().map(|r| r.id).collect::<Vec<_>>(), vec![third.id, second.id]);
+        assert_eq!(listed[1].status, PeeringStatus::Rejected);
+
+        // At the limit only the servers already waiting may ask again
+        let other = PeeringRequest {
+            id: Uuid::new_v4(),
+            server_name: "c".to_string(),
+            ..third.clone()
+        };
+        assert!(!store.save_peering_request(&other, Some(1)).await.expect("over the limit"));
+        let again = request("key-4", "2024-01-05T00:00:00Z");
+        assert!(store.save_peering_request(&again, Some(1)).await.expect("replace"));
+        assert_eq!(store.list_peering_requests().await.expect("list").len(), 2);
+    }
+
+    #[tokio::test]
+    async fn mentions_list_newest_first_and_skip_deleted_messages() {
+        let store = SqliteStore::in_memory().expect("store");
+        store.init().await.expect("init");
+        let alice = store.create_user("alice", true, None).await.expect("alice");
+        let bob = store.create_user("bob", true, None).await.expect("bob");
+        let channel = store.create_channel("general", "local").await.expect("channel");
+        store.add_channel_member(channel.id, bob.id).await.expect("member");
+        assert_eq!(store.list_channel_members(channel.id).await.expect("members")[0].id, bob.id);
+        let mut mentions = Vec::new();
+        for (kind, sent_at) in [(MentionKind::User, "2024-01-01T00:00:00Z"), (MentionKind::Channel, "2024-01-01T00:01:00Z")] {
+            let message = store
+                .create_message(MessageKind::Channel, "hi", alice.id, None, Some(channel.id), None, sent_at)
+                .await
+                .expect("message");
+            mentions.push(Mention { message_id: message.id, user_id: bob.id, kind, sent_at: sent_at.to_string() });
+        }
+        store.add_mentions(&mentions).await.expect("mentions");
+        store.add_mentions(&mentions).await.expect("repeat");
+
+        let listed = store.list_mentions(bob.id, 10).await.expect("list");
+        assert_eq!(listed.iter().map(|m| m.kind).collect::<Vec<_>>(), vec![MentionKind::Channel, MentionKind::User]);
+        store.delete_message(mentions[1].message_id, "2024-01-01T00:02:00Z").await.expect("delete");
+        let listed = store.list_mentions(bob.id, 10).await.expect("list");
+        assert_eq!(listed.iter().map(|m| m.message_id).collect::<Vec<_>>(), vec![mentions[0].message_id]);
+        assert!(store.list_mentions(alice.id, 10).await.expect("list").is_empty());
+    }
 }
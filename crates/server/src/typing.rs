@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+use uuid::Uuid;
+
+use crate::{
+    api::AppState,
+    domain::User,
+    error::AppError,
+    federation::{
+        outbox,
+        protocol::{FederatedChannel, FederatedTyping, FederatedUser},
+    },
+    websocket::MessageBroadcaster,
+};
+
+/// How long a typing indicator lasts without being refreshed. Clients resend
+/// `typing` every few seconds while the user keeps typing.
+pub const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// The conversation someone is typing in.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+pub enum TypingTarget {
+    Channel(Uuid),
+    Dm { recipient: Uuid },
+}
+
+/// Who is typing where. Each refresh gets a new generation so an expiry
+/// scheduled for an older refresh does nothing.
+#[derive(Clone, Default)]
+pub struct TypingStore {
+    inner: Arc<Mutex<TypingState>>,
+}
+
+#[derive(Default)]
+struct TypingState {
+    next_generation: u64,
+    typing: HashMap<(TypingTarget, Uuid), u64>,
+}
+
+impl TypingStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record that `user_id` is typing. Returns the generation to expire and
+    /// whether the user was not typing there before.
+    pub fn start(&self, target: TypingTarget, user_id: Uuid) -> (u64, bool) {
+        let mut state = self.inner.lock().expect("typing mutex");
+        state.next_generation += 1;
+        let generation = state.next_generation;
+        let started = state.typing.insert((target, user_id), generation).is_none();
+        (generation, started)
+    }
+
+    /// Clear the indicator, but only if it is still at `generation` when one
+    /// is given. Returns true if the user was typing.
+    pub fn stop(&self, target: TypingTarget, user_id: Uuid, generation: Option<u64>) -> bool {
+        let mut state = self.inner.lock().expect("typing mutex");
+        match state.typing.get(&(target, user_id)) {
+            Some(current) if generation.is_none_or(|g| g == *current) => {
+                state.typing.remove(&(target, user_id));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_typing(&self, target: TypingTarget, user_id: Uuid) -> bool {
+        let state = self.inner.lock().expect("typing mutex");
+        state.typing.contains_key(&(target, user_id))
+    }
+}
+
+/// Start or stop `user`'s typing indicator and tell local clients when it
+/// changes. A started indicator stops by itself after `TYPING_TIMEOUT`
+/// unless it is refreshed. Federation is up to the caller.
+pub fn set_typing(state: &AppState, target: TypingTarget, user: &User, typing: bool) {
+    if !typing {
+        if state.typing.stop(target, user.id, None) {
+            crate::websocket::notify_typing(&state.message_broadcaster, target, user, false);
+        }
+        return;
+    }
+
+    let (generation, started) = state.typing.start(target, user.id);
+    if started {
+        crate::websocket::notify_typing(&state.message_broadcaster, target, user, true);
+    }
+    let store = state.typing.clone();
+    let broadcaster: MessageBroadcaster = state.message_broadcaster.clone();
+    let user = user.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(TYPING_TIMEOUT).await;
+        if store.stop(target, user.id, Some(generation)) {
+            crate::websocket::notify_typing(&broadcaster, target, &user, false);
+        }
+    });
+}
+
+/// Body of `POST /api/typing` and of the WebSocket `typing` message. Exactly
+/// one of `channel_id` and `dm_user_id` is given, as for read markers.
+#[derive(Debug, Deserialize)]
+pub struct TypingRequest {
+    pub channel_id: Option<String>,
+    pub dm_user_id: Option<String>,
+    #[serde(default = "default_typing")]
+    pub typing: bool,
+}
+
+fn default_typing() -> bool {
+    true
+}
+
+/// Apply a local user's typing update and forward it to the peers that
+/// take part in the conversation: the channel's origin and member servers,
+/// or the DM partner's server.
+pub async fn update(state: &AppState, user: &User, request: TypingRequest) -> Result<(), AppError> {
+    let parse = |id: &str, what: &str| {
+        Uuid::parse_str(id).map_err(|_| AppError::BadRequest(format!("Invalid {} ID", what)))
+    };
+    let (target, fed_channel, fed_recipient, mut servers) = match (request.channel_id.as_deref(), request.dm_user_id.as_deref()) {
+        (Some(channel_id), None) => {
+            let channel = state
+                .store
+                .get_channel_by_id(parse(channel_id, "channel")?)
+                .await?
+                .ok_or_else(|| AppError::BadRequest("unknown channel".to_string()))?;
+            let mut servers = state.store.list_channel_member_servers(channel.id).await?;
+            if channel.origin_server != state.config.server_name {
+                servers.extend(state.store.get_server_by_name(&channel.origin_server).await?);
+            }
+            let fed_channel = FederatedChannel {
+                name: channel.name,
+                origin_server: channel.origin_server,
+            };
+            (TypingTarget::Channel(channel.id), Some(fed_channel), None, servers)
+        }
+        (None, Some(user_id)) => {
+            let recipient = state
+                .store
+                .get_user_by_id(parse(user_id, "user")?)
+                .await?
+                .ok_or_else(|| AppError::BadRequest("unknown user".to_string()))?;
+            let server = match recipient.server_id {
+                Some(server_id) => state.store.get_server_by_id(&server_id).await?,
+                None => None,
+            };
+            let fed_recipient = server.as_ref().map(|server| FederatedUser {
+                username: recipient.username.clone(),
+                server: server.name.clone(),
+                display_name: None,
+            });
+            (TypingTarget::Dm { recipient: recipient.id }, None, fed_recipient, server.into_iter().collect())
+        }
+        _ => return Err(AppError::BadRequest("give either channel_id or dm_user_id".to_string())),
+    };
+
+    set_typing(state, target, user, request.typing);
+
+    servers.retain(|s| s.name != state.config.server_name);
+    servers.sort_by(|a, b| a.name.cmp(&b.name));
+    servers.dedup_by(|a, b| a.name == b.name);
+    if servers.is_empty() {
+        return Ok(());
+    }
+    let event = FederatedTyping {
+        sender: FederatedUser {
+            username: user.username.clone(),
+            server: state.config.server_name.clone(),
+            display_name: user.display_name.clone(),
+        },
+        channel: fed_channel,
+        recipient: fed_recipient,
+        typing: request.typing,
+    };
+    // Typing is best effort; don't hold up the client for slow peers.
+    let http = state.http.clone();
+    let signer = state.signer.clone();
+    tokio::spawn(async move {
+        for server in servers {
+            if let Err(e) = outbox::send_typing(&http, &signer, &server, &event).await {
+                tracing::debug!(target: "federation", server = %server.name, "typing send failed: {:?}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_latest_refresh_expires_an_indicator() {
+        let store = TypingStore::new();
+        let target = TypingTarget::Channel(Uuid::new_v4());
+        let user_id = Uuid::new_v4();
+
+        let (first, started) = store.start(target, user_id);
+        assert!(started);
+        let (second, started) = store.start(target, user_id);
+        assert!(!started);
+
+        assert!(!store.stop(target, user_id, Some(first)));
+        assert!(store.is_typing(target, user_id));
+        assert!(store.stop(target, user_id, Some(second)));
+        assert!(!store.stop(target, user_id, None));
+    }
+}
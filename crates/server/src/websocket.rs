@@ -8,7 +8,7 @@ use std::sync::Arc;
 use tokio::sync::broadcast;
 use futures_util::stream::unfold;
 
-use crate::{api::AppState, channel_call::ChannelCallStore, config::Config, error::AppError, presence::PresenceGuard, storage::SqliteStore};
+use crate::{api::AppState, channel_call::ChannelCallStore, domain::Message, error::AppError, federation::signing::RequestSigner, presence::PresenceGuard, storage::DynStore};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MessageNotification {
@@ -41,9 +41,9 @@ pub async fn sse_handler(
 
     // Check user session first, fall back to DB token
     let user = if let Some(user_id) = state.sessions.validate_user_session(&token) {
-        state.store.get_user_by_id(user_id)?.ok_or(AppError::Unauthorized)?
+        state.store.get_user_by_id(user_id).await?.ok_or(AppError::Unauthorized)?
     } else {
-        state.store.get_user_by_token(&token)?.ok_or(AppError::Unauthorized)?
+        state.store.get_user_by_token(&token).await?.ok_or(AppError::Unauthorized)?
     };
     
     tracing::info!(target: "presence", "🟢 SSE user '{}' (id: {}) coming ONLINE", user.username, user.id);
@@ -51,7 +51,7 @@ pub async fn sse_handler(
     // Broadcast that someone came online
     notify_presence_changed(&state.message_broadcaster);
     
-    let guard = PresenceGuard::new(state.presence.clone(), user.id);
+    let guard = PresenceGuard::new(&state, user.id);
     let rx = state.message_broadcaster.subscribe();
     
     // Helper to notify when the stream is closed
@@ -62,9 +62,9 @@ pub async fn sse_handler(
         channel_calls: ChannelCallStore,
         user_id: String,
         display_name: Option<String>,
-        store: SqliteStore,
-        config: Config,
+        store: DynStore,
         http: reqwest::Client,
+        signer: RequestSigner,
     }
     impl Drop for NotifyDrop {
         fn drop(&mut self) {
@@ -86,17 +86,17 @@ pub async fn sse_handler(
             // Broadcast leave events to federated servers
             if !affected_channels.is_empty() {
                 let store = self.store.clone();
-                let config = self.config.clone();
                 let http = self.http.clone();
+                let signer = self.signer.clone();
                 let username = self.username.clone();
                 let server_name = self.server_name.clone();
                 let user_id = self.user_id.clone();
                 let display_name = self.display_name.clone();
                 let affected = affected_channels.clone();
                 tokio::spawn(async move {
-                    let servers = store.list_servers().unwrap_or_default();
+                    let servers = store.list_servers().await.unwrap_or_default();
                     for channel_id in affected {
-                        if let Ok(Some(channel)) = store.get_channel_by_id(channel_id) {
+                        if let Ok(Some(channel)) = store.get_channel_by_id(channel_id).await {
                             let fed_event = crate::federation::protocol::FederatedChannelCallEvent {
                                 channel: crate::federation::protocol::FederatedChannel {
                                     name: channel.name,
@@ -113,7 +113,7 @@ pub async fn sse_handler(
                             for server in &servers {
                                 let _ = crate::federation::outbox::send_channel_call_event(
                                     &http,
-                                    &config.server_token,
+                                    &signer,
                                     server,
                                     &fed_event,
                                 ).await;
@@ -134,8 +134,8 @@ pub async fn sse_handler(
         user_id: user.id.to_string(),
         display_name: user.display_name.clone(),
         store: state.store.clone(),
-        config: state.config.clone(),
         http: state.http.clone(),
+        signer: state.signer.clone(),
     };
     
     // Use futures stream to convert async recv operations to a stream
@@ -172,6 +172,24 @@ pub fn notify_new_message(
     let _ = broadcaster.send(notification);
 }
 
+/// A reply was posted in a channel thread. Sent instead of `new_message` so
+/// clients can update the root's reply count without treating it as unread.
+pub fn notify_thread_reply(broadcaster: &MessageBroadcaster, message: &Message) {
+    let payload = serde_json::json!({
+        "message_id": message.id,
+        "thread_root_id": message.thread_root_id,
+    })
+    .to_string();
+    let notification = MessageNotification {
+        event: "thread_reply".to_string(),
+        user_id: None,
+        channel_id: message.channel_id.map(|id| id.to_string()),
+        target_user_id: None,
+        payload: Some(payload),
+    };
+    let _ = broadcaster.send(notification);
+}
+
 pub fn notify_presence_changed(
     broadcaster: &MessageBroadcaster,
 ) {
@@ -217,3 +235,158 @@ pub fn notify_channel_call_event(
     let _ = broadcaster.send(notification);
 }
 
+
+/// Broadcast `message_edited`, or `message_deleted` once the message is a
+/// tombstone. As with `new_message`, `user_id` is the recipient for DMs. The
+/// payload carries `message_id`, `edited_at` and `deleted_at`; clients fetch
+/// the new body themselves, since every session receives every event.
+pub fn notify_message_changed(broadcaster: &MessageBroadcaster, message: &Message) {
+    let event = if message.deleted_at.is_some() { "message_deleted" } else { "message_edited" };
+    let payload = serde_json::json!({
+        "message_id": message.id,
+        "edited_at": message.edited_at,
+        "deleted_at": message.deleted_at,
+    })
+    .to_string();
+    let notification = MessageNotification {
+        event: event.to_string(),
+        user_id: message.recipient_user_id.map(|id| id.to_string()),
+        channel_id: message.channel_id.map(|id| id.to_string()),
+        target_user_id: None,
+        payload: Some(payload),
+    };
+    let _ = broadcaster.send(notification);
+}
+
+/// A reaction was added to or removed from a message. Routed like the
+/// message itself.
+pub fn notify_reaction_changed(
+    broadcaster: &MessageBroadcaster,
+    message: &Message,
+    emoji: &str,
+    reactor_id: uuid::Uuid,
+    added: bool,
+) {
+    let payload = serde_json::json!({
+        "message_id": message.id,
+        "emoji": emoji,
+        "user_id": reactor_id,
+        "added": added,
+    })
+    .to_string();
+    let notification = MessageNotification {
+        event: "reaction_changed".to_string(),
+        user_id: message.recipient_user_id.map(|id| id.to_string()),
+        channel_id: message.channel_id.map(|id| id.to_string()),
+        target_user_id: None,
+        payload: Some(payload),
+    };
+    let _ = broadcaster.send(notification);
+}
+
+/// Tell a user they were mentioned. The payload names the conversation so
+/// clients can jump to it.
+pub fn notify_mention(broadcaster: &MessageBroadcaster, message: &Message, mention: &crate::domain::Mention) {
+    let payload = serde_json::json!({
+        "message_id": message.id,
+        "author_user_id": message.author_user_id,
+        "channel_id": message.channel_id,
+        "kind": mention.kind,
+    })
+    .to_string();
+    let notification = MessageNotification {
+        event: "mention".to_string(),
+        user_id: None,
+        channel_id: message.channel_id.map(|id| id.to_string()),
+        target_user_id: Some(mention.user_id.to_string()),
+        payload: Some(payload),
+    };
+    let _ = broadcaster.send(notification);
+}
+
+/// A message was pinned or unpinned in a channel.
+pub fn notify_pin_changed(
+    broadcaster: &MessageBroadcaster,
+    channel_id: uuid::Uuid,
+    message_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+    pinned: bool,
+) {
+    let payload = serde_json::json!({
+        "message_id": message_id,
+        "user_id": user_id,
+        "pinned": pinned,
+    })
+    .to_string();
+    let notification = MessageNotification {
+        event: "pin_changed".to_string(),
+        user_id: None,
+        channel_id: Some(channel_id.to_string()),
+        target_user_id: None,
+        payload: Some(payload),
+    };
+    let _ = broadcaster.send(notification);
+}
+
+/// Tell the user's other sessions that a read marker moved. The payload is
+/// the marker as returned by `POST /api/read-marker`.
+pub fn notify_read_marker_updated(
+    broadcaster: &MessageBroadcaster,
+    target_user_id: &str,
+    channel_id: Option<String>,
+    payload: &str,
+) {
+    let notification = MessageNotification {
+        event: "read_marker_updated".to_string(),
+        user_id: None,
+        channel_id,
+        target_user_id: Some(target_user_id.to_string()),
+        payload: Some(payload.to_string()),
+    };
+    let _ = broadcaster.send(notification);
+}
+
+/// Someone started or stopped typing. Channel events carry `channel_id`;
+/// DM events are targeted at the recipient. `user_id` is the typist.
+pub fn notify_typing(
+    broadcaster: &MessageBroadcaster,
+    target: crate::typing::TypingTarget,
+    user: &crate::domain::User,
+    started: bool,
+) {
+    let (channel_id, target_user_id) = match target {
+        crate::typing::TypingTarget::Channel(channel_id) => (Some(channel_id.to_string()), None),
+        crate::typing::TypingTarget::Dm { recipient } => (None, Some(recipient.to_string())),
+    };
+    let payload = serde_json::json!({
+        "user_id": user.id,
+        "username": user.username,
+        "display_name": user.display_name,
+    })
+    .to_string();
+    let notification = MessageNotification {
+        event: if started { "typing_started" } else { "typing_stopped" }.to_string(),
+        user_id: Some(user.id.to_string()),
+        channel_id,
+        target_user_id,
+        payload: Some(payload),
+    };
+    let _ = broadcaster.send(notification);
+}
+
+/// A message's delivery to a peer was confirmed or given up on. Targeted at
+/// the author; the payload is the `MessageDelivery`.
+pub fn notify_delivery_changed(
+    broadcaster: &MessageBroadcaster,
+    author_id: uuid::Uuid,
+    delivery: &crate::domain::MessageDelivery,
+) {
+    let notification = MessageNotification {
+        event: "delivery_changed".to_string(),
+        user_id: None,
+        channel_id: None,
+        target_user_id: Some(author_id.to_string()),
+        payload: serde_json::to_string(delivery).ok(),
+    };
+    let _ = broadcaster.send(notification);
+}
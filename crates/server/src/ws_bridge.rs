@@ -5,7 +5,7 @@ use axum::http::HeaderMap;
 use futures_util::StreamExt;
 use serde_json::json;
 
-use crate::api::AppState;
+use crate::{api::AppState, domain::User};
 
 async fn extract_token_from_headers(headers: &HeaderMap) -> Option<String> {
     if let Some(v) = headers.get("x-admin-token") {
@@ -37,21 +37,26 @@ pub async fn ws_handler(
 
     // Check user session first, fall back to DB token
     let user = if let Some(user_id) = state.sessions.validate_user_session(&token) {
-        match state.store.get_user_by_id(user_id).ok().flatten() {
+        match state.store.get_user_by_id(user_id).await.ok().flatten() {
             Some(u) => u,
             None => return (axum::http::StatusCode::UNAUTHORIZED, "Invalid token").into_response(),
         }
     } else {
-        match state.store.get_user_by_token(&token).ok().flatten() {
+        match state.store.get_user_by_token(&token).await.ok().flatten() {
             Some(u) => u,
             None => return (axum::http::StatusCode::UNAUTHORIZED, "Invalid token").into_response(),
         }
     };
 
-    ws.on_upgrade(move |socket| bridge(socket, state, token, user.id.to_string())).into_response()
+    ws.on_upgrade(move |socket| bridge(socket, state, token, user)).into_response()
 }
 
-async fn bridge(mut socket: WebSocket, state: AppState, token: String, user_id: String) {
+async fn bridge(mut socket: WebSocket, state: AppState, token: String, user: User) {
+    let user_id = user.id.to_string();
+    // The user stays online while the socket is open; dropping the guard on
+    // any return below records their last-seen time.
+    let _presence = crate::presence::PresenceGuard::new(&state, user.id);
+    crate::websocket::notify_presence_changed(&state.message_broadcaster);
     // Subscribe to internal broadcaster (same source as SSE)
     let mut rx = state.message_broadcaster.subscribe();
 
@@ -108,6 +113,21 @@ async fn bridge(mut socket: WebSocket, state: AppState, token: String, user_id:
                                                 .send().await;
                                         }
                                     }
+                                    "activity" => {
+                                        if state.presence.record_activity(user.id) {
+                                            crate::websocket::notify_presence_changed(&state.message_broadcaster);
+                                        }
+                                    }
+                                    "typing" => {
+                                        match serde_json::from_value::<crate::typing::TypingRequest>(obj.clone()) {
+                                            Ok(request) => {
+                                                if let Err(e) = crate::typing::update(&state, &user, request).await {
+                                                    tracing::debug!(target: "ws_bridge", "typing update rejected: {}", e);
+                                                }
+                                            }
+                                            Err(e) => tracing::debug!(target: "ws_bridge", "bad typing message: {}", e),
+                                        }
+                                    }
                                     _ => {
                                         // unknown kind - ignore or log
                                         tracing::debug!(target: "ws_bridge", "Unknown client ws message kind: {}", kind);